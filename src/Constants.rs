@@ -4,31 +4,113 @@ use std::str::FromStr;
 
 // Usamos `lazy_static` para parsear las direcciones desde string una sola vez.
 lazy_static! {
-    // --- Direcciones de Tokens Comunes (Arbitrum) ---
+    // --- Direcciones de Tokens Comunes (Arbitrum; en otras chains WETH sale de `WETH_ADDRESS`) ---
     pub static ref WETH_ADDRESS: H160 = H160::from_str("0x82af49447d8a07e3bd95bd0d56f35241523fbab1").unwrap();
     pub static ref USDC_ADDRESS: H160 = H160::from_str("0xaf88d065e77c8cC2239327C5EDb3A432268e5831").unwrap();
     pub static ref WBTC_ADDRESS: H160 = H160::from_str("0x2f2a2543B76A4166549F7aaB2e75Bef0aefC5B0f").unwrap();
 
-    // --- Direcciones de Factories V3 (Arbitrum) ---
+    // --- Direcciones de Factories V3 (Arbitrum; en otras chains, `<DEX>_FACTORY` en la configuración) ---
     pub static ref UNISWAP_V3_FACTORY: H160 = H160::from_str("0x1F98431c8aD98523631AE4a59f267346ea31F984").unwrap();
     pub static ref SUSHISWAP_V3_FACTORY: H160 = H160::from_str("0xbACEB8eC6b9355Dfc0269C18bac9d6E2Bdc29C4F").unwrap();
     pub static ref PANCAKESWAP_V3_FACTORY: H160 = H160::from_str("0x0BFbCF9fa4f9C56B0F40a671Ad40E0805A091865").unwrap();
 
-    // --- Direcciones de Quoters V2 (para simulación de swaps) ---
+    // --- Direcciones de Quoters V2 (para simulación de swaps; Arbitrum) ---
     pub static ref UNISWAP_V3_QUOTER: H160 = H160::from_str("0xb27308f9F90D607463bb33eA1BeBb41C27CE5AB6").unwrap();
     pub static ref SUSHISWAP_V3_QUOTER: H160 = H160::from_str("0xf2614A233c7C3e7f08b1F887Ba133a13f1eb2c55").unwrap();
     pub static ref PANCAKESWAP_V3_QUOTER: H160 = H160::from_str("0xFE6508f0015C778Bdcc1fB5465bA5ebE224C9912").unwrap();
 
-    // --- Direcciones de Contratos de Oráculos (Arbitrum) ---
+    // --- Multicall3 (misma dirección en Arbitrum y la mayoría de chains EVM) ---
+    pub static ref MULTICALL3_ADDRESS: H160 = H160::from_str("0xcA11bde05977b3631167028862bE2a173976CA11").unwrap();
+    // EntryPoint v0.7 de ERC-4337 (misma dirección en todas las chains)
+    pub static ref ENTRY_POINT_V07: H160 = H160::from_str("0x0000000071727De22E5E9d8BAf0edAc6f37da032").unwrap();
+
+    // --- Direcciones de Contratos de Oráculos (Arbitrum; en otras chains, `PYTH_CONTRACT`) ---
     // Contrato principal de Pyth Network
-// Contrato principal de Pyth Network
     pub static ref PYTH_ORACLE_CONTRACT: H160 = H160::from_str("0xff1f2b4adb936f69af13e454ec231792e8dc5028").unwrap();
+
+    // Predeploy `GasPriceOracle` de las chains OP Stack (misma dirección en todas).
+    pub static ref OP_GAS_PRICE_ORACLE: H160 = H160::from_str("0x420000000000000000000000000000000000000F").unwrap();
 }
 
+// Chain de las direcciones de arriba; en las demás se configuran por chain (ver `Config::dexes`).
+pub const ARBITRUM_CHAIN_ID: u64 = 42161;
+// Chains OP Stack (Optimism, Base), cuyo `GasPriceOracle` cotiza el fee de datos L1.
+pub const OP_STACK_CHAIN_IDS: [u64; 2] = [10, 8453];
+// Bloques de despliegue de las factories en Arbitrum, desde los que se buscan sus `PoolCreated`.
+pub const UNISWAP_V3_DEPLOY_BLOCK: u64 = 420;
+pub const SUSHISWAP_V3_DEPLOY_BLOCK: u64 = 19_620_263;
+pub const PANCAKESWAP_V3_DEPLOY_BLOCK: u64 = 61_748_453;
+
 // --- Parámetros por Defecto para `config.rs` ---
+pub const DEFAULT_WS_HEARTBEAT_INTERVAL_SECS: u64 = 15;
+pub const DEFAULT_WS_HEARTBEAT_TIMEOUT_SECS: u64 = 5;
+pub const DEFAULT_HEAD_DIVERGENCE_TOLERANCE_BLOCKS: u64 = 2; // diferencia de head HTTP/WS tolerada; 0 = sin comprobación
+pub const DEFAULT_MEMPOOL_WORKERS: usize = 8;
+pub const DEFAULT_MEMPOOL_QUEUE_SIZE: usize = 1024;
 pub const DEFAULT_GAS_LIMIT: u64 = 2_000_000;
+pub const DEFAULT_GAS_BASE: u64 = 350_000; // flashloan + overhead del contrato
+pub const DEFAULT_GAS_PER_HOP: u64 = 150_000; // cada swap V3 adicional
+pub const DEFAULT_GAS_ESTIMATE_CACHE_BLOCKS: u64 = 0; // 0 = desactivado (estimación en vivo siempre)
+pub const DEFAULT_ORACLE_MAX_RPS: f64 = 0.0; // lecturas de oráculo por segundo; 0 = sin límite
+pub const DEFAULT_ORACLE_BURST: u32 = 10;
+pub const DEFAULT_PARALLEL_GRID_POINTS: usize = 8; // simulaciones concurrentes de la rejilla (SEARCH_STRATEGY=parallel_grid)
+pub const DEFAULT_L1_FEE_PER_CALLDATA_BYTE_WEI: u64 = 0; // 0 = sin componente de datos L1
+pub const DEFAULT_CANARY_AMOUNT_USD: f64 = 10.0; // tamaño del trade canario de arranque
+pub const DEFAULT_SWEEP_INTERVAL_SECS: u64 = 0; // 0 = sweeper desactivado
+pub const DEFAULT_SWEEP_MIN_USD: f64 = 100.0; // saldo mínimo por token que merece el gas del barrido
 pub const DEFAULT_MIN_PROFIT_USD: f64 = 0.1;
+pub const DEFAULT_MIN_ROI_BPS: f64 = 0.0; // 0 = sin mínimo de ROI
+pub const DEFAULT_MIN_PROFIT_IMPROVEMENT: f64 = 0.0; // p. ej. 0.1 = +10% sobre el último envío; 0 = desactivado
+pub const DEFAULT_MIN_WINRATE_FOR_EXECUTION: f64 = 0.0; // 0 = desactivado
+pub const DEFAULT_MIN_ATTEMPTS_FOR_WINRATE: u64 = 10;
+pub const DEFAULT_COOLDOWN_SETTLE_BLOCKS: u64 = 0; // bloques con el precio estable en los pools de la ruta para salir del cooldown; 0 = sólo por bloques
+pub const DEFAULT_COOLDOWN_SETTLE_TOLERANCE_BPS: u32 = 5; // variación de precio que no cuenta como movimiento del pool
+pub const DEFAULT_COOLDOWN_SETTLE_MAX_WAIT_BLOCKS: u64 = 20; // espera extra máxima tras el cooldown fijo; 0 = sin tope
+pub const DEFAULT_IDENTICAL_REVERT_THRESHOLD: u64 = 3; // reverts seguidos con el mismo motivo antes de vetar la ruta; 0 = desactivado
+pub const DEFAULT_ROUTE_BLACKLIST_BLOCKS: u64 = 14_400; // duración del veto (~1h a 250ms por bloque); 0 = permanente
+pub const DEFAULT_MAX_PLAUSIBLE_EDGE_BPS: u32 = 100; // 1% de margen bruto máximo esperable por ciclo
+pub const DEFAULT_SEARCH_MIN_USD: f64 = 100.0; // límites de la búsqueda del tamaño óptimo, en USD del token de entrada
+pub const DEFAULT_SEARCH_MAX_USD: f64 = 100_000.0;
+pub const DEFAULT_SPOT_QUOTE_USD: f64 = 100.0; // notional del trade marginal para el precio spot
+pub const DEFAULT_MAX_POOLS_PER_TOKEN: usize = 75;
+pub const DEFAULT_MIN_POOL_AGE_BLOCKS: u64 = 0; // 0 = sin mínimo de antigüedad
+pub const DEFAULT_POOL_ROTATION_SLOTS: usize = 0; // 0 = sin rotación, sólo el top por TVL
+pub const DEFAULT_PATH_GENERATION_BUDGET_MS: u64 = 0; // 0 = sin límite de tiempo
+pub const DEFAULT_MAX_PRICE_IMPACT_BPS: u32 = 0; // 0 = filtro de impacto desactivado
+pub const DEFAULT_PRICE_IMPACT_REFERENCE_USD: f64 = 10_000.0;
 pub const DEFAULT_MIN_ORACLE_LAG: f64 = 0.08;
 pub const DEFAULT_MAX_ORACLE_AGE_SECS: u64 = 120;
 pub const DEFAULT_PATH_REFRESH_INTERVAL_BLOCKS: u64 = 100;
+pub const DEFAULT_PATH_REFRESH_MIN_BLOCKS: u64 = 20; // límites del intervalo adaptativo (ADAPTIVE_PATH_REFRESH)
+pub const DEFAULT_PATH_REFRESH_MAX_BLOCKS: u64 = 500;
+pub const DEFAULT_PNL_SUMMARY_INTERVAL_BLOCKS: u64 = 100; // 0 = desactivado
 pub const DEFAULT_MAX_BRIBE_PERCENT: f64 = 0.80; // 80%
+pub const DEFAULT_PRIORITY_FEE_FLOOR_PERCENTILE: f64 = 0.0; // p. ej. 50.0 = mediana; 0 = desactivado
+pub const DEFAULT_MIN_PRIORITY_FEE_WEI: u64 = 1; // nunca enviar con tip cero
+pub const DEFAULT_OUTPUT_HAIRCUT_USD: f64 = 0.0; // 0 = desactivado
+pub const DEFAULT_HOP_CAPACITY_SHARE: f64 = 0.5; // fracción del balance de salida de cada salto; 0 = desactivado
+pub const DEFAULT_BAIT_PRICE_DEVIATION_BPS: u32 = 0; // 0 = detector de cebos desactivado
+pub const DEFAULT_BAIT_LARGE_SWAP_USD: f64 = 100_000.0;
+pub const DEFAULT_MAX_ORACLE_POOL_DIVERGENCE_BPS: u32 = 0; // 0 = sin comprobación por salto
+pub const DEFAULT_BAIT_EXTRA_PROFIT_USD: f64 = 0.0; // 0 = descartar; > 0 = exigir este margen extra
+pub const DEFAULT_OWN_TRADE_LOOKBACK_BLOCKS: u64 = 0; // bloques tras un trade propio con escrutinio extra; 0 = desactivado
+pub const DEFAULT_OWN_TRADE_EXTRA_PROFIT_USD: f64 = 0.0; // 0 = descartar; > 0 = exigir este margen extra
+pub const DEFAULT_DEADLINE_BUFFER_BLOCKS: u64 = 2; // deadline = 2 bloques del tiempo de bloque observado
+pub const DEFAULT_BLOCK_TIME_MS: u64 = 12_000; // chains sin entrada en CHAIN_BLOCK_TIMES_MS
+// Tiempo de bloque por defecto (ms) por chain id: mainnet, Optimism, BSC, Polygon, Base, Arbitrum.
+pub const CHAIN_BLOCK_TIMES_MS: [(u64, u64); 6] = [(1, 12_000), (10, 2_000), (56, 3_000), (137, 2_000), (8453, 2_000), (42161, 250)];
+pub const DEFAULT_MAX_PRICE_DERIVATION_HOPS: u32 = 2;
+pub const DEFAULT_PRICE_SNAPSHOT_MAX_AGE_SECS: u64 = 300; // sólo rellena tokens no re-derivables: más de 5 min se descarta
+pub const DEFAULT_PRICE_MAP_TTL_SECS: u64 = 600; // precios arrastrados entre refrescos sin re-derivar
+pub const DEFAULT_CACHE_DRIFT_THRESHOLD: f64 = 0.1; // pools creados desde la caché / pools en caché; 0 = sin comprobación
+pub const DEFAULT_MIN_POOLS_PER_DEX: usize = 3; // por debajo, el quoter/factory del DEX probablemente no es de esta chain
+pub const DEFAULT_PUSHGATEWAY_INTERVAL_SECS: u64 = 15;
+pub const DEFAULT_MAX_INFLIGHT_BUNDLES: usize = 1;
+pub const DEFAULT_USER_OP_VERIFICATION_GAS: u64 = 150_000; // validateUserOp de una smart account ECDSA simple
+pub const DEFAULT_USER_OP_PRE_VERIFICATION_GAS: u64 = 60_000;
+pub const OPPORTUNITY_BUNDLE_SIZE: usize = 5; // oportunidades no conflictivas por bundle
+pub const DEFAULT_MAX_CONCURRENT_SENDS: usize = OPPORTUNITY_BUNDLE_SIZE; // todo el bundle a la vez
+pub const DEFAULT_MAX_ETH_CALLS_PER_BLOCK: u64 = 0; // 0 = sin límite
+pub const DEFAULT_SHADOW_PRIORITY_FEE_WEI: u64 = 1_000_000; // 0.001 gwei
+pub const DEFAULT_SHADOW_GAS_RESERVE_ETH: f64 = 0.05;
+pub const DEFAULT_FLASH_LOAN_FEE_BPS: u32 = 0; // Balancer no cobra fee por flashloan actualmente
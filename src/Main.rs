@@ -2,10 +2,17 @@
 /// Configura el runtime asíncrono de Tokio y lanza la función principal del bot.
 #[tokio::main]
 async fn main() {
-    // Llama a la función `run` de nuestra librería (lib.rs).
-    // Si `run` devuelve un error (lo que significa que el bot se detuvo por un fallo crítico),
+    // Llama a la función `run` de nuestra librería (lib.rs), o a `replay` con el subcomando
+    // `replay <archivo>` para re-ejecutar un bloque capturado.
+    // Si devuelve un error (lo que significa que el bot se detuvo por un fallo crítico),
     // lo imprimirá en el log y terminará el programa con un código de salida de error.
-    if let Err(e) = mev_bot_arbitrage_v4::run().await {
+    let args: Vec<String> = std::env::args().collect();
+    let result = match (args.get(1).map(String::as_str), args.get(2)) {
+        (Some("replay"), Some(file)) => mev_bot_arbitrage_v4::replay(file).await,
+        (Some("replay"), None) => Err(anyhow::anyhow!("Uso: replay <archivo_de_bloque_capturado>")),
+        _ => mev_bot_arbitrage_v4::run().await,
+    };
+    if let Err(e) = result {
         log::error!("La aplicación ha terminado con un error fatal: {:?}", e);
         std::process::exit(1);
     }
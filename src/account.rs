@@ -0,0 +1,356 @@
+//! Cuentas de abstracción para el envío del arbitraje: la delegación EIP-7702 de la EOA (TX de tipo 4
+//! con la autorización firmada) y las UserOperations ERC-4337 (EntryPoint v0.7) enviadas a un bundler.
+//! La llamada en sí es siempre `execute(contrato, 0, calldata)`, que construye `execution`.
+
+use crate::{config::CONFIG, execution};
+use anyhow::{anyhow, Result};
+use ethers::{
+    abi::{self, Token},
+    prelude::*,
+    types::transaction::eip2718::TypedTransaction,
+    utils::{keccak256, rlp::RlpStream},
+};
+use log::info;
+use serde_json::{json, Value};
+use std::{sync::Arc, time::Duration};
+
+abigen!(
+    IEntryPoint,
+    r#"[function getNonce(address sender, uint192 key) external view returns (uint256)]"#,
+);
+
+/// Prefijo del código de una EOA delegada con EIP-7702: `0xef0100 || delegate`.
+const DELEGATION_PREFIX: [u8; 3] = [0xef, 0x01, 0x00];
+/// Tipo de TX de EIP-7702 y prefijo (`MAGIC`) del mensaje que firma cada autorización.
+const SET_CODE_TX_TYPE: u8 = 0x04;
+const AUTHORIZATION_MAGIC: u8 = 0x05;
+/// Gas de la TX de delegación: 21k base más 25k por la autorización (PER_EMPTY_ACCOUNT_COST), con margen.
+const DELEGATION_TX_GAS: u64 = 60_000;
+
+// Espera del recibo de una UserOperation: el bundler la incluye en un bundle propio.
+const USER_OP_RECEIPT_TIMEOUT_SECS: u64 = 60;
+const USER_OP_RECEIPT_POLL_INTERVAL_MS: u64 = 500;
+
+type Client = SignerMiddleware<Provider<Http>, LocalWallet>;
+
+/// `true` si `code` es la designación de delegación EIP-7702 hacia `delegate`.
+pub fn is_delegated_to(code: &[u8], delegate: H160) -> bool {
+    code.len() == 23 && code[..3] == DELEGATION_PREFIX && code[3..] == delegate[..]
+}
+
+/// Autorización EIP-7702 firmada: la EOA delega su código en `address` mientras su nonce sea `nonce`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Authorization {
+    pub chain_id: u64,
+    pub address: H160,
+    pub nonce: U256,
+    pub y_parity: u8,
+    pub r: U256,
+    pub s: U256,
+}
+
+/// Mensaje que firma la EOA: `keccak256(0x05 || rlp([chain_id, address, nonce]))`.
+fn authorization_hash(chain_id: u64, address: H160, nonce: U256) -> H256 {
+    let mut stream = RlpStream::new_list(3);
+    stream.append(&chain_id).append(&address).append(&nonce);
+    let mut message = vec![AUTHORIZATION_MAGIC];
+    message.extend_from_slice(&stream.out());
+    H256(keccak256(message))
+}
+
+pub fn sign_authorization(wallet: &LocalWallet, chain_id: u64, delegate: H160, nonce: U256) -> Result<Authorization> {
+    let signature = wallet.sign_hash(authorization_hash(chain_id, delegate, nonce))?;
+    Ok(Authorization {
+        chain_id,
+        address: delegate,
+        nonce,
+        y_parity: (signature.v - 27) as u8,
+        r: signature.r,
+        s: signature.s,
+    })
+}
+
+/// Campos de una TX de tipo 4 sin firma: los de EIP-1559 más la lista de autorizaciones.
+fn append_set_code_fields(stream: &mut RlpStream, tx: &Eip1559TransactionRequest, to: H160, authorizations: &[Authorization]) {
+    stream
+        .append(&tx.chain_id.unwrap_or_default().as_u64())
+        .append(&tx.nonce.unwrap_or_default())
+        .append(&tx.max_priority_fee_per_gas.unwrap_or_default())
+        .append(&tx.max_fee_per_gas.unwrap_or_default())
+        .append(&tx.gas.unwrap_or_default())
+        .append(&to)
+        .append(&tx.value.unwrap_or_default())
+        .append(&tx.data.as_ref().map(|data| data.to_vec()).unwrap_or_default())
+        .append(&tx.access_list);
+    stream.begin_list(authorizations.len());
+    for auth in authorizations {
+        stream
+            .begin_list(6)
+            .append(&auth.chain_id)
+            .append(&auth.address)
+            .append(&auth.nonce)
+            .append(&auth.y_parity)
+            .append(&auth.r)
+            .append(&auth.s);
+    }
+}
+
+/// TX de tipo 4 firmada (`0x04 || rlp([...campos, y_parity, r, s])`), lista para `eth_sendRawTransaction`.
+/// `tx` debe traer nonce, gas, fees, chain id y destino.
+pub fn signed_set_code_transaction(wallet: &LocalWallet, tx: &Eip1559TransactionRequest, authorizations: &[Authorization]) -> Result<Bytes> {
+    let Some(NameOrAddress::Address(to)) = tx.to else {
+        return Err(anyhow!("La TX de tipo 4 necesita una dirección de destino"));
+    };
+    let mut unsigned = RlpStream::new_list(10);
+    append_set_code_fields(&mut unsigned, tx, to, authorizations);
+    let mut payload = vec![SET_CODE_TX_TYPE];
+    payload.extend_from_slice(&unsigned.out());
+    let signature = wallet.sign_hash(H256(keccak256(&payload)))?;
+
+    let mut signed = RlpStream::new_list(13);
+    append_set_code_fields(&mut signed, tx, to, authorizations);
+    signed.append(&((signature.v - 27) as u8)).append(&signature.r).append(&signature.s);
+    let mut raw = vec![SET_CODE_TX_TYPE];
+    raw.extend_from_slice(&signed.out());
+    Ok(raw.into())
+}
+
+/// Delega la EOA en `EIP7702_DELEGATE` si aún no lo está, con una TX de tipo 4 a sí misma que sólo lleva
+/// la autorización. A partir de ahí las TX de arbitraje son EIP-1559 normales a `execute` de la propia EOA.
+pub async fn ensure_delegated(client: &Arc<Client>) -> Result<()> {
+    let delegate = CONFIG.eip7702_delegate.ok_or_else(|| anyhow!("ACCOUNT_TYPE=7702 sin EIP7702_DELEGATE"))?;
+    let address = client.address();
+    if is_delegated_to(&client.get_code(address, None).await?, delegate) {
+        info!("EOA {address:?} ya delegada en {delegate:?} (EIP-7702).");
+        return Ok(());
+    }
+    let nonce = client.get_transaction_count(address, Some(BlockNumber::Pending.into())).await?;
+    // La EOA es a la vez emisora y autoridad: su nonce sube al validar la TX, antes de aplicar la autorización.
+    let authorization = sign_authorization(client.signer(), CONFIG.chain_id, delegate, nonce + 1)?;
+    let (max_fee_per_gas, max_priority_fee_per_gas) = client.estimate_eip1559_fees(None).await?;
+    let tx = Eip1559TransactionRequest::new()
+        .to(address)
+        .nonce(nonce)
+        .gas(DELEGATION_TX_GAS)
+        .max_fee_per_gas(max_fee_per_gas)
+        .max_priority_fee_per_gas(max_priority_fee_per_gas)
+        .chain_id(CONFIG.chain_id);
+    let raw = signed_set_code_transaction(client.signer(), &tx, &[authorization])?;
+    let (tx_hash, _) = execution::submit_raw_transaction(client, raw).await?;
+    info!("Delegando la EOA {address:?} en {delegate:?} con la TX {tx_hash:?}...");
+    execution::wait_for_receipt(client.as_ref(), tx_hash)
+        .await
+        .ok_or_else(|| anyhow!("Sin recibo de la TX de delegación {tx_hash:?}"))?;
+    if !is_delegated_to(&client.get_code(address, None).await?, delegate) {
+        return Err(anyhow!("La TX {tx_hash:?} se incluyó pero la EOA no quedó delegada en {delegate:?}"));
+    }
+    info!("EOA {address:?} delegada en {delegate:?}.");
+    Ok(())
+}
+
+/// UserOperation de EntryPoint v0.7 sin initCode ni paymaster (la cuenta ya existe y paga su gas).
+#[derive(Debug, Clone, Default)]
+pub struct UserOperation {
+    pub sender: H160,
+    pub nonce: U256,
+    pub call_data: Bytes,
+    pub call_gas_limit: U256,
+    pub verification_gas_limit: U256,
+    pub pre_verification_gas: U256,
+    pub max_fee_per_gas: U256,
+    pub max_priority_fee_per_gas: U256,
+    pub signature: Bytes,
+}
+
+/// Dos cantidades de 128 bits en una palabra (`high << 128 | low`), como `accountGasLimits` y `gasFees`.
+fn pack_u128_pair(high: U256, low: U256) -> [u8; 32] {
+    let mut word = [0u8; 32];
+    ((high << 128) | (low & U256::from(u128::MAX))).to_big_endian(&mut word);
+    word
+}
+
+impl UserOperation {
+    /// Hash que firma la cuenta: `keccak256(abi.encode(keccak256(pack(op)), entryPoint, chainId))`.
+    pub fn hash(&self, entry_point: H160, chain_id: u64) -> H256 {
+        let empty = keccak256([]);
+        let packed = abi::encode(&[
+            Token::Address(self.sender),
+            Token::Uint(self.nonce),
+            Token::FixedBytes(empty.to_vec()),
+            Token::FixedBytes(keccak256(&self.call_data).to_vec()),
+            Token::FixedBytes(pack_u128_pair(self.verification_gas_limit, self.call_gas_limit).to_vec()),
+            Token::Uint(self.pre_verification_gas),
+            Token::FixedBytes(pack_u128_pair(self.max_priority_fee_per_gas, self.max_fee_per_gas).to_vec()),
+            Token::FixedBytes(empty.to_vec()),
+        ]);
+        H256(keccak256(abi::encode(&[
+            Token::FixedBytes(keccak256(packed).to_vec()),
+            Token::Address(entry_point),
+            Token::Uint(chain_id.into()),
+        ])))
+    }
+
+    /// Formato sin empaquetar de `eth_sendUserOperation` para v0.7.
+    fn to_rpc(&self) -> Value {
+        json!({
+            "sender": self.sender,
+            "nonce": self.nonce,
+            "callData": self.call_data,
+            "callGasLimit": self.call_gas_limit,
+            "verificationGasLimit": self.verification_gas_limit,
+            "preVerificationGas": self.pre_verification_gas,
+            "maxFeePerGas": self.max_fee_per_gas,
+            "maxPriorityFeePerGas": self.max_priority_fee_per_gas,
+            "signature": self.signature,
+        })
+    }
+}
+
+/// UserOperation para `tx` (ya envuelta en `execute` de `SMART_ACCOUNT_ADDRESS`), con el gas y las fees
+/// de la TX y firmada por la wallet (firma EIP-191 del hash, la que valida una cuenta ECDSA simple).
+pub async fn build_user_operation<M: Middleware + 'static>(
+    provider: Arc<M>,
+    wallet: &LocalWallet,
+    tx: &TypedTransaction,
+) -> Result<UserOperation> {
+    let sender = CONFIG.smart_account_address.ok_or_else(|| anyhow!("ACCOUNT_TYPE=erc4337 sin SMART_ACCOUNT_ADDRESS"))?;
+    let nonce = IEntryPoint::new(CONFIG.entry_point, provider).get_nonce(sender, U256::zero()).call().await?;
+    let (max_fee_per_gas, max_priority_fee_per_gas) = match tx.as_eip1559_ref() {
+        Some(eip1559) => (eip1559.max_fee_per_gas.unwrap_or_default(), eip1559.max_priority_fee_per_gas.unwrap_or_default()),
+        None => (tx.gas_price().unwrap_or_default(), tx.gas_price().unwrap_or_default()),
+    };
+    let mut op = UserOperation {
+        sender,
+        nonce,
+        call_data: tx.data().cloned().unwrap_or_default(),
+        call_gas_limit: tx.gas().copied().unwrap_or_default(),
+        verification_gas_limit: CONFIG.user_op_verification_gas.into(),
+        pre_verification_gas: CONFIG.user_op_pre_verification_gas.into(),
+        max_fee_per_gas,
+        max_priority_fee_per_gas,
+        signature: Bytes::new(),
+    };
+    let hash = op.hash(CONFIG.entry_point, CONFIG.chain_id);
+    op.signature = wallet.sign_message(hash.as_bytes()).await?.to_vec().into();
+    Ok(op)
+}
+
+/// Envía `tx` como UserOperation al bundler de `BUNDLER_URL` y espera a que la incluya: devuelve el hash
+/// de la TX del bundle, que es la que se sigue igual que una TX propia.
+pub async fn send_user_operation(client: &Arc<Client>, tx: &TypedTransaction) -> Result<TxHash> {
+    let bundler_url = CONFIG.bundler_url.as_deref().ok_or_else(|| anyhow!("ACCOUNT_TYPE=erc4337 sin BUNDLER_URL"))?;
+    let bundler = Provider::<Http>::try_from(bundler_url)?;
+    let op = build_user_operation(Arc::new(client.provider().clone()), client.signer(), tx).await?;
+    let op_hash: H256 = bundler.request("eth_sendUserOperation", (op.to_rpc(), CONFIG.entry_point)).await?;
+    info!("UserOperation {op_hash:?} enviada al bundler (nonce {}).", op.nonce);
+    let polls = USER_OP_RECEIPT_TIMEOUT_SECS * 1000 / USER_OP_RECEIPT_POLL_INTERVAL_MS;
+    for _ in 0..polls {
+        let receipt: Option<Value> = bundler.request("eth_getUserOperationReceipt", [op_hash]).await?;
+        if let Some(tx_hash) = receipt.and_then(|r| serde_json::from_value(r["receipt"]["transactionHash"].clone()).ok()) {
+            return Ok(tx_hash);
+        }
+        tokio::time::sleep(Duration::from_millis(USER_OP_RECEIPT_POLL_INTERVAL_MS)).await;
+    }
+    Err(anyhow!("UserOperation {op_hash:?} sin incluir tras {USER_OP_RECEIPT_TIMEOUT_SECS}s"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ethers::utils::rlp::Rlp;
+
+    fn wallet() -> LocalWallet {
+        "4c0883a69102937d6231471b5dbb6204fe5129617082792ae468d01a3f362318".parse().unwrap()
+    }
+
+    fn delegate() -> H160 {
+        H160::from_low_u64_be(0x7702)
+    }
+
+    #[test]
+    fn delegation_code_matches_only_the_configured_delegate() {
+        let mut code = DELEGATION_PREFIX.to_vec();
+        code.extend_from_slice(delegate().as_bytes());
+        assert!(is_delegated_to(&code, delegate()));
+        assert!(!is_delegated_to(&code, H160::from_low_u64_be(1)));
+        assert!(!is_delegated_to(&[], delegate()));
+        // Una smart account con bytecode propio no es una delegación.
+        assert!(!is_delegated_to(&[0x60, 0x80, 0x60, 0x40], delegate()));
+    }
+
+    #[test]
+    fn authorization_recovers_to_the_signing_eoa() {
+        let wallet = wallet();
+        let auth = sign_authorization(&wallet, 42161, delegate(), 8.into()).unwrap();
+        assert_eq!((auth.chain_id, auth.address, auth.nonce), (42161, delegate(), 8.into()));
+        assert!(auth.y_parity <= 1);
+        let signature = Signature { r: auth.r, s: auth.s, v: auth.y_parity as u64 + 27 };
+        let signer = signature.recover(RecoveryMessage::Hash(authorization_hash(42161, delegate(), 8.into()))).unwrap();
+        assert_eq!(signer, wallet.address());
+    }
+
+    #[test]
+    fn set_code_transaction_carries_the_authorization_and_a_valid_signature() {
+        let wallet = wallet();
+        let auth = sign_authorization(&wallet, 42161, delegate(), 4.into()).unwrap();
+        let tx = Eip1559TransactionRequest::new()
+            .to(wallet.address())
+            .nonce(3)
+            .gas(DELEGATION_TX_GAS)
+            .max_fee_per_gas(2_000_000_000u64)
+            .max_priority_fee_per_gas(1_000_000u64)
+            .chain_id(42161);
+        let raw = signed_set_code_transaction(&wallet, &tx, std::slice::from_ref(&auth)).unwrap();
+        assert_eq!(raw[0], SET_CODE_TX_TYPE);
+
+        let rlp = Rlp::new(&raw[1..]);
+        assert_eq!(rlp.item_count().unwrap(), 13);
+        assert_eq!(rlp.val_at::<u64>(0).unwrap(), 42161);
+        assert_eq!(rlp.val_at::<U256>(1).unwrap(), 3.into());
+        assert_eq!(rlp.val_at::<H160>(5).unwrap(), wallet.address());
+        let authorization = rlp.at(9).unwrap();
+        assert_eq!(authorization.item_count().unwrap(), 1);
+        assert_eq!(authorization.at(0).unwrap().val_at::<H160>(1).unwrap(), delegate());
+        assert_eq!(authorization.at(0).unwrap().val_at::<U256>(2).unwrap(), auth.nonce);
+
+        // La firma es sobre `0x04 || rlp(campos sin firma)`.
+        let mut unsigned = RlpStream::new_list(10);
+        append_set_code_fields(&mut unsigned, &tx, wallet.address(), &[auth]);
+        let mut payload = vec![SET_CODE_TX_TYPE];
+        payload.extend_from_slice(&unsigned.out());
+        let signature = Signature {
+            v: rlp.val_at::<u64>(10).unwrap() + 27,
+            r: rlp.val_at(11).unwrap(),
+            s: rlp.val_at(12).unwrap(),
+        };
+        assert_eq!(signature.recover(RecoveryMessage::Hash(H256(keccak256(&payload)))).unwrap(), wallet.address());
+    }
+
+    #[test]
+    fn user_operation_hash_binds_gas_fees_entry_point_and_chain() {
+        let op = UserOperation {
+            sender: H160::from_low_u64_be(0x4337),
+            nonce: 1.into(),
+            call_data: vec![0xb6, 0x1d, 0x27, 0xf6].into(),
+            call_gas_limit: 700_000.into(),
+            verification_gas_limit: 150_000.into(),
+            pre_verification_gas: 60_000.into(),
+            max_fee_per_gas: 20_000_000.into(),
+            max_priority_fee_per_gas: 1_000_000.into(),
+            signature: Bytes::new(),
+        };
+        let entry_point = H160::from_low_u64_be(0xe7);
+        let hash = op.hash(entry_point, 42161);
+        assert_ne!(hash, op.hash(entry_point, 1));
+        assert_ne!(hash, op.hash(H160::from_low_u64_be(0xe8), 42161));
+        assert_ne!(hash, UserOperation { call_gas_limit: 700_001.into(), ..op.clone() }.hash(entry_point, 42161));
+        assert_ne!(hash, UserOperation { max_priority_fee_per_gas: 2_000_000.into(), ..op.clone() }.hash(entry_point, 42161));
+        // La firma no forma parte del hash.
+        assert_eq!(hash, UserOperation { signature: vec![1u8; 65].into(), ..op }.hash(entry_point, 42161));
+
+        // `accountGasLimits` = verificationGasLimit en los 16 bytes altos y callGasLimit en los bajos.
+        let word = pack_u128_pair(150_000.into(), 700_000.into());
+        assert_eq!(U256::from_big_endian(&word[..16]), 150_000.into());
+        assert_eq!(U256::from_big_endian(&word[16..]), 700_000.into());
+    }
+}
@@ -0,0 +1,100 @@
+use crate::{instance, strategy};
+use log::{info, warn};
+use std::net::SocketAddr;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+
+// Tope de la cabecera que se lee de cada petición; el cuerpo no se usa.
+const MAX_REQUEST_HEAD_BYTES: usize = 8 * 1024;
+
+/// Estado HTTP y cuerpo de la respuesta a la línea de petición (`POST /refresh HTTP/1.1`).
+fn route(request_line: &str) -> (&'static str, &'static str) {
+    let mut parts = request_line.split_whitespace();
+    match (parts.next(), parts.next()) {
+        (Some("POST"), Some("/refresh")) => {
+            info!("Refresco de pools y rutas pedido desde el servidor de control: se hará en el próximo bloque.");
+            strategy::request_refresh();
+            ("202 Accepted", "refresco programado para el próximo bloque\n")
+        }
+        (Some(_), Some("/refresh")) => ("405 Method Not Allowed", "usa POST /refresh\n"),
+        _ => ("404 Not Found", "ruta desconocida\n"),
+    }
+}
+
+async fn handle(mut socket: TcpStream) -> std::io::Result<()> {
+    let mut head = Vec::new();
+    let mut chunk = [0u8; 1024];
+    while !head.windows(2).any(|w| w == b"\r\n") && head.len() < MAX_REQUEST_HEAD_BYTES {
+        let read = socket.read(&mut chunk).await?;
+        if read == 0 { break; }
+        head.extend_from_slice(&chunk[..read]);
+    }
+    let request = String::from_utf8_lossy(&head);
+    let (status, body) = route(request.lines().next().unwrap_or_default());
+    let response = format!(
+        "HTTP/1.1 {status}\r\ncontent-type: text/plain; charset=utf-8\r\ncontent-length: {}\r\nconnection: close\r\n\r\n{body}",
+        body.len()
+    );
+    socket.write_all(response.as_bytes()).await
+}
+
+/// Atiende las peticiones de `listener` para la instancia actual. Cada conexión hereda la instancia,
+/// así que `POST /refresh` refresca sólo la chain que escucha en ese puerto.
+pub async fn serve(listener: TcpListener) {
+    loop {
+        match listener.accept().await {
+            Ok((socket, peer)) => {
+                instance::spawn(async move {
+                    if let Err(e) = handle(socket).await {
+                        warn!("Petición de control de {peer} fallida: {e}");
+                    }
+                });
+            }
+            Err(e) => warn!("El servidor de control no pudo aceptar una conexión: {e}"),
+        }
+    }
+}
+
+/// Abre el servidor de control en `addr` (ver `CONTROL_ADDR`) y lo atiende indefinidamente.
+pub async fn run_control_server(addr: SocketAddr) -> anyhow::Result<()> {
+    let listener = TcpListener::bind(addr).await?;
+    info!("Servidor de control escuchando en {addr} (POST /refresh).");
+    serve(listener).await;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::instance::with_test_config;
+
+    async fn send(addr: SocketAddr, request_line: &str) -> String {
+        let mut socket = TcpStream::connect(addr).await.unwrap();
+        socket.write_all(format!("{request_line}\r\nhost: localhost\r\n\r\n").as_bytes()).await.unwrap();
+        let mut response = String::new();
+        socket.read_to_string(&mut response).await.unwrap();
+        response
+    }
+
+    #[tokio::test]
+    async fn post_refresh_forces_a_refresh_on_the_next_block() {
+        with_test_config(|_| {}, async {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let addr = listener.local_addr().unwrap();
+            instance::spawn(serve(listener));
+
+            // Sin petición, el bloque siguiente al último refresco no refresca.
+            assert_eq!(strategy::take_refresh_due(100, 101, 50), None);
+            assert!(send(addr, "GET /refresh HTTP/1.1").await.starts_with("HTTP/1.1 405"));
+            assert!(send(addr, "POST /pause HTTP/1.1").await.starts_with("HTTP/1.1 404"));
+            assert_eq!(strategy::take_refresh_due(100, 101, 50), None);
+
+            assert!(send(addr, "POST /refresh HTTP/1.1").await.starts_with("HTTP/1.1 202"));
+            assert_eq!(strategy::take_refresh_due(100, 101, 50), Some(true));
+            // La petición se consume: el siguiente bloque vuelve al intervalo.
+            assert_eq!(strategy::take_refresh_due(100, 102, 50), None);
+            assert_eq!(strategy::take_refresh_due(100, 150, 50), Some(false));
+        })
+        .await;
+    }
+}
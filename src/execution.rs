@@ -1,19 +1,113 @@
 use crate::{
-    config::CONFIG,
-    constants::WETH_ADDRESS,
-    optimization::ArbitrageOpportunity,
+    config::{AccountType, ApprovalTokenPolicy, EncodingVersion, TradeRecordSink, CONFIG},
+    instance::{self, InstanceLocal},
+    optimization::{ArbitrageOpportunity, FlashLoanLeg, ROUTE_STATS},
     oracle::OracleMap,
+    multi::IERC20,
     paths::ArbPath,
     provider,
 };
 use anyhow::{anyhow, Error, Result};
 use chrono::Local;
 use ethers::{prelude::*, types::transaction::eip2718::TypedTransaction, abi::Token};
-use log::{error, info, warn};
-use std::sync::Arc;
-use tokio::task::JoinSet;
+use lazy_static::lazy_static;
+use log::{debug, error, info, warn};
+use once_cell::sync::OnceCell;
+use prometheus::{register_gauge_vec, register_histogram_vec, register_int_counter_vec, GaugeVec, HistogramVec, IntCounterVec};
+use serde::Serialize;
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+use tokio::{
+    io::AsyncWriteExt,
+    sync::{mpsc, Semaphore},
+    task::JoinSet,
+};
 
 abigen!(IArbitrageBot, "./abi/ArbitrageBotV4_abi.json");
+abigen!(
+    IApprovalReset,
+    r#"[function supportsApprovalReset() external view returns (bool)]"#,
+);
+abigen!(
+    IMinProfitGuard,
+    r#"[function minProfit() external view returns (uint256)]"#,
+);
+abigen!(
+    ISmartAccount,
+    r#"[function execute(address target, uint256 value, bytes data) external payable]"#,
+);
+
+lazy_static! {
+    static ref SHADOW_INCLUDED: IntCounterVec = register_int_counter_vec!("shadow_trades_included_total", "TX en modo sombra incluidas con bribe bajo", &["chain"]).unwrap();
+    static ref SHADOW_MISSED: IntCounterVec = register_int_counter_vec!("shadow_trades_missed_total", "TX en modo sombra no incluidas", &["chain"]).unwrap();
+    static ref REALIZED_SLIPPAGE_BPS: HistogramVec = register_histogram_vec!(
+        "realized_slippage_bps", "Slippage realizado vs expected_output (bps)", &["chain"],
+        vec![0.0, 1.0, 2.0, 5.0, 10.0, 20.0, 50.0, 100.0]
+    ).unwrap();
+    static ref NONCE_GAPS_FILLED: IntCounterVec = register_int_counter_vec!("nonce_gaps_filled_total", "Huecos de nonce rellenados con TX de cancelación", &["chain"]).unwrap();
+    static ref REALIZED_PNL_USD: GaugeVec = register_gauge_vec!("realized_pnl_usd", "PnL realizado acumulado según los recibos (USD)", &["chain"]).unwrap();
+    static ref SIMULATED_PNL_USD: GaugeVec = register_gauge_vec!("simulated_pnl_usd", "Profit simulado acumulado de los trades con recibo exitoso (USD)", &["chain"]).unwrap();
+    static ref REORG_CANCELLATIONS: IntCounterVec = register_int_counter_vec!("reorg_cancellations_total", "TX en vuelo canceladas por un reorg de su bloque", &["chain"]).unwrap();
+    static ref GAS_SPENT_ETH: GaugeVec = register_gauge_vec!("gas_spent_eth", "Gas pagado por los trades con recibo, incluidos los revertidos (ETH)", &["chain"]).unwrap();
+    static ref PRIVATE_ONLY_SKIPPED: IntCounterVec = register_int_counter_vec!("private_only_skipped_total", "TX no enviadas por falta de relay privado con PRIVATE_ONLY", &["chain"]).unwrap();
+}
+
+// TX enviadas cuyo recibo aún no se ha resuelto, con el bloque sobre el que se construyeron.
+static INFLIGHT_TXS: InstanceLocal<Mutex<HashMap<TxHash, InflightTx>>> = InstanceLocal::new(|| Mutex::new(HashMap::new()));
+// Relay privado de envío (`PRIVATE_RPC_URL`); las lecturas siguen yendo a los nodos habituales.
+static PRIVATE_PROVIDER: InstanceLocal<Option<Provider<Http>>> = InstanceLocal::new(|| {
+    CONFIG.private_rpc_url.as_deref().and_then(|url| Provider::<Http>::try_from(url).ok())
+});
+// Próximo nonce a asignar a una TX de arbitraje; `None` hasta la primera asignación.
+static NEXT_NONCE: InstanceLocal<Mutex<Option<U256>>> = InstanceLocal::new(|| Mutex::new(None));
+
+// Tip de las TX de cancelación que rellenan huecos de nonce.
+const CANCEL_PRIORITY_FEE_GWEI: u64 = 3;
+// Fees del reemplazo en % de los de la TX pendiente (los nodos exigen al menos +10%).
+const REPLACEMENT_FEE_BUMP_PERCENT: u64 = 113;
+
+/// Nonces que asignó un bundle a sus TX y los que llegaron a enviarse.
+#[derive(Debug, Default)]
+pub struct BundleNonces {
+    allocated: Vec<U256>,
+    sent: Vec<U256>,
+}
+
+#[derive(Debug, Clone)]
+struct InflightTx {
+    block_number: u64,
+    path_key: String,
+}
+
+static LAST_BLOCK_TIMESTAMP: InstanceLocal<AtomicU64> = InstanceLocal::new(|| AtomicU64::new(0));
+static BLOCK_TIME_MS: InstanceLocal<AtomicU64> = InstanceLocal::new(|| AtomicU64::new(0));
+
+// Bloques recientes consultados en `eth_feeHistory` para el piso del priority fee.
+const PRIORITY_FEE_HISTORY_BLOCKS: u64 = 10;
+// Piso del priority fee (wei) según el percentil configurado de los bloques recientes; 0 = sin datos.
+static PRIORITY_FEE_FLOOR_WEI: InstanceLocal<AtomicU64> = InstanceLocal::new(|| AtomicU64::new(0));
+
+// Fracción máxima del profit neto que puede consumir el colchón absoluto sobre `expected_output`.
+const MAX_HAIRCUT_PROFIT_SHARE: f64 = 0.25;
+
+// Divergencia relativa entre profit simulado y realizado a partir de la cual se avisa.
+const PROFIT_DIVERGENCE_WARN_RATIO: f64 = 0.5;
+
+// Tiempo máximo que el tracker de recibos espera a que una TX sea minada.
+const RECEIPT_TIMEOUT_SECS: u64 = 60;
+const RECEIPT_POLL_INTERVAL_MS: u64 = 500;
+
+// Tiempo máximo de espera por el recibo de una TX en modo sombra.
+const SHADOW_RECEIPT_TIMEOUT_SECS: u64 = 60;
+
+// Rutas que el canario cotiza, en orden, buscando una que cubra el repago del flashloan.
+const CANARY_CANDIDATE_PATHS: usize = 20;
 
 fn generate_session_id() -> [u8; 32] {
     let mut bytes = [0u8; 32];
@@ -26,86 +120,697 @@ fn calculate_amount_out_min(expected_amount: U256, slippage_bps: u32) -> U256 {
     let slippage = U256::from(slippage_bps);
     expected_amount * (basis_points - slippage) / basis_points
 }
-fn deadline_from_now_aggressive() -> U256 {
-    U256::from(Local::now().timestamp() as u64 + 25)
+/// Resta a `expected_output` un colchón fijo de `output_haircut_usd` antes de aplicar el slippage,
+/// acotado a `MAX_HAIRCUT_PROFIT_SHARE` del profit neto para no volver el trade no rentable.
+fn apply_output_haircut(expected_output: U256, decimals: u8, token_price_usd: f64, net_profit_usd: f64) -> U256 {
+    if CONFIG.output_haircut_usd <= 0.0 || token_price_usd <= 0.0 { return expected_output; }
+    let haircut_usd = CONFIG.output_haircut_usd.min(net_profit_usd.max(0.0) * MAX_HAIRCUT_PROFIT_SHARE);
+    let haircut_units = U256::from((haircut_usd / token_price_usd * 10f64.powi(decimals as i32)) as u128);
+    expected_output.saturating_sub(haircut_units)
+}
+/// Monto que el contrato debe devolver al vault: principal + fee del flashloan.
+fn flash_loan_repayment(amount_in: U256) -> U256 {
+    let basis_points = U256::from(10_000);
+    amount_in * (basis_points + U256::from(CONFIG.flash_loan_fee_bps)) / basis_points
+}
+/// Invariante del flashloan: incluso en el peor caso permitido por el slippage, el output debe cubrir
+/// el repago. Si no lo cubre devuelve `(amount_out_min, repago)`.
+fn repayment_shortfall(final_output: U256, slippage_bps: u32, loan_amount: U256) -> Option<(U256, U256)> {
+    let amount_out_min = calculate_amount_out_min(final_output, slippage_bps);
+    let repayment = flash_loan_repayment(loan_amount);
+    (amount_out_min < repayment).then_some((amount_out_min, repayment))
+}
+/// Registra el timestamp de cada bloque nuevo para estimar el tiempo de bloque de la chain (media móvil).
+pub fn observe_block_timestamp(timestamp: u64) {
+    let previous = LAST_BLOCK_TIMESTAMP.swap(timestamp, Ordering::Relaxed);
+    if previous == 0 || timestamp < previous { return; }
+    let interval_ms = (timestamp - previous) * 1000;
+    let current = BLOCK_TIME_MS.load(Ordering::Relaxed);
+    let updated = if current == 0 { interval_ms } else { (current * 4 + interval_ms) / 5 };
+    BLOCK_TIME_MS.store(updated, Ordering::Relaxed);
+}
+
+/// Siembra la media móvil con el tiempo de bloque medido al arrancar, si aún no hay observaciones.
+pub fn seed_block_time_ms(block_time_ms: u64) {
+    let _ = BLOCK_TIME_MS.compare_exchange(0, block_time_ms, Ordering::Relaxed, Ordering::Relaxed);
+}
+
+/// Tiempo de bloque vigente: el observado en la chain o, sin observaciones, `block_time_ms` de la configuración.
+pub fn block_time_ms() -> u64 {
+    match BLOCK_TIME_MS.load(Ordering::Relaxed) {
+        0 => CONFIG.block_time_ms,
+        observed => observed,
+    }
+}
+
+/// Actualiza el piso del priority fee con la mediana, sobre los últimos bloques, del percentil
+/// `priority_fee_floor_percentile` de los tips incluidos (`eth_feeHistory`).
+pub async fn refresh_priority_fee_floor<M: Middleware>(provider: &M) {
+    if CONFIG.priority_fee_floor_percentile <= 0.0 { return; }
+    let history = match provider
+        .fee_history(PRIORITY_FEE_HISTORY_BLOCKS, BlockNumber::Latest, &[CONFIG.priority_fee_floor_percentile])
+        .await
+    {
+        Ok(history) => history,
+        Err(e) => {
+            warn!("No se pudo leer eth_feeHistory para el piso del priority fee: {e}");
+            return;
+        }
+    };
+    PRIORITY_FEE_FLOOR_WEI.store(priority_fee_floor_from_rewards(&history.reward), Ordering::Relaxed);
+}
+
+/// Mediana por bloque del percentil pedido; bloques sin recompensas no cuentan.
+fn priority_fee_floor_from_rewards(rewards: &[Vec<U256>]) -> u64 {
+    let mut per_block: Vec<u64> = rewards
+        .iter()
+        .filter_map(|r| r.first())
+        .map(|fee| fee.min(&U256::from(u64::MAX)).as_u64())
+        .collect();
+    if per_block.is_empty() { return 0; }
+    per_block.sort_unstable();
+    per_block[per_block.len() / 2]
+}
+
+/// Tip por gas del bribe, en wei directamente: pasar por gwei enteros truncaría los bribes pequeños a un tip cero.
+fn bribe_tip_wei(bribe_in_eth: f64) -> u128 {
+    (bribe_in_eth * 1e18) as u128
+}
+
+/// Eleva `priority_fee_wei` al piso (el mayor entre `min_priority_fee_wei` y el de inclusión reciente)
+/// si el presupuesto de bribe lo permite. `Err` si ni con todo el presupuesto se alcanza: la TX no se incluiría.
+fn apply_priority_fee_floor(priority_fee_wei: u128, budget_fee_wei: u128, route_key: &str) -> Result<u128> {
+    let recent_floor_wei = if CONFIG.priority_fee_floor_percentile > 0.0 { PRIORITY_FEE_FLOOR_WEI.load(Ordering::Relaxed) } else { 0 };
+    let floor_wei = recent_floor_wei.max(CONFIG.min_priority_fee_wei) as u128;
+    if priority_fee_wei >= floor_wei { return Ok(priority_fee_wei); }
+    if budget_fee_wei < floor_wei {
+        return Err(anyhow!(
+            "el presupuesto de bribe ({budget_fee_wei} wei) no alcanza el priority fee mínimo ({floor_wei} wei)"
+        ));
+    }
+    info!(
+        "Ruta {route_key}: priority fee elevado al piso ({}): {} -> {} wei",
+        if recent_floor_wei as u128 == floor_wei { format!("inclusión p{}", CONFIG.priority_fee_floor_percentile) } else { "MIN_PRIORITY_FEE_WEI".to_string() },
+        priority_fee_wei, floor_wei
+    );
+    Ok(floor_wei)
+}
+
+/// Margen del deadline: `deadline_buffer_secs` si está fijado; si no, `deadline_buffer_blocks` bloques
+/// del tiempo de bloque vigente (mínimo 1s).
+fn deadline_buffer_secs() -> u64 {
+    if let Some(secs) = CONFIG.deadline_buffer_secs { return secs; }
+    (CONFIG.deadline_buffer_blocks * block_time_ms()).div_ceil(1000).max(1)
+}
+/// Deadline de la TX: el timestamp del último bloque más el margen. El reloj local sólo se usa antes
+/// de ver el primer bloque (puede ir desfasado respecto a la chain).
+fn deadline_from_block() -> U256 {
+    let block_timestamp = match LAST_BLOCK_TIMESTAMP.load(Ordering::Relaxed) {
+        0 => Local::now().timestamp() as u64,
+        timestamp => timestamp,
+    };
+    U256::from(block_timestamp + deadline_buffer_secs())
 }
+// Bytes de la TX firmada fuera del calldata (tipo, chain id, nonce, fees, gas, destino, valor y firma),
+// que también se publican en L1.
+const SIGNED_TX_ENVELOPE_BYTES: usize = 110;
+
+/// Bytes que publica en L1 la TX de arbitraje con `user_data`: la llamada a `startFlashloanArbitrage`,
+/// envuelta en `execute` de la cuenta si no se opera desde una EOA, más el sobre de la TX firmada.
+pub fn arb_tx_data_len(user_data: &Bytes) -> usize {
+    // `startFlashloanArbitrage` y `execute` comparten la forma `(address, uint256, bytes)`.
+    let call_len = |data_len: usize| {
+        4 + ethers::abi::encode(&[Token::Address(H160::zero()), Token::Uint(U256::zero()), Token::Bytes(vec![0; data_len])]).len()
+    };
+    let arb_call_len = call_len(user_data.len());
+    let calldata_len = match CONFIG.account_type {
+        AccountType::Eoa => arb_call_len,
+        AccountType::SmartAccount | AccountType::Eip7702 | AccountType::Erc4337 => call_len(arb_call_len),
+    };
+    calldata_len + SIGNED_TX_ENVELOPE_BYTES
+}
+
+/// Codifica la ruta para el contrato. Con `leg` (préstamo en otro token) la ruta se rodea con los
+/// swaps préstamo -> token_a y token_a -> préstamo, y `expected_output` está en el token prestado.
+/// El layout del struct sigue `ENCODING_VERSION`: v4 `(path, sessionId, deadline, amountOutMin)`;
+/// v5 añade `(flashLoanSource, minProfit)`, con `min_profit` en unidades del token prestado.
 pub fn encode_arb_data(
-    path: &ArbPath, expected_output: U256, slippage_bps: u32,
+    path: &ArbPath, expected_output: U256, slippage_bps: u32, leg: Option<&FlashLoanLeg>, min_profit: U256,
 ) -> Result<Bytes> {
     let mut path_bytes = Vec::new();
+    if let Some(leg) = leg {
+        path_bytes.extend_from_slice(leg.token.as_bytes());
+        path_bytes.extend_from_slice(&leg.fee.to_be_bytes()[1..]);
+    }
     path_bytes.extend_from_slice(path.token_a.as_bytes());
     path_bytes.extend_from_slice(&path.pool_1.fee.to_be_bytes()[1..]);
     path_bytes.extend_from_slice(path.token_b.as_bytes());
     path_bytes.extend_from_slice(&path.pool_2.fee.to_be_bytes()[1..]);
     path_bytes.extend_from_slice(path.token_c.as_bytes());
+    if let Some(leg) = leg {
+        // El cierre del ciclo se explicita para que el swap de vuelta parta de token_a.
+        path_bytes.extend_from_slice(&path.pool_3.fee.to_be_bytes()[1..]);
+        path_bytes.extend_from_slice(path.token_a.as_bytes());
+        path_bytes.extend_from_slice(&leg.fee.to_be_bytes()[1..]);
+        path_bytes.extend_from_slice(leg.token.as_bytes());
+    }
     let amount_out_min = calculate_amount_out_min(expected_output, slippage_bps);
-    let arb_data_tuple = Token::Tuple(vec![
+    let mut fields = vec![
         Token::Bytes(path_bytes),
         Token::FixedBytes(generate_session_id().to_vec()),
-        Token::Uint(deadline_from_now_aggressive()),
+        Token::Uint(deadline_from_block()),
         Token::Uint(amount_out_min),
-    ]);
-    Ok(ethers::abi::encode(&[arb_data_tuple]).into())
+    ];
+    if CONFIG.encoding_version == EncodingVersion::V5 {
+        fields.push(Token::Address(CONFIG.balancer_vault));
+        fields.push(Token::Uint(min_profit));
+    }
+    Ok(ethers::abi::encode(&[Token::Tuple(fields)]).into())
 }
 pub async fn execute_arbitrage_bundle(
     client: Arc<SignerMiddleware<Provider<Http>, LocalWallet>>,
     opportunities: Vec<ArbitrageOpportunity>,
     base_fee: U256,
+    block_number: u64,
 ) -> Vec<Result<(TxHash, String), (anyhow::Error, String)>> {
     info!(" Ejecutando bundle con {} oportunidades...", opportunities.len());
     let mut set = JoinSet::new();
+    // Limita los envíos simultáneos al nodo de ejecución (cada uno con sus reintentos y RPCs).
+    let send_limit = Arc::new(Semaphore::new(CONFIG.max_concurrent_sends.max(1)));
+    let nonces = Arc::new(Mutex::new(BundleNonces::default()));
     for opp in opportunities {
         let client_clone = client.clone();
         let path_key = opp.path.key();
-        set.spawn(async move {
-            match execute_single_transaction(client_clone, opp, base_fee).await {
-                Ok(tx_hash) => Ok((tx_hash, path_key)),
+        let send_limit = send_limit.clone();
+        let nonces = nonces.clone();
+        set.spawn(instance::in_current(async move {
+            let tracked_opp = opp.clone();
+            let permit = send_limit.acquire_owned().await;
+            let result = execute_single_transaction(client_clone.clone(), opp, base_fee, block_number, &nonces).await;
+            drop(permit);
+            match result {
+                Ok(tx_hash) => {
+                    crate::mark_opportunity_sent(block_number, &tracked_opp.path);
+                    crate::optimization::record_submission(&tracked_opp, block_number);
+                    register_inflight_tx(tx_hash, block_number, path_key.clone());
+                    instance::spawn(track_execution_receipt(client_clone, tx_hash, tracked_opp));
+                    Ok((tx_hash, path_key))
+                }
                 Err(e) => Err((e, path_key)),
             }
-        });
+        }));
     }
     let mut results = Vec::new();
     while let Some(res) = set.join_next().await {
         if let Ok(result) = res { results.push(result); }
     }
+    // Un nonce asignado cuyo envío falló deja un hueco que bloquea a las TX con nonces superiores.
+    let nonces = std::mem::take(&mut *nonces.lock().unwrap());
+    if let Err(e) = fill_nonce_gaps(&client, &nonces, base_fee).await {
+        warn!("No se pudieron rellenar los huecos de nonce tras el bundle: {e:?}");
+    }
     results
 }
+
+/// Recalcula el profit sustituyendo el gas modelado (`modeled_gas`) por el de la simulación previa
+/// al envío; `Err` si deja de superar el profit mínimo.
+fn check_profit_with_simulated_gas(opp: &ArbitrageOpportunity, simulated_gas: U256, modeled_gas: u64, base_fee: U256) -> Result<()> {
+    let wei_to_usd = |gas: U256| crate::math::to_usd(gas.saturating_mul(base_fee), CONFIG.weth_address, opp.eth_price_usd, 18);
+    let adjusted_profit_usd = opp.net_profit_usd + wei_to_usd(U256::from(modeled_gas)) - wei_to_usd(simulated_gas);
+    debug!(
+        "Ruta {}: gas simulado {} vs modelado {} -> profit ${:.2} (antes ${:.2})",
+        opp.path.key(), simulated_gas, modeled_gas, adjusted_profit_usd, opp.net_profit_usd
+    );
+    if adjusted_profit_usd <= crate::optimization::min_profit_usd() {
+        return Err(anyhow!(
+            "con el gas simulado ({simulated_gas}) el profit cae a ${adjusted_profit_usd:.2}, bajo el mínimo"
+        ));
+    }
+    Ok(())
+}
+
+/// Gas límite de la TX de arbitraje. Con `PRESEND_GAS_SIMULATION` y relay privado, el `gasUsed` de su
+/// `eth_callBundle` (la TX real, flashloan incluido, contra el estado en que se ejecutará) es el gas
+/// autoritativo: rehace con él el check de profit y fija el límite. Sin simulación, `eth_estimateGas`.
+#[allow(clippy::too_many_arguments)]
+async fn presend_gas_limit<P: JsonRpcClient>(
+    client: &SignerMiddleware<Provider<Http>, LocalWallet>,
+    relay: Option<&Provider<P>>,
+    call: &ContractCall<SignerMiddleware<Provider<Http>, LocalWallet>, ()>,
+    tx: &TypedTransaction,
+    opp: &ArbitrageOpportunity,
+    block_number: u64,
+    base_fee: U256,
+    fallback_gas: u64,
+) -> Result<U256> {
+    let simulated_gas = match relay {
+        Some(relay) if CONFIG.presend_gas_simulation => {
+            // Holgura de gas para que la simulación no se quede corta; el nonce es el pendiente de la cuenta.
+            let mut sim_tx = tx.clone();
+            sim_tx.set_gas(U256::from(fallback_gas) * 4);
+            if let Some(eip1559) = sim_tx.as_eip1559_mut() {
+                eip1559.max_fee_per_gas = Some(base_fee * 2);
+                eip1559.max_priority_fee_per_gas = Some(U256::zero());
+            }
+            client.fill_transaction(&mut sim_tx, None).await?;
+            let signature = client.signer().sign_transaction(&sim_tx).await?;
+            provider::simulate_bundle_gas(relay, &sim_tx.rlp_signed(&signature), block_number).await?
+        }
+        _ => None,
+    };
+    match simulated_gas {
+        Some(gas) => {
+            check_profit_with_simulated_gas(opp, gas, fallback_gas, base_fee)?;
+            // Mismo margen del 25% que la estimación clásica.
+            Ok(gas * 125 / 100)
+        }
+        None => provider::estimate_gas_cached(call, &opp.path.key(), opp.optimal_amount_in, block_number, fallback_gas).await,
+    }
+}
+
+/// Precio de gas máximo (wei) al que la oportunidad sigue dejando `min_profit_usd`: cada wei por unidad
+/// de gas cuesta `gas_units * eth_price / 1e18` USD. Cero si ni siquiera sin gas alcanza el mínimo.
+pub fn max_gas_price_for_profit(gross_profit_usd: f64, min_profit_usd: f64, gas_units: U256, eth_price_usd: f64) -> U256 {
+    let margin_usd = gross_profit_usd - min_profit_usd;
+    if margin_usd <= 0.0 || gas_units.is_zero() || eth_price_usd <= 0.0 { return U256::zero(); }
+    U256::from((margin_usd / eth_price_usd * 1e18 / gas_units.low_u64() as f64) as u128)
+}
+
+/// Slippage del fill en bps respecto a `expected` (negativo si el fill fue mejor). Convierte sin
+/// `as_u128`, que entra en pánico con montos de más de 128 bits.
+fn realized_slippage_bps(expected: U256, realized: U256) -> f64 {
+    let expected = crate::math::token_units(expected, 0);
+    if expected <= 0.0 { return 0.0; }
+    (expected - crate::math::token_units(realized, 0)) / expected * 10_000.0
+}
+
+/// Registra una TX enviada como en vuelo hasta que el tracker de recibos la resuelva.
+fn register_inflight_tx(tx_hash: TxHash, block_number: u64, path_key: String) {
+    INFLIGHT_TXS.lock().unwrap().insert(tx_hash, InflightTx { block_number, path_key });
+}
+
+/// Tras un reorg que invalida el bloque `fork_block` o posteriores, reemplaza por cancelaciones las TX
+/// aún pendientes que se construyeron sobre esos bloques: su estado de partida ya no es canónico.
+/// Las oportunidades se re-evalúan con normalidad sobre el nuevo head.
+pub async fn cancel_inflight_after_reorg(
+    client: Arc<SignerMiddleware<Provider<Http>, LocalWallet>>,
+    fork_block: u64,
+    base_fee: U256,
+) {
+    for (hash, inflight) in inflight_since(fork_block) {
+        let pending = match client.get_transaction(hash).await {
+            Ok(Some(tx)) if tx.block_number.is_none() => tx,
+            Ok(_) => continue,
+            Err(e) => {
+                warn!("No se pudo consultar la TX {hash:?} tras el reorg: {e:?}");
+                continue;
+            }
+        };
+        let bump = |fee: Option<U256>| fee.unwrap_or_default() * REPLACEMENT_FEE_BUMP_PERCENT / 100 + 1;
+        let min_fees = (bump(pending.max_priority_fee_per_gas), bump(pending.max_fee_per_gas));
+        match send_cancel_transaction(&client, pending.nonce, base_fee, Some(min_fees)).await {
+            Ok(cancel_hash) => {
+                instance::labeled(&REORG_CANCELLATIONS).inc();
+                warn!(
+                    "Reorg desde el bloque #{fork_block}: TX {hash:?} de la ruta {} (bloque #{}) cancelada con {cancel_hash:?}.",
+                    inflight.path_key, inflight.block_number
+                );
+            }
+            Err(e) => error!("No se pudo cancelar la TX {hash:?} de la ruta {} tras el reorg: {e:?}", inflight.path_key),
+        }
+    }
+}
+
+/// TX en vuelo construidas sobre `fork_block` o posteriores, por bloque.
+fn inflight_since(fork_block: u64) -> Vec<(TxHash, InflightTx)> {
+    let mut affected: Vec<(TxHash, InflightTx)> = INFLIGHT_TXS
+        .lock()
+        .unwrap()
+        .iter()
+        .filter(|(_, tx)| tx.block_number >= fork_block)
+        .map(|(hash, tx)| (*hash, tx.clone()))
+        .collect();
+    affected.sort_by_key(|(hash, tx)| (tx.block_number, *hash));
+    affected
+}
+
+/// Asigna el nonce de una TX de arbitraje: el mayor entre el contador local y el nonce `pending` de la
+/// cuenta, para respetar también las TX enviadas por otras vías (cancelaciones, sweeps, canarios).
+async fn allocate_nonce(client: &SignerMiddleware<Provider<Http>, LocalWallet>, nonces: &Mutex<BundleNonces>) -> Result<U256> {
+    let nonce = next_nonce(client).await?;
+    nonces.lock().unwrap().allocated.push(nonce);
+    Ok(nonce)
+}
+
+/// Siguiente nonce del contador compartido por todas las TX de la cuenta.
+async fn next_nonce(client: &SignerMiddleware<Provider<Http>, LocalWallet>) -> Result<U256> {
+    let pending = client.get_transaction_count(client.address(), Some(BlockNumber::Pending.into())).await?;
+    let mut next = NEXT_NONCE.lock().unwrap();
+    let nonce = next.map_or(pending, |n| n.max(pending));
+    *next = Some(nonce + 1);
+    Ok(nonce)
+}
+
+/// Envía una TX ajena a los bundles de arbitraje (sweeps) con un nonce del mismo contador, para que no
+/// colisione con las de arbitraje en vuelo. Si el envío falla, el nonce se devuelve al contador o, si ya
+/// hay otros asignados por encima, el contador se resincroniza con el `pending` del nodo.
+pub async fn submit_with_shared_nonce(
+    client: &SignerMiddleware<Provider<Http>, LocalWallet>,
+    mut tx: TypedTransaction,
+) -> Result<(TxHash, SubmissionMode)> {
+    let nonce = next_nonce(client).await?;
+    tx.set_nonce(nonce);
+    let sent = submit_transaction(client, tx).await;
+    if sent.is_err() && !release_nonce(nonce) {
+        *NEXT_NONCE.lock().unwrap() = None;
+    }
+    sent
+}
+
+/// Devuelve al contador un nonce sin usar si sigue siendo el último asignado; `false` si ya hay otros por encima.
+fn release_nonce(nonce: U256) -> bool {
+    let mut next = NEXT_NONCE.lock().unwrap();
+    if *next != Some(nonce + 1) { return false; }
+    *next = Some(nonce);
+    true
+}
+
+/// Resuelve los nonces que el bundle asignó pero no llegó a enviar: el último asignado se devuelve al
+/// contador y los que ya tienen nonces superiores se ocupan con una TX de cancelación (0 ETH a sí misma)
+/// para no dejar varadas las superiores. Sólo se tocan nonces de este bundle: las TX en vuelo de
+/// bundles anteriores nunca se cancelan.
+async fn fill_nonce_gaps(
+    client: &Arc<SignerMiddleware<Provider<Http>, LocalWallet>>,
+    nonces: &BundleNonces,
+    base_fee: U256,
+) -> Result<()> {
+    for nonce in release_unsent_nonces(nonces) {
+        instance::labeled(&NONCE_GAPS_FILLED).inc();
+        warn!("Hueco de nonce {} detectado tras un envío fallido del bundle. Enviando cancelación.", nonce);
+        send_cancel_transaction(client, nonce, base_fee, None).await?;
+    }
+    Ok(())
+}
+
+/// Devuelve al contador los nonces sin enviar que quedaron al final y lista, de mayor a menor, los que
+/// tienen nonces enviados por encima (huecos que hay que ocupar).
+fn release_unsent_nonces(nonces: &BundleNonces) -> Vec<U256> {
+    let mut unsent: Vec<U256> = nonces.allocated.iter().copied().filter(|n| !nonces.sent.contains(n)).collect();
+    // De mayor a menor, para poder devolver varios nonces finales consecutivos.
+    unsent.sort_unstable_by(|a, b| b.cmp(a));
+    unsent.into_iter().filter(|nonce| !release_nonce(*nonce)).collect()
+}
+
+/// Canal por el que se envió una TX.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SubmissionMode {
+    Public,
+    Private,
+    /// Modo sombra: priority fee fijo y bajo.
+    Shadow,
+    /// UserOperation ERC-4337 incluida por el bundler; el hash es el de la TX del bundle.
+    UserOperation,
+}
+
+/// Registro estructurado de un trade enviado, para contabilidad externa (una línea JSON por trade).
+#[derive(Debug, Clone, Serialize)]
+pub struct TradeRecord {
+    /// Instancia (`CHAIN_INSTANCES`) y chain del trade: varias instancias pueden escribir al mismo destino.
+    pub chain: String,
+    pub chain_id: u64,
+    pub block_number: u64,
+    pub timestamp: i64,
+    pub route_key: String,
+    pub token_path: Vec<H160>,
+    pub optimal_amount_in: U256,
+    pub expected_output: U256,
+    pub slippage_bps: u32,
+    pub bribe_usd: f64,
+    pub max_fee_per_gas: U256,
+    pub max_priority_fee_per_gas: U256,
+    pub tx_hash: TxHash,
+    pub submission_mode: SubmissionMode,
+}
+
+// Cola hacia la tarea que escribe los registros por trade de la instancia; sin inicializar si su
+// `TRADE_RECORD_SINK` no está activo.
+static TRADE_RECORD_SENDER: InstanceLocal<OnceCell<mpsc::UnboundedSender<TradeRecord>>> = InstanceLocal::new(OnceCell::new);
+
+/// Lanza la tarea que escribe los registros por trade de la instancia en curso en su destino. El envío
+/// desde la ruta de ejecución sólo encola, así que un destino lento nunca retrasa un trade.
+pub fn spawn_trade_record_sink() {
+    if CONFIG.trade_record_sink == TradeRecordSink::Disabled { return; }
+    let (sender, mut receiver) = mpsc::unbounded_channel::<TradeRecord>();
+    if TRADE_RECORD_SENDER.set(sender).is_err() { return; }
+    instance::spawn(async move {
+        let mut file = match &CONFIG.trade_record_sink {
+            TradeRecordSink::File(path) => match tokio::fs::OpenOptions::new().create(true).append(true).open(path).await {
+                Ok(file) => Some(file),
+                Err(e) => {
+                    error!("No se pudo abrir {path} para los registros por trade: {e}");
+                    return;
+                }
+            },
+            _ => None,
+        };
+        while let Some(record) = receiver.recv().await {
+            let line = match serde_json::to_string(&record) {
+                Ok(line) => line,
+                Err(e) => { warn!("Registro por trade no serializable: {e}"); continue; }
+            };
+            match file.as_mut() {
+                Some(file) => {
+                    if let Err(e) = file.write_all(format!("{line}\n").as_bytes()).await {
+                        warn!("No se pudo escribir el registro por trade: {e}");
+                    }
+                }
+                None => println!("{line}"),
+            }
+        }
+    });
+}
+
+/// Encola el registro de un trade enviado (no bloquea).
+fn emit_trade_record(opp: &ArbitrageOpportunity, block_number: u64, tx: &TypedTransaction, tx_hash: TxHash, submission_mode: SubmissionMode) {
+    let Some(sender) = TRADE_RECORD_SENDER.get() else { return };
+    let (max_fee_per_gas, max_priority_fee_per_gas) = match tx.as_eip1559_ref() {
+        Some(eip1559) => (eip1559.max_fee_per_gas.unwrap_or_default(), eip1559.max_priority_fee_per_gas.unwrap_or_default()),
+        None => (tx.gas_price().unwrap_or_default(), U256::zero()),
+    };
+    let _ = sender.send(TradeRecord {
+        chain: instance::current_name(),
+        chain_id: CONFIG.chain_id,
+        block_number,
+        timestamp: Local::now().timestamp(),
+        route_key: opp.path.key(),
+        token_path: vec![opp.path.token_a, opp.path.token_b, opp.path.token_c, opp.path.token_a],
+        optimal_amount_in: opp.optimal_amount_in,
+        expected_output: opp.expected_output,
+        slippage_bps: opp.slippage_bps,
+        bribe_usd: opp.bribe_usd,
+        max_fee_per_gas,
+        max_priority_fee_per_gas,
+        tx_hash,
+        submission_mode,
+    });
+}
+
+/// Envía una TX: por el relay privado si está configurado y, si falla, por el mempool público salvo
+/// con `PRIVATE_ONLY`, en cuyo caso la TX no se envía (ningún reintento ni fallback toca el mempool).
+pub async fn submit_transaction(
+    client: &SignerMiddleware<Provider<Http>, LocalWallet>,
+    mut tx: TypedTransaction,
+) -> Result<(TxHash, SubmissionMode)> {
+    // Se completa (nonce, gas, fees ausentes) y se firma aquí: el envío es siempre en crudo.
+    client.fill_transaction(&mut tx, None).await?;
+    let signature = client.signer().sign_transaction(&tx).await?;
+    submit_raw_transaction(client, tx.rlp_signed(&signature)).await
+}
+
+/// Envía una TX ya firmada con la misma política de relay privado y `PRIVATE_ONLY` que `submit_transaction`.
+pub async fn submit_raw_transaction(
+    client: &SignerMiddleware<Provider<Http>, LocalWallet>,
+    raw: Bytes,
+) -> Result<(TxHash, SubmissionMode)> {
+    match PRIVATE_PROVIDER.as_ref() {
+        Some(private) => match private.send_raw_transaction(raw.clone()).await {
+            Ok(pending) => return Ok((pending.tx_hash(), SubmissionMode::Private)),
+            Err(e) if CONFIG.private_only => {
+                instance::labeled(&PRIVATE_ONLY_SKIPPED).inc();
+                warn!("PRIVATE_ONLY: relay privado no disponible ({e}). La TX no se envía al mempool público.");
+                return Err(e.into());
+            }
+            Err(e) => warn!("Envío por el relay privado fallido ({e}); se recurre al mempool público."),
+        },
+        None if CONFIG.private_only => {
+            instance::labeled(&PRIVATE_ONLY_SKIPPED).inc();
+            warn!("PRIVATE_ONLY: sin relay privado configurado. La TX no se envía al mempool público.");
+            return Err(Error::msg("PRIVATE_ONLY sin relay privado: envío omitido."));
+        }
+        None => {}
+    }
+    Ok((client.provider().send_raw_transaction(raw).await?.tx_hash(), SubmissionMode::Public))
+}
+
+/// Envía la TX de arbitraje según `ACCOUNT_TYPE`: con ERC-4337 como UserOperation al bundler; el resto
+/// (EOA, smart account propia y EOA delegada con EIP-7702) como TX normal.
+async fn submit_for_account(
+    client: &Arc<SignerMiddleware<Provider<Http>, LocalWallet>>,
+    tx: TypedTransaction,
+) -> Result<(TxHash, SubmissionMode)> {
+    match CONFIG.account_type {
+        AccountType::Erc4337 => Ok((crate::account::send_user_operation(client, &tx).await?, SubmissionMode::UserOperation)),
+        AccountType::Eoa | AccountType::SmartAccount | AccountType::Eip7702 => submit_transaction(client, tx).await,
+    }
+}
+
+/// TX de 0 ETH a la propia cuenta en `nonce`, con un tip alto para que entre cuanto antes.
+/// `min_fees` (tip, max fee) obliga a superar los de una TX pendiente que se quiere reemplazar.
+async fn send_cancel_transaction(
+    client: &Arc<SignerMiddleware<Provider<Http>, LocalWallet>>,
+    nonce: U256,
+    base_fee: U256,
+    min_fees: Option<(U256, U256)>,
+) -> Result<TxHash> {
+    let mut priority_fee = U256::from(CANCEL_PRIORITY_FEE_GWEI) * U256::exp10(9);
+    let mut max_fee: U256 = base_fee * 2 + priority_fee;
+    if let Some((min_priority_fee, min_max_fee)) = min_fees {
+        priority_fee = priority_fee.max(min_priority_fee);
+        max_fee = max_fee.max(min_max_fee).max(base_fee * 2 + priority_fee);
+    }
+    let tx = Eip1559TransactionRequest::new()
+        .to(client.address())
+        .value(U256::zero())
+        .nonce(nonce)
+        .gas(21_000)
+        .max_priority_fee_per_gas(priority_fee)
+        .max_fee_per_gas(max_fee)
+        .chain_id(CONFIG.chain_id);
+    Ok(submit_transaction(client, tx.into()).await?.0)
+}
+#[cfg_attr(feature = "otel", tracing::instrument(
+    name = "execute_single_transaction", skip_all,
+    fields(block = block_number, path = %opp.path.key(), profit_usd = opp.net_profit_usd),
+))]
 pub async fn execute_single_transaction(
     client: Arc<SignerMiddleware<Provider<Http>, LocalWallet>>,
     opp: ArbitrageOpportunity,
     base_fee: U256,
+    block_number: u64,
+    nonces: &Mutex<BundleNonces>,
 ) -> Result<TxHash> {
     if opp.optimal_amount_in.is_zero() || opp.expected_output <= opp.optimal_amount_in {
         return Err(Error::msg("Monto inválido o no rentable."));
     }
+    // Con préstamo cruzado el repago y el output final están en el token prestado.
+    let (loan_token, loan_amount, final_output) = match &opp.flash_loan_leg {
+        Some(leg) => (
+            leg.token,
+            leg.amount,
+            apply_output_haircut(leg.expected_return, leg.decimals, leg.price_usd, opp.net_profit_usd),
+        ),
+        None => (
+            opp.path.token_a,
+            opp.optimal_amount_in,
+            apply_output_haircut(opp.expected_output, opp.path.get_input_decimals(), opp.token_price_usd, opp.net_profit_usd),
+        ),
+    };
+    if let Some((amount_out_min, repayment)) = repayment_shortfall(final_output, opp.slippage_bps, loan_amount) {
+        warn!(
+            "Ruta {} descartada: amount_out_min {} no cubre el repago del flashloan {} (slippage {} bps).",
+            opp.path.key(), amount_out_min, repayment, opp.slippage_bps
+        );
+        return Err(Error::msg("El peor caso tras slippage no repaga el flashloan."));
+    }
+    let repayment = flash_loan_repayment(loan_amount);
     let contract = IArbitrageBot::new(CONFIG.contract_address, client.clone());
-    let user_data = encode_arb_data(&opp.path, opp.expected_output, opp.slippage_bps)?;
-    let call = contract.start_flashloan_arbitrage(opp.path.token_a, opp.optimal_amount_in, user_data);
+    // Profit mínimo on-chain (sólo lo usa el layout v5), en unidades del token prestado.
+    let (loan_decimals, loan_price_usd) = match &opp.flash_loan_leg {
+        Some(leg) => (leg.decimals, leg.price_usd),
+        None => (opp.path.get_input_decimals(), opp.token_price_usd),
+    };
+    let min_profit = if loan_price_usd > 0.0 {
+        U256::from((crate::optimization::min_profit_usd() / loan_price_usd * 10f64.powi(loan_decimals as i32)) as u128)
+    } else {
+        U256::zero()
+    };
+    let user_data = encode_arb_data(&opp.path, final_output, opp.slippage_bps, opp.flash_loan_leg.as_ref(), min_profit)?;
+    let arb_call = contract.start_flashloan_arbitrage(loan_token, loan_amount, user_data);
+    let call = match CONFIG.account_type {
+        AccountType::Eoa => arb_call,
+        AccountType::SmartAccount | AccountType::Eip7702 | AccountType::Erc4337 => wrap_for_smart_account(client.clone(), client.address(), &arb_call)?,
+    };
 
     // CORRECCIÓN FINAL: Clonamos `call.tx` para evitar el error de "partial move".
     let mut tx: TypedTransaction = call.tx.clone();
     tx.set_chain_id(CONFIG.chain_id);
-    tx.set_gas(provider::estimate_gas(&call).await?);
+    let hops = opp.path.hop_count() + if opp.flash_loan_leg.is_some() { 2 } else { 0 };
+    let fallback_gas = CONFIG.gas_for_hops(hops);
+    let gas_limit = presend_gas_limit(&client, PRIVATE_PROVIDER.as_ref(), &call, &tx, &opp, block_number, base_fee, fallback_gas).await?;
+    tx.set_gas(gas_limit);
 
     let oracle_map = Arc::new(OracleMap::new());
-    let eth_price = oracle_map.get_price(&*WETH_ADDRESS, client.provider().clone().into()).await.ok_or_else(|| anyhow!("Failed to get ETH price"))?.price;
+    let eth_price = crate::feeds::get_price(&oracle_map, &CONFIG.weth_address, Arc::new(client.provider().clone())).await.ok_or_else(|| anyhow!("Failed to get ETH price"))?.price;
     let bribe_in_eth = opp.bribe_usd / eth_price;
-    let mut priority_fee_in_gwei = (bribe_in_eth * 1e9) as u64;
+    info!(
+        "Ruta {}: bribe {:.1}% del profit bruto (${:.2}, tope {:.1}%)",
+        opp.path.key(), opp.bribe_percent * 100.0, opp.bribe_usd, CONFIG.max_bribe_percent * 100.0
+    );
+    let mut priority_fee_wei = bribe_tip_wei(bribe_in_eth);
+    // Techo de gas propio de la ruta: por encima, la TX se paga a sí misma hasta quedar bajo el mínimo.
+    // Aplica también al modo sombra, que envía la TX real y paga su gas.
+    let (expected_return, loan_price_usd) = match &opp.flash_loan_leg {
+        Some(leg) => (leg.expected_return, leg.price_usd),
+        None => (opp.expected_output, opp.token_price_usd),
+    };
+    let gross_profit_usd = crate::math::to_usd(expected_return.saturating_sub(repayment), loan_token, loan_price_usd, loan_decimals);
+    let gas_units = tx.gas().copied().unwrap_or_else(|| U256::from(fallback_gas));
+    let gas_price_ceiling = max_gas_price_for_profit(gross_profit_usd, crate::optimization::min_profit_usd(), gas_units, eth_price);
+    info!(
+        "Ruta {}: techo de gas {:.3} gwei (profit bruto ${:.2}, {} de gas, base fee {:.3} gwei)",
+        opp.path.key(), crate::math::token_units(gas_price_ceiling, 9), gross_profit_usd, gas_units, crate::math::token_units(base_fee, 9)
+    );
+    if gas_price_ceiling <= base_fee {
+        return Err(Error::msg("El base fee supera el precio de gas máximo rentable de la ruta."));
+    }
+    // El nonce se fija una sola vez: los reintentos con más fee reemplazan la misma TX.
+    // Con ERC-4337 el nonce es el de la cuenta en el EntryPoint, no el de la EOA.
+    let nonce = match CONFIG.account_type {
+        AccountType::Erc4337 => None,
+        _ => Some(allocate_nonce(&client, nonces).await?),
+    };
+    if let Some(nonce) = nonce {
+        tx.set_nonce(nonce);
+    }
+    if CONFIG.shadow_mode {
+        let tx_hash = send_shadow_transaction(client, tx, base_fee, U256::from(priority_fee_wei), &opp, block_number).await?;
+        nonces.lock().unwrap().sent.extend(nonce);
+        return Ok(tx_hash);
+    }
+    // El presupuesto es el bribe al tope `max_bribe_percent` del profit bruto de la oportunidad.
+    let budget_fee_wei = if opp.bribe_percent > 0.0 {
+        (priority_fee_wei as f64 * CONFIG.max_bribe_percent / opp.bribe_percent) as u128
+    } else {
+        priority_fee_wei
+    };
+    priority_fee_wei = apply_priority_fee_floor(priority_fee_wei, budget_fee_wei, &opp.path.key())?;
     for attempt in 0..3 {
         if attempt > 0 {
             warn!("Reintento de TX #{}: aumentando priority_fee...", attempt + 1);
-            priority_fee_in_gwei = (priority_fee_in_gwei as f64 * 1.5) as u64;
+            priority_fee_wei = priority_fee_wei * 3 / 2;
         }
-        let priority_fee = U256::from(priority_fee_in_gwei) * U256::exp10(9);
+        let priority_fee = U256::from(priority_fee_wei).min(gas_price_ceiling - base_fee);
         let max_fee_per_gas = base_fee + priority_fee;
         if let Some(eip1559) = tx.as_eip1559_mut() {
             eip1559.max_fee_per_gas = Some(max_fee_per_gas);
             eip1559.max_priority_fee_per_gas = Some(priority_fee);
         }
-        match client.send_transaction(tx.clone(), None).await {
-            Ok(pending) => {
-                let tx_hash = pending.tx_hash();
+        match submit_for_account(&client, tx.clone()).await {
+            Ok((tx_hash, mode)) => {
+                nonces.lock().unwrap().sent.extend(nonce);
                 info!(" TX enviada con éxito! Hash: {tx_hash:?}");
+                emit_trade_record(&opp, block_number, &tx, tx_hash, mode);
                 return Ok(tx_hash);
             }
             Err(e) if attempt < 2 => {
@@ -117,3 +822,991 @@ pub async fn execute_single_transaction(
     }
     Err(Error::msg("Lógica de reintentos de envío de TX falló."))
 }
+
+/// Envuelve la llamada de arbitraje en `execute(contract, 0, calldata)` de la cuenta: la propia EOA si
+/// está delegada con EIP-7702 y `SMART_ACCOUNT_ADDRESS` en el resto.
+fn wrap_for_smart_account<M: Middleware>(client: Arc<M>, eoa: H160, arb_call: &ContractCall<M, ()>) -> Result<ContractCall<M, ()>> {
+    let calldata = arb_call.calldata().ok_or_else(|| anyhow!("La llamada de arbitraje no tiene calldata"))?;
+    let account_address = match CONFIG.account_type {
+        AccountType::Eip7702 => eoa,
+        _ => CONFIG.smart_account_address.ok_or_else(|| anyhow!("Falta SMART_ACCOUNT_ADDRESS"))?,
+    };
+    let account = ISmartAccount::new(account_address, client);
+    Ok(account.execute(CONFIG.contract_address, U256::zero(), calldata))
+}
+
+/// Modo sombra: envía la TX real pero con un priority fee fijo y bajo, y registra si fue incluida.
+/// Sirve para medir la tasa de inclusión frente a la competencia y calibrar `max_bribe_percent`.
+async fn send_shadow_transaction(
+    client: Arc<SignerMiddleware<Provider<Http>, LocalWallet>>,
+    mut tx: TypedTransaction,
+    base_fee: U256,
+    competitive_priority_fee: U256,
+    opp: &ArbitrageOpportunity,
+    block_number: u64,
+) -> Result<TxHash> {
+    // Guarda de reserva: nunca gastar gas en modo sombra por debajo del colchón configurado.
+    let balance = client.get_balance(client.address(), None).await?;
+    let reserve = U256::from((CONFIG.shadow_gas_reserve_eth * 1e18) as u128);
+    if balance < reserve {
+        return Err(Error::msg("Modo sombra: balance por debajo de la reserva de gas, envío omitido."));
+    }
+
+    let priority_fee = U256::from(CONFIG.shadow_priority_fee_wei);
+    if let Some(eip1559) = tx.as_eip1559_mut() {
+        eip1559.max_fee_per_gas = Some(base_fee + priority_fee);
+        eip1559.max_priority_fee_per_gas = Some(priority_fee);
+    }
+    let (tx_hash, _) = submit_transaction(&client, tx.clone()).await?;
+    emit_trade_record(opp, block_number, &tx, tx_hash, SubmissionMode::Shadow);
+    // La inclusión se sigue aparte para no retener el permiso de envío mientras se espera el recibo.
+    instance::spawn(track_shadow_inclusion(client, tx_hash, opp.path.key(), priority_fee, competitive_priority_fee));
+    Ok(tx_hash)
+}
+
+/// Espera el recibo de una TX en modo sombra y registra si fue incluida con el tip bajo.
+async fn track_shadow_inclusion(
+    client: Arc<SignerMiddleware<Provider<Http>, LocalWallet>>,
+    tx_hash: TxHash,
+    path_key: String,
+    priority_fee: U256,
+    competitive_priority_fee: U256,
+) {
+    let pending = PendingTransaction::new(tx_hash, client.provider());
+    let included = matches!(
+        tokio::time::timeout(Duration::from_secs(SHADOW_RECEIPT_TIMEOUT_SECS), pending).await,
+        Ok(Ok(Some(_)))
+    );
+    if included { instance::labeled(&SHADOW_INCLUDED).inc(); } else { instance::labeled(&SHADOW_MISSED).inc(); }
+    let total = instance::labeled(&SHADOW_INCLUDED).get() + instance::labeled(&SHADOW_MISSED).get();
+    info!(
+        "[SOMBRA] Ruta {path_key} | TX {tx_hash:?} | incluida: {included} | tip usado: {priority_fee} wei vs competitivo: {competitive_priority_fee} wei | inclusión acumulada: {}/{}",
+        instance::labeled(&SHADOW_INCLUDED).get(), total
+    );
+}
+
+/// Lee el profit mínimo que exige el contrato (`minProfit()`, en unidades de `token_in`) y, si supera
+/// el `min_profit_usd` configurado, lo adopta como umbral efectivo del optimizador.
+/// Un revert o una respuesta vacía significan que el contrato no expone el getter y no se hace nada;
+/// cualquier otro fallo (del nodo o de decodificación) se devuelve como error.
+pub async fn sync_onchain_min_profit<M: Middleware + 'static>(provider: Arc<M>, oracle_map: &OracleMap) -> Result<()> {
+    let guard = IMinProfitGuard::new(CONFIG.contract_address, provider.clone());
+    let call = guard.min_profit();
+    let raw = match provider.call(&call.tx, None).await {
+        Ok(raw) if raw.is_empty() => None,
+        Ok(raw) => Some(raw),
+        Err(e) if e.as_error_response().and_then(|r| r.as_revert_data()).is_some() => None,
+        Err(e) => return Err(anyhow!("No se pudo leer minProfit() del contrato: {e}")),
+    };
+    let Some(raw) = raw else {
+        info!("El contrato no expone minProfit(); se usa sólo MIN_PROFIT_USD.");
+        return Ok(());
+    };
+    let min_profit_units = ethers::abi::decode(&[ethers::abi::ParamType::Uint(256)], &raw)?
+        .pop()
+        .and_then(Token::into_uint)
+        .ok_or_else(|| anyhow!("minProfit() devolvió {raw}, no un uint256"))?;
+    if min_profit_units.is_zero() { return Ok(()); }
+    let decimals = IERC20::new(CONFIG.token_in_address, provider.clone()).decimals().call().await?;
+    let price = crate::feeds::get_price(oracle_map, &CONFIG.token_in_address, provider)
+        .await
+        .ok_or_else(|| anyhow!("Sin precio de oráculo para token_in"))?
+        .price;
+    let onchain_usd = crate::math::to_usd(min_profit_units, CONFIG.token_in_address, price, decimals);
+    if onchain_usd > CONFIG.min_profit_usd {
+        warn!(
+            "El contrato exige un profit mínimo de ${:.2} ({} unidades), mayor que MIN_PROFIT_USD ${:.2}. Se usará ${:.2}.",
+            onchain_usd, min_profit_units, CONFIG.min_profit_usd, onchain_usd
+        );
+        crate::optimization::set_onchain_min_profit_usd(onchain_usd);
+    }
+    Ok(())
+}
+
+/// Con `APPROVAL_TOKEN_POLICY=contract_reset`, comprueba que el contrato declara resetear el allowance
+/// antes de aprobar (`supportsApprovalReset()`). Sin el getter se considera no soportado.
+pub async fn verify_approval_reset_support<M: Middleware + 'static>(provider: Arc<M>) {
+    if CONFIG.approval_token_policy != ApprovalTokenPolicy::ContractReset || CONFIG.non_standard_approval_tokens.is_empty() {
+        return;
+    }
+    let contract = IApprovalReset::new(CONFIG.contract_address, provider);
+    let supported = contract.supports_approval_reset().call().await.unwrap_or(false);
+    if supported {
+        info!("El contrato resetea el allowance antes de aprobar: se mantienen las rutas con tokens de approve no estándar.");
+    } else {
+        warn!("El contrato no confirma supportsApprovalReset(): las rutas con tokens de approve no estándar se excluyen.");
+    }
+    crate::paths::set_approval_reset_supported(supported);
+}
+
+/// Llamada del trade canario: `path` con `amount_in` unidades de `token_a`, sin output mínimo ni profit
+/// exigido, para ejercitar approvals, codificación y gas del contrato sin depender de una oportunidad.
+pub fn build_canary_call<M: Middleware>(client: Arc<M>, path: &ArbPath, amount_in: U256) -> Result<ContractCall<M, ()>> {
+    let user_data = encode_arb_data(path, U256::zero(), 0, None, U256::zero())?;
+    Ok(IArbitrageBot::new(CONFIG.contract_address, client).start_flashloan_arbitrage(path.token_a, amount_in, user_data))
+}
+
+/// Trade canario de arranque (`CANARY_TRADE`): simula de punta a punta, sin enviar nada, un arbitraje
+/// mínimo (`eth_call` y `eth_estimateGas` de la llamada completa, envuelta según `ACCOUNT_TYPE`). Usa la
+/// primera ruta cuyo output cotizado cubre el repago del flashloan: por un ciclo perdedor revertiría por
+/// economía y no por approvals, codificación o gas. Si revierte se devuelve el motivo decodificado.
+pub async fn run_canary_trade<M: Middleware + 'static>(
+    client: Arc<M>,
+    account: H160,
+    paths: &[ArbPath],
+    oracle_map: &OracleMap,
+) -> Result<()> {
+    let token = paths.first().ok_or_else(|| anyhow!("Canario: no hay rutas con las que construir el trade."))?.token_a;
+    let price = crate::feeds::get_price(oracle_map, &token, client.clone()).await
+        .ok_or_else(|| anyhow!("Canario: sin precio de oráculo para {token:?}"))?.price;
+    let candidates: Vec<&ArbPath> = paths.iter().filter(|p| p.token_a == token).take(CANARY_CANDIDATE_PATHS).collect();
+    let amount_in = U256::from((CONFIG.canary_amount_usd / price * 10f64.powi(candidates[0].get_input_decimals() as i32)) as u128);
+    let repayment = flash_loan_repayment(amount_in);
+    let mut selected = None;
+    for path in candidates {
+        if path.simulate_v3_path_at(client.clone(), amount_in, None).await.is_some_and(|out| out >= repayment) {
+            selected = Some(path);
+            break;
+        }
+    }
+    let Some(path) = selected else {
+        warn!(
+            "Canario omitido: ninguna de las primeras {CANARY_CANDIDATE_PATHS} rutas devuelve el repago de {repayment} unidades; \
+             sin un ciclo que cubra el flashloan la simulación revertiría por economía."
+        );
+        return Ok(());
+    };
+    info!("Canario: ruta {} con {} unidades (~${:.2}), sólo simulado.", path.key(), amount_in, CONFIG.canary_amount_usd);
+
+    let arb_call = build_canary_call(client.clone(), path, amount_in)?;
+    let call = match CONFIG.account_type {
+        AccountType::Eoa => arb_call,
+        AccountType::SmartAccount | AccountType::Eip7702 | AccountType::Erc4337 => wrap_for_smart_account(client.clone(), account, &arb_call)?,
+    }
+    .from(account);
+    if let Err(e) = call.call().await {
+        let reason = provider::revert_reason(&e).unwrap_or_else(|| e.to_string());
+        return Err(anyhow!("Canario revertido en eth_call por la ruta {}: {reason}", path.key()));
+    }
+    let gas = provider::estimate_gas(&call, CONFIG.gas_for_hops(path.hop_count())).await
+        .map_err(|e| anyhow!("Canario revertido en la estimación de gas: {e}"))?;
+    info!(" Canario OK: la ruta {} se ejecutaría con {gas} de gas (no se envió ninguna TX).", path.key());
+    Ok(())
+}
+
+/// PnL realizado acumulado en esta ejecución del proceso (USD).
+pub fn realized_pnl_usd() -> f64 {
+    instance::labeled(&REALIZED_PNL_USD).get()
+}
+
+/// Profit simulado acumulado de los mismos trades que cuentan en `realized_pnl_usd` (USD).
+pub fn simulated_pnl_usd() -> f64 {
+    instance::labeled(&SIMULATED_PNL_USD).get()
+}
+
+/// Suma al PnL de la ejecución un trade con recibo exitoso: su profit realizado y el simulado.
+pub(crate) fn record_trade_pnl(realized_usd: f64, simulated_usd: f64) {
+    instance::labeled(&REALIZED_PNL_USD).add(realized_usd);
+    instance::labeled(&SIMULATED_PNL_USD).add(simulated_usd);
+}
+
+/// Gas total pagado por los trades con recibo en esta ejecución del proceso (ETH).
+pub fn gas_spent_eth() -> f64 {
+    instance::labeled(&GAS_SPENT_ETH).get()
+}
+
+/// Espera el recibo de una TX consultando al nodo hasta `RECEIPT_TIMEOUT_SECS`.
+pub async fn wait_for_receipt<M: Middleware>(client: &M, tx_hash: TxHash) -> Option<TransactionReceipt> {
+    let polls = RECEIPT_TIMEOUT_SECS * 1000 / RECEIPT_POLL_INTERVAL_MS;
+    for _ in 0..polls {
+        if let Ok(Some(receipt)) = client.get_transaction_receipt(tx_hash).await {
+            return Some(receipt);
+        }
+        tokio::time::sleep(Duration::from_millis(RECEIPT_POLL_INTERVAL_MS)).await;
+    }
+    None
+}
+
+/// Output realizado a partir del balance del contrato en el token de repago al cierre del bloque de la TX
+/// frente al anterior: el contrato retiene el profit tras repagar el flashloan, así que el output es el repago
+/// más esa variación, sin depender de qué pool o evento movió los fondos. Otra TX propia del mismo bloque
+/// sobre el mismo token entra en el mismo delta.
+pub async fn realized_output_from_balance<M: Middleware + 'static>(provider: Arc<M>, opp: &ArbitrageOpportunity, block: u64) -> Option<U256> {
+    let (token, loan_amount) = match &opp.flash_loan_leg {
+        Some(leg) => (leg.token, leg.amount),
+        None => (opp.path.token_a, opp.optimal_amount_in),
+    };
+    let erc20 = IERC20::new(token, provider);
+    let before = erc20.balance_of(CONFIG.contract_address).block(block.saturating_sub(1)).call().await.ok()?;
+    let after = erc20.balance_of(CONFIG.contract_address).block(block).call().await.ok()?;
+    let repayment = flash_loan_repayment(loan_amount);
+    Some(if after >= before { repayment + (after - before) } else { repayment.saturating_sub(before - after) })
+}
+
+/// Profit neto realizado en USD: output recibido menos repago del flashloan y gas realmente pagado.
+/// Con préstamo cruzado, output y repago están en el token prestado y se valoran a su precio.
+pub fn realized_profit_usd(realized_output: U256, opp: &ArbitrageOpportunity, gas_cost_wei: U256) -> f64 {
+    let (token, loan_amount, price_usd, decimals) = match &opp.flash_loan_leg {
+        Some(leg) => (leg.token, leg.amount, leg.price_usd, leg.decimals),
+        None => (opp.path.token_a, opp.optimal_amount_in, opp.token_price_usd, opp.path.get_input_decimals()),
+    };
+    let repayment = flash_loan_repayment(loan_amount);
+    let gross_usd = if realized_output >= repayment {
+        crate::math::to_usd(realized_output - repayment, token, price_usd, decimals)
+    } else {
+        -crate::math::to_usd(repayment - realized_output, token, price_usd, decimals)
+    };
+    gross_usd - crate::math::to_usd(gas_cost_wei, CONFIG.weth_address, opp.eth_price_usd, 18)
+}
+
+/// Tracker de recibos: compara el output realizado con `expected_output` (slippage por ruta y agregado)
+/// y usa el profit realizado como fuente de verdad para el PnL y `ROUTE_STATS`.
+#[cfg_attr(feature = "otel", tracing::instrument(name = "track_receipt", skip_all, fields(tx = ?tx_hash)))]
+async fn track_execution_receipt(
+    client: Arc<SignerMiddleware<Provider<Http>, LocalWallet>>,
+    tx_hash: TxHash,
+    opp: ArbitrageOpportunity,
+) {
+    let receipt = wait_for_receipt(client.as_ref(), tx_hash).await;
+    INFLIGHT_TXS.lock().unwrap().remove(&tx_hash);
+    let receipt = match receipt {
+        Some(receipt) => receipt,
+        None => {
+            warn!("Sin recibo para {tx_hash:?} tras {RECEIPT_TIMEOUT_SECS}s.");
+            // La TX pudo salir del mempool: el próximo nonce se resincroniza con el `pending` del nodo.
+            *NEXT_NONCE.lock().unwrap() = None;
+            return;
+        }
+    };
+    let gas_cost_wei = receipt.gas_used.unwrap_or_default() * receipt.effective_gas_price.unwrap_or_default();
+    instance::labeled(&GAS_SPENT_ETH).add(crate::math::token_units(gas_cost_wei, 18));
+    if receipt.status != Some(U64::from(1)) {
+        let revert = match receipt.block_number {
+            Some(block) => provider::onchain_revert_reason(client.as_ref(), tx_hash, block).await.map(|reason| (reason, block)),
+            None => None,
+        };
+        warn!(
+            "TX {tx_hash:?} de la ruta {} revertida on-chain: {}",
+            opp.path.key(), revert.as_ref().map_or("motivo desconocido", |(reason, _)| reason.as_str())
+        );
+        if let Some((reason, block)) = revert {
+            crate::optimization::record_route_revert(&opp.path.key(), &reason, block.as_u64());
+        }
+        return;
+    }
+    let Some(block) = receipt.block_number else { return };
+    // Solo un trade minado con éxito ha movido los pools de la ruta.
+    crate::pools::record_own_trade(&[opp.path.address(1), opp.path.address(2), opp.path.address(3)], block.as_u64());
+    let realized = match realized_output_from_balance(client.clone(), &opp, block.as_u64()).await {
+        Some(realized) => realized,
+        None => {
+            warn!("No se pudo leer el balance del contrato para la ruta {} en {tx_hash:?}.", opp.path.key());
+            return;
+        }
+    };
+    let expected_output = opp.flash_loan_leg.as_ref().map_or(opp.expected_output, |leg| leg.expected_return);
+    if expected_output.is_zero() { return; }
+
+    let slippage_bps = realized_slippage_bps(expected_output, realized);
+    instance::labeled(&REALIZED_SLIPPAGE_BPS).observe(slippage_bps.max(0.0));
+    let profit_usd = realized_profit_usd(realized, &opp, gas_cost_wei);
+    record_trade_pnl(profit_usd, opp.net_profit_usd);
+    {
+        let mut stats_map = ROUTE_STATS.lock().unwrap();
+        let stats = stats_map.entry(opp.path.key()).or_default();
+        stats.record_realized_slippage(slippage_bps);
+        stats.realized_profit_usd += profit_usd;
+        stats.record_realized_profit(profit_usd, opp.net_profit_usd);
+        stats.clear_revert_streak();
+    }
+    info!(
+        " Ruta {}: slippage realizado {:.2} bps (presupuesto {} bps) | profit realizado ${:.2} vs simulado ${:.2}",
+        opp.path.key(), slippage_bps, opp.slippage_bps, profit_usd, opp.net_profit_usd
+    );
+    if opp.net_profit_usd > 0.0
+        && ((profit_usd - opp.net_profit_usd) / opp.net_profit_usd).abs() > PROFIT_DIVERGENCE_WARN_RATIO
+    {
+        warn!(
+            "Profit realizado de la ruta {} diverge del simulado: ${:.2} vs ${:.2} ({tx_hash:?})",
+            opp.path.key(), profit_usd, opp.net_profit_usd
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::instance::with_test_config;
+    use crate::paths::tests::{addr, path};
+    use crate::types::DexVariant;
+    use ethers::abi::ParamType;
+
+    fn decode_arb_data(data: &Bytes, version: EncodingVersion) -> Vec<Token> {
+        let mut params = vec![ParamType::Bytes, ParamType::FixedBytes(32), ParamType::Uint(256), ParamType::Uint(256)];
+        if version == EncodingVersion::V5 {
+            params.extend([ParamType::Address, ParamType::Uint(256)]);
+        }
+        let decoded = ethers::abi::decode(&[ParamType::Tuple(params)], data).unwrap();
+        decoded.into_iter().next().unwrap().into_tuple().unwrap()
+    }
+
+    fn leg() -> FlashLoanLeg {
+        FlashLoanLeg {
+            token: addr(9),
+            variant: DexVariant::UniswapV3,
+            fee: 3000,
+            decimals: 6,
+            amount: U256::from(1_000_000u64),
+            expected_return: U256::from(1_010_000u64),
+            price_usd: 1.0,
+        }
+    }
+
+    #[tokio::test]
+    async fn encode_arb_data_with_leg_wraps_the_cycle_with_the_loan_swaps() {
+        let route = path([1, 2, 3]);
+        let leg = leg();
+        let data = with_test_config(|c| c.encoding_version = EncodingVersion::V4, async {
+            encode_arb_data(&route, U256::from(1_010_000u64), 0, Some(&leg), U256::zero()).unwrap()
+        })
+        .await;
+        let path_bytes = decode_arb_data(&data, EncodingVersion::V4)[0].clone().into_bytes().unwrap();
+        assert_eq!(path_bytes.len(), 20 * 6 + 3 * 5);
+        assert_eq!(&path_bytes[..20], leg.token.as_bytes());
+        assert_eq!(&path_bytes[20..23], &3000u32.to_be_bytes()[1..]);
+        assert_eq!(&path_bytes[23..43], addr(1).as_bytes());
+        // Vuelta explícita a token_a antes del swap de repago.
+        assert_eq!(&path_bytes[92..112], addr(1).as_bytes());
+        assert_eq!(&path_bytes[path_bytes.len() - 20..], leg.token.as_bytes());
+    }
+
+    #[tokio::test]
+    async fn encode_arb_data_without_leg_encodes_the_cycle() {
+        let route = path([1, 2, 3]);
+        let data = with_test_config(|c| c.encoding_version = EncodingVersion::V4, async {
+            encode_arb_data(&route, U256::from(10_000), 100, None, U256::zero()).unwrap()
+        })
+        .await;
+        let fields = decode_arb_data(&data, EncodingVersion::V4);
+        assert_eq!(fields.len(), 4);
+        let path_bytes = fields[0].clone().into_bytes().unwrap();
+        // token_a, fee, token_b, fee, token_c: el contrato cierra el ciclo hacia token_a.
+        assert_eq!(path_bytes.len(), 20 * 3 + 3 * 2);
+        assert_eq!(&path_bytes[..20], addr(1).as_bytes());
+        assert_eq!(&path_bytes[20..23], &500u32.to_be_bytes()[1..]);
+        assert_eq!(&path_bytes[46..66], addr(3).as_bytes());
+        assert_eq!(fields[3].clone().into_uint(), Some(U256::from(9_900)));
+    }
+
+    #[tokio::test]
+    async fn encode_arb_data_v5_appends_loan_source_and_min_profit() {
+        let route = path([1, 2, 3]);
+        let leg = leg();
+        let encode = |version: EncodingVersion| with_test_config(move |c| c.encoding_version = version, async {
+            (encode_arb_data(&route, U256::from(1_010_000u64), 0, Some(&leg), U256::from(7)).unwrap(), CONFIG.balancer_vault)
+        });
+        let (v4, _) = encode(EncodingVersion::V4).await;
+        let (v5, vault) = encode(EncodingVersion::V5).await;
+        let fields = decode_arb_data(&v5, EncodingVersion::V5);
+        assert_eq!(fields.len(), 6);
+        assert_eq!(fields[3].clone().into_uint(), Some(leg.expected_return));
+        assert_eq!(fields[4].clone().into_address(), Some(vault));
+        assert_eq!(fields[5].clone().into_uint(), Some(U256::from(7)));
+        // v4 ignora el profit mínimo: los dos campos nuevos sólo se añaden al final de la tupla.
+        assert_eq!(decode_arb_data(&v4, EncodingVersion::V4).len(), 4);
+        assert_eq!(v5.len(), v4.len() + 2 * 32);
+    }
+
+    #[tokio::test]
+    async fn flash_loan_repayment_adds_the_fee() {
+        let amount = U256::from(2_000_000u64);
+        with_test_config(|c| c.flash_loan_fee_bps = 0, async {
+            assert_eq!(flash_loan_repayment(amount), amount);
+        })
+        .await;
+        with_test_config(|c| c.flash_loan_fee_bps = 5, async {
+            assert_eq!(flash_loan_repayment(amount), U256::from(2_001_000u64));
+        })
+        .await;
+    }
+
+    #[tokio::test]
+    async fn repayment_shortfall_checks_the_worst_case_after_slippage() {
+        let loan = U256::from(1_000_000u64);
+        // 1% de margen bruto; el repago con 5 bps de fee es 1.000.500.
+        let output = U256::from(1_010_000u64);
+        with_test_config(|c| c.flash_loan_fee_bps = 5, async {
+            assert_eq!(repayment_shortfall(output, 50, loan), None);
+            assert_eq!(repayment_shortfall(output, 100, loan), Some((U256::from(999_900u64), U256::from(1_000_500u64))));
+            assert!(repayment_shortfall(loan, 0, loan).is_some());
+        })
+        .await;
+    }
+
+    #[test]
+    fn realized_slippage_handles_amounts_above_u128() {
+        assert_eq!(realized_slippage_bps(U256::from(10_000), U256::from(9_990)), 10.0);
+        assert_eq!(realized_slippage_bps(U256::from(10_000), U256::from(10_010)), -10.0);
+        assert_eq!(realized_slippage_bps(U256::zero(), U256::one()), 0.0);
+        let huge = U256::MAX / 2;
+        assert!((realized_slippage_bps(huge, huge - huge / 1_000) - 10.0).abs() < 1e-6);
+    }
+
+    #[tokio::test]
+    async fn realized_output_comes_from_the_contract_balance_delta() {
+        use crate::paths::tests::path;
+        use crate::testing::MockChain;
+
+        let route = path([1, 2, 3]);
+        let opp = ArbitrageOpportunity {
+            path: route.clone(),
+            optimal_amount_in: U256::from(1_000_000u64),
+            expected_output: U256::from(1_010_000u64),
+            net_profit_usd: 0.0,
+            bribe_usd: 0.0,
+            bribe_percent: 0.0,
+            lag: 0.0,
+            tvl: 0.0,
+            score: 0.0,
+            slippage_bps: 0,
+            token_price_usd: 0.0,
+            eth_price_usd: 0.0,
+            flash_loan_leg: None,
+        };
+        with_test_config(|c| c.flash_loan_fee_bps = 5, async {
+            let contract = CONFIG.contract_address;
+            // El contrato ganó 8.000 unidades de token_a en el bloque 100.
+            let chain = MockChain::new()
+                .balance_of_at(route.token_a, contract, 99, U256::from(50_000u64))
+                .balance_of_at(route.token_a, contract, 100, U256::from(58_000u64))
+                .balance_of_at(route.token_a, contract, 101, U256::from(40_000u64));
+            assert_eq!(realized_output_from_balance(chain.provider(), &opp, 100).await, Some(U256::from(1_008_500u64)));
+            // Una pérdida deja el output por debajo del repago.
+            assert_eq!(realized_output_from_balance(chain.provider(), &opp, 101).await, Some(U256::from(982_500u64)));
+            assert_eq!(realized_output_from_balance(chain.provider(), &opp, 200).await, None);
+        })
+        .await;
+    }
+
+
+    #[test]
+    fn calculate_amount_out_min_applies_the_slippage() {
+        assert_eq!(calculate_amount_out_min(U256::from(10_000), 50), U256::from(9_950));
+        assert_eq!(calculate_amount_out_min(U256::from(10_000), 0), U256::from(10_000));
+        assert_eq!(calculate_amount_out_min(U256::from(10_000), 10_000), U256::zero());
+        assert_eq!(calculate_amount_out_min(U256::from(10_000), 20_000), U256::zero());
+    }
+
+    #[tokio::test]
+    async fn output_haircut_comes_off_before_the_slippage() {
+        // 10.000 USDC (6 decimales, $1) esperados y $2 de colchón.
+        let expected = U256::from(10_000_000_000u64);
+        with_test_config(|c| c.output_haircut_usd = 2.0, async {
+            let haircut = apply_output_haircut(expected, 6, 1.0, 100.0);
+            assert_eq!(haircut, U256::from(9_998_000_000u64));
+            // El slippage se aplica sobre el output ya recortado: el colchón no depende de los bps.
+            assert_eq!(calculate_amount_out_min(haircut, 50), U256::from(9_948_010_000u64));
+            assert_eq!(calculate_amount_out_min(haircut, 0), haircut);
+            // Con $4 de profit el colchón se limita al 25% ($1).
+            assert_eq!(apply_output_haircut(expected, 6, 1.0, 4.0), U256::from(9_999_000_000u64));
+            // Sin profit o sin precio no hay recorte.
+            assert_eq!(apply_output_haircut(expected, 6, 1.0, -5.0), expected);
+            assert_eq!(apply_output_haircut(expected, 6, 0.0, 100.0), expected);
+        })
+        .await;
+        with_test_config(|c| c.output_haircut_usd = 0.0, async {
+            assert_eq!(apply_output_haircut(expected, 6, 1.0, 100.0), expected);
+        })
+        .await;
+    }
+
+
+    #[tokio::test]
+    async fn deadline_follows_the_block_timestamp_and_block_time() {
+        let deadline = |block_time_ms: u64, buffer_secs: Option<u64>| {
+            with_test_config(move |c| (c.block_time_ms, c.deadline_buffer_blocks, c.deadline_buffer_secs) = (block_time_ms, 8, buffer_secs), async {
+                observe_block_timestamp(1_000_000);
+                deadline_from_block().as_u64()
+            })
+        };
+        // 8 bloques: 2s en una L2 de 250 ms, 96s en una chain de 12s.
+        assert_eq!(deadline(250, None).await, 1_000_002);
+        assert_eq!(deadline(12_000, None).await, 1_000_096);
+        // Un margen en segundos fijado a mano tiene prioridad.
+        assert_eq!(deadline(250, Some(30)).await, 1_000_030);
+    }
+
+
+    #[tokio::test]
+    async fn mid_bundle_send_failure_leaves_no_nonce_gap() {
+        let nonces = |allocated: [u64; 3], sent: &[u64]| BundleNonces {
+            allocated: allocated.map(U256::from).to_vec(),
+            sent: sent.iter().copied().map(U256::from).collect(),
+        };
+        with_test_config(|_| {}, async {
+            // Falla el envío del medio: el 6 queda entre dos enviados y hay que ocuparlo con una cancelación.
+            *NEXT_NONCE.lock().unwrap() = Some(U256::from(8));
+            assert_eq!(release_unsent_nonces(&nonces([5, 6, 7], &[5, 7])), [U256::from(6)]);
+            assert_eq!(*NEXT_NONCE.lock().unwrap(), Some(U256::from(8)));
+
+            // Fallan los dos últimos: se devuelven al contador y el siguiente bundle los reutiliza.
+            assert!(release_unsent_nonces(&nonces([5, 6, 7], &[5])).is_empty());
+            assert_eq!(*NEXT_NONCE.lock().unwrap(), Some(U256::from(6)));
+
+            // Otro bundle ya asignó nonces por encima: el fallido no se puede devolver.
+            *NEXT_NONCE.lock().unwrap() = Some(U256::from(10));
+            assert_eq!(release_unsent_nonces(&nonces([5, 6, 7], &[5, 6])), [U256::from(7)]);
+        })
+        .await;
+    }
+
+    #[tokio::test]
+    async fn contract_min_profit_raises_the_threshold_only_when_exposed() {
+        use crate::{config::OracleFeed, optimization::min_profit_usd, testing::MockChain};
+
+        let feed = addr(700);
+        let now = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap().as_secs();
+        let priced = || {
+            MockChain::new()
+                .decimals(CONFIG.token_in_address, 18)
+                .latest_round_data(feed, 200_000_000_000, now)
+                .returns(feed, "decimals()", &[], &[Token::Uint(8.into())])
+        };
+        let sync = |chain: MockChain| {
+            with_test_config(
+                move |c| {
+                    c.min_profit_usd = 1.0;
+                    c.oracle_feeds = HashMap::from([(c.token_in_address, OracleFeed::Chainlink(feed))]);
+                },
+                async move {
+                    let synced = sync_onchain_min_profit(chain.provider(), &OracleMap::new()).await;
+                    (synced.map_err(|e| e.to_string()), min_profit_usd())
+                },
+            )
+        };
+        crate::config::init_test_env();
+        let contract = CONFIG.contract_address;
+
+        // 0,01 WETH a $2.000: el contrato exige $20, por encima de los $1 configurados.
+        let floor = priced().returns(contract, "minProfit()", &[], &[Token::Uint(U256::exp10(16))]);
+        assert_eq!(sync(floor).await, (Ok(()), 20.0));
+        // Sin el getter (revert) el umbral sigue siendo el configurado.
+        assert_eq!(sync(priced()).await, (Ok(()), 1.0));
+        // Un fallo del nodo no se confunde con un contrato sin getter.
+        let flaky = priced().errors(contract, "minProfit()", &[], "header not found");
+        let (synced, threshold) = sync(flaky).await;
+        assert!(synced.unwrap_err().contains("header not found"));
+        assert_eq!(threshold, 1.0);
+    }
+
+
+    #[test]
+    fn priority_fee_floor_is_the_median_of_blocks_with_rewards() {
+        let rewards = vec![vec![U256::from(3)], vec![U256::from(1)], vec![], vec![U256::from(2)]];
+        assert_eq!(priority_fee_floor_from_rewards(&rewards), 2);
+        assert_eq!(priority_fee_floor_from_rewards(&[]), 0);
+        assert_eq!(priority_fee_floor_from_rewards(&[vec![]]), 0);
+        assert_eq!(priority_fee_floor_from_rewards(&[vec![U256::MAX]]), u64::MAX);
+    }
+
+    #[tokio::test]
+    async fn recent_inclusion_fees_floor_the_priority_fee() {
+        let (provider, mock) = Provider::mocked();
+        let gwei = |n: u64| U256::from(n) * U256::exp10(9);
+        // p50 de los tips incluidos en los últimos bloques: 2, 3 y 1 gwei -> piso de 2 gwei.
+        mock.push(FeeHistory {
+            base_fee_per_gas: vec![],
+            gas_used_ratio: vec![0.5; 3],
+            oldest_block: U256::from(100),
+            reward: vec![vec![gwei(2)], vec![gwei(3)], vec![gwei(1)]],
+        })
+        .unwrap();
+        with_test_config(
+            |c| {
+                c.priority_fee_floor_percentile = 50.0;
+                c.min_priority_fee_wei = 0;
+            },
+            async {
+                refresh_priority_fee_floor(&provider).await;
+                // Por encima del piso no cambia; por debajo sube al piso si el presupuesto llega.
+                assert_eq!(apply_priority_fee_floor(3_000_000_000, 5_000_000_000, "ruta").unwrap(), 3_000_000_000);
+                assert_eq!(apply_priority_fee_floor(500_000_000, 5_000_000_000, "ruta").unwrap(), 2_000_000_000);
+                // Ni con todo el presupuesto se alcanza: el trade se salta.
+                let error = apply_priority_fee_floor(500_000_000, 1_500_000_000, "ruta").unwrap_err();
+                assert!(error.to_string().contains("2000000000 wei"), "{error}");
+            },
+        )
+        .await;
+    }
+
+
+    #[tokio::test]
+    async fn reorg_cancels_only_the_txs_built_on_the_abandoned_blocks() {
+        use crate::testing::FakeNode;
+        use ethers::utils::rlp::Rlp;
+        use serde_json::{json, Value};
+
+        let (stale, reorged, mined) = (TxHash::from_low_u64_be(1), TxHash::from_low_u64_be(2), TxHash::from_low_u64_be(3));
+        let node = FakeNode::start(move |method, params| match method {
+            "eth_getTransactionByHash" => {
+                let hash: TxHash = serde_json::from_value(params[0].clone()).unwrap();
+                // La del bloque 101 ya se minó en la nueva cadena; la del 100 sigue pendiente con nonce 7.
+                let block_number = if hash == mined { json!("0x66") } else { Value::Null };
+                Ok(json!({
+                    "hash": hash, "nonce": "0x7", "blockNumber": block_number, "from": Address::zero(), "to": Address::zero(),
+                    "value": "0x0", "gas": "0x5208", "input": "0x", "type": "0x2", "chainId": "0xa4b1",
+                    "maxFeePerGas": "0x3b9aca00", "maxPriorityFeePerGas": "0x5f5e100", "v": "0x0", "r": "0x1", "s": "0x1",
+                }))
+            }
+            "eth_sendRawTransaction" => Ok(json!(TxHash::from_low_u64_be(99))),
+            _ => Err(format!("método inesperado {method}")),
+        })
+        .await;
+        with_test_config(|_| {}, async {
+            for (hash, block) in [(stale, 98), (reorged, 100), (mined, 101)] {
+                register_inflight_tx(hash, block, format!("ruta-{block}"));
+            }
+            cancel_inflight_after_reorg(node.client(), 100, U256::from(100_000_000u64)).await;
+        })
+        .await;
+
+        // Se consultan sólo las TX construidas desde el bloque 100, y sólo la pendiente se cancela.
+        assert_eq!(node.requests("eth_getTransactionByHash").len(), 2);
+        let sent = node.requests("eth_sendRawTransaction");
+        assert_eq!(sent.len(), 1);
+        let raw: Bytes = serde_json::from_value(sent[0][0].clone()).unwrap();
+        let (cancel, _) = TypedTransaction::decode_signed(&Rlp::new(&raw)).unwrap();
+        assert_eq!(cancel.nonce(), Some(&U256::from(7)));
+        assert_eq!(cancel.value(), Some(&U256::zero()));
+        // Reemplazo válido: supera en un `REPLACEMENT_FEE_BUMP_PERCENT` los fees de la pendiente.
+        let Some(eip1559) = cancel.as_eip1559_ref() else { panic!("cancelación no EIP-1559") };
+        assert!(eip1559.max_fee_per_gas.unwrap() > U256::from(1_000_000_000u64) * REPLACEMENT_FEE_BUMP_PERCENT / 100);
+    }
+
+
+    #[tokio::test]
+    async fn presend_simulation_gas_sets_the_limit_and_the_profit_check() {
+        use crate::testing::FakeNode;
+        use serde_json::json;
+
+        let node = FakeNode::start(|method, _| match method {
+            "eth_getTransactionCount" => Ok(json!("0x7")),
+            "eth_estimateGas" => Ok(json!("0x30d40")),
+            _ => Err(format!("método inesperado {method}")),
+        })
+        .await;
+        let relay_with_gas = |gas_used: u64| FakeNode::start(move |method, _| match method {
+            "eth_callBundle" => Ok(json!({ "results": [{ "gasUsed": gas_used }], "totalGasUsed": gas_used })),
+            _ => Err(format!("método inesperado {method}")),
+        });
+        let simulating = relay_with_gas(321_000).await;
+        // 50M de gas a 1 gwei y $2.000/ETH cuestan $100, más que los $50 de profit.
+        let too_expensive = relay_with_gas(50_000_000).await;
+        let reverting = FakeNode::start(|_, _| Ok(json!({ "results": [{ "error": "execution reverted", "revert": "STF" }] }))).await;
+        let unsupported = FakeNode::start(|method, _| Err(format!("the method {method} does not exist"))).await;
+
+        let client = node.client();
+        let gas_limits = with_test_config(|c| c.presend_gas_simulation = true, async {
+            let opp = ArbitrageOpportunity {
+                path: path([1, 2, 3]),
+                optimal_amount_in: U256::exp10(18),
+                expected_output: U256::exp10(18),
+                net_profit_usd: 50.0,
+                bribe_usd: 0.0,
+                bribe_percent: 0.0,
+                lag: 0.0,
+                tvl: 0.0,
+                score: 0.0,
+                slippage_bps: 0,
+                token_price_usd: 2_000.0,
+                eth_price_usd: 2_000.0,
+                flash_loan_leg: None,
+            };
+            let call = IArbitrageBot::new(CONFIG.contract_address, client.clone())
+                .start_flashloan_arbitrage(opp.path.token_a, opp.optimal_amount_in, Bytes::default());
+            let mut tx = call.tx.clone();
+            tx.set_chain_id(CONFIG.chain_id);
+            let base_fee = U256::exp10(9);
+            let mut results = Vec::new();
+            for relay in [&simulating, &too_expensive, &reverting, &unsupported] {
+                let relay = Provider::<Http>::try_from(relay.url.as_str()).unwrap();
+                results.push(presend_gas_limit(&client, Some(&relay), &call, &tx, &opp, 100, base_fee, 400_000).await);
+            }
+            results.push(presend_gas_limit::<Http>(&client, None, &call, &tx, &opp, 100, base_fee, 400_000).await);
+            results
+        })
+        .await;
+
+        // El gas simulado fija el límite (+25%) sin pasar por `eth_estimateGas`.
+        assert_eq!(gas_limits[0].as_ref().unwrap(), &U256::from(401_250u64));
+        let bundle = &simulating.requests("eth_callBundle")[0][0];
+        assert_eq!(bundle["blockNumber"], json!("0x65"));
+        assert_eq!(bundle["txs"].as_array().unwrap().len(), 1);
+        // Y es el que decide el profit: con él la ruta ya no es rentable.
+        assert!(gas_limits[1].as_ref().unwrap_err().to_string().contains("gas simulado"));
+        assert!(gas_limits[2].as_ref().unwrap_err().to_string().contains("STF"));
+        // Sin simulación (relay sin el método o sin relay) se recurre a la estimación: 200k + 25%.
+        assert_eq!(gas_limits[3].as_ref().unwrap(), &U256::from(250_000u64));
+        assert_eq!(gas_limits[4].as_ref().unwrap(), &U256::from(250_000u64));
+        assert_eq!(node.requests("eth_estimateGas").len(), 2);
+    }
+
+
+    #[tokio::test]
+    async fn bundle_sends_never_exceed_the_concurrency_limit() {
+        use crate::testing::FakeNode;
+        use std::time::Duration;
+
+        assert_eq!(crate::constants::DEFAULT_MAX_CONCURRENT_SENDS, crate::constants::OPPORTUNITY_BUNDLE_SIZE);
+        // Cada envío hace una sola petición (la estimación de gas, que revierte) y el nodo tarda en
+        // responderla: sin el límite, las cinco coincidirían.
+        let node = FakeNode::start(|method, _| match method {
+            "eth_estimateGas" => Err("execution reverted: STF".to_string()),
+            _ => Err(format!("método inesperado {method}")),
+        })
+        .await
+        .with_latency(Duration::from_millis(50));
+        let opportunities: Vec<_> = (0..5u64)
+            .map(|i| ArbitrageOpportunity {
+                path: path([1, 10 + i, 20 + i]),
+                optimal_amount_in: U256::exp10(18),
+                expected_output: U256::exp10(18) * 101 / 100,
+                net_profit_usd: 0.0,
+                bribe_usd: 0.0,
+                bribe_percent: 0.0,
+                lag: 0.0,
+                tvl: 0.0,
+                score: 0.0,
+                slippage_bps: 0,
+                token_price_usd: 0.0,
+                eth_price_usd: 0.0,
+                flash_loan_leg: None,
+            })
+            .collect();
+        let client = node.client();
+        let results = with_test_config(
+            |c| {
+                c.max_concurrent_sends = 2;
+                c.flash_loan_fee_bps = 0;
+            },
+            execute_arbitrage_bundle(client, opportunities, U256::exp10(8), 100),
+        )
+        .await;
+
+        assert_eq!(results.len(), 5);
+        assert!(results.iter().all(|r| r.is_err()));
+        assert_eq!(node.requests("eth_estimateGas").len(), 5);
+        assert_eq!(node.peak_pending(), 2);
+    }
+
+
+    #[test]
+    fn max_gas_price_for_profit_spends_only_the_margin() {
+        // $8 de margen a $2/ETH son 4 ETH repartidos entre 1M de gas.
+        let gas_price = max_gas_price_for_profit(10.0, 2.0, U256::from(1_000_000u64), 2.0);
+        assert_eq!(gas_price, U256::from(4_000_000_000_000u64));
+        assert_eq!(max_gas_price_for_profit(2.0, 2.0, U256::from(1_000_000u64), 2.0), U256::zero());
+        assert_eq!(max_gas_price_for_profit(10.0, 2.0, U256::zero(), 2.0), U256::zero());
+        assert_eq!(max_gas_price_for_profit(10.0, 2.0, U256::from(1_000_000u64), 0.0), U256::zero());
+    }
+
+    #[test]
+    fn thin_margins_get_a_lower_gas_ceiling_than_fat_ones() {
+        // 500k de gas a $2.000/ETH: $0,50 de margen aguantan 0,5 gwei; $50 aguantan 50 gwei.
+        let gas_units = U256::from(500_000u64);
+        let thin = max_gas_price_for_profit(1.5, 1.0, gas_units, 2_000.0);
+        let fat = max_gas_price_for_profit(51.0, 1.0, gas_units, 2_000.0);
+        assert_eq!(thin, U256::from(500_000_000u64));
+        assert_eq!(fat, U256::from(50_000_000_000u64));
+    }
+
+
+    #[tokio::test]
+    async fn canary_call_encodes_a_minimal_unguarded_arbitrage() {
+        use crate::testing::MockChain;
+
+        let route = path([1, 2, 3]);
+        let call = with_test_config(|c| c.encoding_version = EncodingVersion::V4, async {
+            build_canary_call(MockChain::new().provider(), &route, U256::exp10(16)).unwrap()
+        })
+        .await;
+        let calldata = call.calldata().unwrap();
+        assert_eq!(&calldata[..4], &ethers::utils::id("startFlashloanArbitrage(address,uint256,bytes)")[..]);
+        let args = ethers::abi::decode(&[ParamType::Address, ParamType::Uint(256), ParamType::Bytes], &calldata[4..]).unwrap();
+        assert_eq!((args[0].clone().into_address(), args[1].clone().into_uint()), (Some(addr(1)), Some(U256::exp10(16))));
+        let fields = decode_arb_data(&args[2].clone().into_bytes().unwrap().into(), EncodingVersion::V4);
+        assert_eq!(&fields[0].clone().into_bytes().unwrap()[..20], addr(1).as_bytes());
+        assert_eq!(fields[3].clone().into_uint(), Some(U256::zero()));
+    }
+
+    #[tokio::test]
+    async fn canary_only_simulates_a_route_that_repays_the_loan() {
+        use crate::{config::OracleFeed, oracle::OracleMap, testing::MockChain};
+
+        // $10 de canario con token_a a $1: 10 tokens; la primera ruta devuelve menos de lo prestado.
+        let amount = U256::exp10(19);
+        let (losing, repaying) = (path([1, 2, 3]), path([1, 2, 4]));
+        let (feed, contract) = (addr(201), addr(300));
+        let chain = || {
+            MockChain::new()
+                .latest_round_data(feed, 100_000_000, crate::pools::unix_now())
+                .decimals(feed, 8)
+                .quote_multi(DexVariant::UniswapV3, losing.encoded_v3_path(), amount, amount / 2)
+                .quote_multi(DexVariant::UniswapV3, repaying.encoded_v3_path(), amount, amount + 1)
+        };
+        let succeeding = chain().responds(contract, "startFlashloanArbitrage(address,uint256,bytes)", |_| Some(vec![]));
+        let reverting = chain();
+        let oracle_map = OracleMap::new();
+        let account = addr(77);
+        let edit = |c: &mut crate::config::Config| {
+            c.oracle_feeds = [(addr(1), OracleFeed::Chainlink(feed))].into();
+            c.canary_amount_usd = 10.0;
+            c.flash_loan_fee_bps = 0;
+            c.account_type = AccountType::Eoa;
+            c.contract_address = contract;
+        };
+        let routes = [losing.clone(), repaying.clone()];
+        with_test_config(edit, async {
+            assert!(run_canary_trade(succeeding.provider(), account, &routes, &oracle_map).await.is_ok());
+            let error = run_canary_trade(reverting.provider(), account, &routes, &oracle_map).await.unwrap_err().to_string();
+            assert!(error.contains("Canario revertido") && error.contains(&repaying.key()), "{error}");
+            // Sin ruta que cubra el repago no se simula nada: el canario se omite sin abortar.
+            assert!(run_canary_trade(succeeding.provider(), account, &routes[..1], &oracle_map).await.is_ok());
+        })
+        .await;
+        // Sólo `eth_call` y `eth_estimateGas` de la llamada; el provider ni siquiera puede firmar.
+        assert_eq!(succeeding.calls_to("startFlashloanArbitrage(address,uint256,bytes)"), 2);
+        assert_eq!(reverting.calls_to("startFlashloanArbitrage(address,uint256,bytes)"), 1);
+    }
+
+
+    #[tokio::test]
+    async fn trade_records_are_written_as_json_lines_with_their_chain() {
+        use ethers::types::Eip1559TransactionRequest;
+
+        let file = std::env::temp_dir().join(format!("trade_records_{}.jsonl", std::process::id()));
+        let _ = std::fs::remove_file(&file);
+        let sink = TradeRecordSink::File(file.to_string_lossy().into_owned());
+        let opp = ArbitrageOpportunity {
+            path: path([1, 2, 3]),
+            optimal_amount_in: U256::exp10(18),
+            expected_output: U256::exp10(18) * 101 / 100,
+            net_profit_usd: 20.0,
+            bribe_usd: 4.0,
+            bribe_percent: 0.2,
+            lag: 0.0,
+            tvl: 0.0,
+            score: 0.0,
+            slippage_bps: 30,
+            token_price_usd: 2_000.0,
+            eth_price_usd: 2_000.0,
+            flash_loan_leg: None,
+        };
+        let tx: TypedTransaction = Eip1559TransactionRequest::new().max_fee_per_gas(3_000_000_000u64).max_priority_fee_per_gas(1_000_000_000u64).into();
+        let tx_hash = TxHash::repeat_byte(0xab);
+
+        let contents = with_test_config(|c| c.trade_record_sink = sink, async {
+            spawn_trade_record_sink();
+            emit_trade_record(&opp, 1_234, &tx, tx_hash, SubmissionMode::Private);
+            // La escritura la hace la tarea del destino: se espera a que aparezca la línea.
+            for _ in 0..100 {
+                match std::fs::read_to_string(&file) {
+                    Ok(contents) if contents.ends_with('\n') => return contents,
+                    _ => tokio::time::sleep(Duration::from_millis(10)).await,
+                }
+            }
+            panic!("el registro por trade no llegó al archivo");
+        })
+        .await;
+        let _ = std::fs::remove_file(&file);
+
+        assert_eq!(contents.lines().count(), 1);
+        let record: serde_json::Value = serde_json::from_str(contents.trim_end()).unwrap();
+        assert_eq!(record["chain"], "test");
+        assert_eq!(record["block_number"], 1_234);
+        assert_eq!(record["route_key"], opp.path.key());
+        assert_eq!(record["token_path"].as_array().unwrap().len(), 4);
+        assert_eq!(record["slippage_bps"], 30);
+        assert_eq!(record["bribe_usd"], 4.0);
+        assert_eq!(record["max_priority_fee_per_gas"], serde_json::to_value(U256::exp10(9)).unwrap());
+        assert_eq!(record["tx_hash"], serde_json::to_value(tx_hash).unwrap());
+        assert_eq!(record["submission_mode"], serde_json::to_value(SubmissionMode::Private).unwrap());
+    }
+
+
+    #[tokio::test]
+    async fn only_a_successful_receipt_marks_the_route_pools_as_own_traded() {
+        use crate::testing::FakeNode;
+        use serde_json::json;
+
+        let node_with_status = |status: &'static str| FakeNode::start(move |method, params| match method {
+            "eth_getTransactionReceipt" => Ok(json!({
+                "transactionHash": params[0], "transactionIndex": "0x0", "blockHash": TxHash::from_low_u64_be(7),
+                "blockNumber": "0xc8", "from": Address::zero(), "to": Address::zero(), "cumulativeGasUsed": "0x5208",
+                "gasUsed": "0x5208", "effectiveGasPrice": "0x1", "logs": [], "logsBloom": format!("0x{}", "0".repeat(512)),
+                "status": status, "type": "0x2",
+            })),
+            _ => Err(format!("método inesperado {method}")),
+        });
+        let (reverted, mined) = (node_with_status("0x0").await, node_with_status("0x1").await);
+        let opportunity = |tokens| ArbitrageOpportunity {
+            path: path(tokens),
+            optimal_amount_in: U256::zero(),
+            expected_output: U256::zero(),
+            net_profit_usd: 10.0,
+            bribe_usd: 0.0,
+            bribe_percent: 0.0,
+            lag: 0.0,
+            tvl: 0.0,
+            score: 0.0,
+            slippage_bps: 0,
+            token_price_usd: 1.0,
+            eth_price_usd: 1.0,
+            flash_loan_leg: None,
+        };
+        let (reverted_route, mined_route) = (opportunity([1, 2, 3]), opportunity([4, 5, 6]));
+        let (reverted_pools, mined_pools): (Vec<_>, Vec<_>) =
+            (1..=3).map(|i| (reverted_route.path.address(i), mined_route.path.address(i))).unzip();
+        let traded = with_test_config(|_| {}, async {
+            track_execution_receipt(reverted.client(), TxHash::from_low_u64_be(1), reverted_route).await;
+            track_execution_receipt(mined.client(), TxHash::from_low_u64_be(2), mined_route).await;
+            let traded = |pools: &[Address]| pools.iter().map(|p| crate::pools::recent_own_trade(p, 201, 1)).collect::<Vec<_>>();
+            (traded(&reverted_pools), traded(&mined_pools))
+        })
+        .await;
+
+        // El envío aceptado no basta: sólo la TX minada con éxito deja sus pools marcados en su bloque.
+        assert_eq!(traded.0, vec![None; 3]);
+        assert_eq!(traded.1, vec![Some(200); 3]);
+    }
+
+
+    #[tokio::test]
+    async fn sub_gwei_bribes_keep_their_tip_and_the_minimum_priority_fee_gates_them() {
+        // Un bribe de 0,3 gwei por gas queda en 300M wei, no en un tip de cero por redondear a gwei.
+        let tip_wei = bribe_tip_wei(3e-10);
+        assert_eq!(tip_wei, 300_000_000);
+
+        let floored = |budget_fee_wei: u128| with_test_config(
+            |c| {
+                c.priority_fee_floor_percentile = 0.0;
+                c.min_priority_fee_wei = 100_000_000;
+            },
+            async move { apply_priority_fee_floor(tip_wei, budget_fee_wei, "ruta").map_err(|e| e.to_string()) },
+        );
+        let raised = |budget_fee_wei: u128| with_test_config(
+            |c| {
+                c.priority_fee_floor_percentile = 0.0;
+                c.min_priority_fee_wei = 500_000_000;
+            },
+            async move { apply_priority_fee_floor(tip_wei, budget_fee_wei, "ruta").map_err(|e| e.to_string()) },
+        );
+
+        // Sobre MIN_PRIORITY_FEE_WEI el tip sub-gwei se envía tal cual.
+        assert_eq!(floored(tip_wei).await, Ok(300_000_000));
+        // Bajo el mínimo se eleva si el presupuesto llega, y si no, la oportunidad se salta.
+        assert_eq!(raised(600_000_000).await, Ok(500_000_000));
+        let skipped = raised(400_000_000).await.unwrap_err();
+        assert!(skipped.contains("500000000 wei"), "{skipped}");
+    }
+}
@@ -0,0 +1,292 @@
+//! Lecturas de precio de oráculo por encima de `OracleMap`: comprueba que los feeds de un token se
+//! han actualizado dentro de `max_oracle_age_secs` antes de aceptar su precio. Los tokens con feed en
+//! `ORACLE_FEEDS_FILE` se leen directamente de ese feed en lugar del integrado. Todas las lecturas que
+//! llegan al nodo, propias o de `OracleMap`, pasan por el limitador de `ORACLE_MAX_RPS`.
+
+use crate::{
+    config::{OracleFeed, CONFIG},
+    instance::{self, InstanceLocal},
+    oracle::{self, OracleMap},
+    paths::ArbPath,
+    provider::OracleThrottled,
+    types::OraclePriceInfo,
+};
+use ethers::prelude::*;
+use lazy_static::lazy_static;
+use log::{debug, info, warn};
+use prometheus::{register_int_counter_vec, IntCounterVec};
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+
+abigen!(
+    IAggregatorV3,
+    r#"[
+        function latestRoundData() external view returns (uint80 roundId, int256 answer, uint256 startedAt, uint256 updatedAt, uint80 answeredInRound)
+        function decimals() external view returns (uint8)
+    ]"#,
+);
+abigen!(
+    IPyth,
+    r#"[function getPriceUnsafe(bytes32 id) external view returns (int64 price, uint64 conf, int32 expo, uint256 publishTime)]"#,
+);
+
+lazy_static! {
+    static ref STALE_ORACLE_REJECTIONS: IntCounterVec = register_int_counter_vec!("stale_oracle_rejections_total", "Precios de oráculo rechazados por superar max_oracle_age_secs", &["chain"]).unwrap();
+}
+
+// Último `updatedAt` leído de cada feed. Un timestamp fresco sigue siéndolo hasta que envejece, así que
+// sólo se vuelve a leer el feed cuando el valor cacheado ya no basta.
+static FEED_UPDATED_AT: InstanceLocal<Mutex<HashMap<H160, u64>>> = InstanceLocal::new(|| Mutex::new(HashMap::new()));
+
+/// `provider` con sus `eth_call` sujetos al limitador de lecturas de oráculo.
+fn throttled<M: Middleware + 'static>(provider: Arc<M>) -> Arc<OracleThrottled<Arc<M>>> {
+    Arc::new(OracleThrottled::new(provider))
+}
+
+/// `true` si un precio publicado en `published_at` sigue dentro de `max_oracle_age_secs` en `now`
+/// (0 = sin límite).
+pub fn is_fresh(published_at: u64, now: u64) -> bool {
+    CONFIG.max_oracle_age_secs == 0 || now.saturating_sub(published_at) <= CONFIG.max_oracle_age_secs
+}
+
+/// Primer feed de `feeds` cuyo último `updatedAt` queda fuera de la ventana, con su antigüedad en segundos.
+/// Un feed que no responde a `latestRoundData` no se puede comprobar y no cuenta como antiguo.
+async fn stale_feed<M: Middleware + 'static>(feeds: &[H160], provider: Arc<M>, now: u64) -> Option<(H160, u64)> {
+    for &feed in feeds {
+        let cached = FEED_UPDATED_AT.lock().unwrap().get(&feed).copied();
+        let updated_at = match cached {
+            Some(updated_at) if is_fresh(updated_at, now) => updated_at,
+            _ => match IAggregatorV3::new(feed, throttled(provider.clone())).latest_round_data().call().await {
+                Ok((_, _, _, updated_at, _)) => {
+                    let updated_at = updated_at.low_u64();
+                    FEED_UPDATED_AT.lock().unwrap().insert(feed, updated_at);
+                    updated_at
+                }
+                Err(e) => {
+                    debug!("No se pudo leer el timestamp del feed {feed:?}: {e}");
+                    continue;
+                }
+            },
+        };
+        if !is_fresh(updated_at, now) {
+            return Some((feed, now.saturating_sub(updated_at)));
+        }
+    }
+    None
+}
+
+/// Precio USD de un feed de `ORACLE_FEEDS_FILE` y su instante de publicación. `None` si el feed no
+/// responde, publica un precio no positivo o es de Pyth en una chain sin `PYTH_CONTRACT`.
+async fn read_configured_feed<M: Middleware + 'static>(feed: OracleFeed, provider: Arc<M>) -> Option<(f64, u64)> {
+    match feed {
+        OracleFeed::Chainlink(aggregator) => {
+            let aggregator = IAggregatorV3::new(aggregator, throttled(provider));
+            let (_, answer, _, updated_at, _) = aggregator.latest_round_data().call().await.ok()?;
+            let decimals = aggregator.decimals().call().await.ok()?;
+            let answer = answer.to_string().parse::<f64>().ok().filter(|a| *a > 0.0)?;
+            Some((answer / 10f64.powi(decimals as i32), updated_at.low_u64()))
+        }
+        OracleFeed::Pyth(id) => {
+            let (price, _, expo, publish_time) = IPyth::new(CONFIG.pyth_contract?, throttled(provider)).get_price_unsafe(id.0).call().await.ok()?;
+            (price > 0).then(|| (price as f64 * 10f64.powi(expo), publish_time.low_u64()))
+        }
+    }
+}
+
+/// Precio del feed configurado para `token`, rechazado si supera `max_oracle_age_secs`.
+async fn configured_price<M: Middleware + 'static>(token: &H160, feed: OracleFeed, provider: Arc<M>) -> Option<f64> {
+    let Some((price, published_at)) = read_configured_feed(feed, provider).await else {
+        debug!("El feed configurado {feed:?} de {token:?} no devolvió precio.");
+        return None;
+    };
+    let now = crate::pools::unix_now();
+    if !is_fresh(published_at, now) {
+        instance::labeled(&STALE_ORACLE_REJECTIONS).inc();
+        warn!(
+            "Precio de {token:?} rechazado: el feed {feed:?} no se actualiza desde hace {}s (máximo {}s).",
+            now.saturating_sub(published_at), CONFIG.max_oracle_age_secs
+        );
+        return None;
+    }
+    Some(price)
+}
+
+/// `true` si el token tiene feed propio, configurado o integrado en `OracleMap`.
+pub fn has_feed(oracle_map: &OracleMap, token: &H160) -> bool {
+    CONFIG.oracle_feeds.contains_key(token) || oracle_map.get_feeds(token).is_some()
+}
+
+/// Comprueba al arrancar que cada feed de `ORACLE_FEEDS_FILE` devuelve precio y avisa de los que no.
+/// Devuelve los tokens cuyo feed no resuelve.
+pub async fn validate_configured_feeds<M: Middleware + 'static>(provider: Arc<M>) -> Vec<H160> {
+    let mut unresolved = Vec::new();
+    for (token, feed) in &CONFIG.oracle_feeds {
+        match configured_price(token, *feed, provider.clone()).await {
+            Some(price) => info!("Feed configurado de {token:?} ({feed:?}): ${price:.4}."),
+            None => {
+                warn!("El feed configurado de {token:?} ({feed:?}) no devuelve un precio válido.");
+                unresolved.push(*token);
+            }
+        }
+    }
+    unresolved
+}
+
+/// `true` si los feeds del token están dentro de `max_oracle_age_secs`. Los tokens sin feed propio
+/// (precio derivado de pools) no tienen timestamp de publicación que comprobar.
+pub async fn price_is_fresh<M: Middleware + 'static>(oracle_map: &OracleMap, token: &H160, provider: Arc<M>) -> bool {
+    if CONFIG.max_oracle_age_secs == 0 { return true; }
+    if let Some(feed) = CONFIG.oracle_feeds.get(token) {
+        return configured_price(token, *feed, provider).await.is_some();
+    }
+    let Some(feeds) = oracle_map.get_feeds(token) else { return true };
+    match stale_feed(&feeds, provider, crate::pools::unix_now()).await {
+        Some((feed, age)) => {
+            instance::labeled(&STALE_ORACLE_REJECTIONS).inc();
+            warn!("Precio de {token:?} rechazado: el feed {feed:?} no se actualiza desde hace {age}s (máximo {}s).", CONFIG.max_oracle_age_secs);
+            false
+        }
+        None => true,
+    }
+}
+
+/// `OracleMap::get_price` (o el feed configurado del token) que devuelve `None` si el feed del token supera `max_oracle_age_secs`.
+pub async fn get_price<M: Middleware + 'static>(oracle_map: &OracleMap, token: &H160, provider: Arc<M>) -> Option<OraclePriceInfo> {
+    if let Some(feed) = CONFIG.oracle_feeds.get(token) {
+        return configured_price(token, *feed, provider).await.map(|price| OraclePriceInfo { price, lag: 0.0 });
+    }
+    let info = oracle_map.get_price(token, throttled(provider.clone())).await?;
+    price_is_fresh(oracle_map, token, provider).await.then_some(info)
+}
+
+/// `oracle::get_max_profit_oracle` para `token_a` que descarta la ruta si el precio de alguno de sus
+/// tokens intermedios supera `max_oracle_age_secs`.
+pub async fn get_max_profit_oracle<M: Middleware + 'static>(
+    path: &ArbPath, spot_price: f64, oracle_map: &OracleMap, provider: Arc<M>,
+) -> Option<OraclePriceInfo> {
+    for token in [path.token_b, path.token_c] {
+        if !price_is_fresh(oracle_map, &token, provider.clone()).await { return None; }
+    }
+    if let Some(feed) = CONFIG.oracle_feeds.get(&path.token_a) {
+        // Un único feed: el lag es la distancia relativa entre el spot de la ruta y el oráculo.
+        let price = configured_price(&path.token_a, *feed, provider).await?;
+        let lag = if spot_price > 0.0 { ((price - spot_price) / spot_price).abs() } else { 0.0 };
+        return Some(OraclePriceInfo { price, lag });
+    }
+    let info = oracle::get_max_profit_oracle(&path.token_a, spot_price, oracle_map, throttled(provider.clone())).await?;
+    price_is_fresh(oracle_map, &path.token_a, provider).await.then_some(info)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::instance::with_test_config;
+    use crate::paths::tests::addr;
+    use crate::testing::MockChain;
+
+    #[tokio::test]
+    async fn stale_feed_rejects_only_outside_the_window() {
+        let now = 1_700_000_000;
+        let (inside, outside) = (addr(1), addr(2));
+        let chain = MockChain::new()
+            .latest_round_data(inside, 200_000_000_000, now - 120)
+            .latest_round_data(outside, 200_000_000_000, now - 121);
+
+        with_test_config(|c| c.max_oracle_age_secs = 120, async {
+            assert_eq!(stale_feed(&[inside], chain.provider(), now).await, None);
+            assert_eq!(stale_feed(&[inside, outside], chain.provider(), now).await, Some((outside, 121)));
+            // Un feed que no responde no se puede comprobar.
+            assert_eq!(stale_feed(&[addr(3)], chain.provider(), now).await, None);
+        })
+        .await;
+        with_test_config(|c| c.max_oracle_age_secs = 0, async {
+            assert_eq!(stale_feed(&[outside], chain.provider(), now).await, None);
+        })
+        .await;
+    }
+
+    #[tokio::test]
+    async fn stale_feed_rereads_a_cached_timestamp_once_it_ages_out() {
+        let now = 1_700_000_000;
+        let feed = addr(1);
+        let chain = MockChain::new().latest_round_data(feed, 1, now - 60);
+
+        with_test_config(|c| c.max_oracle_age_secs = 120, async {
+            assert_eq!(stale_feed(&[feed], chain.provider(), now).await, None);
+            assert_eq!(stale_feed(&[feed], chain.provider(), now + 60).await, None);
+            assert_eq!(chain.calls_to("latestRoundData()"), 1);
+            assert_eq!(stale_feed(&[feed], chain.provider(), now + 61).await, Some((feed, 121)));
+            assert_eq!(chain.calls_to("latestRoundData()"), 2);
+        })
+        .await;
+    }
+
+    #[tokio::test]
+    async fn configured_feeds_load_from_a_file_and_replace_the_built_in_ones() {
+        use crate::config::{init_test_env, Config};
+        use ethers::abi::Token;
+
+        let now = crate::pools::unix_now();
+        let (pyth_token, chainlink_token, broken_token) = (addr(1), addr(2), addr(3));
+        let (aggregator, broken_aggregator) = (addr(20), addr(30));
+        let pyth_id = H256::repeat_byte(0xab);
+        let file = std::env::temp_dir().join(format!("oracle_feeds_{}.json", std::process::id()));
+        std::fs::write(
+            &file,
+            format!(
+                r#"{{"pyth": {{"{pyth_token:?}": "{pyth_id:?}"}}, "chainlink": {{"{chainlink_token:?}": "{aggregator:?}", "{broken_token:?}": "{broken_aggregator:?}"}}}}"#
+            ),
+        )
+        .unwrap();
+        init_test_env();
+        std::env::set_var("FEEDFILE_ORACLE_FEEDS_FILE", &file);
+        let config = Config::for_chain_instance("feedfile").unwrap();
+        std::fs::remove_file(&file).unwrap();
+        assert_eq!(config.oracle_feeds.len(), 3);
+        assert_eq!(config.oracle_feeds[&pyth_token], OracleFeed::Pyth(pyth_id));
+        assert_eq!(config.oracle_feeds[&chainlink_token], OracleFeed::Chainlink(aggregator));
+
+        let chain = MockChain::new()
+            .latest_round_data(aggregator, 200_000_000_000, now)
+            .returns(aggregator, "decimals()", &[], &[Token::Uint(8.into())])
+            // 6.123,45 con exponente -2, publicado hace 10 s.
+            .returns(
+                *crate::constants::PYTH_ORACLE_CONTRACT,
+                "getPriceUnsafe(bytes32)",
+                &[Token::FixedBytes(pyth_id.0.to_vec())],
+                &[
+                    Token::Int(612_345.into()),
+                    Token::Uint(0.into()),
+                    Token::Int(I256::from(-2).into_raw()),
+                    Token::Uint((now - 10).into()),
+                ],
+            );
+        let oracle_map = OracleMap::new();
+        with_test_config(
+            |c| {
+                c.oracle_feeds = config.oracle_feeds.clone();
+                c.max_oracle_age_secs = 60;
+            },
+            async {
+                let chainlink_price = get_price(&oracle_map, &chainlink_token, chain.provider()).await;
+                assert_eq!(chainlink_price.map(|info| info.price), Some(2_000.0));
+                let pyth_price = get_price(&oracle_map, &pyth_token, chain.provider()).await.unwrap();
+                assert!((pyth_price.price - 6_123.45).abs() < 1e-9);
+                assert!(has_feed(&oracle_map, &broken_token));
+                assert_eq!(validate_configured_feeds(chain.provider()).await, vec![broken_token]);
+            },
+        )
+        .await;
+        // Fuera de la ventana de antigüedad el precio de Pyth se rechaza.
+        with_test_config(
+            |c| {
+                c.oracle_feeds = config.oracle_feeds.clone();
+                c.max_oracle_age_secs = 5;
+            },
+            async { assert!(get_price(&oracle_map, &pyth_token, chain.provider()).await.is_none()) },
+        )
+        .await;
+    }
+}
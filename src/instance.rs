@@ -0,0 +1,201 @@
+//! Instancias independientes de la estrategia por chain dentro de un mismo proceso (`CHAIN_INSTANCES`).
+//!
+//! Las tareas de cada `StrategyInstance` corren dentro de su contexto task-local: `CONFIG` resuelve a la
+//! configuración de la instancia y cada estado declarado con `InstanceLocal` (estadísticas de rutas,
+//! locks, pools, nonces...) tiene una copia propia. Fuera de una instancia hay una única copia de proceso.
+use crate::config::Config;
+use once_cell::sync::OnceCell;
+use prometheus::core::{MetricVec, MetricVecBuilder};
+use std::{
+    any::Any,
+    collections::HashMap,
+    future::Future,
+    ops::Deref,
+    sync::{Arc, RwLock},
+};
+
+tokio::task_local! {
+    static CURRENT: Arc<StrategyInstance>;
+}
+
+// Etiqueta `chain` de las métricas fuera de una instancia (modo de una sola chain).
+const DEFAULT_INSTANCE_NAME: &str = "default";
+
+/// Instancia de la estrategia para una chain: su configuración y su copia del estado por instancia.
+/// Los proveedores, la wallet y el universo de pools salen de esa configuración al arrancarla.
+pub struct StrategyInstance {
+    name: String,
+    config: &'static Config,
+    locals: RwLock<HashMap<usize, &'static (dyn Any + Send + Sync)>>,
+}
+
+impl StrategyInstance {
+    /// La configuración y el estado de la instancia viven lo que el proceso.
+    pub fn new(name: &str, config: Config) -> Arc<Self> {
+        Arc::new(StrategyInstance {
+            name: name.to_string(),
+            config: Box::leak(Box::new(config)),
+            locals: RwLock::new(HashMap::new()),
+        })
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn config(&self) -> &'static Config {
+        self.config
+    }
+
+    /// Ejecuta `fut` dentro de la instancia.
+    pub async fn scope<F: Future>(self: &Arc<Self>, fut: F) -> F::Output {
+        CURRENT.scope(self.clone(), fut).await
+    }
+
+    /// Copia de la instancia del estado `key`, creada en el primer acceso.
+    fn local<T: Send + Sync + 'static>(&self, key: &InstanceLocal<T>) -> &'static T {
+        let id = key as *const InstanceLocal<T> as usize;
+        let existing = self.locals.read().unwrap().get(&id).copied();
+        // El inicializador corre sin el lock: puede leer a su vez `CONFIG` u otro estado por instancia.
+        let value = existing.unwrap_or_else(|| {
+            let fresh: &'static (dyn Any + Send + Sync) = Box::leak(Box::new((key.init)()));
+            *self.locals.write().unwrap().entry(id).or_insert(fresh)
+        });
+        value.downcast_ref::<T>().expect("estado por instancia con un tipo distinto al declarado")
+    }
+}
+
+/// Estado global con una copia por `StrategyInstance` (y otra de proceso fuera de ellas).
+/// Se declara como un `static` normal y se usa igual que el valor que envuelve.
+pub struct InstanceLocal<T: 'static> {
+    init: fn() -> T,
+    process: OnceCell<T>,
+}
+
+impl<T> InstanceLocal<T> {
+    pub const fn new(init: fn() -> T) -> Self {
+        InstanceLocal { init, process: OnceCell::new() }
+    }
+}
+
+impl<T: Send + Sync + 'static> Deref for InstanceLocal<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        match CURRENT.try_with(Arc::clone) {
+            Ok(instance) => instance.local(self),
+            Err(_) => self.process.get_or_init(self.init),
+        }
+    }
+}
+
+/// Configuración de la instancia en curso, si la hay.
+pub fn current_config() -> Option<&'static Config> {
+    CURRENT.try_with(|instance| instance.config).ok()
+}
+
+/// Nombre de la instancia en curso, para la etiqueta `chain` de las métricas.
+pub fn current_name() -> String {
+    CURRENT
+        .try_with(|instance| instance.name.clone())
+        .unwrap_or_else(|_| DEFAULT_INSTANCE_NAME.to_string())
+}
+
+/// Serie de `metric` con la etiqueta `chain` de la instancia en curso: contadores, topes de la ejecución
+/// y PnL son por chain, no del proceso.
+pub fn labeled<T: MetricVecBuilder>(metric: &MetricVec<T>) -> T::M {
+    metric.with_label_values(&[&current_name()])
+}
+
+/// Envuelve `fut` para que corra en la instancia en curso aunque se lance con `tokio::spawn`
+/// (las tareas nuevas no heredan el contexto task-local).
+pub fn in_current<F: Future>(fut: F) -> impl Future<Output = F::Output> {
+    let instance = CURRENT.try_with(Arc::clone).ok();
+    async move {
+        match instance {
+            Some(instance) => CURRENT.scope(instance, fut).await,
+            None => fut.await,
+        }
+    }
+}
+
+/// `tokio::spawn` dentro de la instancia en curso.
+pub fn spawn<F>(fut: F) -> tokio::task::JoinHandle<F::Output>
+where
+    F: Future + Send + 'static,
+    F::Output: Send + 'static,
+{
+    tokio::spawn(in_current(fut))
+}
+
+/// Ejecuta `fut` en una instancia de test con la configuración base modificada por `edit`: aísla a
+/// los tests que dependen de `CONFIG` o del estado por instancia.
+#[cfg(test)]
+pub(crate) async fn with_test_config<F: Future>(edit: impl FnOnce(&mut Config), fut: F) -> F::Output {
+    crate::config::init_test_env();
+    let mut config = Config::from_env().expect("configuración de test válida");
+    edit(&mut config);
+    StrategyInstance::new("test", config).scope(fut).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{init_test_env, Config, CONFIG};
+    use crate::optimization::ROUTE_STATS;
+
+    fn test_instance(name: &str, chain_id: u64) -> Arc<StrategyInstance> {
+        init_test_env();
+        let mut config = Config::for_chain_instance(name).expect("configuración de test válida");
+        config.chain_id = chain_id;
+        StrategyInstance::new(name, config)
+    }
+
+    #[tokio::test]
+    async fn instances_have_isolated_config_and_state() {
+        let alfa = test_instance("alfa", 10);
+        let beta = test_instance("beta", 8453);
+
+        alfa.scope(async {
+            assert_eq!(CONFIG.chain_id, 10);
+            assert_eq!(current_name(), "alfa");
+            assert!(CONFIG.cache_path.contains(".alfa"));
+            ROUTE_STATS.lock().unwrap().entry("ruta-alfa".to_string()).or_default().successes += 1;
+        })
+        .await;
+        beta.scope(async {
+            assert_eq!(CONFIG.chain_id, 8453);
+            assert!(ROUTE_STATS.lock().unwrap().get("ruta-alfa").is_none());
+            ROUTE_STATS.lock().unwrap().entry("ruta-beta".to_string()).or_default().failures += 1;
+        })
+        .await;
+
+        alfa.scope(async {
+            let stats = ROUTE_STATS.lock().unwrap();
+            assert_eq!(stats.get("ruta-alfa").map(|s| s.successes), Some(1));
+            assert!(stats.get("ruta-beta").is_none());
+        })
+        .await;
+        assert_eq!(current_name(), DEFAULT_INSTANCE_NAME);
+        assert!(ROUTE_STATS.lock().unwrap().get("ruta-alfa").is_none());
+    }
+
+    #[tokio::test]
+    async fn spawned_tasks_inherit_the_instance() {
+        let gamma = test_instance("gamma", 137);
+        let chain_id = gamma.scope(async { spawn(async { CONFIG.chain_id }).await.unwrap() }).await;
+        assert_eq!(chain_id, 137);
+
+        let outside = gamma.scope(async { tokio::spawn(async { current_name() }).await.unwrap() }).await;
+        assert_eq!(outside, DEFAULT_INSTANCE_NAME);
+    }
+
+    #[tokio::test]
+    async fn labeled_metrics_are_per_instance() {
+        let trades = prometheus::IntGaugeVec::new(prometheus::Opts::new("test_trades", "test"), &["chain"]).unwrap();
+        test_instance("delta", 10).scope(async { labeled(&trades).add(3) }).await;
+        test_instance("epsilon", 8453).scope(async { assert_eq!(labeled(&trades).get(), 0) }).await;
+        assert_eq!(trades.with_label_values(&["delta"]).get(), 3);
+        assert_eq!(labeled(&trades).get(), 0);
+    }
+}
@@ -1,41 +1,74 @@
+pub mod account;
 pub mod config;
 pub mod constants;
+pub mod control;
 pub mod execution;
+pub mod feeds;
+pub mod instance;
+pub mod math;
 pub mod multi;
 pub mod oracle;
 pub mod optimization;
 pub mod paths;
 pub mod pools;
 pub mod provider;
+pub mod replay;
 pub mod simulator;
 pub mod streams;
 pub mod strategy;
+pub mod sweeper;
+pub mod telemetry;
+#[cfg(test)]
+mod testing;
 pub mod types;
 pub mod utils;
 
 use crate::config::CONFIG;
+use crate::instance::InstanceLocal;
 use anyhow::Result;
 use ethers::prelude::*;
-use lazy_static::lazy_static;
-use log::{error, info};
-use std::collections::HashSet;
+use log::{error, info, warn};
+use std::collections::{hash_map::Entry, HashMap};
 use std::sync::{Arc, Mutex};
 use tokio::task::JoinSet;
 
-lazy_static! {
-    static ref EXECUTED_OPPORTUNITIES: Mutex<HashSet<String>> = Mutex::new(HashSet::new());
+// Clave `bloque-ruta` -> si la TX llegó a enviarse. Un lock sin envío no debe contar como ejecutado.
+static EXECUTED_OPPORTUNITIES: InstanceLocal<Mutex<HashMap<String, bool>>> = InstanceLocal::new(|| Mutex::new(HashMap::new()));
+
+fn lock_key(block_number: u64, path: &paths::ArbPath) -> String {
+    format!("{}-{}", block_number, path.key())
 }
 
 pub fn lock_opportunity(block_number: u64, path: &paths::ArbPath) -> bool {
-    let key = path.key();
-    let lock_key = format!("{}-{}", block_number, key);
     let mut executed = EXECUTED_OPPORTUNITIES.lock().unwrap();
-    executed.insert(lock_key)
+    match executed.entry(lock_key(block_number, path)) {
+        Entry::Occupied(_) => false,
+        Entry::Vacant(slot) => {
+            slot.insert(false);
+            true
+        }
+    }
+}
+
+/// Marca que la TX de la oportunidad bloqueada llegó a enviarse.
+pub fn mark_opportunity_sent(block_number: u64, path: &paths::ArbPath) {
+    if let Some(sent) = EXECUTED_OPPORTUNITIES.lock().unwrap().get_mut(&lock_key(block_number, path)) {
+        *sent = true;
+    }
+}
+
+/// Libera el lock de una oportunidad que no llegó a enviarse (p. ej. bundle descartado).
+pub fn release_opportunity_lock(block_number: u64, path: &paths::ArbPath) {
+    let mut executed = EXECUTED_OPPORTUNITIES.lock().unwrap();
+    let key = lock_key(block_number, path);
+    if executed.get(&key) == Some(&false) {
+        executed.remove(&key);
+    }
 }
 
 pub fn clear_old_locks(current_block: u64) {
     let mut executed = EXECUTED_OPPORTUNITIES.lock().unwrap();
-    executed.retain(|k| {
+    executed.retain(|k, _| {
         if let Some(bn_str) = k.split('-').next() {
             if let Ok(bn) = bn_str.parse::<u64>() {
                 return bn.saturating_add(10) >= current_block;
@@ -45,39 +78,179 @@ pub fn clear_old_locks(current_block: u64) {
     });
 }
 
+/// Re-ejecuta la evaluación de un bloque capturado previamente (`CAPTURE_DIR`).
+pub async fn replay(file: &str) -> Result<()> {
+    dotenv::dotenv().ok();
+    utils::setup_logger()?;
+    utils::with_final_metrics_push(async {
+        let provider = Arc::new(Provider::<Http>::try_from(CONFIG.simulation_url.as_str())?);
+        replay::replay_captured_block(std::path::Path::new(file), provider).await?;
+        Ok(())
+    })
+    .await
+}
+
 pub async fn run() -> Result<()> {
     dotenv::dotenv().ok();
     utils::setup_logger()?;
+    telemetry::init_tracing()?;
 
     info!(" Arrancando MEV Harvester v4.0...");
+    // Valida la configuración antes de tocar `CONFIG`, mostrando todos los problemas de una vez.
+    if let Err(e) = config::Config::from_env() {
+        error!("{e}");
+        return Err(e.into());
+    }
+    // Cada instancia por chain se valida entera antes de arrancar ninguna.
+    let mut instances = Vec::new();
+    for name in &CONFIG.chain_instances {
+        match config::Config::for_chain_instance(name) {
+            Ok(instance_config) => instances.push(instance::StrategyInstance::new(name, instance_config)),
+            Err(e) => {
+                error!("{e}");
+                return Err(e.into());
+            }
+        }
+    }
+
+    let result = utils::with_final_metrics_push(run_instances(&instances)).await;
+    telemetry::shutdown();
+    result
+}
+
+/// Lanza las estrategias (la base o una por instancia) y espera a Ctrl+C o a que termine una tarea esencial.
+async fn run_instances(instances: &[Arc<instance::StrategyInstance>]) -> Result<()> {
+    let mut set = JoinSet::new();
+    info!(" Lanzando tareas asíncronas...");
+    if let Some(url) = &CONFIG.pushgateway_url {
+        info!("Empujando métricas a {} cada {}s.", url, CONFIG.pushgateway_interval_secs);
+        set.spawn(utils::run_metrics_pusher(
+            url.clone(),
+            CONFIG.pushgateway_job.clone(),
+            std::time::Duration::from_secs(CONFIG.pushgateway_interval_secs.max(1)),
+        ));
+    }
+
+    if instances.is_empty() {
+        start_strategy(&mut set).await?;
+    }
+    for instance in instances {
+        info!(" Arrancando la instancia '{}' (chain {})...", instance.name(), instance.config().chain_id);
+        instance.scope(start_strategy(&mut set)).await?;
+    }
+
+    info!(" Bot V4 corriendo. Presiona Ctrl+C para terminar.");
+
+    // --- FASE 4: Gestión del Ciclo de Vida ---
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => {
+            info!("Señal de Ctrl+C recibida. Abortando todas las tareas...");
+            set.abort_all();
+            info!("Tareas abortadas. Saliendo.");
+        }
+        Some(res) = set.join_next() => {
+            match res {
+                Ok(_) => error!("Una tarea esencial ha terminado inesperadamente sin error."),
+                Err(e) => error!("Una tarea esencial ha fallado (JoinError): {e:?}. El bot se detendrá."),
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Conecta, sincroniza y lanza en `set` las tareas de la estrategia con la `CONFIG` en curso: la base,
+/// o la de la instancia por chain si se llama dentro de su `scope` (las tareas heredan la instancia).
+async fn start_strategy(set: &mut JoinSet<()>) -> Result<()> {
+    config::validate_address_checksums()?;
+    info!("Configuración efectiva:");
+    for line in CONFIG.redacted_summary() {
+        info!("  {line}");
+    }
+    execution::spawn_trade_record_sink();
+    if CONFIG.shadow_mode {
+        warn!(
+            " MODO SOMBRA ACTIVO: se enviarán TX reales con priority fee fijo de {} wei (reserva mínima {} ETH).",
+            CONFIG.shadow_priority_fee_wei, CONFIG.shadow_gas_reserve_eth
+        );
+    }
 
     // --- FASE 1: Conexión e Inicialización ---
     let provider = Provider::<Http>::try_from(CONFIG.https_url.as_str())?;
     let wallet = CONFIG.private_key.parse::<LocalWallet>()?.with_chain_id(CONFIG.chain_id);
     let client = Arc::new(SignerMiddleware::new(provider, wallet));
     let provider_ws = Arc::new(Provider::<Ws>::connect(&CONFIG.wss_url).await?);
+    provider::verify_chain_id(client.provider(), "HTTP").await?;
+    provider::verify_chain_id(provider_ws.as_ref(), "WS").await?;
+    if CONFIG.account_type == config::AccountType::Eip7702 {
+        account::ensure_delegated(&client).await?;
+    }
+    if CONFIG.measure_block_time {
+        if let Some(measured) = provider::measure_block_time(client.provider()).await {
+            execution::seed_block_time_ms(measured);
+        }
+    }
+    // Las lecturas (`eth_call`) van al nodo de simulación; los envíos, al de ejecución de `client`.
+    let sim_provider = Arc::new(Provider::<Http>::try_from(CONFIG.simulation_url.as_str())?);
+    if CONFIG.simulate_pending && !provider::detect_pending_support(sim_provider.as_ref()).await {
+        warn!("SIMULATE_PENDING activo pero el nodo de simulación no expone el bloque 'pending'. Se simula contra 'latest'.");
+    }
     let oracle_map = Arc::new(oracle::OracleMap::new());
+    feeds::validate_configured_feeds(sim_provider.clone()).await;
+    if let Err(e) = execution::sync_onchain_min_profit(sim_provider.clone(), &oracle_map).await {
+        warn!("No se pudo leer el profit mínimo del contrato: {e:?}");
+    }
+    execution::verify_approval_reset_support(sim_provider.clone()).await;
 
     // --- FASE 2: Sincronización Inicial ---
+    if let Ok(cached) = pools::read_pool_cache() {
+        pools::check_cache_drift(&sim_provider, cached.len()).await;
+    }
     info!("Realizando sincronización inicial de pools (puede tardar varios minutos)...");
-    let initial_pools = pools::load_all_pools_v3(provider_ws.clone(), &oracle_map).await?;
+    let initial_pools = pools::load_all_pools_v3(sim_provider.clone(), &oracle_map).await?;
+    if CONFIG.quoter_sanity_check {
+        simulator::verify_quoters(sim_provider.clone(), &initial_pools).await;
+    }
     let initial_paths = paths::generate_triangular_paths(&initial_pools, CONFIG.token_in_address, &oracle_map);
+    paths::log_universe_summary(&initial_pools, &initial_paths, &oracle_map, log::Level::Info);
+    if let Some(dump_file) = &CONFIG.paths_dump_file {
+        if let Err(e) = paths::dump_paths(&initial_paths, std::path::Path::new(dump_file), CONFIG.paths_dump_token) {
+            error!("No se pudo volcar el set de rutas a {dump_file}: {e:?}");
+        }
+    }
+
+    if CONFIG.canary_trade {
+        info!("CANARY_TRADE activo: validando el camino de ejecución con un trade mínimo...");
+        execution::run_canary_trade(client.clone(), client.address(), &initial_paths, &oracle_map).await?;
+    }
 
     // --- FASE 3: Lanzamiento de Tareas Asíncronas ---
-    let (event_sender, _) = tokio::sync::broadcast::channel(512);
-    let mut set = JoinSet::new();
+    // Suscribimos la estrategia ANTES de lanzar el stream para que ningún bloque se pierda en el arranque.
+    let (event_sender, event_receiver) = tokio::sync::broadcast::channel(512);
 
-    info!(" Lanzando tareas asíncronas...");
-    set.spawn(streams::stream_new_blocks(provider_ws.clone(), event_sender.clone()));
+    if let Some(addr) = CONFIG.control_addr {
+        instance::spawn(async move {
+            if let Err(e) = control::run_control_server(addr).await {
+                warn!("No se pudo abrir el servidor de control en {addr}: {e:?}");
+            }
+        });
+    }
+    if !CONFIG.watch_tokens.is_empty() {
+        set.spawn(instance::in_current(streams::stream_watch_token_events(provider_ws.clone())));
+    }
+    set.spawn(instance::in_current(streams::stream_new_blocks(provider_ws.clone(), event_sender.clone())));
+    if CONFIG.sweep_interval_secs > 0 && !CONFIG.sweep_tokens.is_empty() {
+        set.spawn(instance::in_current(sweeper::run_sweeper(client.clone(), oracle_map.clone())));
+    }
 
     let strategy_client = client.clone();
     let strategy_oracles = oracle_map.clone();
-    set.spawn(async move {
+    set.spawn(instance::in_current(async move {
         if let Err(e) = strategy::event_handler(
             strategy_client,
-            provider_ws,
+            sim_provider,
             strategy_oracles,
-            event_sender,
+            event_receiver,
             initial_pools,
             initial_paths,
         )
@@ -85,24 +258,6 @@ pub async fn run() -> Result<()> {
         {
             error!("El manejador de estrategia ha fallado críticamente: {e:?}");
         }
-    });
-
-    info!(" Bot V4 corriendo. Presiona Ctrl+C para terminar.");
-
-    // --- FASE 4: Gestión del Ciclo de Vida ---
-    tokio::select! {
-        _ = tokio::signal::ctrl_c() => {
-            info!("Señal de Ctrl+C recibida. Abortando todas las tareas...");
-            set.abort_all();
-            info!("Tareas abortadas. Saliendo.");
-        }
-        Some(res) = set.join_next() => {
-            match res {
-                Ok(_) => error!("Una tarea esencial ha terminado inesperadamente sin error."),
-                Err(e) => error!("Una tarea esencial ha fallado (JoinError): {e:?}. El bot se detendrá."),
-            }
-        }
-    }
-
+    }));
     Ok(())
 }
@@ -0,0 +1,53 @@
+use ethers::types::{H160, U256};
+use log::debug;
+use rust_decimal::{prelude::ToPrimitive, Decimal};
+use std::str::FromStr;
+
+/// Valor en USD de `amount` unidades base de `token` (con `decimals` decimales) a `price` USD por token.
+/// Conversión única para el ROI, el notional de las oportunidades y el profit on-chain: un precio no
+/// finito o no positivo da 0.
+pub fn to_usd(amount: U256, token: H160, price: f64, decimals: u8) -> f64 {
+    if !price.is_finite() || price <= 0.0 {
+        debug!("Precio inválido ({price}) para convertir a USD el token {token:?}.");
+        return 0.0;
+    }
+    token_units(amount, decimals) * price
+}
+
+/// `amount` en unidades enteras del token. Usa `Decimal` para no perder precisión y recurre a `f64`
+/// cuando el valor excede su rango (28 dígitos).
+pub fn token_units(amount: U256, decimals: u8) -> f64 {
+    let scale = 10u128.checked_pow(decimals as u32).and_then(|s| Decimal::from_str(&s.to_string()).ok());
+    match (Decimal::from_str(&amount.to_string()), scale) {
+        (Ok(value), Some(scale)) => (value / scale).to_f64().unwrap_or(0.0),
+        _ => amount.to_string().parse::<f64>().unwrap_or(0.0) / 10f64.powi(decimals as i32),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn to_usd_scales_by_decimals_and_price() {
+        let token = H160::zero();
+        // 18 decimales: 1,5 WETH a $2.000.
+        assert_eq!(to_usd(U256::exp10(18) * 3 / 2, token, 2_000.0, 18), 3_000.0);
+        // 8 decimales: 0,0025 WBTC a $60.000.
+        assert_eq!(to_usd(U256::from(250_000u64), token, 60_000.0, 8), 150.0);
+        // 6 decimales: 1,25 USDC a $1 y a $0,998.
+        assert_eq!(to_usd(U256::from(1_250_000u64), token, 1.0, 6), 1.25);
+        assert!((to_usd(U256::from(1_250_000u64), token, 0.998, 6) - 1.2475).abs() < 1e-12);
+        assert_eq!(to_usd(U256::from(1_250_000u64), token, 0.0, 6), 0.0);
+        assert_eq!(to_usd(U256::from(1_250_000u64), token, -1.0, 6), 0.0);
+        assert_eq!(to_usd(U256::from(1_250_000u64), token, f64::NAN, 6), 0.0);
+        assert_eq!(to_usd(U256::from(1_250_000u64), token, f64::INFINITY, 6), 0.0);
+    }
+
+    #[test]
+    fn token_units_handles_amounts_beyond_decimal_range() {
+        assert_eq!(token_units(U256::exp10(30), 18), 1e12);
+        assert_eq!(token_units(U256::exp10(40), 18), 1e22);
+        assert_eq!(token_units(U256::from(5), 0), 5.0);
+    }
+}
@@ -1,16 +1,24 @@
-use anyhow::Result;
+use crate::config::CONFIG;
+use crate::instance::InstanceLocal;
+use anyhow::{anyhow, Result};
 use ethers::{
+    abi::Token,
     prelude::*,
     types::{H160, U256},
 };
-use std::{collections::HashMap, sync::Arc};
+use log::{debug, info, warn};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
 
 // ABIs para los contratos con los que interactuaremos en el multicall.
 abigen!(IUniswapV3Pool, "./abi/IUniswapV3Pool.json");
 abigen!(IERC20, "./abi/IERC20.json");
 
 /// Estructura para contener los datos brutos de un pool obtenidos del multicall.
-#[derive(Debug, Clone, Copy, Default)]
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
 pub struct RawPoolData {
     pub factory: H160,
     pub token0: H160,
@@ -19,64 +27,174 @@ pub struct RawPoolData {
     pub decimals1: u8,
     pub liquidity: u128,
     pub sqrt_price_x96: U256,
+    /// Tick actual de `slot0`.
+    #[serde(default)]
+    pub tick: i32,
+    /// Flag `unlocked` de `slot0`: `false` durante una reentrada o en un estado anómalo del pool.
+    #[serde(default = "default_unlocked")]
+    pub unlocked: bool,
     pub fee: u32,
     pub balance0: U256,
     pub balance1: U256,
 }
 
-/// Obtiene los datos esenciales de una lista de pools V3 usando multicall.
-pub async fn batch_get_pool_data<M: Middleware + 'static>(
-    provider: Arc<M>,
-    pool_addresses: &[H160],
-) -> Result<HashMap<H160, RawPoolData>> {
-    let mut multicall = Multicall::new(provider.clone(), None).await?;
+// Las capturas anteriores a leer `unlocked` sólo contenían pools operables.
+fn default_unlocked() -> bool {
+    true
+}
+
+/// Atributos inmutables de un pool: se obtienen una sola vez y se reutilizan entre refrescos (y entre
+/// arranques, a través del snapshot de precios).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct StaticPoolData {
+    pub factory: H160,
+    pub token0: H160,
+    pub token1: H160,
+    pub decimals0: u8,
+    pub decimals1: u8,
+    pub fee: u32,
+}
+
+static STATIC_POOL_DATA: InstanceLocal<Mutex<HashMap<H160, StaticPoolData>>> = InstanceLocal::new(|| Mutex::new(HashMap::new()));
+
+/// Decodifica los 4 resultados estáticos de un pool (factory, token0, token1, fee).
+/// Devuelve `None` si cualquier sub-llamada falló o no tiene el tipo esperado.
+fn decode_static_results(results: &[Result<Token, Bytes>]) -> Option<(H160, H160, H160, u32)> {
+    let token = |i: usize| -> Option<Token> { results.get(i)?.as_ref().ok().cloned() };
+    let factory = token(0)?.into_address()?;
+    let token0 = token(1)?.into_address()?;
+    let token1 = token(2)?.into_address()?;
+    let fee = token(3)?.into_uint()?.as_u32();
+    Some((factory, token0, token1, fee))
+}
 
-    // --- 1. Primera Pasada: Obtener datos principales de los pools ---
+/// Campos de `slot0` que usa el bot: precio, tick y el flag `unlocked`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Slot0 {
+    pub sqrt_price_x96: U256,
+    pub tick: i32,
+    pub unlocked: bool,
+}
+
+/// Decodifica la tupla completa de `slot0` (sqrtPriceX96, tick, ..., unlocked). El flag es siempre el
+/// último campo, tanto en Uniswap como en los forks con `feeProtocol` de otro tamaño.
+pub fn decode_slot0(slot0_tokens: Vec<Token>) -> Option<Slot0> {
+    let sqrt_price_x96 = slot0_tokens.first()?.clone().into_uint()?;
+    let tick = I256::from_raw(slot0_tokens.get(1)?.clone().into_int()?).as_i32();
+    let unlocked = slot0_tokens.last()?.clone().into_bool()?;
+    Some(Slot0 { sqrt_price_x96, tick, unlocked })
+}
+
+/// Decodifica los 4 resultados dinámicos de un pool (liquidity, slot0, balance0, balance1).
+fn decode_dynamic_results(results: &[Result<Token, Bytes>]) -> Option<(u128, Slot0, U256, U256)> {
+    let token = |i: usize| -> Option<Token> { results.get(i)?.as_ref().ok().cloned() };
+    let liquidity = token(0)?.into_uint()?.as_u128();
+    let slot0 = decode_slot0(token(1)?.into_tuple()?)?;
+    // Un balance que falla no invalida el pool (sólo afecta al TVL), como antes.
+    let balance0 = token(2).and_then(Token::into_uint).unwrap_or_default();
+    let balance1 = token(3).and_then(Token::into_uint).unwrap_or_default();
+    Some((liquidity, slot0, balance0, balance1))
+}
+
+async fn new_multicall<M: Middleware + 'static>(provider: Arc<M>) -> Result<Multicall<M>> {
+    Multicall::new(provider, Some(CONFIG.multicall_address))
+        .await
+        .map_err(|e| anyhow!(
+            "No se pudo inicializar Multicall en {:?}: {e}. Define MULTICALL_ADDRESS en .env con la dirección de Multicall3 de esta chain.",
+            CONFIG.multicall_address
+        ))
+}
+
+/// Atributos inmutables conocidos de los pools, para guardarlos en el snapshot de arranque.
+pub fn static_pool_data() -> HashMap<H160, StaticPoolData> {
+    STATIC_POOL_DATA.lock().unwrap().clone()
+}
+
+/// Siembra los atributos inmutables guardados en un snapshot: esos pools ya no pasan por la pasada estática.
+/// Los decimales fijados por `TOKEN_DECIMALS_OVERRIDES` prevalecen sobre los guardados.
+pub fn seed_static_pool_data(data: HashMap<H160, StaticPoolData>) -> usize {
+    let overrides = &CONFIG.token_decimals_overrides;
+    let mut cache = STATIC_POOL_DATA.lock().unwrap();
+    let seeded = data.len();
+    for (addr, mut pool) in data {
+        pool.decimals0 = overrides.get(&pool.token0).copied().unwrap_or(pool.decimals0);
+        pool.decimals1 = overrides.get(&pool.token1).copied().unwrap_or(pool.decimals1);
+        cache.entry(addr).or_insert(pool);
+    }
+    seeded
+}
+
+/// Obtiene (factory, tokens, fee, decimales) de los pools que aún no están en `STATIC_POOL_DATA`.
+async fn fetch_static_pool_data<M: Middleware + 'static>(provider: Arc<M>, pool_addresses: &[H160]) -> Result<()> {
+    let mut multicall = new_multicall(provider.clone()).await?;
+
+    // --- 1. Primera Pasada: atributos inmutables de los pools ---
     for &addr in pool_addresses {
         let pool_contract = IUniswapV3Pool::new(addr, provider.clone());
         multicall.add_call(pool_contract.factory(), true);
         multicall.add_call(pool_contract.token_0(), true);
         multicall.add_call(pool_contract.token_1(), true);
-        multicall.add_call(pool_contract.liquidity(), true);
-        multicall.add_call(pool_contract.slot_0(), true);
         multicall.add_call(pool_contract.fee(), true);
     }
     let results_pools = multicall.call_raw().await?;
     multicall.clear_calls();
 
-    let mut intermediate_data = HashMap::new();
-    let mut token_contracts = HashMap::new();
-    let num_calls_per_pool = 6;
+    let num_calls_per_pool = 4;
+    // Si el número de resultados no cuadra, el indexado por `i * 4` atribuiría datos al pool equivocado.
+    let expected_results = pool_addresses.len() * num_calls_per_pool;
+    if results_pools.len() != expected_results {
+        return Err(anyhow!(
+            "Multicall devolvió {} resultados para los pools, se esperaban {}",
+            results_pools.len(), expected_results
+        ));
+    }
 
+    let mut intermediate_data = HashMap::new();
+    let mut unique_tokens: Vec<H160> = Vec::new();
     for (i, &addr) in pool_addresses.iter().enumerate() {
         let start_idx = i * num_calls_per_pool;
-        if results_pools[start_idx].is_ok() {
-            let factory: H160 = results_pools[start_idx].clone().unwrap().into_address().unwrap_or_default();
-            let token0: H160 = results_pools[start_idx + 1].clone().unwrap().into_address().unwrap_or_default();
-            let token1: H160 = results_pools[start_idx + 2].clone().unwrap().into_address().unwrap_or_default();
-            let liquidity: u128 = results_pools[start_idx + 3].clone().unwrap().into_uint().unwrap_or_default().as_u128();
-            let slot0_tokens = results_pools[start_idx + 4].clone().unwrap().into_tuple().unwrap_or_default();
-            let sqrt_price_x96 = slot0_tokens.get(0).and_then(|t| t.clone().into_uint()).unwrap_or_default();
-            let fee: u32 = results_pools[start_idx + 5].clone().unwrap().into_uint().unwrap_or_default().as_u32();
-
-            if !token0.is_zero() && !token1.is_zero() {
-                intermediate_data.insert(addr, (factory, token0, token1, liquidity, sqrt_price_x96, fee));
-                token_contracts.entry(token0).or_insert_with(|| IERC20::new(token0, provider.clone()));
-                token_contracts.entry(token1).or_insert_with(|| IERC20::new(token1, provider.clone()));
-            }
+        let (factory, token0, token1, fee) =
+            match decode_static_results(&results_pools[start_idx..start_idx + num_calls_per_pool]) {
+                Some(decoded) => decoded,
+                None => {
+                    warn!("Pool {addr:?}: alguna sub-llamada del multicall falló o no decodifica. Descartado.");
+                    continue;
+                }
+            };
+
+        // Consistencia interna: un pool V3 siempre tiene factory y token0 < token1.
+        if factory.is_zero() || token0.is_zero() || token1.is_zero() || token0 >= token1 {
+            warn!("Datos inconsistentes para el pool {addr:?} (factory {factory:?}, token0 {token0:?}, token1 {token1:?}). Descartado.");
+            continue;
+        }
+        intermediate_data.insert(addr, (factory, token0, token1, fee));
+        for token in [token0, token1] {
+            if !unique_tokens.contains(&token) { unique_tokens.push(token); }
         }
     }
 
     // --- 2. Segunda Pasada: Obtener decimales de los tokens únicos ---
-    let unique_tokens: Vec<H160> = token_contracts.keys().cloned().collect();
-    for &token_addr in &unique_tokens {
-        multicall.add_call(token_contracts.get(&token_addr).unwrap().decimals(), true);
+    // Los tokens con decimales fijados por configuración no se consultan on-chain.
+    let mut token_decimals: HashMap<H160, u8> = HashMap::new();
+    for token_addr in &unique_tokens {
+        if let Some(&decimals) = CONFIG.token_decimals_overrides.get(token_addr) {
+            info!("Decimales de {token_addr:?} fijados por TOKEN_DECIMALS_OVERRIDES: {decimals}.");
+            token_decimals.insert(*token_addr, decimals);
+        }
+    }
+    let to_fetch: Vec<H160> = unique_tokens.iter().copied().filter(|t| !token_decimals.contains_key(t)).collect();
+    for &token_addr in &to_fetch {
+        multicall.add_call(IERC20::new(token_addr, provider.clone()).decimals(), true);
+    }
+    let results_decimals = if to_fetch.is_empty() { Vec::new() } else { multicall.call_raw().await? };
+    if results_decimals.len() != to_fetch.len() {
+        return Err(anyhow!(
+            "Multicall devolvió {} decimales, se esperaban {}",
+            results_decimals.len(), to_fetch.len()
+        ));
     }
-    let results_decimals = multicall.call_raw().await?;
-    multicall.clear_calls();
 
-    let mut token_decimals: HashMap<H160, u8> = HashMap::new();
-    for (i, &token_addr) in unique_tokens.iter().enumerate() {
+    for (i, &token_addr) in to_fetch.iter().enumerate() {
         if let Ok(decimals_token) = &results_decimals[i] {
             token_decimals.insert(token_addr, decimals_token.clone().into_uint().unwrap_or_default().as_u32() as u8);
         } else {
@@ -84,28 +202,242 @@ pub async fn batch_get_pool_data<M: Middleware + 'static>(
         }
     }
 
-    // --- 3. Tercera Pasada: Obtener balances de los pools ---
-    for (pool_addr, (_, token0, token1, _, _, _)) in &intermediate_data {
-        multicall.add_call(token_contracts.get(token0).unwrap().balance_of(*pool_addr), true);
-        multicall.add_call(token_contracts.get(token1).unwrap().balance_of(*pool_addr), true);
+    let mut cache = STATIC_POOL_DATA.lock().unwrap();
+    for (addr, (factory, token0, token1, fee)) in intermediate_data {
+        cache.insert(addr, StaticPoolData {
+            factory, token0, token1, fee,
+            decimals0: token_decimals.get(&token0).cloned().unwrap_or(18),
+            decimals1: token_decimals.get(&token1).cloned().unwrap_or(18),
+        });
+    }
+    Ok(())
+}
+
+/// Obtiene los datos esenciales de una lista de pools V3 usando multicall.
+/// Los atributos estáticos se piden sólo para pools nuevos; cada refresco sólo trae liquidez,
+/// `slot0` y balances en una única pasada.
+pub async fn batch_get_pool_data<M: Middleware + 'static>(
+    provider: Arc<M>,
+    pool_addresses: &[H160],
+) -> Result<HashMap<H160, RawPoolData>> {
+    let missing: Vec<H160> = {
+        let cache = STATIC_POOL_DATA.lock().unwrap();
+        pool_addresses.iter().copied().filter(|a| !cache.contains_key(a)).collect()
+    };
+    if !missing.is_empty() {
+        debug!("Obteniendo datos estáticos de {} pools nuevos.", missing.len());
+        fetch_static_pool_data(provider.clone(), &missing).await?;
+    }
+    let static_data: Vec<(H160, StaticPoolData)> = {
+        let cache = STATIC_POOL_DATA.lock().unwrap();
+        pool_addresses.iter().filter_map(|a| cache.get(a).map(|d| (*a, *d))).collect()
+    };
+
+    // --- 3. Pasada dinámica: liquidez, precio y balances ---
+    let mut multicall = new_multicall(provider.clone()).await?;
+    for (addr, data) in &static_data {
+        let pool_contract = IUniswapV3Pool::new(*addr, provider.clone());
+        multicall.add_call(pool_contract.liquidity(), true);
+        multicall.add_call(pool_contract.slot_0(), true);
+        multicall.add_call(IERC20::new(data.token0, provider.clone()).balance_of(*addr), true);
+        multicall.add_call(IERC20::new(data.token1, provider.clone()).balance_of(*addr), true);
+    }
+    let results_dynamic = multicall.call_raw().await?;
+    let num_calls_per_pool = 4;
+    if results_dynamic.len() != static_data.len() * num_calls_per_pool {
+        return Err(anyhow!(
+            "Multicall devolvió {} resultados dinámicos, se esperaban {}",
+            results_dynamic.len(), static_data.len() * num_calls_per_pool
+        ));
     }
-    let results_balances = multicall.call_raw().await?;
 
     // --- 4. Ensamblaje Final ---
     let mut final_reserves = HashMap::new();
-    let mut balance_idx = 0;
-    for (pool_addr, (factory, token0, token1, liquidity, sqrt_price_x96, fee)) in intermediate_data {
-        let balance0 = results_balances.get(balance_idx).and_then(|r| r.as_ref().ok()).and_then(|t| t.clone().into_uint()).unwrap_or_default();
-        let balance1 = results_balances.get(balance_idx + 1).and_then(|r| r.as_ref().ok()).and_then(|t| t.clone().into_uint()).unwrap_or_default();
-        balance_idx += 2;
-
+    for (i, (pool_addr, data)) in static_data.into_iter().enumerate() {
+        let start_idx = i * num_calls_per_pool;
+        let Some((liquidity, slot0, balance0, balance1)) =
+            decode_dynamic_results(&results_dynamic[start_idx..start_idx + num_calls_per_pool])
+        else {
+            warn!("Pool {pool_addr:?}: liquidity/slot0 no decodifican en este refresco. Descartado.");
+            continue;
+        };
         final_reserves.insert(pool_addr, RawPoolData {
-            factory, token0, token1,
-            decimals0: token_decimals.get(&token0).cloned().unwrap_or(18),
-            decimals1: token_decimals.get(&token1).cloned().unwrap_or(18),
-            liquidity, sqrt_price_x96, fee, balance0, balance1
+            factory: data.factory, token0: data.token0, token1: data.token1,
+            decimals0: data.decimals0,
+            decimals1: data.decimals1,
+            liquidity, sqrt_price_x96: slot0.sqrt_price_x96, tick: slot0.tick, unlocked: slot0.unlocked,
+            fee: data.fee, balance0, balance1
         });
     }
 
     Ok(final_reserves)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::instance::with_test_config;
+    use crate::paths::tests::addr;
+    use crate::testing::MockChain;
+
+    #[tokio::test]
+    async fn batch_get_pool_data_assembles_pools() {
+        let (pool, broken, factory, weth, usdc) = (addr(500), addr(501), addr(1), addr(2), addr(3));
+        let sqrt_price = U256::from(2).pow(96.into());
+        let chain = MockChain::new()
+            .pool(pool, factory, weth, usdc, 500, 1_000_000)
+            .slot0(pool, sqrt_price, -200, true)
+            .decimals(weth, 18)
+            .decimals(usdc, 6)
+            .balance_of(weth, pool, U256::exp10(20))
+            .balance_of(usdc, pool, U256::from(300_000_000_000u64));
+
+        with_test_config(|_| {}, async {
+            // `broken` no responde a nada: se descarta sin afectar al resto.
+            let data = batch_get_pool_data(chain.provider(), &[pool, broken]).await.unwrap();
+            assert_eq!(data.len(), 1);
+            let raw = data[&pool];
+            assert_eq!((raw.factory, raw.token0, raw.token1, raw.fee), (factory, weth, usdc, 500));
+            assert_eq!((raw.decimals0, raw.decimals1), (18, 6));
+            assert_eq!((raw.liquidity, raw.sqrt_price_x96, raw.tick, raw.unlocked), (1_000_000, sqrt_price, -200, true));
+            assert_eq!((raw.balance0, raw.balance1), (U256::exp10(20), U256::from(300_000_000_000u64)));
+        })
+        .await;
+    }
+
+    #[tokio::test]
+    async fn refresh_fetches_only_dynamic_data() {
+        let (pool, factory, weth, usdc) = (addr(510), addr(1), addr(2), addr(3));
+        let chain = MockChain::new()
+            .pool(pool, factory, weth, usdc, 3000, 5_000)
+            .slot0(pool, U256::from(2).pow(96.into()), 0, true)
+            .decimals(weth, 18)
+            .decimals(usdc, 6)
+            .balance_of(weth, pool, U256::exp10(18))
+            .balance_of(usdc, pool, U256::exp10(9));
+
+        with_test_config(|_| {}, async {
+            let first = batch_get_pool_data(chain.provider(), &[pool]).await.unwrap();
+            let second = batch_get_pool_data(chain.provider(), &[pool]).await.unwrap();
+            // Factory, tokens, fee y decimales una sola vez; liquidez, slot0 y balances en cada refresco.
+            for signature in ["factory()", "token0()", "token1()", "fee()"] {
+                assert_eq!(chain.calls_to(signature), 1, "{signature}");
+            }
+            assert_eq!(chain.calls_to("decimals()"), 2);
+            assert_eq!(chain.calls_to("liquidity()"), 2);
+            assert_eq!(chain.calls_to("slot0()"), 2);
+            assert_eq!(chain.calls_to("balanceOf(address)"), 4);
+            let (first, second) = (first[&pool], second[&pool]);
+            assert_eq!((second.token0, second.token1, second.fee), (first.token0, first.token1, first.fee));
+            assert_eq!((second.decimals0, second.decimals1), (18, 6));
+        })
+        .await;
+    }
+
+    #[tokio::test]
+    async fn batch_get_pool_data_drops_only_the_pool_with_a_failing_call() {
+        let (first, middle, last, factory) = (addr(600), addr(601), addr(602), addr(1));
+        let (weth, usdc, arb) = (addr(2), addr(3), addr(4));
+        let sqrt_price = U256::from(2).pow(96.into());
+        let mut chain = MockChain::new()
+            .pool(first, factory, weth, usdc, 500, 1)
+            // `fee()` del pool del medio revierte; el resto de sus llamadas responde.
+            .returns(middle, "factory()", &[], &[Token::Address(factory)])
+            .returns(middle, "token0()", &[], &[Token::Address(weth)])
+            .returns(middle, "token1()", &[], &[Token::Address(arb)])
+            .returns(middle, "liquidity()", &[], &[Token::Uint(2.into())])
+            .pool(last, factory, usdc, arb, 3000, 3)
+            .decimals(weth, 18)
+            .decimals(usdc, 6)
+            .decimals(arb, 18);
+        for (pool, n) in [(first, 1), (middle, 2), (last, 3)] {
+            chain = chain.slot0(pool, sqrt_price, n, true);
+        }
+
+        with_test_config(|_| {}, async {
+            let data = batch_get_pool_data(chain.provider(), &[first, middle, last]).await.unwrap();
+            assert_eq!(data.len(), 2);
+            assert!(!data.contains_key(&middle));
+            // Los pools a ambos lados del fallo conservan sus propios datos.
+            assert_eq!((data[&first].token1, data[&first].fee, data[&first].liquidity, data[&first].tick), (usdc, 500, 1, 1));
+            assert_eq!((data[&last].token0, data[&last].fee, data[&last].liquidity, data[&last].tick), (usdc, 3000, 3, 3));
+            assert_eq!((data[&last].decimals0, data[&last].decimals1), (6, 18));
+        })
+        .await;
+    }
+
+    #[tokio::test]
+    async fn batch_get_pool_data_skips_a_pool_whose_slot0_reverts() {
+        let (healthy, no_slot0, factory, weth, usdc) = (addr(700), addr(701), addr(1), addr(2), addr(3));
+        let chain = MockChain::new()
+            .pool(healthy, factory, weth, usdc, 500, 10)
+            .slot0(healthy, U256::from(2).pow(96.into()), 0, true)
+            // Atributos estáticos y liquidez válidos, pero `slot0()` revierte.
+            .pool(no_slot0, factory, weth, usdc, 3000, 20)
+            .decimals(weth, 18)
+            .decimals(usdc, 6);
+
+        with_test_config(|_| {}, async {
+            let data = batch_get_pool_data(chain.provider(), &[no_slot0, healthy]).await.unwrap();
+            assert_eq!(data.keys().collect::<Vec<_>>(), vec![&healthy]);
+            assert_eq!((data[&healthy].fee, data[&healthy].liquidity), (500, 10));
+        })
+        .await;
+    }
+
+
+    #[tokio::test]
+    async fn decimals_overrides_win_over_the_onchain_value() {
+        let (pool, factory, weth, usdc) = (addr(520), addr(1), addr(2), addr(3));
+        // El token informa 18 decimales on-chain, pero son 6.
+        let chain = MockChain::new()
+            .pool(pool, factory, weth, usdc, 500, 1_000)
+            .slot0(pool, U256::from(2).pow(96.into()), 0, true)
+            .decimals(weth, 18)
+            .decimals(usdc, 18)
+            .balance_of(weth, pool, U256::exp10(18))
+            .balance_of(usdc, pool, U256::exp10(9));
+
+        with_test_config(|c| c.token_decimals_overrides = HashMap::from([(usdc, 6)]), async {
+            let raw = batch_get_pool_data(chain.provider(), &[pool]).await.unwrap()[&pool];
+            assert_eq!((raw.decimals0, raw.decimals1), (18, 6));
+            // Sólo se consulta on-chain el token sin override.
+            assert_eq!(chain.calls_to("decimals()"), 1);
+        })
+        .await;
+    }
+
+
+    fn slot0_tokens(tick: i32, unlocked: bool, fee_protocol: Token) -> Vec<Token> {
+        vec![
+            Token::Uint(U256::from(79_228_162_514_264_337_593_543_950_336u128)),
+            Token::Int(I256::from(tick).into_raw()),
+            Token::Uint(U256::from(12)),
+            Token::Uint(U256::from(50)),
+            Token::Uint(U256::from(50)),
+            fee_protocol,
+            Token::Bool(unlocked),
+        ]
+    }
+
+    #[test]
+    fn decode_slot0_reads_price_tick_and_unlocked() {
+        let slot0 = decode_slot0(slot0_tokens(-887_000, true, Token::Uint(U256::zero()))).unwrap();
+        assert_eq!(
+            slot0,
+            Slot0 { sqrt_price_x96: U256::from(79_228_162_514_264_337_593_543_950_336u128), tick: -887_000, unlocked: true }
+        );
+        // Forks con `feeProtocol` de otro tamaño: `unlocked` sigue siendo el último campo.
+        let fork = decode_slot0(slot0_tokens(42, false, Token::Uint(U256::from(0x4444u64)))).unwrap();
+        assert_eq!((fork.tick, fork.unlocked), (42, false));
+    }
+
+    #[test]
+    fn decode_slot0_rejects_unexpected_tuples() {
+        assert_eq!(decode_slot0(Vec::new()), None);
+        assert_eq!(decode_slot0(vec![Token::Uint(U256::one())]), None);
+        let mut wrong_flag = slot0_tokens(1, true, Token::Uint(U256::zero()));
+        *wrong_flag.last_mut().unwrap() = Token::Uint(U256::one());
+        assert_eq!(decode_slot0(wrong_flag), None);
+    }
+}
@@ -1,11 +1,13 @@
 use crate::execution;
+use crate::math::to_usd;
 use futures::future::join_all;
 use crate::{
-    config::CONFIG,
+    config::{ApprovalTokenPolicy, SearchStrategy, CONFIG},
+    instance::{self, InstanceLocal},
     oracle::OracleMap,
     paths::ArbPath,
-    types::{OraclePriceInfo, Pool},
-    constants::WETH_ADDRESS,
+    simulator,
+    types::{DexVariant, OraclePriceInfo, Pool},
 };
 use anyhow::{anyhow, Result};
 use ethers::{
@@ -13,28 +15,221 @@ use ethers::{
     types::{H160, U256},
 };
 use lazy_static::lazy_static;
+use log::{debug, info, warn};
+use prometheus::{register_int_counter_vec, IntCounterVec};
 use rust_decimal::{prelude::*, Decimal, MathematicalOps};
+use serde::{Deserialize, Serialize};
 use std::{
     collections::{HashMap, VecDeque},
     str::FromStr,
-    sync::{Arc, Mutex},
+    sync::{
+        atomic::{AtomicU64, Ordering as AtomicOrdering},
+        Arc, Mutex,
+    },
 };
 
-#[derive(Debug, Default, Clone)]
+// Peso de la muestra nueva en la media móvil exponencial del slippage realizado.
+const SLIPPAGE_EWMA_ALPHA: f64 = 0.3;
+// Muestras mínimas antes de que el slippage realizado ajuste el presupuesto de la ruta.
+const MIN_SLIPPAGE_SAMPLES: u64 = 3;
+// Tope del presupuesto de slippage adaptativo por ruta.
+const MAX_ADAPTIVE_SLIPPAGE_BPS: u32 = 100;
+// Penalización del score de rutas con tokens de approve no estándar (política `deprioritize`).
+const NON_STANDARD_APPROVAL_SCORE_FACTOR: f64 = 0.5;
+// Tolerancia de la búsqueda: se detiene cuando el intervalo baja de `b / SEARCH_TOLERANCE_DIVISOR`.
+const SEARCH_TOLERANCE_DIVISOR: u64 = 100_000;
+// Iteraciones golden-section de la búsqueda secuencial.
+const GOLDEN_SECTION_ITERATIONS: usize = 15;
+// Iteraciones del refinamiento tras la rejilla paralela: el intervalo ya llega acotado a dos celdas.
+const GRID_REFINE_ITERATIONS: usize = 5;
+// Se registra cuando el tamaño factible de la ruta es menos de 1/N del límite de búsqueda.
+const FEASIBLE_BOUND_LOG_RATIO: u64 = 10;
+// Peso de la muestra nueva en la media móvil del ratio profit realizado / simulado.
+const REALIZATION_EWMA_ALPHA: f64 = 0.2;
+// Cotas del ratio de realización aplicado al score: castiga rutas que entregan de menos
+// sin anular su score, y no premia más allá del simulado.
+const MIN_REALIZATION_RATIO: f64 = 0.25;
+const MAX_REALIZATION_RATIO: f64 = 1.0;
+// Intentos mínimos antes de ajustar el bribe según el historial de la ruta.
+const MIN_ATTEMPTS_FOR_DYNAMIC_BRIBE: u64 = 5;
+// Fracción de `max_bribe_percent` que paga una ruta sin competencia (winrate 100%).
+const MIN_BRIBE_SHARE: f64 = 0.5;
+// Palabras de 32 bytes de la muestra con la que se cotiza el fee L1 por byte.
+const L1_FEE_SAMPLE_WORDS: u64 = 16;
+
+abigen!(IGasPriceOracle, r#"[function getL1Fee(bytes data) external view returns (uint256)]"#);
+
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
 pub struct RouteHistory {
     pub successes: u64,
     pub failures: u64,
     pub last_attempt_block: u64,
     pub last_failure_block: u64,
+    /// Bloque en que la ruta salió del cooldown de su último fallo.
+    #[serde(default)]
+    pub cooldown_cleared_block: u64,
+    pub realized_slippage_bps_ewma: f64,
+    pub slippage_samples: u64,
+    pub realized_profit_usd: f64,
+    #[serde(default)]
+    pub last_submitted_block: u64,
+    #[serde(default)]
+    pub last_submitted_profit_usd: f64,
+    /// Lag spot/oráculo de la ruta en su último envío.
+    #[serde(default)]
+    pub last_submitted_edge: f64,
+    #[serde(default)]
+    pub realized_profit_usd_ewma: f64,
+    #[serde(default)]
+    pub realization_ratio_ewma: f64,
+    #[serde(default)]
+    pub realization_samples: u64,
+    #[serde(default)]
+    pub last_revert_reason: Option<String>,
+    #[serde(default)]
+    pub identical_reverts: u64,
+    /// Vetada tras `identical_revert_threshold` reverts seguidos con el mismo motivo.
+    #[serde(default)]
+    pub blacklisted: bool,
+    /// Bloque del revert que vetó la ruta; el veto expira `route_blacklist_blocks` después.
+    #[serde(default)]
+    pub blacklisted_block: u64,
 }
 impl RouteHistory {
     pub fn winrate(&self) -> f64 {
         let total = self.successes + self.failures;
         if total == 0 { 0.5 } else { self.successes as f64 / total as f64 }
     }
+    /// Ruta apartada de la ejecución: con al menos `min_attempts` intentos, su winrate no supera
+    /// `min_winrate`. Un umbral de 0 desactiva el filtro.
+    pub fn is_benched(&self, min_winrate: f64, min_attempts: u64) -> bool {
+        min_winrate > 0.0 && self.successes + self.failures >= min_attempts && self.winrate() <= min_winrate
+    }
+    pub fn record_realized_slippage(&mut self, slippage_bps: f64) {
+        self.realized_slippage_bps_ewma = if self.slippage_samples == 0 {
+            slippage_bps
+        } else {
+            SLIPPAGE_EWMA_ALPHA * slippage_bps + (1.0 - SLIPPAGE_EWMA_ALPHA) * self.realized_slippage_bps_ewma
+        };
+        self.slippage_samples += 1;
+    }
+    /// Reenvío sin mejora: la ruta se envió hace menos de `window_blocks` bloques sin fallar y el
+    /// profit actual no supera el de entonces en al menos `min_improvement` (fracción).
+    pub fn is_stale_resubmission(&self, profit_usd: f64, current_block: u64, min_improvement: f64, window_blocks: u64) -> bool {
+        self.submitted_without_failure(current_block, min_improvement, window_blocks)
+            && profit_usd < self.last_submitted_profit_usd * (1.0 + min_improvement)
+    }
+    /// Como `is_stale_resubmission`, con el lag spot/oráculo que se conoce antes de optimizar la ruta.
+    pub fn is_stale_edge(&self, edge: f64, current_block: u64, min_improvement: f64, window_blocks: u64) -> bool {
+        self.submitted_without_failure(current_block, min_improvement, window_blocks)
+            && edge < self.last_submitted_edge * (1.0 + min_improvement)
+    }
+    fn submitted_without_failure(&self, current_block: u64, min_improvement: f64, window_blocks: u64) -> bool {
+        min_improvement > 0.0
+            && self.last_submitted_block > 0
+            && self.last_failure_block < self.last_submitted_block
+            && current_block.saturating_sub(self.last_submitted_block) < window_blocks
+    }
+    /// Registra el profit realizado de una ejecución frente al simulado para esa misma oportunidad.
+    pub fn record_realized_profit(&mut self, realized_usd: f64, simulated_usd: f64) {
+        if !realized_usd.is_finite() || !simulated_usd.is_finite() || simulated_usd <= 0.0 { return; }
+        let ratio = realized_usd / simulated_usd;
+        if self.realization_samples == 0 {
+            self.realized_profit_usd_ewma = realized_usd;
+            self.realization_ratio_ewma = ratio;
+        } else {
+            self.realized_profit_usd_ewma =
+                REALIZATION_EWMA_ALPHA * realized_usd + (1.0 - REALIZATION_EWMA_ALPHA) * self.realized_profit_usd_ewma;
+            self.realization_ratio_ewma =
+                REALIZATION_EWMA_ALPHA * ratio + (1.0 - REALIZATION_EWMA_ALPHA) * self.realization_ratio_ewma;
+        }
+        self.realization_samples += 1;
+    }
+    /// Factor de realización para el score: 1.0 sin muestras, acotado a
+    /// [`MIN_REALIZATION_RATIO`, `MAX_REALIZATION_RATIO`] en cuanto hay fills reales.
+    pub fn realization_ratio(&self) -> f64 {
+        if self.realization_samples == 0 { return 1.0; }
+        self.realization_ratio_ewma.clamp(MIN_REALIZATION_RATIO, MAX_REALIZATION_RATIO)
+    }
+    /// Porcentaje de bribe para la ruta: más agresivo cuanto más pierde la carrera de inclusión,
+    /// menos en rutas sin competencia. Siempre acotado por `max_bribe_percent`.
+    pub fn bribe_percent(&self) -> f64 {
+        if self.successes + self.failures < MIN_ATTEMPTS_FOR_DYNAMIC_BRIBE { return CONFIG.max_bribe_percent; }
+        let share = MIN_BRIBE_SHARE + (1.0 - MIN_BRIBE_SHARE) * (1.0 - self.winrate());
+        CONFIG.max_bribe_percent * share
+    }
+    /// Registra un revert y devuelve cuántos seguidos llevan el mismo motivo.
+    pub fn record_revert(&mut self, reason: &str) -> u64 {
+        if self.last_revert_reason.as_deref() == Some(reason) {
+            self.identical_reverts += 1;
+        } else {
+            self.last_revert_reason = Some(reason.to_string());
+            self.identical_reverts = 1;
+        }
+        self.identical_reverts
+    }
+    /// Una ejecución correcta corta la racha de reverts.
+    pub fn clear_revert_streak(&mut self) {
+        self.last_revert_reason = None;
+        self.identical_reverts = 0;
+    }
+    /// Presupuesto de slippage derivado de los fills reales, si la ruta tiene suficientes muestras.
+    pub fn adaptive_slippage_bps(&self) -> Option<u32> {
+        if self.slippage_samples < MIN_SLIPPAGE_SAMPLES || self.realized_slippage_bps_ewma <= 0.0 { return None; }
+        Some((self.realized_slippage_bps_ewma.ceil() as u32).min(MAX_ADAPTIVE_SLIPPAGE_BPS))
+    }
+    /// Exceso del slippage realizado sobre `budget_bps`, si la ruta tiene suficientes muestras y lo supera.
+    /// No amplía la tolerancia de la TX: se descuenta del profit esperado de la ruta.
+    pub fn excess_slippage_bps(&self, budget_bps: u32) -> Option<u32> {
+        self.adaptive_slippage_bps().filter(|&bps| bps > budget_bps).map(|bps| bps - budget_bps)
+    }
 }
 lazy_static! {
-    pub static ref ROUTE_STATS: Mutex<HashMap<String, RouteHistory>> = Mutex::new(HashMap::new());
+    static ref ROUTES_BLACKLISTED: IntCounterVec = register_int_counter_vec!("routes_blacklisted_total", "Rutas vetadas por reverts repetidos con el mismo motivo", &["chain"]).unwrap();
+}
+
+pub static ROUTE_STATS: InstanceLocal<Mutex<HashMap<String, RouteHistory>>> = InstanceLocal::new(|| Mutex::new(HashMap::new()));
+
+/// Registra el motivo de un revert de la ruta. Varios seguidos con el mismo motivo (p. ej. "STF") apuntan a
+/// un problema estructural (approve, token con lista negra) y no a perder la carrera: al llegar a
+/// `identical_revert_threshold` la ruta se veta y se alerta con el motivo. Sólo cuentan los reverts on-chain:
+/// los de la simulación previa al envío suelen ser carreras perdidas, que repiten motivo sin ser estructurales.
+pub fn record_route_revert(path_key: &str, reason: &str, block_number: u64) {
+    let mut stats_map = ROUTE_STATS.lock().unwrap();
+    let stats = stats_map.entry(path_key.to_string()).or_default();
+    let streak = stats.record_revert(reason);
+    debug!("Ruta {path_key}: revert '{reason}' ({streak} seguidos con el mismo motivo)");
+    if CONFIG.identical_revert_threshold == 0 || stats.blacklisted || streak < CONFIG.identical_revert_threshold { return; }
+    stats.blacklisted = true;
+    stats.blacklisted_block = block_number;
+    instance::labeled(&ROUTES_BLACKLISTED).inc();
+    warn!(
+        " ALERTA: ruta {path_key} vetada tras {streak} reverts seguidos con el mismo motivo: '{reason}'. Revisa approvals y tokens de la ruta."
+    );
+}
+
+/// Si la ruta sigue vetada en `block_number`. Pasados `route_blacklist_blocks` el veto se levanta y la racha
+/// vuelve a cero: un problema ya corregido (approve, token) no deja la ruta fuera para siempre.
+pub fn route_is_blacklisted(path_key: &str, block_number: u64) -> bool {
+    let mut stats_map = ROUTE_STATS.lock().unwrap();
+    let Some(stats) = stats_map.get_mut(path_key).filter(|s| s.blacklisted) else { return false };
+    if CONFIG.route_blacklist_blocks == 0 || block_number.saturating_sub(stats.blacklisted_block) < CONFIG.route_blacklist_blocks {
+        return true;
+    }
+    stats.blacklisted = false;
+    stats.clear_revert_streak();
+    info!("Ruta {path_key}: expira el veto del bloque #{}, vuelve a ejecutarse.", stats.blacklisted_block);
+    false
+}
+
+/// Anota en el historial de la ruta una oportunidad ya enviada: su profit y su lag son la referencia
+/// que un reenvío debe mejorar (`is_stale_resubmission`, `is_stale_edge`).
+pub fn record_submission(opp: &ArbitrageOpportunity, block_number: u64) {
+    let mut stats_map = ROUTE_STATS.lock().unwrap();
+    let stats = stats_map.entry(opp.path.key()).or_default();
+    stats.last_submitted_block = block_number;
+    stats.last_submitted_profit_usd = opp.net_profit_usd;
+    stats.last_submitted_edge = opp.lag;
 }
 
 pub fn u256_to_decimal(val: U256, decimals: u8) -> Result<Decimal> {
@@ -42,7 +237,49 @@ pub fn u256_to_decimal(val: U256, decimals: u8) -> Result<Decimal> {
 }
 pub fn decimal_to_u256(val: Decimal, decimals: u8) -> Result<U256> {
     let scaled = val * Decimal::from(10u128.pow(decimals as u32));
-    U256::from_str(&scaled.round().to_string()).map_err(|e| anyhow!("error parseando U256: {e}"))
+    U256::from_dec_str(&scaled.round().to_string()).map_err(|e| anyhow!("error parseando U256: {e}"))
+}
+// Piso de profit exigido por el contrato on-chain, en USD (bits de f64; 0 = sin piso leído).
+static ONCHAIN_MIN_PROFIT_USD_BITS: InstanceLocal<AtomicU64> = InstanceLocal::new(|| AtomicU64::new(0));
+// Fee de datos L1 por byte leído del oráculo de gas de la chain en el último bloque (0 = sin lectura).
+static L1_FEE_PER_BYTE_WEI: InstanceLocal<AtomicU64> = InstanceLocal::new(|| AtomicU64::new(0));
+
+/// Fija el piso de profit del contrato (USD) leído al arrancar.
+pub fn set_onchain_min_profit_usd(value: f64) {
+    ONCHAIN_MIN_PROFIT_USD_BITS.store(value.to_bits(), AtomicOrdering::Relaxed);
+}
+
+/// Profit mínimo efectivo: el mayor entre `min_profit_usd` de config y el piso del contrato.
+pub fn min_profit_usd() -> f64 {
+    CONFIG.min_profit_usd.max(f64::from_bits(ONCHAIN_MIN_PROFIT_USD_BITS.load(AtomicOrdering::Relaxed)))
+}
+
+/// Gas de la ruta para el cálculo de profit: la última estimación real si está en caché; si no, el
+/// modelo base + incremento por salto de `Config::gas_for_hops`.
+pub fn modeled_gas_units(path: &ArbPath) -> u64 {
+    crate::provider::cached_route_gas(&path.key()).unwrap_or_else(|| CONFIG.gas_for_hops(path.hop_count()))
+}
+
+/// Notional mínimo en USD que podría cubrir los costes fijos (gas + min profit) más el fee del flashloan,
+/// asumiendo el mayor margen bruto plausible por ciclo. Devuelve `None` si el fee ya supera ese margen.
+pub fn break_even_floor_usd(gas_cost_usd: f64, min_profit_usd: f64, flash_loan_fee_bps: u32, max_edge_bps: u32) -> Option<f64> {
+    let net_edge = (max_edge_bps as f64 - flash_loan_fee_bps as f64) / 10_000.0;
+    if net_edge <= 0.0 { return None; }
+    Some((gas_cost_usd + min_profit_usd) / net_edge)
+}
+/// Préstamo en un token distinto de `token_a`: se pide `amount` de `token`, se cambia a `token_a`
+/// antes del ciclo y el output del ciclo se devuelve a `token` para repagar.
+#[derive(Debug, Clone)]
+pub struct FlashLoanLeg {
+    pub token: H160,
+    /// DEX y fee tier del pool de los swaps préstamo <-> token_a, y decimales de `token`.
+    pub variant: DexVariant,
+    pub fee: u32,
+    pub decimals: u8,
+    pub amount: U256,
+    /// Monto de `token` esperado tras el swap de vuelta (lo que debe cubrir el repago).
+    pub expected_return: U256,
+    pub price_usd: f64,
 }
 #[derive(Debug, Clone)]
 pub struct ArbitrageOpportunity {
@@ -51,73 +288,925 @@ pub struct ArbitrageOpportunity {
     pub expected_output: U256,
     pub net_profit_usd: f64,
     pub bribe_usd: f64,
+    pub bribe_percent: f64,
     pub lag: f64,
     pub tvl: f64,
     pub score: f64,
     pub slippage_bps: u32,
+    pub token_price_usd: f64,
+    pub eth_price_usd: f64,
+    pub flash_loan_leg: Option<FlashLoanLeg>,
+}
+/// Muestra de calldata con la que se cotiza el fee L1 por byte: bytes no nulos e incompresibles, como
+/// las direcciones y montos de una TX de arbitraje.
+fn l1_fee_sample() -> Vec<u8> {
+    (0..L1_FEE_SAMPLE_WORDS).flat_map(|i: u64| ethers::utils::keccak256(i.to_be_bytes())).collect()
+}
+
+/// Relee el fee de datos L1 por byte del `GasPriceOracle` en chains OP Stack cotizando `l1_fee_sample`.
+/// Fuera de ellas, o si el oráculo no responde, queda `l1_fee_per_calldata_byte_wei`.
+pub async fn refresh_l1_fee_per_byte<M: Middleware + 'static>(provider: Arc<M>) {
+    if !crate::constants::OP_STACK_CHAIN_IDS.contains(&CONFIG.chain_id) { return; }
+    let sample = l1_fee_sample();
+    let per_byte = match IGasPriceOracle::new(*crate::constants::OP_GAS_PRICE_ORACLE, provider).get_l1_fee(sample.clone().into()).call().await {
+        Ok(fee) => (fee / sample.len()).min(U256::from(u64::MAX)).as_u64(),
+        Err(e) => {
+            warn!("No se pudo leer el fee L1 del GasPriceOracle; se usa L1_FEE_PER_CALLDATA_BYTE_WEI: {e}");
+            0
+        }
+    };
+    L1_FEE_PER_BYTE_WEI.store(per_byte, AtomicOrdering::Relaxed);
 }
+
+/// Fee L1 por byte vigente: el del oráculo de la chain si se leyó, o el configurado.
+fn l1_fee_per_byte_wei() -> u64 {
+    match L1_FEE_PER_BYTE_WEI.load(AtomicOrdering::Relaxed) {
+        0 => CONFIG.l1_fee_per_calldata_byte_wei,
+        oracle => oracle,
+    }
+}
+
+/// Fee de datos L1 (wei) de la TX de arbitraje: proporcional a lo que publica en L1 con el
+/// `encode_arb_data` real de la ruta, así que las rutas con préstamo cruzado pagan su calldata extra.
+pub fn l1_data_fee_wei(path: &ArbPath, leg: Option<&FlashLoanLeg>) -> U256 {
+    let per_byte = l1_fee_per_byte_wei();
+    if per_byte == 0 { return U256::zero(); }
+    let data_len = execution::encode_arb_data(path, U256::zero(), 0, leg, U256::zero()).map_or(0, |user_data| execution::arb_tx_data_len(&user_data));
+    U256::from(data_len) * U256::from(per_byte)
+}
+/// ROI en bps del profit neto sobre el notional; 0 si no hay notional con el que compararlo.
+fn roi_bps(net_profit_usd: f64, notional_usd: f64) -> f64 {
+    if notional_usd > 0.0 { net_profit_usd / notional_usd * 10_000.0 } else { 0.0 }
+}
+
+/// `true` si el ROI alcanza `min_roi_bps` (el mínimo es inclusivo).
+fn meets_min_roi(roi_bps: f64) -> bool {
+    roi_bps >= CONFIG.min_roi_bps
+}
+#[allow(clippy::too_many_arguments)]
 async fn get_profit_for_amount<M: Middleware + 'static>(
-    provider: &Arc<M>, path: &ArbPath, amount_in: U256, base_gas_price_wei: U256, oracle_price_usd: f64, eth_price_usd: f64,
+    provider: &Arc<M>, path: &ArbPath, amount_in: U256, base_gas_price_wei: U256, l1_fee_wei: U256, oracle_price_usd: f64, eth_price_usd: f64, bribe_percent: f64, sim_block: Option<BlockId>,
 ) -> f64 {
     if amount_in.is_zero() || oracle_price_usd <= 0.0 || eth_price_usd <= 0.0 { return -1.0; }
-    let gross_amount_out = match path.simulate_v3_path(provider.clone(), amount_in).await {
+    let gross_amount_out = match path.simulate_v3_path_at(provider.clone(), amount_in, sim_block).await {
         Some(out) if out > amount_in => out,
         _ => return -1.0,
     };
     let gross_profit_u256 = gross_amount_out - amount_in;
     let gross_profit_dec = u256_to_decimal(gross_profit_u256, path.get_input_decimals()).unwrap_or_default();
     let gross_profit_usd = gross_profit_dec.to_f64().unwrap_or(0.0) * oracle_price_usd;
-    let bribe_usd = gross_profit_usd * CONFIG.max_bribe_percent;
+    let bribe_usd = gross_profit_usd * bribe_percent;
     let bribe_eth = bribe_usd / eth_price_usd;
     let priority_fee_wei = decimal_to_u256(Decimal::from_f64(bribe_eth).unwrap_or_default(), 18).unwrap_or_default();
     let total_gas_price = base_gas_price_wei + priority_fee_wei;
-    let gas_cost_eth = u256_to_decimal(total_gas_price * U256::from(CONFIG.gas_limit), 18).unwrap_or_default();
+    let gas_cost_eth = u256_to_decimal(total_gas_price * U256::from(modeled_gas_units(path)) + l1_fee_wei, 18).unwrap_or_default();
     let gas_cost_usd = gas_cost_eth.to_f64().unwrap_or(0.0) * eth_price_usd;
     gross_profit_usd - gas_cost_usd
 }
 pub async fn find_best_trade_golden_section<M: Middleware + 'static>(
     provider: Arc<M>, path: &mut ArbPath, base_gas_price_wei: U256, oracle_info: OraclePriceInfo, oracle_map: &Arc<OracleMap>, current_block: u64,
 ) -> Option<ArbitrageOpportunity> {
-    let (mut a, mut b, tol) = (U256::from(10).pow(17.into()), U256::from(10).pow(20.into()), U256::from(10).pow(15.into()));
-    let eth_price = oracle_map.get_price(&*WETH_ADDRESS, provider.clone()).await?.price;
-    let oracle_price = oracle_info.price;
-    let lag = oracle_info.lag;
+    let eth_price = crate::feeds::get_price(oracle_map, &CONFIG.weth_address, provider.clone()).await?.price;
+    let sim_block = crate::provider::simulation_block();
+    let opp = optimize_with_prices(
+        provider.clone(), path, base_gas_price_wei, oracle_info.price, oracle_info.lag, eth_price, current_block, sim_block,
+    ).await?;
+    match CONFIG.flash_loan_token {
+        Some(borrow_token) if borrow_token != opp.path.token_a => {
+            let borrow_price = crate::feeds::get_price(oracle_map, &borrow_token, provider.clone()).await?.price;
+            with_flash_loan_leg(provider, opp, borrow_token, borrow_price, base_gas_price_wei, sim_block).await
+        }
+        _ => Some(opp),
+    }
+}
+/// Re-evalúa la oportunidad pidiendo el préstamo en `borrow_token`: cotiza el swap de entrada
+/// (préstamo -> token_a), el ciclo con lo recibido y el swap de salida (token_a -> préstamo),
+/// y descuenta el repago y el gas de los dos saltos extra. Devuelve `None` si deja de ser rentable.
+async fn with_flash_loan_leg<M: Middleware + 'static>(
+    provider: Arc<M>, mut opp: ArbitrageOpportunity, borrow_token: H160, borrow_price: f64, base_gas_price_wei: U256, sim_block: Option<BlockId>,
+) -> Option<ArbitrageOpportunity> {
+    if borrow_price <= 0.0 { return None; }
+    let token_a = opp.path.token_a;
+    let Some((variant, fee, decimals)) = crate::pools::deepest_pool_between(borrow_token, token_a) else {
+        debug!("Ruta {}: sin pool entre {:?} y token_a para el préstamo cruzado.", opp.path.key(), borrow_token);
+        return None;
+    };
+    // Se pide el equivalente en USD de `optimal_amount_in` más el fee del swap de entrada.
+    let amount_in_usd = to_usd(opp.optimal_amount_in, token_a, opp.token_price_usd, opp.path.get_input_decimals());
+    let borrow_units = amount_in_usd / borrow_price * (1.0 + fee as f64 / 1_000_000.0);
+    let borrow_amount = decimal_to_u256(Decimal::from_f64(borrow_units)?, decimals).ok()?;
+
+    let entry_out = simulator::quote_exact_input_single(provider.clone(), variant, borrow_token, token_a, fee, borrow_amount, sim_block).await.ok()?;
+    let cycle_out = opp.path.simulate_v3_path_at(provider.clone(), entry_out, sim_block).await?;
+    let exit_out = simulator::quote_exact_input_single(provider, variant, token_a, borrow_token, fee, cycle_out, sim_block).await.ok()?;
+
+    let repayment = borrow_amount * U256::from(10_000 + CONFIG.flash_loan_fee_bps) / U256::from(10_000);
+    if exit_out <= repayment { return None; }
+    let gross_usd = to_usd(exit_out - repayment, borrow_token, borrow_price, decimals);
+    let leg = FlashLoanLeg { token: borrow_token, variant, fee, decimals, amount: borrow_amount, expected_return: exit_out, price_usd: borrow_price };
+    // El bribe se cobra como priority fee igual que en `get_profit_for_amount`, sobre el profit bruto del préstamo cruzado.
+    let bribe_usd = gross_usd * opp.bribe_percent;
+    let priority_fee_wei = decimal_to_u256(Decimal::from_f64(bribe_usd / opp.eth_price_usd)?, 18).ok()?;
+    // Los swaps préstamo <-> token_a son dos saltos más, y su path alarga el calldata.
+    let gas_units = CONFIG.gas_for_hops(opp.path.hop_count() + 2);
+    let l1_fee_wei = l1_data_fee_wei(&opp.path, Some(&leg));
+    let gas_cost_usd = u256_to_decimal((base_gas_price_wei + priority_fee_wei) * gas_units + l1_fee_wei, 18).ok()?.to_f64()? * opp.eth_price_usd;
+    let net_profit_usd = gross_usd - gas_cost_usd;
+    debug!(
+        "Ruta {}: préstamo en {:?} -> profit ${:.2} (sin préstamo cruzado ${:.2})",
+        opp.path.key(), borrow_token, net_profit_usd, opp.net_profit_usd
+    );
+    if net_profit_usd <= min_profit_usd() { return None; }
+
+    opp.optimal_amount_in = entry_out;
+    opp.expected_output = cycle_out;
+    opp.net_profit_usd = net_profit_usd;
+    opp.bribe_usd = bribe_usd;
+    opp.flash_loan_leg = Some(leg);
+    Some(opp)
+}
+
+/// Múltiplos del tamaño óptimo en los que se muestrea la curva de profit del diagnóstico.
+const PROFIT_CURVE_SIZE_MULTIPLES: [f64; 8] = [0.1, 0.25, 0.5, 0.75, 1.0, 1.5, 2.0, 4.0];
+
+/// Muestrea el profit neto (USD) de la oportunidad en varios tamaños alrededor del óptimo. La forma de la
+/// curva distingue un óptimo limitado por liquidez (caída brusca al crecer) de uno limitado por gas
+/// (caída brusca al reducir). Cada punto es una simulación completa de la ruta.
+pub async fn sample_profit_curve<M: Middleware + 'static>(
+    provider: Arc<M>, opp: &ArbitrageOpportunity, base_gas_price_wei: U256, sim_block: Option<BlockId>,
+) -> Vec<(U256, f64)> {
+    let l1_fee_wei = l1_data_fee_wei(&opp.path, None);
+    let mut curve = Vec::with_capacity(PROFIT_CURVE_SIZE_MULTIPLES.len());
+    for multiple in PROFIT_CURVE_SIZE_MULTIPLES {
+        let amount_in = opp.optimal_amount_in * U256::from((multiple * 1_000.0) as u64) / U256::from(1_000);
+        let profit_usd = get_profit_for_amount(
+            &provider, &opp.path, amount_in, base_gas_price_wei, l1_fee_wei, opp.token_price_usd, opp.eth_price_usd, opp.bribe_percent, sim_block,
+        ).await;
+        curve.push((amount_in, profit_usd));
+    }
+    curve
+}
+
+/// Registra la curva de `sample_profit_curve` con el profit relativo al del tamaño óptimo.
+pub fn log_profit_curve(opp: &ArbitrageOpportunity, curve: &[(U256, f64)]) {
+    let optimum = curve.iter().find(|(amount, _)| *amount == opp.optimal_amount_in).map_or(opp.net_profit_usd, |(_, p)| *p);
+    let points: Vec<String> = PROFIT_CURVE_SIZE_MULTIPLES.iter().zip(curve).map(|(multiple, (amount, profit))| {
+        let relative = if optimum > 0.0 { profit / optimum * 100.0 } else { 0.0 };
+        format!("{multiple}x ({amount}) -> ${profit:.2} ({relative:.0}%)")
+    }).collect();
+    info!("Curva de profit de la ruta {}: {}", opp.path.key(), points.join(" | "));
+}
+
+/// Pre-pasada de `SearchStrategy::ParallelGrid`: simula a la vez `PARALLEL_GRID_POINTS` tamaños
+/// equiespaciados en `[a, b]` y devuelve el intervalo entre los vecinos del mejor, que contiene el
+/// óptimo si la curva de profit es unimodal.
+#[allow(clippy::too_many_arguments)]
+async fn grid_bracket<M: Middleware + 'static>(
+    provider: &Arc<M>, path: &ArbPath, a: U256, b: U256, base_gas_price_wei: U256, l1_fee_wei: U256, oracle_price: f64, eth_price: f64, bribe_percent: f64, sim_block: Option<BlockId>,
+) -> (U256, U256) {
+    let points = CONFIG.parallel_grid_points.max(3);
+    let step = (b - a) / U256::from(points - 1);
+    let amounts: Vec<U256> = (0..points).map(|i| if i == points - 1 { b } else { a + step * U256::from(i) }).collect();
+    let profits = join_all(amounts.iter().map(|&amount| {
+        get_profit_for_amount(provider, path, amount, base_gas_price_wei, l1_fee_wei, oracle_price, eth_price, bribe_percent, sim_block)
+    })).await;
+    let best = profits.iter().enumerate().max_by(|x, y| x.1.total_cmp(y.1)).map_or(0, |(i, _)| i);
+    debug!("Ruta {}: rejilla de {} puntos, mejor {} (${:.2})", path.key(), points, amounts[best], profits[best]);
+    (amounts[best.saturating_sub(1)], amounts[(best + 1).min(points - 1)])
+}
+
+/// Límites `(a, b, tol)` de la búsqueda en unidades del token de entrada, a partir de
+/// `search_min_usd`/`search_max_usd` con su precio y decimales: unos límites fijos en unidades
+/// serían absurdos para tokens de 6 u 8 decimales (1e17 unidades de WBTC son 1e9 WBTC).
+pub fn search_bounds(token_price_usd: f64, decimals: u8) -> Option<(U256, U256, U256)> {
+    if token_price_usd <= 0.0 || !token_price_usd.is_finite() { return None; }
+    let units = |usd: f64| decimal_to_u256(Decimal::from_f64(usd / token_price_usd)?, decimals).ok();
+    let (a, b) = (units(CONFIG.search_min_usd)?, units(CONFIG.search_max_usd)?);
+    if a.is_zero() || a >= b { return None; }
+    Some((a, b, (b / U256::from(SEARCH_TOLERANCE_DIVISOR)).max(U256::one())))
+}
+
+/// Núcleo de la búsqueda con los precios ya resueltos. `sim_block` fija las simulaciones a un bloque
+/// concreto, lo que permite re-ejecutar la evaluación de forma determinista (ver `replay`).
+#[allow(clippy::too_many_arguments)]
+pub async fn optimize_with_prices<M: Middleware + 'static>(
+    provider: Arc<M>, path: &mut ArbPath, base_gas_price_wei: U256, oracle_price: f64, lag: f64, eth_price: f64, current_block: u64, sim_block: Option<BlockId>,
+) -> Option<ArbitrageOpportunity> {
+    let (mut a, mut b, tol) = search_bounds(oracle_price, path.get_input_decimals())?;
+    let bribe_percent = ROUTE_STATS.lock().unwrap().get(&path.key()).map_or(CONFIG.max_bribe_percent, |s| s.bribe_percent());
+    // Acotar el extremo superior a lo que los saltos intermedios pueden absorber antes de simular.
+    if CONFIG.hop_capacity_share > 0.0 && oracle_price > 0.0 {
+        if let Some(feasible_usd) = path.feasible_input_usd(CONFIG.hop_capacity_share) {
+            let feasible_amount = decimal_to_u256(Decimal::from_f64(feasible_usd / oracle_price).unwrap_or_default(), path.get_input_decimals()).ok()?;
+            if feasible_amount < b {
+                if feasible_amount * FEASIBLE_BOUND_LOG_RATIO < b {
+                    info!("Ruta {}: tamaño factible ${:.0} muy por debajo del límite de búsqueda ({} < {} unidades)", path.key(), feasible_usd, feasible_amount, b);
+                }
+                b = feasible_amount;
+            }
+        }
+    }
+    let l1_fee_wei = l1_data_fee_wei(path, None);
+    // Empezar la búsqueda por encima del punto de equilibrio: por debajo ningún tamaño puede ser rentable.
+    let fixed_gas_cost_usd = eth_price * u256_to_decimal(base_gas_price_wei * modeled_gas_units(path) + l1_fee_wei, 18).ok()?.to_f64().unwrap_or_default();
+    let floor_usd = break_even_floor_usd(fixed_gas_cost_usd, min_profit_usd(), CONFIG.flash_loan_fee_bps, CONFIG.max_plausible_edge_bps)?;
+    let floor_amount = decimal_to_u256(Decimal::from_f64(floor_usd / oracle_price).unwrap_or_default(), path.get_input_decimals()).ok()?;
+    debug!("Ruta {}: piso de equilibrio ${:.2} ({} unidades)", path.key(), floor_usd, floor_amount);
+    a = a.max(floor_amount);
+    if a >= b { return None; }
+    let mut iterations = GOLDEN_SECTION_ITERATIONS;
+    if CONFIG.search_strategy == SearchStrategy::ParallelGrid {
+        (a, b) = grid_bracket(&provider, path, a, b, base_gas_price_wei, l1_fee_wei, oracle_price, eth_price, bribe_percent, sim_block).await;
+        iterations = GRID_REFINE_ITERATIONS;
+    }
     let gr = (Decimal::from(5).sqrt().unwrap() - Decimal::ONE) / Decimal::TWO;
     let gr_u256 = decimal_to_u256(gr, 18).ok()?;
     let mut x1 = a + (b - a) * (U256::exp10(18) - gr_u256) / U256::exp10(18);
     let mut x2 = a + (b - a) * gr_u256 / U256::exp10(18);
-    let mut f1 = get_profit_for_amount(&provider, path, x1, base_gas_price_wei, oracle_price, eth_price).await;
-    let mut f2 = get_profit_for_amount(&provider, path, x2, base_gas_price_wei, oracle_price, eth_price).await;
-    for _ in 0..15 {
+    let mut f1 = get_profit_for_amount(&provider, path, x1, base_gas_price_wei, l1_fee_wei, oracle_price, eth_price, bribe_percent, sim_block).await;
+    let mut f2 = get_profit_for_amount(&provider, path, x2, base_gas_price_wei, l1_fee_wei, oracle_price, eth_price, bribe_percent, sim_block).await;
+    for _ in 0..iterations {
         if (b - a) <= tol { break; }
         if f1 > f2 {
             b = x2; x2 = x1; f2 = f1;
             x1 = a + (b - a) * (U256::exp10(18) - gr_u256) / U256::exp10(18);
-            f1 = get_profit_for_amount(&provider, path, x1, base_gas_price_wei, oracle_price, eth_price).await;
+            f1 = get_profit_for_amount(&provider, path, x1, base_gas_price_wei, l1_fee_wei, oracle_price, eth_price, bribe_percent, sim_block).await;
         } else {
             a = x1; x1 = x2; f1 = f2;
             x2 = a + (b - a) * gr_u256 / U256::exp10(18);
-            f2 = get_profit_for_amount(&provider, path, x2, base_gas_price_wei, oracle_price, eth_price).await;
+            f2 = get_profit_for_amount(&provider, path, x2, base_gas_price_wei, l1_fee_wei, oracle_price, eth_price, bribe_percent, sim_block).await;
         }
     }
     let optimal_amount = (a + b) / 2;
     let net_profit_usd = f1.max(f2);
-    if net_profit_usd <= CONFIG.min_profit_usd { return None; }
-    let expected_output = path.simulate_v3_path(provider, optimal_amount).await.unwrap_or_default();
+    if net_profit_usd <= min_profit_usd() { return None; }
+    // ROI relativo al notional: evita trades enormes con margen mínimo.
+    let notional_usd = to_usd(optimal_amount, path.token_a, oracle_price, path.get_input_decimals());
+    let roi_bps = roi_bps(net_profit_usd, notional_usd);
+    if !meets_min_roi(roi_bps) {
+        debug!(
+            "Ruta {} descartada por ROI: ${:.2} sobre ${:.0} ({:.2} bps < {:.2} bps)",
+            path.key(), net_profit_usd, notional_usd, roi_bps, CONFIG.min_roi_bps
+        );
+        return None;
+    }
+    debug!("Ruta {}: profit ${:.2} | ROI {:.2} bps sobre ${:.0}", path.key(), net_profit_usd, roi_bps, notional_usd);
+    // Si la re-simulación final falla, la oportunidad se rechaza: un output cero contaminaría el ranking y los conflictos del bundle.
+    let Some(expected_output) = path.simulate_v3_path_at(provider, optimal_amount, sim_block).await else {
+        debug!("Ruta {} descartada: falló la simulación final con {} unidades.", path.key(), optimal_amount);
+        return None;
+    };
+    if expected_output <= optimal_amount { return None; }
     let path_key = path.key();
     let mut stats_map = ROUTE_STATS.lock().unwrap();
-    let stats = stats_map.entry(path_key).or_default();
+    let stats = stats_map.entry(path_key.clone()).or_default();
     stats.last_attempt_block = current_block;
     let total_fee_bps = (path.pool_1.fee + path.pool_2.fee + path.pool_3.fee) as f64;
     let fee_efficiency = 1.0 / (1.0 + total_fee_bps / 10000.0);
-    let tvl_avg = (path.pool_1.tvl_usd + path.pool_2.tvl_usd + path.pool_3.tvl_usd) / 3.0;
-    let score = net_profit_usd * (1.0 + lag) * stats.winrate() * fee_efficiency * tvl_avg.log10().max(1.0);
+    let Some((tvl_avg, tvl_factor)) = tvl_score_factor(path) else {
+        warn!("Ruta {} descartada: TVL medio inválido", path_key);
+        return None;
+    };
+    // Con la política `deprioritize`, las rutas por tokens de approve no estándar quedan por detrás.
+    let approval_factor = match crate::paths::non_standard_approval_token(path) {
+        Some(_) if CONFIG.approval_token_policy == ApprovalTokenPolicy::Deprioritize => NON_STANDARD_APPROVAL_SCORE_FACTOR,
+        _ => 1.0,
+    };
+    let score = net_profit_usd * (1.0 + lag) * stats.winrate() * stats.realization_ratio() * fee_efficiency * tvl_factor * approval_factor;
+    if !score.is_finite() {
+        warn!("Ruta {} descartada: score no finito ({})", path_key, score);
+        return None;
+    }
     path.score = score;
-    let gas_cost_usd_estimate = (eth_price * u256_to_decimal(base_gas_price_wei * CONFIG.gas_limit, 18).unwrap_or_default().to_f64().unwrap_or_default());
+    let gas_cost_usd_estimate = (eth_price * u256_to_decimal(base_gas_price_wei * modeled_gas_units(path) + l1_fee_wei, 18).unwrap_or_default().to_f64().unwrap_or_default());
     let gross_profit_usd = net_profit_usd + gas_cost_usd_estimate;
-    let bribe_usd = gross_profit_usd * CONFIG.max_bribe_percent;
+    let bribe_usd = gross_profit_usd * bribe_percent;
     Some(ArbitrageOpportunity {
         path: path.clone(), optimal_amount_in: optimal_amount, expected_output, net_profit_usd,
-        bribe_usd, lag, tvl: tvl_avg, score, slippage_bps: 0,
+        bribe_usd, bribe_percent, lag, tvl: tvl_avg, score, slippage_bps: 0,
+        token_price_usd: oracle_price, eth_price_usd: eth_price, flash_loan_leg: None,
     })
 }
+
+/// TVL medio de la ruta y su término logarítmico del score, acotado a [1, ∞) para que TVLs por debajo
+/// de $10 no penalicen de forma inconsistente. `None` si el TVL no es finito y positivo: no debería
+/// haber pasado el filtrado y se descarta antes de contaminar el ranking.
+fn tvl_score_factor(path: &ArbPath) -> Option<(f64, f64)> {
+    let tvl_avg = (path.pool_1.tvl_usd + path.pool_2.tvl_usd + path.pool_3.tvl_usd) / 3.0;
+    if !tvl_avg.is_finite() || tvl_avg <= 0.0 { return None; }
+    Some((tvl_avg, tvl_avg.max(10.0).log10()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::instance::with_test_config;
+
+    fn history(successes: u64, failures: u64) -> RouteHistory {
+        RouteHistory { successes, failures, ..Default::default() }
+    }
+
+    #[tokio::test]
+    async fn flash_loan_leg_swaps_through_the_deepest_pool_of_the_loan_token() {
+        use crate::{
+            constants::{SUSHISWAP_V3_FACTORY, UNISWAP_V3_FACTORY},
+            multi::RawPoolData,
+            paths::tests::{addr, path},
+            pools::{PoolMetrics, POOL_METRICS},
+            testing::MockChain,
+        };
+
+        // Préstamo en un token de 6 decimales a $1 (addr 9) para un ciclo de token_a a $2.000 (addr 1).
+        let (token_a, usdc) = (addr(1), addr(9));
+        let leg_pool = |factory: H160, fee: u32, effective_tvl_usd: f64| PoolMetrics {
+            raw: RawPoolData { factory, token0: token_a, token1: usdc, decimals0: 18, decimals1: 6, fee, ..Default::default() },
+            raw_tvl_usd: effective_tvl_usd,
+            effective_tvl_usd,
+            price0_usd: 2_000.0,
+            price1_usd: 1.0,
+        };
+        let unit = U256::exp10(18);
+        // $2.000 más el 0,3% del swap de entrada: 2.006 unidades del préstamo.
+        let borrow_amount = U256::from(2_006_000_000u64);
+        let route = path([1, 2, 3]);
+        let chain = MockChain::new()
+            .quote_single(DexVariant::SushiV3, usdc, token_a, 3000, borrow_amount, unit)
+            .quote_multi(DexVariant::UniswapV3, route.encoded_v3_path(), unit, unit * 101 / 100)
+            .quote_single(DexVariant::SushiV3, token_a, usdc, 3000, unit * 101 / 100, U256::from(2_030_000_000u64));
+        let opp = ArbitrageOpportunity {
+            path: route,
+            optimal_amount_in: unit,
+            expected_output: unit * 101 / 100,
+            net_profit_usd: 15.0,
+            bribe_usd: 0.0,
+            bribe_percent: 0.0,
+            lag: 0.0,
+            tvl: 0.0,
+            score: 0.0,
+            slippage_bps: 0,
+            token_price_usd: 2_000.0,
+            eth_price_usd: 2_000.0,
+            flash_loan_leg: None,
+        };
+
+        let edit = |c: &mut crate::config::Config| {
+            c.flash_loan_fee_bps = 0;
+            c.l1_fee_per_calldata_byte_wei = 0;
+            c.min_profit_usd = 1.0;
+        };
+        let (leg_opp, gas_units) = with_test_config(edit, async {
+            POOL_METRICS.lock().unwrap().extend([
+                (addr(900), leg_pool(*SUSHISWAP_V3_FACTORY, 3000, 5e6)),
+                (addr(901), leg_pool(*UNISWAP_V3_FACTORY, 500, 1e6)),
+            ]);
+            let gas_units = CONFIG.gas_for_hops(5);
+            (with_flash_loan_leg(chain.provider(), opp, usdc, 1.0, U256::exp10(9), None).await.unwrap(), gas_units)
+        })
+        .await;
+
+        let leg = leg_opp.flash_loan_leg.as_ref().unwrap();
+        assert!(matches!(leg.variant, DexVariant::SushiV3));
+        assert_eq!((leg.fee, leg.decimals, leg.amount), (3000, 6, borrow_amount));
+        assert_eq!(leg.expected_return, U256::from(2_030_000_000u64));
+        // $24 de profit bruto en el token prestado, menos el gas de 3 + 2 saltos a 1 gwei y $2.000/ETH.
+        let gas_usd = gas_units as f64 * 1e9 / 1e18 * 2_000.0;
+        assert!((leg_opp.net_profit_usd - (24.0 - gas_usd)).abs() < 1e-6, "{}", leg_opp.net_profit_usd);
+    }
+
+    #[test]
+    fn break_even_floor_covers_fixed_costs_with_the_net_edge() {
+        // ($3 de gas + $1 de profit) / 1% de margen neto.
+        assert_eq!(break_even_floor_usd(3.0, 1.0, 0, 100), Some(400.0));
+        assert_eq!(break_even_floor_usd(3.0, 1.0, 50, 100), Some(800.0));
+        assert_eq!(break_even_floor_usd(3.0, 1.0, 100, 100), None);
+        assert_eq!(break_even_floor_usd(3.0, 1.0, 200, 100), None);
+    }
+
+    #[test]
+    fn realized_slippage_is_an_ewma_that_needs_samples() {
+        let mut stats = RouteHistory::default();
+        stats.record_realized_slippage(10.0);
+        assert_eq!(stats.realized_slippage_bps_ewma, 10.0);
+        stats.record_realized_slippage(20.0);
+        assert!((stats.realized_slippage_bps_ewma - 13.0).abs() < 1e-9);
+        assert_eq!(stats.adaptive_slippage_bps(), None);
+        stats.record_realized_slippage(20.0);
+        // 0.3 * 20 + 0.7 * 13 = 15.1 -> 16 bps.
+        assert_eq!(stats.adaptive_slippage_bps(), Some(16));
+        // Sólo el exceso sobre el presupuesto cuenta, y nunca amplía la tolerancia.
+        assert_eq!(stats.excess_slippage_bps(10), Some(6));
+        assert_eq!(stats.excess_slippage_bps(16), None);
+
+        let mut noisy = RouteHistory::default();
+        for _ in 0..MIN_SLIPPAGE_SAMPLES {
+            noisy.record_realized_slippage(500.0);
+        }
+        assert_eq!(noisy.adaptive_slippage_bps(), Some(MAX_ADAPTIVE_SLIPPAGE_BPS));
+    }
+
+    #[tokio::test]
+    async fn bribe_percent_depends_on_the_winrate() {
+        with_test_config(|c| c.max_bribe_percent = 0.8, async {
+            assert_eq!(history(1, 1).bribe_percent(), 0.8);
+            assert!((history(10, 0).bribe_percent() - 0.4).abs() < 1e-9);
+            assert!((history(0, 10).bribe_percent() - 0.8).abs() < 1e-9);
+        })
+        .await;
+    }
+
+    #[tokio::test]
+    async fn min_roi_rejects_thin_margins_on_large_notionals() {
+        // $20 sobre $1M es 0,2 bps; sobre $2k, 100 bps.
+        assert!((roi_bps(20.0, 1_000_000.0) - 0.2).abs() < 1e-9);
+        assert!((roi_bps(20.0, 2_000.0) - 100.0).abs() < 1e-9);
+        assert_eq!(roi_bps(20.0, 0.0), 0.0);
+        with_test_config(|c| c.min_roi_bps = 10.0, async {
+            assert!(meets_min_roi(roi_bps(20.0, 20_000.0)));
+            assert!(!meets_min_roi(roi_bps(19.99, 20_000.0)));
+            assert!(!meets_min_roi(roi_bps(20.0, 1_000_000.0)));
+        })
+        .await;
+        with_test_config(|c| c.min_roi_bps = 0.0, async {
+            assert!(meets_min_roi(roi_bps(20.0, 1_000_000.0)));
+        })
+        .await;
+    }
+
+    #[test]
+    fn tvl_score_factor_rejects_invalid_tvl() {
+        let with_tvl = |tvl: [f64; 3]| {
+            let mut route = crate::paths::tests::path([1, 2, 3]);
+            (route.pool_1.tvl_usd, route.pool_2.tvl_usd, route.pool_3.tvl_usd) = (tvl[0], tvl[1], tvl[2]);
+            tvl_score_factor(&route)
+        };
+        assert_eq!(with_tvl([1e6, 1e6, 1e6]), Some((1e6, 6.0)));
+        // Por debajo de $10 el término no baja de 1.
+        assert_eq!(with_tvl([3.0, 3.0, 3.0]), Some((3.0, 1.0)));
+        assert_eq!(with_tvl([1e6, -3e6, 1e6]), None);
+        assert_eq!(with_tvl([0.0, 0.0, 0.0]), None);
+        assert_eq!(with_tvl([f64::NAN, 1e6, 1e6]), None);
+        assert_eq!(with_tvl([f64::INFINITY, 1e6, 1e6]), None);
+    }
+
+    /// Optimiza la ruta 1 -> 2 -> 3 -> 1 de `path` (token de entrada y ETH a $2.000) contra `chain`.
+    async fn optimize_route(chain: &crate::testing::MockChain, edit: impl FnOnce(&mut crate::config::Config)) -> Option<ArbitrageOpportunity> {
+        optimize_route_with_history(chain, RouteHistory::default(), edit).await
+    }
+
+    /// Como [`optimize_route`], partiendo del historial `history` para la ruta.
+    async fn optimize_route_with_history(
+        chain: &crate::testing::MockChain,
+        history: RouteHistory,
+        edit: impl FnOnce(&mut crate::config::Config),
+    ) -> Option<ArbitrageOpportunity> {
+        optimize_route_seeded(chain, move |route| { ROUTE_STATS.lock().unwrap().insert(route.key(), history); }, edit).await
+    }
+
+    /// Como [`optimize_route`]; `seed` prepara el estado de la instancia antes de optimizar.
+    async fn optimize_route_seeded(
+        chain: &crate::testing::MockChain,
+        seed: impl FnOnce(&ArbPath),
+        edit: impl FnOnce(&mut crate::config::Config),
+    ) -> Option<ArbitrageOpportunity> {
+        let mut route = crate::paths::tests::path([1, 2, 3]);
+        let edit = |c: &mut crate::config::Config| {
+            c.min_profit_usd = 1.0;
+            c.min_roi_bps = 0.0;
+            c.flash_loan_fee_bps = 0;
+            c.l1_fee_per_calldata_byte_wei = 0;
+            c.hop_capacity_share = 0.0;
+            c.max_bribe_percent = 0.0;
+            c.search_strategy = SearchStrategy::GoldenSection;
+            edit(c);
+        };
+        let gas_price = U256::from(10_000_000u64);
+        with_test_config(edit, async move {
+            seed(&route);
+            optimize_with_prices(chain.provider(), &mut route, gas_price, 2_000.0, 0.0, 2_000.0, 100, None).await
+        })
+        .await
+    }
+
+    #[tokio::test]
+    async fn failed_final_resimulation_rejects_the_opportunity() {
+        use crate::testing::{MockChain, QUOTE_MULTI};
+        use std::sync::atomic::AtomicUsize;
+
+        let encoded = crate::paths::tests::path([1, 2, 3]).encoded_v3_path();
+        // 0,5% de margen bruto a cualquier tamaño.
+        let healthy = MockChain::new().quote_multi_with(DexVariant::UniswapV3, encoded.clone(), |amount| Some(amount * 1005 / 1000));
+        let opp = optimize_route(&healthy, |_| {}).await.expect("ruta rentable");
+        assert!(opp.expected_output > opp.optimal_amount_in);
+        let simulations = healthy.calls_to(QUOTE_MULTI);
+
+        // La misma búsqueda, pero la re-simulación final (la última cotización) revierte.
+        let calls = Arc::new(AtomicUsize::new(0));
+        let counter = calls.clone();
+        let failing = MockChain::new().quote_multi_with(DexVariant::UniswapV3, encoded, move |amount| {
+            (counter.fetch_add(1, AtomicOrdering::Relaxed) + 1 < simulations).then(|| amount * 1005 / 1000)
+        });
+        assert!(optimize_route(&failing, |_| {}).await.is_none());
+        assert_eq!(calls.load(AtomicOrdering::Relaxed), simulations);
+    }
+
+
+    #[tokio::test]
+    async fn gas_scales_with_hops_and_prefers_the_measured_estimate() {
+        use crate::paths::tests::path;
+
+        with_test_config(|_| {}, async {
+            assert!(CONFIG.gas_for_hops(4) > CONFIG.gas_for_hops(2));
+            assert_eq!(CONFIG.gas_for_hops(4) - CONFIG.gas_for_hops(2), 2 * CONFIG.gas_per_hop);
+
+            let route = path([1, 2, 3]);
+            assert_eq!(route.hop_count(), 3);
+            assert_eq!(modeled_gas_units(&route), CONFIG.gas_for_hops(3));
+            // Con una estimación real de la ruta, el profit usa ese gas y no el modelo.
+            crate::provider::cache_gas_estimate(&route.key(), U256::from(412_345), U256::exp10(18), 100);
+            assert_eq!(modeled_gas_units(&route), 412_345);
+            assert_eq!(modeled_gas_units(&path([4, 5, 6])), CONFIG.gas_for_hops(3));
+        })
+        .await;
+    }
+
+
+    #[test]
+    fn winrate_and_bench() {
+        assert_eq!(history(0, 0).winrate(), 0.5);
+        assert_eq!(history(3, 1).winrate(), 0.75);
+        assert!(history(1, 4).is_benched(0.2, 5));
+        assert!(!history(1, 3).is_benched(0.25, 5));
+        assert!(!history(0, 9).is_benched(0.0, 5));
+    }
+
+
+    #[tokio::test]
+    async fn low_realization_routes_rank_below_equivalent_ones() {
+        use crate::testing::MockChain;
+
+        let encoded = crate::paths::tests::path([1, 2, 3]).encoded_v3_path();
+        let chain = MockChain::new().quote_multi_with(DexVariant::UniswapV3, encoded, |amount| Some(amount * 1005 / 1000));
+        let realizing = |ratio: f64| {
+            let mut history = history(4, 1);
+            (0..5).for_each(|_| history.record_realized_profit(10.0 * ratio, 10.0));
+            history
+        };
+        let untested = optimize_route_with_history(&chain, history(4, 1), |_| {}).await.expect("ruta rentable");
+        let delivers = optimize_route_with_history(&chain, realizing(1.0), |_| {}).await.expect("ruta rentable");
+        let underdelivers = optimize_route_with_history(&chain, realizing(0.3), |_| {}).await.expect("ruta rentable");
+
+        // Misma simulación; sólo cambia lo que la ruta llegó a realizar.
+        assert_eq!(delivers.net_profit_usd, underdelivers.net_profit_usd);
+        assert_eq!(untested.score, delivers.score);
+        assert!(underdelivers.score < delivers.score);
+        assert!((underdelivers.score / delivers.score - 0.3).abs() < 1e-9);
+    }
+
+    #[test]
+    fn realization_ratio_is_clamped_once_there_are_fills() {
+        let mut stats = RouteHistory::default();
+        assert_eq!(stats.realization_ratio(), 1.0);
+        stats.record_realized_profit(5.0, 0.0);
+        assert_eq!(stats.realization_samples, 0);
+        stats.record_realized_profit(1.0, 10.0);
+        assert_eq!(stats.realization_ratio(), MIN_REALIZATION_RATIO);
+        stats.record_realized_profit(30.0, 10.0);
+        // 0.2 * 3.0 + 0.8 * 0.1
+        assert!((stats.realization_ratio_ewma - 0.68).abs() < 1e-9);
+    }
+
+
+    #[tokio::test]
+    async fn thin_middle_hop_caps_the_search_before_simulating() {
+        use crate::{multi::RawPoolData, pools::{PoolMetrics, POOL_METRICS}, testing::MockChain};
+        use std::sync::Mutex;
+
+        let route = crate::paths::tests::path([1, 2, 3]);
+        let encoded = route.encoded_v3_path();
+        // Todos los tokens a $2.000; el salto 2 sólo guarda 5 unidades del token que entrega ($10k).
+        let metrics = |pool: &crate::types::Pool, out_balance: u64| PoolMetrics {
+            raw: RawPoolData {
+                token0: pool.token0,
+                token1: pool.token1,
+                decimals0: 18,
+                decimals1: 18,
+                balance0: U256::exp10(24),
+                balance1: U256::exp10(18) * out_balance,
+                ..Default::default()
+            },
+            raw_tvl_usd: 0.0,
+            effective_tvl_usd: 0.0,
+            price0_usd: 2_000.0,
+            price1_usd: 2_000.0,
+        };
+        let seeded = [
+            (route.pool_1.clone(), 1_000_000),
+            (route.pool_2.clone(), 5),
+            (route.pool_3.clone(), 1_000_000),
+        ];
+        let largest_quote = |max_seen: Arc<Mutex<U256>>| {
+            MockChain::new().quote_multi_with(DexVariant::UniswapV3, encoded.clone(), move |amount| {
+                let mut max = max_seen.lock().unwrap();
+                *max = (*max).max(amount);
+                Some(amount * 1005 / 1000)
+            })
+        };
+        let seed = |_: &ArbPath| {
+            *POOL_METRICS.lock().unwrap() = seeded.iter().map(|(pool, out)| (pool.address, metrics(pool, *out))).collect();
+        };
+
+        let unbounded = Arc::new(Mutex::new(U256::zero()));
+        optimize_route_seeded(&largest_quote(unbounded.clone()), seed, |_| {}).await.expect("ruta rentable");
+        let bounded = Arc::new(Mutex::new(U256::zero()));
+        let opp = optimize_route_seeded(&largest_quote(bounded.clone()), seed, |c| c.hop_capacity_share = 0.5)
+            .await
+            .expect("ruta rentable");
+
+        // La mitad de $10k a $2.000: ninguna simulación pasa de 2,5 tokens.
+        let feasible = U256::exp10(17) * 25;
+        assert!(*unbounded.lock().unwrap() > feasible);
+        assert!(*bounded.lock().unwrap() <= feasible);
+        assert!(opp.optimal_amount_in <= feasible);
+    }
+
+
+    #[tokio::test]
+    async fn deprioritized_approval_tokens_halve_the_score() {
+        use crate::config::ApprovalTokenPolicy;
+        use crate::testing::MockChain;
+
+        let encoded = crate::paths::tests::path([1, 2, 3]).encoded_v3_path();
+        let chain = MockChain::new().quote_multi_with(DexVariant::UniswapV3, encoded, |amount| Some(amount * 1005 / 1000));
+        let flag = |policy: ApprovalTokenPolicy| move |c: &mut crate::config::Config| {
+            c.non_standard_approval_tokens = vec![crate::paths::tests::addr(2)];
+            c.approval_token_policy = policy;
+        };
+        let standard = optimize_route_with_history(&chain, history(4, 1), |_| {}).await.expect("ruta rentable");
+        let penalized = optimize_route_with_history(&chain, history(4, 1), flag(ApprovalTokenPolicy::Deprioritize)).await.expect("ruta rentable");
+        let reset = optimize_route_with_history(&chain, history(4, 1), flag(ApprovalTokenPolicy::ContractReset)).await.expect("ruta rentable");
+
+        assert_eq!(penalized.net_profit_usd, standard.net_profit_usd);
+        assert!((penalized.score / standard.score - NON_STANDARD_APPROVAL_SCORE_FACTOR).abs() < 1e-9);
+        // Con el contrato reseteando el allowance la ruta no se penaliza.
+        assert_eq!(reset.score, standard.score);
+    }
+
+
+    #[tokio::test]
+    async fn l1_fee_follows_the_full_calldata_of_each_route() {
+        use crate::{config::{AccountType, EncodingVersion}, constants::OP_GAS_PRICE_ORACLE, paths::tests::{addr, path}, testing::MockChain};
+        use ethers::abi::Token;
+
+        // 1.000 wei por byte según el GasPriceOracle de la chain OP Stack.
+        let sample = l1_fee_sample();
+        let chain = MockChain::new().returns(
+            *OP_GAS_PRICE_ORACLE, "getL1Fee(bytes)", &[Token::Bytes(sample.clone())], &[Token::Uint(U256::from(sample.len() * 1_000))],
+        );
+        let route = path([1, 2, 3]);
+        // Préstamo en otro token: dos saltos más en el path codificado.
+        let leg = FlashLoanLeg {
+            token: addr(9), variant: DexVariant::UniswapV3, fee: 500, decimals: 6,
+            amount: U256::zero(), expected_return: U256::zero(), price_usd: 1.0,
+        };
+        let edit = |c: &mut crate::config::Config| {
+            c.chain_id = 10;
+            c.l1_fee_per_calldata_byte_wei = 7;
+            c.account_type = AccountType::Eoa;
+            c.encoding_version = EncodingVersion::V4;
+        };
+        with_test_config(edit, async {
+            let user_data = execution::encode_arb_data(&route, U256::zero(), 0, None, U256::zero()).unwrap();
+            // Selector + (address, uint256, offset, longitud, datos con padding) + sobre de la TX firmada.
+            let tx_len = 4 + 4 * 32 + user_data.len().div_ceil(32) * 32 + 110;
+            assert_eq!(execution::arb_tx_data_len(&user_data), tx_len);
+            // Sin lectura del oráculo se usa el valor configurado.
+            assert_eq!(l1_data_fee_wei(&route, None), U256::from(tx_len * 7));
+
+            refresh_l1_fee_per_byte(chain.provider()).await;
+            let short = l1_data_fee_wei(&route, None);
+            let long = l1_data_fee_wei(&route, Some(&leg));
+            assert_eq!(short, U256::from(tx_len * 1_000));
+            assert!(long > short, "{long} <= {short}");
+        })
+        .await;
+        // Fuera de OP Stack no se consulta el oráculo.
+        with_test_config(|c| { edit(c); c.chain_id = 42161; }, async {
+            refresh_l1_fee_per_byte(chain.provider()).await;
+            assert_eq!(l1_fee_per_byte_wei(), 7);
+        })
+        .await;
+        assert_eq!(chain.calls_to("getL1Fee(bytes)"), 1);
+    }
+
+
+    #[tokio::test]
+    async fn profit_curve_samples_a_synthetic_profit_function_around_the_optimum() {
+        use crate::{paths::tests::path, testing::MockChain};
+
+        // Ganancia bruta en tokens: 2% del tamaño menos x²/50, con el óptimo en 0,5 unidades.
+        let unit = U256::exp10(18);
+        let gain = move |x: U256| (x * U256::from(2) / U256::from(100)).checked_sub(x * x / (unit * U256::from(50)));
+        let route = path([1, 2, 3]);
+        let chain = MockChain::new().quote_multi_with(DexVariant::UniswapV3, route.encoded_v3_path(), move |x| Some(x + gain(x).unwrap_or_default()));
+        let opp = ArbitrageOpportunity {
+            path: route,
+            optimal_amount_in: unit / 2,
+            expected_output: unit / 2 + unit / 200,
+            net_profit_usd: 10.0,
+            bribe_usd: 0.0,
+            bribe_percent: 0.0,
+            lag: 0.0,
+            tvl: 0.0,
+            score: 0.0,
+            slippage_bps: 0,
+            token_price_usd: 2_000.0,
+            eth_price_usd: 2_000.0,
+            flash_loan_leg: None,
+        };
+
+        // Sin coste de gas, el profit de cada punto es la ganancia bruta a $2.000 por token.
+        let edit = |c: &mut crate::config::Config| c.l1_fee_per_calldata_byte_wei = 0;
+        let curve = with_test_config(edit, sample_profit_curve(chain.provider(), &opp, U256::zero(), None)).await;
+        log_profit_curve(&opp, &curve);
+
+        let sizes: Vec<U256> = curve.iter().map(|(amount, _)| *amount).collect();
+        let expected_sizes: Vec<U256> = PROFIT_CURVE_SIZE_MULTIPLES.iter().map(|m| unit / 2 * U256::from((m * 1_000.0) as u64) / U256::from(1_000)).collect();
+        assert_eq!(sizes, expected_sizes);
+        for ((amount, profit), multiple) in curve.iter().zip(PROFIT_CURVE_SIZE_MULTIPLES) {
+            let x = 0.5 * multiple;
+            let expected = (0.02 * x - x * x / 50.0) * 2_000.0;
+            if expected > 0.0 {
+                assert!((profit - expected).abs() < 1e-6, "{amount}: {profit} vs {expected}");
+            } else {
+                // A partir de 2x la ruta ya no devuelve más de lo que entra.
+                assert_eq!(*profit, -1.0, "{amount}");
+            }
+        }
+        let best = curve.iter().max_by(|a, b| a.1.total_cmp(&b.1)).unwrap();
+        assert_eq!(best.0, opp.optimal_amount_in);
+        assert!((best.1 - 10.0).abs() < 1e-6);
+    }
+
+
+    /// Rejilla sobre `[1, 8]` tokens con un nodo simulado: cada cotización devuelve el monto de entrada
+    /// más `bonus[i]` milésimas de token.
+    async fn grid_with_bonuses(bonus: [u64; 8]) -> (U256, U256) {
+        let (provider, mock) = Provider::mocked();
+        let unit = U256::exp10(18);
+        // El mock responde en orden inverso al de inserción; la rejilla cotiza los puntos en orden.
+        for (i, bonus) in bonus.iter().enumerate().rev() {
+            let out = unit * U256::from(i + 1) + U256::exp10(15) * U256::from(*bonus);
+            mock.push::<Bytes, _>(Bytes::from(ethers::abi::encode(&[ethers::abi::Token::Uint(out)]))).unwrap();
+        }
+        let route = crate::paths::tests::path([1, 2, 3]);
+        with_test_config(
+            |c| {
+                c.parallel_grid_points = 8;
+                c.max_eth_calls_per_block = 0;
+            },
+            grid_bracket(&Arc::new(provider), &route, unit, unit * 8, U256::zero(), U256::zero(), 2_000.0, 2_000.0, 0.0, None),
+        )
+        .await
+    }
+
+    #[tokio::test]
+    async fn grid_bracket_returns_the_neighbours_of_the_best_point() {
+        let unit = U256::exp10(18);
+        assert_eq!(grid_with_bonuses([1, 2, 3, 4, 5, 9, 2, 1]).await, (unit * 5, unit * 7));
+        // En los extremos el intervalo se queda dentro de la rejilla.
+        assert_eq!(grid_with_bonuses([9, 2, 1, 1, 1, 1, 1, 1]).await, (unit, unit * 2));
+        assert_eq!(grid_with_bonuses([1, 1, 1, 1, 1, 1, 2, 9]).await, (unit * 7, unit * 8));
+    }
+
+    #[tokio::test]
+    async fn parallel_grid_cuts_latency_and_keeps_the_optimum() {
+        use crate::testing::{MockChain, QUOTE_MULTI};
+        use std::time::{Duration, Instant};
+
+        // Ganancia bruta de 0,5% del tamaño menos x²/4.000 (en tokens): óptimo en 10 tokens, $50 a $2.000.
+        let unit = U256::exp10(18);
+        let encoded = crate::paths::tests::path([1, 2, 3]).encoded_v3_path();
+        let simulator = || {
+            MockChain::new()
+                .quote_multi_with(DexVariant::UniswapV3, encoded.clone(), move |x| {
+                    let gain = (x * U256::from(5) / U256::from(1_000)).checked_sub(x * x / (unit * U256::from(4_000)))?;
+                    Some(x + gain)
+                })
+                .with_latency(Duration::from_millis(10))
+        };
+        let search = |strategy: SearchStrategy| async move {
+            let chain = simulator();
+            let started = Instant::now();
+            let opp = optimize_route(&chain, |c| {
+                c.search_strategy = strategy;
+                c.parallel_grid_points = 8;
+            })
+            .await
+            .expect("ruta rentable");
+            (opp, started.elapsed(), chain.calls_to(QUOTE_MULTI))
+        };
+
+        let (sequential, sequential_latency, sequential_calls) = search(SearchStrategy::GoldenSection).await;
+        let (parallel, parallel_latency, parallel_calls) = search(SearchStrategy::ParallelGrid).await;
+        // Misma calidad: los dos tamaños cerca del óptimo y el profit a menos del 1% entre sí.
+        for opp in [&sequential, &parallel] {
+            let tokens = opp.optimal_amount_in.as_u128() as f64 / 1e18;
+            assert!((tokens - 10.0).abs() < 1.0, "{tokens}");
+        }
+        assert!((parallel.net_profit_usd - sequential.net_profit_usd).abs() < sequential.net_profit_usd * 0.01);
+        // Ocho cotizaciones a la vez en lugar de encadenadas: menos rondas aunque no menos RPCs.
+        assert!(parallel_calls + 4 >= sequential_calls, "{parallel_calls} vs {sequential_calls}");
+        assert!(parallel_latency * 3 < sequential_latency * 2, "{parallel_latency:?} vs {sequential_latency:?}");
+    }
+
+
+    #[test]
+    fn revert_streak_counts_identical_reasons() {
+        let mut stats = RouteHistory::default();
+        assert_eq!(stats.record_revert("STF"), 1);
+        assert_eq!(stats.record_revert("STF"), 2);
+        assert_eq!(stats.record_revert("TooLittleReceived"), 1);
+        stats.clear_revert_streak();
+        assert_eq!(stats.identical_reverts, 0);
+        assert_eq!(stats.record_revert("TooLittleReceived"), 1);
+    }
+
+    #[tokio::test]
+    async fn route_is_blacklisted_after_identical_reverts() {
+        with_test_config(|c| c.identical_revert_threshold = 3, async {
+            record_route_revert("ruta", "STF", 100);
+            record_route_revert("ruta", "STF", 101);
+            assert!(!route_is_blacklisted("ruta", 102));
+            record_route_revert("ruta", "STF", 102);
+            assert!(route_is_blacklisted("ruta", 103));
+        })
+        .await;
+        with_test_config(|c| c.identical_revert_threshold = 0, async {
+            for block in 100..105 {
+                record_route_revert("ruta", "STF", block);
+            }
+            assert!(!route_is_blacklisted("ruta", 105));
+        })
+        .await;
+    }
+
+    #[tokio::test]
+    async fn route_blacklist_expires_after_its_window() {
+        let blacklisted_at = |window: u64, block: u64| with_test_config(
+            move |c| {
+                c.identical_revert_threshold = 2;
+                c.route_blacklist_blocks = window;
+            },
+            async move {
+                record_route_revert("ruta", "STF", 100);
+                record_route_revert("ruta", "STF", 110);
+                let blacklisted = route_is_blacklisted("ruta", block);
+                (blacklisted, ROUTE_STATS.lock().unwrap()["ruta"].identical_reverts)
+            },
+        );
+
+        // El veto cuenta desde el revert que lo disparó (bloque 110) y al expirar la racha vuelve a cero.
+        assert_eq!(blacklisted_at(50, 159).await, (true, 2));
+        assert_eq!(blacklisted_at(50, 160).await, (false, 0));
+        // Con 0 el veto es permanente.
+        assert_eq!(blacklisted_at(0, 1_000_000).await, (true, 2));
+    }
+
+
+    #[test]
+    fn decimal_to_u256_uses_decimal_digits() {
+        let amount = decimal_to_u256(Decimal::from_str("1.5").unwrap(), 18).unwrap();
+        assert_eq!(amount, U256::from(1_500_000_000_000_000_000u64));
+        assert_eq!(u256_to_decimal(amount, 18).unwrap(), Decimal::from_str("1.5").unwrap());
+        assert_eq!(decimal_to_u256(Decimal::from_str("0.0000004").unwrap(), 6).unwrap(), U256::zero());
+    }
+
+    #[tokio::test]
+    async fn search_bounds_converts_usd_limits_to_token_units() {
+        let bounds = |price, decimals| {
+            with_test_config(
+                |c| {
+                    c.search_min_usd = 100.0;
+                    c.search_max_usd = 100_000.0;
+                },
+                async move { search_bounds(price, decimals) },
+            )
+        };
+        let eth = U256::exp10(18);
+        assert_eq!(
+            bounds(2_000.0, 18).await,
+            Some((eth / 20, eth * 50, eth * 50 / U256::from(SEARCH_TOLERANCE_DIVISOR)))
+        );
+        // 6 decimales (USDC): 100 y 100k tokens.
+        let (a, b, tol) = bounds(1.0, 6).await.unwrap();
+        assert_eq!((a, b), (U256::from(100_000_000u64), U256::from(100_000_000_000u64)));
+        assert_eq!(tol, U256::from(1_000_000u64));
+        // 8 decimales (WBTC a $60k): ~0.0016 y ~1.6 WBTC, no 1e17 unidades (1e9 WBTC).
+        let (a, b, _) = bounds(60_000.0, 8).await.unwrap();
+        assert_eq!((a, b), (U256::from(166_667u64), U256::from(166_666_667u64)));
+        assert_eq!(bounds(0.0, 18).await, None);
+        assert_eq!(bounds(f64::NAN, 18).await, None);
+        // Un precio tan alto que el mínimo no llega a una unidad.
+        assert_eq!(bounds(1e30, 6).await, None);
+    }
+}
@@ -1,5 +1,9 @@
 use crate::{
+    config::{ApprovalTokenPolicy, CONFIG},
+    constants::{USDC_ADDRESS, WBTC_ADDRESS, WETH_ADDRESS},
+    instance::{self, InstanceLocal},
     oracle::OracleMap,
+    pools::{estimate_price_impact_bps, hop_output_capacity_usd, PoolMetrics, PriceSource, POOL_METRICS},
     simulator,
     types::{Pool, DexVariant},
 };
@@ -8,14 +12,94 @@ use ethers::{
     prelude::*,
     types::{H160, U256},
 };
-use log::info;
-use std::{cmp::Ordering, collections::HashMap, sync::Arc, time::Instant};
+use lazy_static::lazy_static;
+use log::{debug, info, log, warn, Level};
+use prometheus::{register_int_counter_vec, IntCounterVec};
+use serde::Serialize;
+use std::{
+    cmp::Ordering,
+    collections::{BTreeMap, HashMap, HashSet},
+    path::Path,
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering as AtomicOrdering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+lazy_static! {
+    static ref PATH_GENERATION_TRUNCATED: IntCounterVec = register_int_counter_vec!("path_generation_truncated_total", "Generaciones de rutas cortadas por presupuesto de tiempo", &["chain"]).unwrap();
+}
+
+// Ronda de rotación: avanza en cada generación para ciclar los pools fuera del top-K.
+static ROTATION_ROUND: InstanceLocal<AtomicUsize> = InstanceLocal::new(|| AtomicUsize::new(0));
+// Pools que entraron en la generación actual por rotación y no por TVL.
+static ROTATED_POOLS: InstanceLocal<Mutex<HashSet<H160>>> = InstanceLocal::new(|| Mutex::new(HashSet::new()));
+
+// Si el contrato confirmó al arrancar que resetea el allowance antes de aprobar (`supportsApprovalReset()`).
+static APPROVAL_RESET_SUPPORTED: InstanceLocal<AtomicBool> = InstanceLocal::new(|| AtomicBool::new(false));
+
+pub fn set_approval_reset_supported(supported: bool) {
+    APPROVAL_RESET_SUPPORTED.store(supported, AtomicOrdering::Relaxed);
+}
+
+/// Token de la ruta con `approve` no estándar, si lo hay.
+pub fn non_standard_approval_token(path: &ArbPath) -> Option<H160> {
+    [path.token_a, path.token_b, path.token_c]
+        .into_iter()
+        .find(|t| CONFIG.non_standard_approval_tokens.contains(t))
+}
+
+/// Aplica `APPROVAL_TOKEN_POLICY` a las rutas con tokens de `approve` no estándar: las excluye salvo
+/// en `deprioritize` (el score se penaliza en el optimizador) o en `contract_reset` con el contrato verificado.
+fn apply_approval_token_policy(paths: &mut Vec<ArbPath>) {
+    if CONFIG.non_standard_approval_tokens.is_empty() { return; }
+    let keep = match CONFIG.approval_token_policy {
+        ApprovalTokenPolicy::Deprioritize => true,
+        ApprovalTokenPolicy::ContractReset => APPROVAL_RESET_SUPPORTED.load(AtomicOrdering::Relaxed),
+        ApprovalTokenPolicy::Exclude => false,
+    };
+    let flagged = paths.iter().filter(|p| non_standard_approval_token(p).is_some()).count();
+    if flagged == 0 { return; }
+    if keep {
+        info!(
+            " {} rutas pasan por tokens con approve no estándar; se mantienen (política {:?}).",
+            flagged, CONFIG.approval_token_policy
+        );
+        return;
+    }
+    paths.retain(|p| non_standard_approval_token(p).is_none());
+    let reason = match CONFIG.approval_token_policy {
+        ApprovalTokenPolicy::ContractReset => "el contrato no confirmó que resetea el allowance",
+        _ => "política exclude",
+    };
+    info!(" {} rutas excluidas por tokens con approve no estándar ({}).", flagged, reason);
+}
+
+pub fn is_rotated_pool(address: &H160) -> bool {
+    ROTATED_POOLS.lock().unwrap().contains(address)
+}
+
+/// Limita la lista (ordenada por TVL) a `limit` pools: los `limit - slots` de mayor TVL más
+/// `slots` pools del resto elegidos de forma cíclica según `round`.
+fn select_with_rotation<'a>(list: &[&'a Pool], limit: usize, slots: usize, round: usize) -> (Vec<&'a Pool>, Vec<H160>) {
+    if list.len() <= limit || slots == 0 {
+        return (list.iter().take(limit).copied().collect(), Vec::new());
+    }
+    let slots = slots.min(limit);
+    let top = limit - slots;
+    let rest = &list[top..];
+    let start = (round * slots) % rest.len();
+    let rotated: Vec<&Pool> = (0..slots.min(rest.len())).map(|i| rest[(start + i) % rest.len()]).collect();
+    let rotated_addresses = rotated.iter().map(|p| p.address).collect();
+    let mut selected: Vec<&Pool> = list[..top].to_vec();
+    selected.extend(rotated);
+    (selected, rotated_addresses)
+}
 
 // --- Constantes de Filtrado del Pathfinder ---
 // Ignorar pools con menos de $50k de liquidez para evitar alto slippage.
 const MIN_TVL_USD: f64 = 50_000.0;
-// Limitar el número de pools por token para evitar una explosión combinatoria.
-const MAX_POOLS_PER_TOKEN: usize = 75;
 
 /// Representa una ruta de arbitraje triangular completa A -> B -> C -> A.
 #[derive(Debug, Clone)]
@@ -39,6 +123,38 @@ impl ArbPath {
         &self,
         provider: Arc<M>,
         amount_in: U256,
+    ) -> Option<U256> {
+        self.simulate_v3_path_at(provider, amount_in, None).await
+    }
+
+    /// Igual que `simulate_v3_path`, pero contra el estado de `block` si se indica.
+    pub async fn simulate_v3_path_at<M: Middleware + 'static>(
+        &self,
+        provider: Arc<M>,
+        amount_in: U256,
+        block: Option<BlockId>,
+    ) -> Option<U256> {
+        // Ruta dentro de un único DEX: una sola cotización multi-hop en lugar de tres. Su error se
+        // registra y descarta la ruta, igual que el de un salto individual.
+        if let Some(variant) = self.single_dex() {
+            let encoded = Bytes::from(self.encoded_v3_path());
+            return match simulator::quote_exact_input(provider, variant, encoded, amount_in, block).await {
+                Ok(amount_out) => (!amount_out.is_zero()).then_some(amount_out),
+                Err(e) => {
+                    debug!("Ruta {}: la cotización multi-hop falló: {e:?}", self.key());
+                    None
+                }
+            };
+        }
+        self.simulate_hop_by_hop(provider, amount_in, block).await
+    }
+
+    /// Cotiza los tres saltos por separado (rutas con más de un DEX).
+    async fn simulate_hop_by_hop<M: Middleware + 'static>(
+        &self,
+        provider: Arc<M>,
+        amount_in: U256,
+        block: Option<BlockId>,
     ) -> Option<U256> {
         // Salto 1: A -> B
         let (token_in_1, token_out_1) = if self.pool_1.token0 == self.token_a {
@@ -47,7 +163,7 @@ impl ArbPath {
             (self.pool_1.token1, self.pool_1.token0)
         };
         let amount_out_1 = simulator::quote_exact_input_single(
-            provider.clone(), self.pool_1.version, token_in_1, token_out_1, self.pool_1.fee, amount_in,
+            provider.clone(), self.pool_1.version, token_in_1, token_out_1, self.pool_1.fee, amount_in, block,
         ).await.ok()?;
 
         if amount_out_1.is_zero() { return None; }
@@ -59,7 +175,7 @@ impl ArbPath {
             (self.pool_2.token1, self.pool_2.token0)
         };
         let amount_out_2 = simulator::quote_exact_input_single(
-            provider.clone(), self.pool_2.version, token_in_2, token_out_2, self.pool_2.fee, amount_out_1,
+            provider.clone(), self.pool_2.version, token_in_2, token_out_2, self.pool_2.fee, amount_out_1, block,
         ).await.ok()?;
 
         if amount_out_2.is_zero() { return None; }
@@ -71,20 +187,81 @@ impl ArbPath {
             (self.pool_3.token1, self.pool_3.token0)
         };
         let final_amount_out = simulator::quote_exact_input_single(
-            provider, self.pool_3.version, token_in_3, token_out_3, self.pool_3.fee, amount_out_2,
+            provider, self.pool_3.version, token_in_3, token_out_3, self.pool_3.fee, amount_out_2, block,
         ).await.ok()?;
 
         Some(final_amount_out)
     }
 
-    /// Obtiene el precio spot aproximado de la ruta simulando con 1 unidad del token de entrada.
-    pub async fn get_spot_price<M: Middleware + 'static>(&self, provider: Arc<M>) -> Result<f64> {
+    /// Obtiene el precio spot aproximado de la ruta (output/input) simulando un trade marginal de
+    /// `spot_quote_usd` convertido con el precio del token de entrada. Sin precio, usa 1 token.
+    pub async fn get_spot_price<M: Middleware + 'static>(&self, provider: Arc<M>, token_price_usd: Option<f64>) -> Result<f64> {
+        let amount_in = self.spot_quote_amount(token_price_usd);
+        let simulated_out = self.simulate_v3_path_at(provider, amount_in, crate::provider::simulation_block()).await.unwrap_or_default();
+
+        Ok(crate::math::token_units(simulated_out, 0) / crate::math::token_units(amount_in, 0))
+    }
+
+    /// Monto de `token_a` equivalente a `spot_quote_usd`; sin precio (o si no llega a una unidad base),
+    /// un token entero.
+    fn spot_quote_amount(&self, token_price_usd: Option<f64>) -> U256 {
         let input_decimals = self.get_input_decimals();
         let one_token = U256::from(10).pow(U256::from(input_decimals));
+        match token_price_usd {
+            Some(price) if price > 0.0 => {
+                let units = CONFIG.spot_quote_usd / price * 10f64.powi(input_decimals as i32);
+                if units >= 1.0 { U256::from(units as u128) } else { one_token }
+            }
+            _ => one_token,
+        }
+    }
+
+    /// DEX común a los tres pools, si lo hay.
+    pub fn single_dex(&self) -> Option<DexVariant> {
+        let variant = self.pool_1.version;
+        let same = |v: DexVariant| std::mem::discriminant(&v) == std::mem::discriminant(&variant);
+        (same(self.pool_2.version) && same(self.pool_3.version)).then_some(variant)
+    }
+
+    /// Ruta V3 completa A -> B -> C -> A codificada como `token, fee(3 bytes), token, ...`.
+    pub fn encoded_v3_path(&self) -> Vec<u8> {
+        let mut path_bytes = Vec::with_capacity(20 * 4 + 3 * 3);
+        path_bytes.extend_from_slice(self.token_a.as_bytes());
+        path_bytes.extend_from_slice(&self.pool_1.fee.to_be_bytes()[1..]);
+        path_bytes.extend_from_slice(self.token_b.as_bytes());
+        path_bytes.extend_from_slice(&self.pool_2.fee.to_be_bytes()[1..]);
+        path_bytes.extend_from_slice(self.token_c.as_bytes());
+        path_bytes.extend_from_slice(&self.pool_3.fee.to_be_bytes()[1..]);
+        path_bytes.extend_from_slice(self.token_a.as_bytes());
+        path_bytes
+    }
 
-        let simulated_out = self.simulate_v3_path(provider, one_token).await.unwrap_or_default();
+    /// Ciclo de tokens de la ruta sin importar el token de inicio: los tokens ordenados y el sentido
+    /// de recorrido. Las dos direcciones de un mismo ciclo comparten tokens y difieren en el sentido.
+    pub fn cycle_direction(&self) -> ([H160; 3], bool) {
+        let mut tokens = [self.token_a, self.token_b, self.token_c];
+        let sequence = tokens;
+        tokens.sort();
+        // Sentido "directo" si, partiendo del token menor, el siguiente es el token intermedio.
+        let start = sequence.iter().position(|t| *t == tokens[0]).unwrap_or(0);
+        (tokens, sequence[(start + 1) % 3] == tokens[1])
+    }
+
+    /// `true` si `other` recorre el mismo ciclo de tokens en sentido contrario (sus pools pueden diferir).
+    pub fn is_reverse_of(&self, other: &ArbPath) -> bool {
+        let (tokens, forward) = self.cycle_direction();
+        let (other_tokens, other_forward) = other.cycle_direction();
+        tokens == other_tokens && forward != other_forward
+    }
 
-        Ok(simulated_out.as_u128() as f64 / 10f64.powi(input_decimals as i32))
+    /// Pools de la ruta en orden de recorrido, uno por swap.
+    pub fn pools(&self) -> [&Pool; 3] {
+        [&self.pool_1, &self.pool_2, &self.pool_3]
+    }
+
+    /// Número de swaps de la ruta.
+    pub fn hop_count(&self) -> u64 {
+        self.pools().len() as u64
     }
 
     /// Devuelve los decimales del token de entrada (token_a) de la ruta.
@@ -96,6 +273,23 @@ impl ArbPath {
         }
     }
 
+    /// Tamaño máximo (USD) que la ruta puede absorber: cada salto debe poder entregar el monto
+    /// intermedio con una fracción `capacity_share` del token de salida que guarda el pool.
+    /// `None` si falta alguna métrica del último refresco.
+    pub fn feasible_input_usd(&self, capacity_share: f64) -> Option<f64> {
+        let metrics = POOL_METRICS.lock().unwrap();
+        let hops = [
+            (&self.pool_1, self.token_b),
+            (&self.pool_2, self.token_c),
+            (&self.pool_3, self.token_a),
+        ];
+        hops.iter()
+            .map(|(pool, token_out)| {
+                metrics.get(&pool.address).map(|m| hop_output_capacity_usd(m, *token_out) * capacity_share)
+            })
+            .try_fold(f64::INFINITY, |acc, cap| cap.map(|c| acc.min(c)))
+    }
+
     // Funciones de conveniencia para acceder a datos anidados.
     pub fn address(&self, index: usize) -> H160 {
         match index {
@@ -107,6 +301,36 @@ impl ArbPath {
     }
 }
 
+/// Descarta saltos cuyo impacto en precio para el trade de referencia supera `max_price_impact_bps`.
+/// Los pools sin datos en vivo no se filtran.
+fn hop_within_impact(metrics: &HashMap<H160, PoolMetrics>, pool: &Pool, token_in: H160) -> bool {
+    if CONFIG.max_price_impact_bps == 0 { return true; }
+    match metrics.get(&pool.address) {
+        Some(m) => estimate_price_impact_bps(m, token_in, CONFIG.price_impact_reference_usd) <= CONFIG.max_price_impact_bps as f64,
+        None => true,
+    }
+}
+
+/// `true` si un token intermedio tiene precio utilizable: feed de oráculo o, con
+/// `ALLOW_DERIVED_INTERMEDIATE_PRICES`, un precio derivado de pools en el mapa de precios.
+fn intermediate_priced(oracle_map: &OracleMap, token: &H160) -> bool {
+    crate::feeds::has_feed(oracle_map, token)
+        || (CONFIG.allow_derived_intermediate_prices
+            && crate::pools::price_source(token).is_some_and(|e| matches!(e.source, Some(PriceSource::DerivedFromPool { .. }))))
+}
+
+/// Vecinos directos de `token_in` (por pools que pasan el filtro) sin precio utilizable como intermedio.
+/// Si son todos, ninguna ruta puede salir de `token_in`.
+fn unpriced_neighbors(pools: &[&Pool], token_in: H160, oracle_map: &OracleMap) -> (usize, Vec<H160>) {
+    let neighbors: HashSet<H160> = pools.iter()
+        .filter(|p| p.token0 == token_in || p.token1 == token_in)
+        .map(|p| if p.token0 == token_in { p.token1 } else { p.token0 })
+        .collect();
+    let mut unpriced: Vec<H160> = neighbors.iter().copied().filter(|t| !intermediate_priced(oracle_map, t)).collect();
+    unpriced.sort();
+    (neighbors.len(), unpriced)
+}
+
 /// Genera todas las rutas de arbitraje triangular (A->B->C->A) a partir de una lista de pools.
 pub fn generate_triangular_paths(
     pools: &[Pool],
@@ -114,10 +338,23 @@ pub fn generate_triangular_paths(
     oracle_map: &OracleMap,
 ) -> Vec<ArbPath> {
     let start_time = Instant::now();
-    info!(" Generando rutas triangulares (TVL >= ${}, top {} pools/token)...", MIN_TVL_USD, MAX_POOLS_PER_TOKEN);
+    info!(
+        " Generando rutas triangulares (TVL >= ${}, top {} pools/token, {} en rotación)...",
+        MIN_TVL_USD, CONFIG.max_pools_per_token, CONFIG.pool_rotation_slots
+    );
 
-    // 1. Filtrar pools por TVL mínimo.
-    let filtered_pools: Vec<&Pool> = pools.iter().filter(|p| p.tvl_usd >= MIN_TVL_USD).collect();
+    // 1. Filtrar pools por TVL mínimo. Los pools con `slot0.unlocked == false` (reentrada en curso o
+    // estado anómalo) cotizan datos engañosos y se excluyen, igual que los de DEX con el quoter desactivado.
+    let locked_pools: HashSet<H160> = POOL_METRICS.lock().unwrap().iter()
+        .filter(|(_, m)| !m.raw.unlocked)
+        .map(|(address, _)| *address)
+        .collect();
+    if !locked_pools.is_empty() {
+        warn!("{} pools con slot0 bloqueado excluidos de la generación de rutas: {:?}", locked_pools.len(), locked_pools);
+    }
+    let filtered_pools: Vec<&Pool> = pools.iter()
+        .filter(|p| p.tvl_usd >= MIN_TVL_USD && !locked_pools.contains(&p.address) && simulator::is_dex_enabled(p.version))
+        .collect();
 
     // 2. Agrupar pools por cada token que contienen.
     let mut pools_by_token: HashMap<H160, Vec<&Pool>> = HashMap::new();
@@ -126,29 +363,44 @@ pub fn generate_triangular_paths(
         pools_by_token.entry(pool.token1).or_default().push(pool);
     }
 
-    // 3. Para cada token, mantener solo los N pools más líquidos para optimizar.
+    // 3. Para cada token, mantener solo los N pools más líquidos (más los rotados) para optimizar.
+    let round = ROTATION_ROUND.fetch_add(1, AtomicOrdering::Relaxed);
+    let mut rotated_pools = HashSet::new();
     for list in pools_by_token.values_mut() {
         list.sort_unstable_by(|a, b| b.tvl_usd.partial_cmp(&a.tvl_usd).unwrap_or(Ordering::Equal));
-        list.truncate(MAX_POOLS_PER_TOKEN);
+        let (selected, rotated) = select_with_rotation(list, CONFIG.max_pools_per_token, CONFIG.pool_rotation_slots, round);
+        *list = selected;
+        rotated_pools.extend(rotated);
     }
+    *ROTATED_POOLS.lock().unwrap() = rotated_pools;
 
+    let pool_metrics = POOL_METRICS.lock().unwrap().clone();
     let mut valid_paths = Vec::new();
+    // Presupuesto de tiempo: al agotarse se devuelven las rutas completas encontradas hasta ahora.
+    let budget = (CONFIG.path_generation_budget_ms > 0).then(|| Duration::from_millis(CONFIG.path_generation_budget_ms));
+    let mut truncated = false;
     // 4. Construir las rutas A -> B -> C -> A.
     if let Some(first_hop_pools) = pools_by_token.get(&token_in) {
-        for &pool_ab in first_hop_pools {
+        'generation: for &pool_ab in first_hop_pools {
+            if !hop_within_impact(&pool_metrics, pool_ab, token_in) { continue; }
             let token_b = if pool_ab.token0 == token_in { pool_ab.token1 } else { pool_ab.token0 };
 
             // Filtro inteligente: no continuar si el token intermedio no tiene oráculo.
-            if oracle_map.get_feeds(&token_b).is_none() { continue; }
+            if !intermediate_priced(oracle_map, &token_b) { continue; }
 
             if let Some(second_hop_pools) = pools_by_token.get(&token_b) {
                 for &pool_bc in second_hop_pools {
+                    if budget.is_some_and(|b| start_time.elapsed() > b) {
+                        truncated = true;
+                        break 'generation;
+                    }
                     if pool_bc.address == pool_ab.address { continue; } // Evitar usar el mismo pool dos veces.
+                    if !hop_within_impact(&pool_metrics, pool_bc, token_b) { continue; }
 
                     let token_c = if pool_bc.token0 == token_b { pool_bc.token1 } else { pool_bc.token0 };
 
                     if token_c == token_in { continue; } // Evitar rutas A->B->A
-                    if oracle_map.get_feeds(&token_c).is_none() { continue; }
+                    if !intermediate_priced(oracle_map, &token_c) { continue; }
 
                     if let Some(third_hop_pools) = pools_by_token.get(&token_c) {
                         for &pool_ca in third_hop_pools {
@@ -158,7 +410,7 @@ pub fn generate_triangular_paths(
                             let closes_loop = (pool_ca.token0 == token_c && pool_ca.token1 == token_in)
                                 || (pool_ca.token1 == token_c && pool_ca.token0 == token_in);
 
-                            if closes_loop {
+                            if closes_loop && hop_within_impact(&pool_metrics, pool_ca, token_c) {
                                 valid_paths.push(ArbPath {
                                     pool_1: (*pool_ab).clone(),
                                     pool_2: (*pool_bc).clone(),
@@ -176,6 +428,555 @@ pub fn generate_triangular_paths(
         }
     }
 
+    if truncated {
+        instance::labeled(&PATH_GENERATION_TRUNCATED).inc();
+        warn!(
+            " Generación de rutas truncada tras {} ms: se usan las {} rutas encontradas hasta ahora.",
+            CONFIG.path_generation_budget_ms, valid_paths.len()
+        );
+    }
+    apply_approval_token_policy(&mut valid_paths);
+    if valid_paths.is_empty() {
+        let (neighbor_count, unpriced) = unpriced_neighbors(&filtered_pools, token_in, oracle_map);
+        if neighbor_count > 0 && unpriced.len() == neighbor_count {
+            warn!(
+                " Ninguna ruta posible: los {} vecinos directos de {} carecen de feed de oráculo: {}. Añade sus feeds al mapa de oráculos{}.",
+                neighbor_count, token_label(token_in), unpriced.iter().map(|t| token_label(*t)).collect::<Vec<_>>().join(", "),
+                if CONFIG.allow_derived_intermediate_prices { "" } else { " o activa ALLOW_DERIVED_INTERMEDIATE_PRICES (menos seguro)" }
+            );
+        }
+    }
     info!(" Rutas generadas: {} en {:.2}s", valid_paths.len(), start_time.elapsed().as_secs_f64());
     valid_paths
 }
+
+/// Fila del volcado de rutas para inspección offline.
+#[derive(Debug, Serialize)]
+struct PathDumpRow {
+    key: String,
+    tokens: String,
+    pool_1: String,
+    fee_1: u32,
+    dex_1: String,
+    tvl_1: f64,
+    pool_2: String,
+    fee_2: u32,
+    dex_2: String,
+    tvl_2: f64,
+    pool_3: String,
+    fee_3: u32,
+    dex_3: String,
+    tvl_3: f64,
+}
+
+/// Devuelve el símbolo de los tokens conocidos o la dirección en hex si no lo es.
+fn token_label(token: H160) -> String {
+    if token == *WETH_ADDRESS {
+        "WETH".to_string()
+    } else if token == *USDC_ADDRESS {
+        "USDC".to_string()
+    } else if token == *WBTC_ADDRESS {
+        "WBTC".to_string()
+    } else {
+        format!("{token:?}")
+    }
+}
+
+/// Escribe el set de rutas generado en un CSV para poder compararlo con lo esperado.
+/// Si se indica `only_token`, sólo se vuelcan las rutas que pasan por ese token.
+pub fn dump_paths(paths: &[ArbPath], file: &Path, only_token: Option<H160>) -> Result<usize> {
+    let mut wtr = csv::Writer::from_path(file)?;
+    let mut written = 0;
+    for path in paths {
+        if let Some(token) = only_token {
+            if path.token_a != token && path.token_b != token && path.token_c != token { continue; }
+        }
+        wtr.serialize(PathDumpRow {
+            key: path.key(),
+            tokens: format!(
+                "{}->{}->{}->{}",
+                token_label(path.token_a), token_label(path.token_b), token_label(path.token_c), token_label(path.token_a)
+            ),
+            pool_1: format!("{:?}", path.pool_1.address),
+            fee_1: path.pool_1.fee,
+            dex_1: format!("{:?}", path.pool_1.version),
+            tvl_1: path.pool_1.tvl_usd,
+            pool_2: format!("{:?}", path.pool_2.address),
+            fee_2: path.pool_2.fee,
+            dex_2: format!("{:?}", path.pool_2.version),
+            tvl_2: path.pool_2.tvl_usd,
+            pool_3: format!("{:?}", path.pool_3.address),
+            fee_3: path.pool_3.fee,
+            dex_3: format!("{:?}", path.pool_3.version),
+            tvl_3: path.pool_3.tvl_usd,
+        })?;
+        written += 1;
+    }
+    wtr.flush()?;
+    info!(" {} rutas volcadas a {:?}", written, file);
+    Ok(written)
+}
+
+/// Resumen del universo cargado: pools por DEX y fee tier, TVL total, cobertura de oráculos
+/// y distribución de rutas. Ayuda a detectar configuraciones erróneas de un vistazo.
+pub fn log_universe_summary(pools: &[Pool], paths: &[ArbPath], oracle_map: &OracleMap, level: Level) {
+    let mut by_dex: BTreeMap<String, usize> = BTreeMap::new();
+    let mut by_fee: BTreeMap<u32, usize> = BTreeMap::new();
+    let mut tokens = HashSet::new();
+    for pool in pools {
+        *by_dex.entry(format!("{:?}", pool.version)).or_default() += 1;
+        *by_fee.entry(pool.fee).or_default() += 1;
+        tokens.insert(pool.token0);
+        tokens.insert(pool.token1);
+    }
+    let metrics = POOL_METRICS.lock().unwrap();
+    let raw_tvl: f64 = pools.iter().filter_map(|p| metrics.get(&p.address)).map(|m| m.raw_tvl_usd).sum();
+    let effective_tvl: f64 = pools.iter().map(|p| p.tvl_usd).sum();
+    drop(metrics);
+    let with_feeds = tokens.iter().filter(|t| crate::feeds::has_feed(oracle_map, t)).count();
+
+    let mut by_input: HashMap<H160, usize> = HashMap::new();
+    let mut by_intermediate: HashMap<H160, usize> = HashMap::new();
+    for path in paths {
+        *by_input.entry(path.token_a).or_default() += 1;
+        *by_intermediate.entry(path.token_b).or_default() += 1;
+        *by_intermediate.entry(path.token_c).or_default() += 1;
+    }
+    let mut top_intermediate: Vec<(H160, usize)> = by_intermediate.into_iter().collect();
+    top_intermediate.sort_unstable_by_key(|&(_, count)| std::cmp::Reverse(count));
+
+    log!(level, "=== Resumen del universo ===");
+    log!(level, "Pools: {} | por DEX: {:?} | por fee tier: {:?}", pools.len(), by_dex, by_fee);
+    log!(level, "TVL total: ${:.0} (bruto) / ${:.0} (efectivo)", raw_tvl, effective_tvl);
+    log!(level, "Tokens únicos: {} | con feed de oráculo: {}", tokens.len(), with_feeds);
+    for (token, count) in &by_input {
+        log!(level, "Rutas con entrada {}: {}", token_label(*token), count);
+    }
+    for (token, count) in top_intermediate.iter().take(10) {
+        log!(level, "  Intermedio {}: {} rutas", token_label(*token), count);
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::instance::with_test_config;
+    use crate::testing::{MockChain, QUOTE_MULTI, QUOTE_SINGLE};
+
+    pub(crate) fn addr(n: u64) -> H160 {
+        H160::from_low_u64_be(n)
+    }
+
+    pub(crate) fn pool(address: u64, token0: u64, token1: u64, tvl_usd: f64) -> Pool {
+        Pool {
+            address: addr(address),
+            token0: addr(token0),
+            token1: addr(token1),
+            decimals0: 18,
+            decimals1: 18,
+            fee: 500,
+            version: DexVariant::UniswapV3,
+            tvl_usd,
+        }
+    }
+
+    /// Ruta `tokens[0] -> tokens[1] -> tokens[2] -> tokens[0]` con un pool de Uniswap V3 por salto.
+    pub(crate) fn path(tokens: [u64; 3]) -> ArbPath {
+        let [a, b, c] = tokens;
+        ArbPath {
+            pool_1: pool(100 * a + b, a, b, 1e6),
+            pool_2: pool(100 * b + c, b, c, 1e6),
+            pool_3: pool(100 * c + a, c, a, 1e6),
+            token_a: addr(a),
+            token_b: addr(b),
+            token_c: addr(c),
+            score: 0.0,
+        }
+    }
+
+    /// Ruta 1 -> 2 -> 3 -> 1 con el segundo salto en SushiSwap: se cotiza salto a salto.
+    fn mixed_dex_path() -> ArbPath {
+        let mut route = path([1, 2, 3]);
+        route.pool_2.version = DexVariant::SushiV3;
+        route
+    }
+
+    #[tokio::test]
+    async fn simulate_v3_path_chains_the_three_hops() {
+        let unit = U256::exp10(18);
+        let chain = MockChain::new()
+            .quote_single(DexVariant::UniswapV3, addr(1), addr(2), 500, unit, unit * 2)
+            .quote_single(DexVariant::SushiV3, addr(2), addr(3), 500, unit * 2, unit * 3)
+            .quote_single(DexVariant::UniswapV3, addr(3), addr(1), 500, unit * 3, unit + 7);
+
+        let out = with_test_config(|_| {}, mixed_dex_path().simulate_v3_path(chain.provider(), unit)).await;
+        assert_eq!(out, Some(unit + 7));
+        assert_eq!(chain.calls_to(QUOTE_SINGLE), 3);
+    }
+
+    #[tokio::test]
+    async fn simulate_v3_path_uses_one_multi_hop_quote_within_a_dex() {
+        let unit = U256::exp10(18);
+        let route = path([1, 2, 3]);
+        let chain = MockChain::new().quote_multi(DexVariant::UniswapV3, route.encoded_v3_path(), unit, unit * 2);
+
+        let out = with_test_config(|_| {}, route.simulate_v3_path(chain.provider(), unit)).await;
+        assert_eq!(out, Some(unit * 2));
+        assert_eq!((chain.calls_to(QUOTE_MULTI), chain.calls_to(QUOTE_SINGLE)), (1, 0));
+    }
+
+    #[tokio::test]
+    async fn simulate_v3_path_stops_at_a_failing_hop() {
+        let unit = U256::exp10(18);
+        let chain = MockChain::new()
+            .quote_single(DexVariant::UniswapV3, addr(1), addr(2), 500, unit, unit * 2)
+            .quote_single_reverts(DexVariant::SushiV3, addr(2), addr(3), 500, unit * 2)
+            .quote_single(DexVariant::UniswapV3, addr(3), addr(1), 500, unit * 3, unit * 2);
+
+        let out = with_test_config(|_| {}, mixed_dex_path().simulate_v3_path(chain.provider(), unit)).await;
+        assert_eq!(out, None);
+        // El tercer salto no llega a cotizarse.
+        assert_eq!(chain.calls_to(QUOTE_SINGLE), 2);
+    }
+
+    #[tokio::test]
+    async fn get_spot_price_quotes_spot_quote_usd_worth_of_token_a() {
+        let unit = U256::exp10(18);
+        // $1.000 a $2.000/token: se cotiza medio token.
+        let half = unit / 2;
+        let chain = MockChain::new()
+            .quote_single(DexVariant::UniswapV3, addr(1), addr(2), 500, half, half)
+            .quote_single(DexVariant::SushiV3, addr(2), addr(3), 500, half, half)
+            .quote_single(DexVariant::UniswapV3, addr(3), addr(1), 500, half, half * 101 / 100)
+            .quote_single(DexVariant::UniswapV3, addr(1), addr(2), 500, unit, unit)
+            .quote_single(DexVariant::SushiV3, addr(2), addr(3), 500, unit, unit)
+            .quote_single(DexVariant::UniswapV3, addr(3), addr(1), 500, unit, unit * 99 / 100);
+        let route = mixed_dex_path();
+
+        let (priced, unpriced, failed) = with_test_config(|c| c.spot_quote_usd = 1_000.0, async {
+            (
+                route.get_spot_price(chain.provider(), Some(2_000.0)).await.unwrap(),
+                // Sin precio del token se cotiza una unidad entera.
+                route.get_spot_price(chain.provider(), None).await.unwrap(),
+                route.get_spot_price(MockChain::new().provider(), Some(2_000.0)).await.unwrap(),
+            )
+        })
+        .await;
+        assert!((priced - 1.01).abs() < 1e-12);
+        assert!((unpriced - 0.99).abs() < 1e-12);
+        assert_eq!(failed, 0.0);
+    }
+
+    #[tokio::test]
+    async fn spot_quote_amount_is_a_marginal_trade_for_any_token_value() {
+        let mut wbtc_route = path([1, 2, 3]);
+        wbtc_route.pool_1.decimals0 = 8;
+        let cheap_route = path([1, 2, 3]);
+        with_test_config(|c| c.spot_quote_usd = 1.0, async {
+            // $1 de WBTC a $60.000: 1.666 satoshis en vez de un BTC entero.
+            assert_eq!(wbtc_route.spot_quote_amount(Some(60_000.0)), U256::from(1_666));
+            // $1 de un token de $0,0001: 10.000 tokens en vez de uno.
+            assert_eq!(cheap_route.spot_quote_amount(Some(0.0001)), U256::exp10(22));
+            assert_eq!(wbtc_route.spot_quote_amount(None), U256::exp10(8));
+            // Precio tan alto que $1 no llega a una unidad base: un token entero.
+            assert_eq!(wbtc_route.spot_quote_amount(Some(1e12)), U256::exp10(8));
+        })
+        .await;
+    }
+
+    #[tokio::test]
+    async fn hop_within_impact_drops_thin_pools_only() {
+        let (thin, deep) = (pool(12, 1, 2, 1e6), pool(13, 1, 3, 1e6));
+        let metrics_for = |pool: &Pool, liquidity: u128| PoolMetrics {
+            raw: crate::multi::RawPoolData {
+                token0: pool.token0,
+                token1: pool.token1,
+                decimals0: 18,
+                decimals1: 18,
+                liquidity,
+                // Precio 1:1 entre dos tokens de $1.
+                sqrt_price_x96: U256::from(2).pow(96.into()),
+                ..Default::default()
+            },
+            raw_tvl_usd: 0.0,
+            effective_tvl_usd: 0.0,
+            price0_usd: 1.0,
+            price1_usd: 1.0,
+        };
+        let metrics = HashMap::from([
+            (thin.address, metrics_for(&thin, 10u128.pow(22))),
+            (deep.address, metrics_for(&deep, 10u128.pow(26))),
+        ]);
+
+        with_test_config(
+            |c| {
+                c.max_price_impact_bps = 50;
+                c.price_impact_reference_usd = 10_000.0;
+            },
+            async {
+                // $10k contra 10k unidades de liquidez mueven el precio un 75%; contra 100M, ~2 bps.
+                assert!(!hop_within_impact(&metrics, &thin, addr(1)));
+                assert!(!hop_within_impact(&metrics, &thin, addr(2)));
+                assert!(hop_within_impact(&metrics, &deep, addr(1)));
+                assert!(hop_within_impact(&metrics, &deep, addr(3)));
+                // Sin datos en vivo no se filtra.
+                assert!(hop_within_impact(&metrics, &pool(14, 1, 4, 1e6), addr(1)));
+            },
+        )
+        .await;
+        with_test_config(|c| c.max_price_impact_bps = 0, async {
+            assert!(hop_within_impact(&metrics, &thin, addr(1)));
+        })
+        .await;
+    }
+
+    fn addresses(pools: &[&Pool]) -> Vec<H160> {
+        pools.iter().map(|p| p.address).collect()
+    }
+
+    #[test]
+    fn select_with_rotation_keeps_top_and_cycles_the_rest() {
+        let pools: Vec<Pool> = (1..=6).map(|i| pool(i, 1, 2, 1e6 / i as f64)).collect();
+        let list: Vec<&Pool> = pools.iter().collect();
+
+        let (selected, rotated) = select_with_rotation(&list, 4, 2, 0);
+        assert_eq!(addresses(&selected), vec![addr(1), addr(2), addr(3), addr(4)]);
+        assert_eq!(rotated, vec![addr(3), addr(4)]);
+
+        let (selected, rotated) = select_with_rotation(&list, 4, 2, 1);
+        assert_eq!(addresses(&selected), vec![addr(1), addr(2), addr(5), addr(6)]);
+        assert_eq!(rotated, vec![addr(5), addr(6)]);
+
+        // La rotación da la vuelta al resto de la lista.
+        let (_, rotated) = select_with_rotation(&list, 4, 2, 2);
+        assert_eq!(rotated, vec![addr(3), addr(4)]);
+    }
+
+    #[test]
+    fn select_with_rotation_without_overflow_or_slots_takes_the_top() {
+        let pools: Vec<Pool> = (1..=3).map(|i| pool(i, 1, 2, 1e6)).collect();
+        let list: Vec<&Pool> = pools.iter().collect();
+
+        let (selected, rotated) = select_with_rotation(&list, 5, 2, 7);
+        assert_eq!(selected.len(), 3);
+        assert!(rotated.is_empty());
+
+        let (selected, rotated) = select_with_rotation(&list, 2, 0, 7);
+        assert_eq!(addresses(&selected), vec![addr(1), addr(2)]);
+        assert!(rotated.is_empty());
+    }
+
+    #[tokio::test]
+    async fn path_generation_returns_complete_paths_when_the_budget_runs_out() {
+        use crate::config::OracleFeed;
+
+        // Universo denso: un pool por cada par de 80 tokens.
+        let tokens = 1..=80u64;
+        let pools: Vec<Pool> = tokens.clone()
+            .flat_map(|a| (a + 1..=80).map(move |b| pool(1_000 * a + b, a, b, 1e6)))
+            .collect();
+        let feeds: HashMap<H160, OracleFeed> = tokens.map(|t| (addr(t), OracleFeed::Chainlink(addr(t)))).collect();
+        let oracle_map = OracleMap::new();
+        let generate = |budget_ms| {
+            let feeds = feeds.clone();
+            with_test_config(
+                move |c| {
+                    c.oracle_feeds = feeds;
+                    c.max_pools_per_token = 1_000;
+                    c.pool_rotation_slots = 0;
+                    c.path_generation_budget_ms = budget_ms;
+                },
+                async { generate_triangular_paths(&pools, addr(1), &oracle_map) },
+            )
+        };
+
+        let started = Instant::now();
+        let full = generate(0).await;
+        assert_eq!(full.len(), 79 * 78);
+        // Un presupuesto de la mitad de lo que tarda la generación completa la corta a medio camino.
+        let partial = generate((started.elapsed().as_millis() as u64 / 2).max(1)).await;
+        assert!(!partial.is_empty() && partial.len() < full.len(), "{} de {}", partial.len(), full.len());
+        for route in &partial {
+            let hops = [(&route.pool_1, route.token_a, route.token_b), (&route.pool_2, route.token_b, route.token_c), (&route.pool_3, route.token_c, route.token_a)];
+            for (hop, from, to) in hops {
+                assert!((hop.token0, hop.token1) == (from, to) || (hop.token0, hop.token1) == (to, from));
+            }
+        }
+    }
+
+
+    #[tokio::test]
+    async fn pending_simulation_quotes_against_the_pending_block() {
+        use crate::provider::{detect_pending_support, simulation_block};
+
+        let unit = U256::exp10(18);
+        let route = path([1, 2, 3]);
+        // Un swap pendiente empeora la cotización del bloque siguiente.
+        let chain = MockChain::new()
+            .quote_multi(DexVariant::UniswapV3, route.encoded_v3_path(), unit, unit * 2)
+            .quote_multi_pending(DexVariant::UniswapV3, route.encoded_v3_path(), unit, unit * 3 / 2);
+        let quote = |simulate_pending: bool, chain: MockChain| {
+            let route = route.clone();
+            with_test_config(move |c| c.simulate_pending = simulate_pending, async move {
+                let supported = detect_pending_support(chain.provider().as_ref()).await;
+                (supported, route.simulate_v3_path_at(chain.provider(), unit, simulation_block()).await)
+            })
+        };
+        assert_eq!(quote(true, chain.clone()).await, (true, Some(unit * 3 / 2)));
+        assert_eq!(quote(false, chain).await, (true, Some(unit * 2)));
+
+        // Un nodo sin bloque `pending` sigue cotizando contra el último confirmado.
+        let latest_only = MockChain::new().quote_multi(DexVariant::UniswapV3, route.encoded_v3_path(), unit, unit * 2);
+        assert_eq!(quote(true, latest_only).await, (false, Some(unit * 2)));
+    }
+
+
+    #[tokio::test]
+    async fn multi_hop_quote_matches_the_three_single_quotes() {
+        let unit = U256::exp10(18);
+        let route = path([1, 2, 3]);
+        let chain = MockChain::new()
+            .quote_single(DexVariant::UniswapV3, addr(1), addr(2), 500, unit, unit * 2)
+            .quote_single(DexVariant::UniswapV3, addr(2), addr(3), 500, unit * 2, unit * 3)
+            .quote_single(DexVariant::UniswapV3, addr(3), addr(1), 500, unit * 3, unit * 11 / 10)
+            .quote_multi(DexVariant::UniswapV3, route.encoded_v3_path(), unit, unit * 11 / 10);
+
+        let (multi, singles) = with_test_config(|_| {}, async {
+            (route.simulate_v3_path(chain.provider(), unit).await, route.simulate_hop_by_hop(chain.provider(), unit, None).await)
+        })
+        .await;
+        assert_eq!(multi, Some(unit * 11 / 10));
+        assert_eq!(multi, singles);
+        assert_eq!((chain.calls_to(QUOTE_MULTI), chain.calls_to(QUOTE_SINGLE)), (1, 3));
+    }
+
+    #[tokio::test]
+    async fn failed_multi_hop_quote_does_not_fall_back_to_single_quotes() {
+        let unit = U256::exp10(18);
+        // Sólo responden las cotizaciones individuales: el quoter multi-hop revierte.
+        let chain = MockChain::new()
+            .quote_single(DexVariant::UniswapV3, addr(1), addr(2), 500, unit, unit * 2)
+            .quote_single(DexVariant::UniswapV3, addr(2), addr(3), 500, unit * 2, unit * 3)
+            .quote_single(DexVariant::UniswapV3, addr(3), addr(1), 500, unit * 3, unit * 2);
+
+        let out = with_test_config(|_| {}, path([1, 2, 3]).simulate_v3_path(chain.provider(), unit)).await;
+        assert_eq!(out, None);
+        assert_eq!((chain.calls_to(QUOTE_MULTI), chain.calls_to(QUOTE_SINGLE)), (1, 0));
+    }
+
+
+    #[tokio::test]
+    async fn flagged_approval_tokens_follow_the_configured_policy() {
+        use crate::config::ApprovalTokenPolicy;
+        use ethers::abi::Token;
+
+        // El token 2 (USDT-style) está marcado; sólo la primera ruta pasa por él.
+        let surviving = |policy: ApprovalTokenPolicy, contract_resets: Option<bool>| async move {
+            with_test_config(
+                |c| {
+                    c.non_standard_approval_tokens = vec![addr(2)];
+                    c.approval_token_policy = policy;
+                },
+                async move {
+                    let mut chain = MockChain::new();
+                    if let Some(resets) = contract_resets {
+                        chain = chain.returns(CONFIG.contract_address, "supportsApprovalReset()", &[], &[Token::Bool(resets)]);
+                    }
+                    crate::execution::verify_approval_reset_support(chain.provider()).await;
+                    let mut paths = vec![path([1, 2, 3]), path([1, 4, 5])];
+                    apply_approval_token_policy(&mut paths);
+                    paths.iter().map(|p| p.token_b).collect::<Vec<_>>()
+                },
+            )
+            .await
+        };
+
+        assert_eq!(surviving(ApprovalTokenPolicy::Exclude, Some(true)).await, vec![addr(4)]);
+        // `deprioritize` las mantiene: la penalización se aplica al score.
+        assert_eq!(surviving(ApprovalTokenPolicy::Deprioritize, None).await, vec![addr(2), addr(4)]);
+        assert_eq!(surviving(ApprovalTokenPolicy::ContractReset, Some(true)).await, vec![addr(2), addr(4)]);
+        // Sin confirmación del contrato (o sin el getter) se excluyen.
+        assert_eq!(surviving(ApprovalTokenPolicy::ContractReset, Some(false)).await, vec![addr(4)]);
+        assert_eq!(surviving(ApprovalTokenPolicy::ContractReset, None).await, vec![addr(4)]);
+    }
+
+    #[test]
+    fn cycle_direction_ignores_the_starting_token() {
+        let forward = path([1, 2, 3]);
+        let rotated = path([2, 3, 1]);
+        let reverse = path([1, 3, 2]);
+
+        assert_eq!(forward.cycle_direction(), ([addr(1), addr(2), addr(3)], true));
+        assert_eq!(rotated.cycle_direction(), forward.cycle_direction());
+        assert_eq!(reverse.cycle_direction(), ([addr(1), addr(2), addr(3)], false));
+    }
+
+    #[test]
+    fn is_reverse_of_matches_only_the_same_cycle_in_the_other_direction() {
+        let forward = path([1, 2, 3]);
+
+        assert!(forward.is_reverse_of(&path([1, 3, 2])));
+        assert!(forward.is_reverse_of(&path([3, 2, 1])));
+        assert!(!forward.is_reverse_of(&path([2, 3, 1])));
+        assert!(!forward.is_reverse_of(&path([1, 2, 4])));
+    }
+
+
+    #[tokio::test]
+    async fn pools_with_a_locked_slot0_are_skipped() {
+        use crate::config::OracleFeed;
+
+        let pools = vec![pool(12, 1, 2, 1e6), pool(23, 2, 3, 1e6), pool(13, 1, 3, 1e6)];
+        let feeds: HashMap<H160, OracleFeed> = (1..=3).map(|t| (addr(t), OracleFeed::Chainlink(addr(t)))).collect();
+        let oracle_map = OracleMap::new();
+        let (pools, oracle_map) = (&pools, &oracle_map);
+        let generate = |locked: Option<H160>| {
+            let feeds = feeds.clone();
+            with_test_config(move |c| c.oracle_feeds = feeds, async move {
+                if let Some(locked) = locked {
+                    let raw = crate::multi::RawPoolData { unlocked: false, ..Default::default() };
+                    POOL_METRICS.lock().unwrap().insert(locked, PoolMetrics { raw, ..Default::default() });
+                }
+                generate_triangular_paths(pools, addr(1), oracle_map).len()
+            })
+        };
+
+        // Las dos direcciones del ciclo 1-2-3, hasta que el pool 2-3 reporta `unlocked == false`.
+        assert_eq!(generate(None).await, 2);
+        assert_eq!(generate(Some(addr(23))).await, 0);
+    }
+
+
+    #[tokio::test]
+    async fn token_in_neighbors_without_feeds_are_diagnosed() {
+        use crate::config::OracleFeed;
+        use crate::pools::PriceMap;
+
+        // Triángulo 1-2-3 con feed sólo para token_in: ninguna ruta puede salir de 1.
+        let pools = vec![pool(12, 1, 2, 1e6), pool(23, 2, 3, 1e6), pool(13, 1, 3, 1e6)];
+        let pool_refs: Vec<&Pool> = pools.iter().collect();
+        let oracle_map = OracleMap::new();
+        let (pools, pool_refs, oracle_map) = (&pools, &pool_refs, &oracle_map);
+        let diagnose = |feeds: Vec<u64>, allow_derived: bool| {
+            let feeds: HashMap<H160, OracleFeed> = feeds.into_iter().map(|t| (addr(t), OracleFeed::Chainlink(addr(t)))).collect();
+            with_test_config(
+                move |c| {
+                    c.oracle_feeds = feeds;
+                    c.allow_derived_intermediate_prices = allow_derived;
+                },
+                async move {
+                    let mut price_map = PriceMap::default();
+                    price_map.insert(addr(3), 2.0, PriceSource::DerivedFromPool { hops: 1 }, 0);
+                    crate::pools::tests::set_price_map(price_map);
+                    (generate_triangular_paths(pools, addr(1), oracle_map).len(), unpriced_neighbors(pool_refs, addr(1), oracle_map))
+                },
+            )
+        };
+
+        assert_eq!(diagnose(vec![1], false).await, (0, (2, vec![addr(2), addr(3)])));
+        // Con feed para 2, sólo 3 queda sin precio.
+        assert_eq!(diagnose(vec![1, 2], false).await.1, (2, vec![addr(3)]));
+        // Con precios derivados permitidos, el precio derivado de 3 cuenta como intermedio.
+        assert_eq!(diagnose(vec![1, 2], true).await, (2, (2, vec![])));
+    }
+}
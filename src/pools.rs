@@ -1,22 +1,25 @@
 use crate::{
-    config::CONFIG,
-    constants::USDC_ADDRESS,
-    multi::batch_get_pool_data,
+    config::{PriceAnchor, CONFIG},
+    constants::{self, USDC_ADDRESS},
+    instance::InstanceLocal,
+    math::to_usd,
+    multi::{self, batch_get_pool_data, RawPoolData, StaticPoolData},
     oracle::OracleMap,
     types::{DexVariant, Pool},
 };
 use anyhow::{anyhow, Result};
-use ethers::{prelude::*, types::H160};
-use log::{info, warn};
-use rust_decimal::{prelude::FromPrimitive, prelude::ToPrimitive, Decimal};
-use serde::Deserialize;
+use ethers::{prelude::*, types::{H160, U256}};
+use log::{debug, info, warn};
+use serde::{Deserialize, Serialize};
 use std::{
-    collections::{HashMap, HashSet},
+    collections::{BTreeMap, HashMap, HashSet},
     fs::{self, File},
     path::PathBuf,
-    sync::Arc,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
     time::SystemTime,
-    str::FromStr,
 };
 
 #[derive(Deserialize, Debug)]
@@ -29,11 +32,251 @@ struct GraphToken { id: H160, decimals: String }
 #[derive(Deserialize, Debug)]
 struct GraphResponse { data: Option<GraphData> }
 
-/// Carga los pools directamente desde el archivo de caché y los enriquece con datos en tiempo real.
-pub async fn load_all_pools_v3(
-    provider: Arc<Provider<Ws>>,
-    oracle_map: &Arc<OracleMap>,
-) -> Result<Vec<Pool>> {
+// Movimiento de precio usado para medir la profundidad efectiva (liquidez en rango).
+const EFFECTIVE_DEPTH_PRICE_MOVE: f64 = 0.01;
+
+/// Fee tiers válidos (en centésimas de bps) para cada DEX soportado.
+pub fn known_fee_tiers(variant: DexVariant) -> &'static [u32] {
+    match variant {
+        DexVariant::UniswapV3 | DexVariant::SushiV3 => &[100, 500, 3000, 10000],
+        DexVariant::PancakeV3 => &[100, 500, 2500, 10000],
+    }
+}
+
+/// Métricas por pool calculadas durante el enriquecimiento.
+/// `Pool` se deserializa del CSV, así que los datos en vivo se guardan aparte, indexados por dirección.
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
+pub struct PoolMetrics {
+    pub raw: RawPoolData,
+    /// TVL a partir de `balanceOf` (incluye posiciones fuera de rango).
+    pub raw_tvl_usd: f64,
+    /// Valor intercambiable dentro de un movimiento de precio de ±1% con la liquidez activa.
+    pub effective_tvl_usd: f64,
+    pub price0_usd: f64,
+    pub price1_usd: f64,
+}
+
+/// Pool de un token vigilado (`WATCH_TOKENS`) descubierto por eventos, aún fuera de la caché CSV.
+#[derive(Debug, Clone, Copy)]
+pub struct WatchedPool {
+    pub token0: H160,
+    pub token1: H160,
+    pub fee: u32,
+    pub version: DexVariant,
+}
+
+// Pools de tokens vigilados que se añaden al universo en cada refresco.
+static WATCHED_POOLS: InstanceLocal<Mutex<HashMap<H160, WatchedPool>>> = InstanceLocal::new(|| Mutex::new(HashMap::new()));
+// Bloque de creación por pool; `None` si no se pudo determinar.
+static POOL_CREATION_BLOCKS: InstanceLocal<Mutex<HashMap<H160, Option<u64>>>> = InstanceLocal::new(|| Mutex::new(HashMap::new()));
+// Último swap grande visto por pool: (bloque, tamaño en USD, sqrtPriceX96 tras el swap).
+type LargeSwap = (u64, f64, U256);
+static RECENT_LARGE_SWAPS: InstanceLocal<Mutex<HashMap<H160, LargeSwap>>> = InstanceLocal::new(|| Mutex::new(HashMap::new()));
+// Último `Swap` por pool: (bloque, sqrtPriceX96 tras el swap). Estabilidad tras un fallo y precio actual.
+static LAST_SWAPS: InstanceLocal<Mutex<HashMap<H160, (u64, U256)>>> = InstanceLocal::new(|| Mutex::new(HashMap::new()));
+// Desde qué bloque el precio del pool se mantiene dentro de `cooldown_settle_tolerance_bps`: (bloque, sqrtPriceX96 de referencia).
+static SETTLE_ANCHORS: InstanceLocal<Mutex<HashMap<H160, (u64, U256)>>> = InstanceLocal::new(|| Mutex::new(HashMap::new()));
+// Bloque del último trade propio ejecutado con éxito a través de cada pool.
+static OWN_TRADED_POOLS: InstanceLocal<Mutex<HashMap<H160, u64>>> = InstanceLocal::new(|| Mutex::new(HashMap::new()));
+// Pools vigilados que ya superaron el filtro de TVL (se alerta una sola vez).
+static ADMITTED_WATCHED_POOLS: InstanceLocal<Mutex<HashSet<H160>>> = InstanceLocal::new(|| Mutex::new(HashSet::new()));
+pub static POOL_METRICS: InstanceLocal<Mutex<HashMap<H160, PoolMetrics>>> = InstanceLocal::new(|| Mutex::new(HashMap::new()));
+// Mapa de precios del último refresco: el siguiente sólo re-consulta las anclas y re-deriva el resto.
+static PRICE_MAP: InstanceLocal<Mutex<PriceMap>> = InstanceLocal::new(|| Mutex::new(PriceMap::default()));
+
+pub fn pool_metrics(address: &H160) -> Option<PoolMetrics> {
+    POOL_METRICS.lock().unwrap().get(address).cloned()
+}
+
+/// Convierte `sqrt_price_x96` a un `sqrt(P)` en unidades base (token1 por token0).
+pub fn sqrt_price_f64(sqrt_price_x96: U256) -> f64 {
+    sqrt_price_x96.to_string().parse::<f64>().unwrap_or(0.0) / 2f64.powi(96)
+}
+
+/// Calcula la profundidad en USD de la liquidez activa alrededor del tick actual:
+/// token0 que sale si el precio sube un 1% más token1 que sale si baja un 1%.
+pub fn effective_tvl_usd(data: &RawPoolData, price0: f64, price1: f64) -> f64 {
+    let sqrt_p = sqrt_price_f64(data.sqrt_price_x96);
+    if sqrt_p <= 0.0 || data.liquidity == 0 { return 0.0; }
+    let liquidity = data.liquidity as f64;
+    let sqrt_p_up = sqrt_p * (1.0 + EFFECTIVE_DEPTH_PRICE_MOVE).sqrt();
+    let sqrt_p_down = sqrt_p * (1.0 - EFFECTIVE_DEPTH_PRICE_MOVE).sqrt();
+    let amount0 = liquidity * (1.0 / sqrt_p - 1.0 / sqrt_p_up) / 10f64.powi(data.decimals0 as i32);
+    let amount1 = liquidity * (sqrt_p - sqrt_p_down) / 10f64.powi(data.decimals1 as i32);
+    amount0 * price0 + amount1 * price1
+}
+
+/// TVL en USD a partir de las reservas virtuales de V3 (`L / sqrt(P)` y `L * sqrt(P)`), para pools con
+/// tokens cuyo `balanceOf(pool)` no refleja la reserva intercambiable (rebasing, proxies de transferencia).
+pub fn virtual_reserves_tvl_usd(data: &RawPoolData, price0: f64, price1: f64) -> f64 {
+    let sqrt_p = sqrt_price_f64(data.sqrt_price_x96);
+    if sqrt_p <= 0.0 || data.liquidity == 0 { return 0.0; }
+    let liquidity = data.liquidity as f64;
+    let amount0 = liquidity / sqrt_p / 10f64.powi(data.decimals0 as i32);
+    let amount1 = liquidity * sqrt_p / 10f64.powi(data.decimals1 as i32);
+    amount0 * price0 + amount1 * price1
+}
+
+/// `true` si alguno de los tokens del pool está marcado en `UNTRUSTED_BALANCE_TOKENS`.
+fn has_untrusted_balance(data: &RawPoolData) -> bool {
+    CONFIG.untrusted_balance_tokens.contains(&data.token0) || CONFIG.untrusted_balance_tokens.contains(&data.token1)
+}
+
+/// TVL bruto en USD: balances del pool, o reservas virtuales si algún token no tiene un `balanceOf` fiable.
+pub fn raw_tvl_usd(data: &RawPoolData, price0: f64, price1: f64) -> f64 {
+    if has_untrusted_balance(data) {
+        return virtual_reserves_tvl_usd(data, price0, price1);
+    }
+    to_usd(data.balance0, data.token0, price0, data.decimals0) + to_usd(data.balance1, data.token1, price1, data.decimals1)
+}
+
+/// Valor en USD del token de salida que un salto puede entregar: el balance del pool en ese token
+/// (o su reserva virtual si el `balanceOf` no es fiable). Ningún input puede sacar más que esto.
+pub fn hop_output_capacity_usd(metrics: &PoolMetrics, token_out: H160) -> f64 {
+    let data = &metrics.raw;
+    let (is_token0, balance, decimals, price) = if token_out == data.token0 {
+        (true, data.balance0, data.decimals0, metrics.price0_usd)
+    } else {
+        (false, data.balance1, data.decimals1, metrics.price1_usd)
+    };
+    if price <= 0.0 { return 0.0; }
+    if !CONFIG.untrusted_balance_tokens.contains(&token_out) {
+        return to_usd(balance, token_out, price, decimals);
+    }
+    let sqrt_p = sqrt_price_f64(data.sqrt_price_x96);
+    if sqrt_p <= 0.0 { return 0.0; }
+    let liquidity = data.liquidity as f64;
+    let amount = if is_token0 { liquidity / sqrt_p } else { liquidity * sqrt_p };
+    amount / 10f64.powi(decimals as i32) * price
+}
+
+/// Estima el impacto en precio (bps) de vender `amount_usd` de un token en el pool,
+/// asumiendo que la operación no cruza ticks (liquidez activa constante).
+pub fn estimate_price_impact_bps(metrics: &PoolMetrics, token_in: H160, amount_usd: f64) -> f64 {
+    let sqrt_p = sqrt_price_f64(metrics.raw.sqrt_price_x96);
+    let liquidity = metrics.raw.liquidity as f64;
+    if sqrt_p <= 0.0 || liquidity <= 0.0 { return f64::INFINITY; }
+    if token_in == metrics.raw.token0 {
+        if metrics.price0_usd <= 0.0 { return f64::INFINITY; }
+        let amount0 = amount_usd / metrics.price0_usd * 10f64.powi(metrics.raw.decimals0 as i32);
+        let new_sqrt_p = liquidity * sqrt_p / (liquidity + amount0 * sqrt_p);
+        (1.0 - (new_sqrt_p / sqrt_p).powi(2)) * 10_000.0
+    } else {
+        if metrics.price1_usd <= 0.0 { return f64::INFINITY; }
+        let amount1 = amount_usd / metrics.price1_usd * 10f64.powi(metrics.raw.decimals1 as i32);
+        let new_sqrt_p = sqrt_p + amount1 / liquidity;
+        ((new_sqrt_p / sqrt_p).powi(2) - 1.0) * 10_000.0
+    }
+}
+
+/// Origen del precio de un token en el mapa de precios.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PriceSource {
+    /// Ancla con precio de oráculo.
+    Oracle,
+    /// Ancla con precio fijo (`SETTLE_ANCHORS`).
+    Peg,
+    /// Derivado del precio de un pool a `hops` saltos de un ancla.
+    DerivedFromPool { hops: u32 },
+}
+
+/// Precio USD de un token en el mapa de precios (y su snapshot), con su origen y el instante en que se obtuvo.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+pub struct PriceSnapshotEntry {
+    pub price: f64,
+    pub timestamp: u64,
+    /// `None` en snapshots anteriores a registrar el origen.
+    #[serde(default)]
+    pub source: Option<PriceSource>,
+}
+
+/// Mapa de precios USD por token del último refresco, con el origen de cada precio.
+#[derive(Debug, Clone, Default)]
+pub struct PriceMap {
+    entries: HashMap<H160, PriceSnapshotEntry>,
+}
+
+impl PriceMap {
+    pub fn price(&self, token: &H160) -> Option<f64> {
+        self.entries.get(token).map(|e| e.price)
+    }
+
+    pub fn entry(&self, token: &H160) -> Option<PriceSnapshotEntry> {
+        self.entries.get(token).copied()
+    }
+
+    pub fn contains(&self, token: &H160) -> bool {
+        self.entries.contains_key(token)
+    }
+
+    pub fn insert(&mut self, token: H160, price: f64, source: PriceSource, timestamp: u64) {
+        self.entries.insert(token, PriceSnapshotEntry { price, timestamp, source: Some(source) });
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+}
+
+/// Origen, precio y antigüedad del precio de `token` en el mapa del último refresco.
+pub fn price_source(token: &H160) -> Option<PriceSnapshotEntry> {
+    PRICE_MAP.lock().unwrap().entry(token)
+}
+
+pub(crate) fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+/// Descarta las entradas del snapshot con más de `max_age_secs` de antigüedad respecto a `now`.
+pub fn fresh_snapshot_entries(
+    snapshot: HashMap<H160, PriceSnapshotEntry>,
+    max_age_secs: u64,
+    now: u64,
+) -> HashMap<H160, PriceSnapshotEntry> {
+    snapshot
+        .into_iter()
+        .filter(|(_, e)| e.price > 0.0 && now.saturating_sub(e.timestamp) <= max_age_secs)
+        .collect()
+}
+
+/// Snapshot de arranque en caliente: el mapa de precios derivado y los atributos inmutables de los pools,
+/// para que un reinicio sólo pida los datos dinámicos de cada pool.
+#[derive(Serialize, Deserialize, Debug, Default)]
+struct WarmStartSnapshot {
+    prices: HashMap<H160, PriceSnapshotEntry>,
+    #[serde(default)]
+    pools: HashMap<H160, StaticPoolData>,
+}
+
+/// Lee el snapshot de arranque; acepta también el formato anterior (sólo el mapa de precios). Un archivo
+/// ausente o corrupto equivale a arrancar en frío.
+fn load_price_snapshot(path: &str) -> WarmStartSnapshot {
+    let Ok(contents) = fs::read_to_string(path) else { return WarmStartSnapshot::default() };
+    serde_json::from_str(&contents)
+        .or_else(|_| serde_json::from_str(&contents).map(|prices| WarmStartSnapshot { prices, pools: HashMap::new() }))
+        .unwrap_or_else(|e| {
+            warn!("Snapshot de precios {} ilegible ({}). Arranque en frío.", path, e);
+            WarmStartSnapshot::default()
+        })
+}
+
+fn save_price_snapshot(path: &str, snapshot: &WarmStartSnapshot) -> Result<()> {
+    if let Some(parent) = PathBuf::from(path).parent() {
+        fs::create_dir_all(parent)?;
+    }
+    fs::write(path, serde_json::to_string(snapshot)?)?;
+    Ok(())
+}
+
+/// Lee los pools pre-descubiertos del CSV de caché (sin datos en vivo).
+pub fn read_pool_cache() -> Result<Vec<Pool>> {
     let cache_path = PathBuf::from(&CONFIG.cache_path);
     info!(" Cargando mapa de pools pre-descubiertos desde {:?}...", cache_path);
 
@@ -41,11 +284,434 @@ pub async fn load_all_pools_v3(
         .map_err(|_| anyhow!("FATAL: No se encontró el archivo de caché 'cache/pools_v4.csv'. Por favor, créalo primero con el script de Python."))?;
 
     let mut rdr = csv::Reader::from_reader(file);
-    let mut pools: Vec<Pool> = rdr.deserialize().filter_map(Result::ok).collect();
+    let pools: Vec<Pool> = rdr.deserialize().filter_map(Result::ok).collect();
 
     if pools.is_empty() {
         return Err(anyhow!("FATAL: La caché de pools está vacía. El bot no puede operar."));
     }
+    Ok(pools)
+}
+
+// Ventana máxima de `PoolCreated` consultada al comprobar la caché (acota el coste de `eth_getLogs`).
+const CACHE_DRIFT_MAX_LOOKBACK_BLOCKS: u64 = 5_000_000;
+
+/// Pools creados on-chain desde la generación de la caché, relativos a los que contiene.
+pub fn cache_drift_ratio(cached_pools: usize, created_since: usize) -> f64 {
+    created_since as f64 / cached_pools.max(1) as f64
+}
+
+/// Comprobación de arranque: estima el bloque en que se generó la caché (por la fecha del archivo y el
+/// tiempo de bloque vigente), cuenta los `PoolCreated` de las factories desde entonces y avisa si superan
+/// `cache_drift_threshold` de los pools en caché. El rango se recorre en tramos del más reciente al más
+/// antiguo y se deja de contar en cuanto la deriva supera el umbral. Devuelve la deriva medida (`None` si
+/// no se pudo comprobar).
+pub async fn check_cache_drift<M: Middleware>(provider: &M, cached_pools: usize) -> Option<f64> {
+    if CONFIG.cache_drift_threshold <= 0.0 { return None; }
+    let age_secs = fs::metadata(&CONFIG.cache_path)
+        .and_then(|m| m.modified())
+        .ok()
+        .and_then(|modified| SystemTime::now().duration_since(modified).ok())?
+        .as_secs();
+    let latest = provider.get_block_number().await.ok()?.as_u64();
+    let blocks_since_cache = age_secs * 1000 / crate::execution::block_time_ms().max(1);
+    let mut capped = blocks_since_cache > CACHE_DRIFT_MAX_LOOKBACK_BLOCKS;
+    let from_block = latest.saturating_sub(blocks_since_cache.min(CACHE_DRIFT_MAX_LOOKBACK_BLOCKS));
+
+    let factories: Vec<H160> = CONFIG.dexes.iter().map(|dex| dex.factory).collect();
+    let filter = Filter::new().address(factories).event(POOL_CREATED_EVENT);
+    // Pools creados a partir de los cuales la deriva ya supera el umbral.
+    let enough = (CONFIG.cache_drift_threshold * cached_pools.max(1) as f64).floor() as usize + 1;
+    let (mut created, mut end) = (0, latest);
+    loop {
+        let start = end.saturating_sub(LOG_CHUNK_BLOCKS - 1).max(from_block);
+        match get_logs_chunked(provider, &filter, start, end).await {
+            Ok(logs) => created += logs.len(),
+            Err(e) => {
+                warn!("No se pudo comprobar la antigüedad de la caché de pools ({} días): {e}", age_secs / 86_400);
+                return None;
+            }
+        }
+        if start == from_block { break; }
+        if created >= enough {
+            capped = true;
+            break;
+        }
+        end = start - 1;
+    }
+    let drift = cache_drift_ratio(cached_pools, created);
+    if drift > CONFIG.cache_drift_threshold {
+        warn!(
+            " Caché de pools desactualizada: {}{} pools creados desde que se generó {} (hace {} días, ~bloque #{}) frente a {} en caché ({:.1}% > {:.1}%). Regenera la caché para no operar a ciegas sobre pools nuevos.",
+            if capped { "al menos " } else { "" }, created, CONFIG.cache_path, age_secs / 86_400, from_block,
+            cached_pools, drift * 100.0, CONFIG.cache_drift_threshold * 100.0
+        );
+    } else {
+        info!("Caché de pools al día: {} pools creados desde su generación ({:.1}% de {}).", created, drift * 100.0, cached_pools);
+    }
+    Some(drift)
+}
+
+/// Firma del evento `PoolCreated` de las factories V3.
+pub const POOL_CREATED_EVENT: &str = "PoolCreated(address,address,uint24,int24,address)";
+
+/// DEX de un pool según su factory; `None` si no es de ninguno de los DEX de la chain.
+pub fn variant_for_factory(factory: H160) -> Option<DexVariant> {
+    CONFIG.dexes.iter().find(|dex| dex.factory == factory).map(|dex| dex.variant)
+}
+
+/// Pool más profundo (TVL efectivo) entre `token` y `other` en el último refresco: su DEX, su fee y
+/// los decimales de `token`.
+pub fn deepest_pool_between(token: H160, other: H160) -> Option<(DexVariant, u32, u8)> {
+    let metrics = POOL_METRICS.lock().unwrap();
+    let (raw, variant) = metrics
+        .values()
+        .filter(|m| (m.raw.token0 == token && m.raw.token1 == other) || (m.raw.token0 == other && m.raw.token1 == token))
+        .filter_map(|m| Some((m, variant_for_factory(m.raw.factory)?)))
+        .max_by(|(a, _), (b, _)| a.effective_tvl_usd.total_cmp(&b.effective_tvl_usd))
+        .map(|(m, variant)| (m.raw, variant))?;
+    let decimals = if raw.token0 == token { raw.decimals0 } else { raw.decimals1 };
+    Some((variant, raw.fee, decimals))
+}
+
+// `PoolCreated` vistos en vivo desde el último refresco (señal de rotación del universo).
+static POOLS_CREATED_SINCE_REFRESH: InstanceLocal<AtomicU64> = InstanceLocal::new(|| AtomicU64::new(0));
+
+/// Anota el bloque de creación de un pool (p. ej. visto en un `PoolCreated` en vivo).
+pub fn record_pool_creation(address: H160, block_number: u64) {
+    POOL_CREATION_BLOCKS.lock().unwrap().insert(address, Some(block_number));
+    POOLS_CREATED_SINCE_REFRESH.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Devuelve y reinicia el número de pools creados en vivo desde la última llamada.
+pub fn take_pools_created() -> u64 {
+    POOLS_CREATED_SINCE_REFRESH.swap(0, Ordering::Relaxed)
+}
+
+/// El fee on-chain es la fuente de verdad: un fee de caché desfasado codifica mal la ruta V3.
+/// Un `fee()` que no se pudo leer (0) deja el de la caché.
+fn reconcile_fee(pool: &mut Pool, onchain_fee: u32) {
+    if onchain_fee != 0 && onchain_fee != pool.fee {
+        warn!("Fee corregido para el pool {:?}: caché {} -> on-chain {}", pool.address, pool.fee, onchain_fee);
+        pool.fee = onchain_fee;
+    }
+    if !known_fee_tiers(pool.version).contains(&pool.fee) {
+        warn!("El pool {:?} ({:?}) tiene un fee tier no estándar: {}", pool.address, pool.version, pool.fee);
+    }
+}
+
+// Bloques por consulta en los barridos de `get_logs`: los proveedores rechazan rangos mayores.
+pub const LOG_CHUNK_BLOCKS: u64 = 10_000;
+// Intentos por tramo ante errores del nodo (límites de ritmo, timeouts) antes de abandonar el barrido.
+const LOG_CHUNK_ATTEMPTS: u32 = 3;
+
+/// `get_logs` de `filter` entre `from_block` y `to_block` en tramos de `LOG_CHUNK_BLOCKS`, reintentando
+/// cada tramo con espera creciente. Falla si algún tramo agota sus intentos.
+pub async fn get_logs_chunked<M: Middleware>(provider: &M, filter: &Filter, from_block: u64, to_block: u64) -> Result<Vec<Log>> {
+    let mut logs = Vec::new();
+    let mut start = from_block;
+    while start <= to_block {
+        let end = to_block.min(start.saturating_add(LOG_CHUNK_BLOCKS - 1));
+        let chunk = filter.clone().from_block(start).to_block(end);
+        let mut attempt = 1;
+        loop {
+            match provider.get_logs(&chunk).await {
+                Ok(found) => break logs.extend(found),
+                Err(e) if attempt < LOG_CHUNK_ATTEMPTS => {
+                    debug!("get_logs #{start}-#{end} falló (intento {attempt}): {e}");
+                    tokio::time::sleep(std::time::Duration::from_millis(200 * attempt as u64)).await;
+                    attempt += 1;
+                }
+                Err(e) => return Err(anyhow!("get_logs #{start}-#{end} tras {attempt} intentos: {e}")),
+            }
+        }
+        start = end + 1;
+    }
+    Ok(logs)
+}
+
+/// Busca el `PoolCreated` de los pools sin bloque de creación conocido con un único barrido por tramos
+/// de las factories de la chain, limitado a los últimos `min_pool_age_blocks`: un pool que no aparece
+/// ahí es más antiguo y se anota con el bloque anterior a la ventana como cota de su creación. Si el
+/// barrido falla, los pools quedan sin anotar y se vuelven a buscar en el siguiente refresco.
+async fn resolve_creation_blocks<M: Middleware>(provider: &M, pools: &[Pool], current_block: u64) {
+    let unknown: HashSet<H160> = {
+        let known = POOL_CREATION_BLOCKS.lock().unwrap();
+        pools.iter().filter(|p| !known.contains_key(&p.address) && CONFIG.dex(p.version).is_some()).map(|p| p.address).collect()
+    };
+    if unknown.is_empty() { return; }
+    let deployed = CONFIG.dexes.iter().map(|d| d.deploy_block).min().unwrap_or(0);
+    let window_start = current_block.saturating_sub(CONFIG.min_pool_age_blocks.saturating_sub(1)).max(deployed);
+    let filter = Filter::new().address(CONFIG.dexes.iter().map(|d| d.factory).collect::<Vec<_>>()).event(POOL_CREATED_EVENT);
+    let logs = match get_logs_chunked(provider, &filter, window_start, current_block).await {
+        Ok(logs) => logs,
+        Err(e) => {
+            warn!("No se pudieron buscar los bloques de creación de {} pools; se reintentará en el próximo refresco: {e}", unknown.len());
+            return;
+        }
+    };
+    let mut creation = POOL_CREATION_BLOCKS.lock().unwrap();
+    for log in logs.iter().filter(|log| log.data.len() >= 64) {
+        let pool = H160::from_slice(&log.data[44..64]);
+        if let (true, Some(block)) = (unknown.contains(&pool), log.block_number) {
+            creation.insert(pool, Some(block.as_u64()));
+        }
+    }
+    for pool in unknown {
+        creation.entry(pool).or_insert(Some(window_start.saturating_sub(1)));
+    }
+}
+
+/// Excluye los pools con menos de `min_pool_age_blocks` bloques de antigüedad. Los de edad desconocida
+/// se mantienen o no según `include_unknown_age_pools`.
+async fn filter_young_pools<M: Middleware>(provider: &M, pools: Vec<Pool>) -> Vec<Pool> {
+    if CONFIG.min_pool_age_blocks == 0 { return pools; }
+    let current_block = match provider.get_block_number().await {
+        Ok(block) => block.as_u64(),
+        Err(e) => {
+            warn!("No se pudo leer el bloque actual para el filtro de antigüedad de pools: {e}");
+            return pools;
+        }
+    };
+    resolve_creation_blocks(provider, &pools, current_block).await;
+    let creation = POOL_CREATION_BLOCKS.lock().unwrap();
+    let (mut too_young, mut unknown) = (0, 0);
+    let kept: Vec<Pool> = pools
+        .into_iter()
+        .filter(|p| match creation.get(&p.address).copied().flatten() {
+            Some(block) if current_block.saturating_sub(block) < CONFIG.min_pool_age_blocks => {
+                too_young += 1;
+                false
+            }
+            Some(_) => true,
+            None => {
+                unknown += 1;
+                CONFIG.include_unknown_age_pools
+            }
+        })
+        .collect();
+    info!(
+        "Filtro de antigüedad ({} bloques): {} pools excluidos por jóvenes, {} de edad desconocida ({}).",
+        CONFIG.min_pool_age_blocks, too_young, unknown,
+        if CONFIG.include_unknown_age_pools { "incluidos" } else { "excluidos" }
+    );
+    kept
+}
+
+/// Firma del evento `Swap` de los pools V3.
+pub const SWAP_EVENT: &str = "Swap(address,address,int256,int256,uint160,uint128,int24)";
+
+// Bloques máximos por consulta de `Swap`: tras un hueco largo sólo se recuperan los más recientes.
+pub const SWAP_LOGS_MAX_RANGE_BLOCKS: u64 = 20;
+
+/// `Swap` de los pools del universo entre `from_block` y `to_block`. El filtro va sólo por topic (una
+/// lista de miles de direcciones hace la consulta más cara que el propio rango) y los logs de pools
+/// ajenos se descartan aquí.
+pub async fn fetch_swap_logs<M: Middleware>(provider: &M, pools: &[Pool], from_block: u64, to_block: u64) -> Result<Vec<Log>> {
+    let known: HashSet<H160> = pools.iter().map(|p| p.address).collect();
+    let filter = Filter::new().event(SWAP_EVENT).from_block(from_block).to_block(to_block);
+    let logs = provider.get_logs(&filter).await.map_err(|e| anyhow!("get_logs de Swap #{from_block}-#{to_block}: {e}"))?;
+    Ok(logs.into_iter().filter(|log| known.contains(&log.address)).collect())
+}
+
+/// Registra los `Swap` cuyo tamaño (lado de entrada, en USD) supera `min_usd`, con el bloque del propio
+/// log (`block_number` si no lo trae).
+pub fn record_large_swaps(logs: &[Log], block_number: u64, min_usd: f64) {
+    let metrics = POOL_METRICS.lock().unwrap();
+    let mut recent = RECENT_LARGE_SWAPS.lock().unwrap();
+    for log in logs {
+        let Some(m) = metrics.get(&log.address) else { continue };
+        // data: amount0 (int256), amount1 (int256), sqrtPriceX96, liquidity, tick.
+        if log.data.len() < 96 { continue; }
+        let signed_abs = |word: &[u8]| {
+            let value = U256::from_big_endian(word);
+            if word[0] & 0x80 != 0 { (!value).overflowing_add(U256::one()).0 } else { value }
+        };
+        let size_usd = to_usd(signed_abs(&log.data[0..32]), m.raw.token0, m.price0_usd, m.raw.decimals0)
+            .max(to_usd(signed_abs(&log.data[32..64]), m.raw.token1, m.price1_usd, m.raw.decimals1));
+        if size_usd >= min_usd {
+            let block = log.block_number.map_or(block_number, |b| b.as_u64());
+            recent.insert(log.address, (block, size_usd, U256::from_big_endian(&log.data[64..96])));
+        }
+    }
+}
+
+/// Anota el bloque y el precio resultante de cada `Swap`, grande o no. El precio de referencia del pool sólo
+/// se reinicia cuando un swap lo aleja más de `cooldown_settle_tolerance_bps`.
+pub fn record_swap_activity(logs: &[Log], block_number: u64) {
+    let mut last = LAST_SWAPS.lock().unwrap();
+    let mut anchors = SETTLE_ANCHORS.lock().unwrap();
+    for log in logs {
+        if log.data.len() < 96 { continue; }
+        let block = log.block_number.map_or(block_number, |b| b.as_u64());
+        let sqrt_price_x96 = U256::from_big_endian(&log.data[64..96]);
+        last.insert(log.address, (block, sqrt_price_x96));
+        let moved = anchors.get(&log.address).is_none_or(|&(_, anchor)| {
+            price_change_bps(anchor, sqrt_price_x96) > f64::from(CONFIG.cooldown_settle_tolerance_bps)
+        });
+        if moved {
+            anchors.insert(log.address, (block, sqrt_price_x96));
+        }
+    }
+}
+
+/// Variación en bps del precio (no de su raíz) entre dos `sqrtPriceX96`.
+fn price_change_bps(from_sqrt_price_x96: U256, to_sqrt_price_x96: U256) -> f64 {
+    let from = sqrt_price_f64(from_sqrt_price_x96);
+    if from <= 0.0 { return f64::INFINITY; }
+    ((sqrt_price_f64(to_sqrt_price_x96) / from).powi(2) - 1.0).abs() * 10_000.0
+}
+
+/// Si el precio del pool lleva al menos `quiet_blocks` bloques dentro de la tolerancia (o sin swaps vistos).
+pub fn pool_settled(address: &H160, block_number: u64, quiet_blocks: u64) -> bool {
+    SETTLE_ANCHORS.lock().unwrap().get(address).is_none_or(|&(block, _)| block_number.saturating_sub(block) >= quiet_blocks)
+}
+
+/// `sqrtPriceX96` más reciente conocido del pool: el del último `Swap` visto o, si no hay, el del último refresco.
+pub fn current_sqrt_price_x96(address: &H160) -> Option<U256> {
+    if let Some(&(_, sqrt_price_x96)) = LAST_SWAPS.lock().unwrap().get(address) {
+        return Some(sqrt_price_x96);
+    }
+    pool_metrics(address).map(|m| m.raw.sqrt_price_x96)
+}
+
+/// Swap grande en el pool dentro de los últimos `lookback_blocks`: (tamaño en USD, sqrtPriceX96 resultante).
+pub fn recent_large_swap(address: &H160, block_number: u64, lookback_blocks: u64) -> Option<(f64, U256)> {
+    let recent = RECENT_LARGE_SWAPS.lock().unwrap();
+    let &(block, size_usd, sqrt_price_x96) = recent.get(address)?;
+    (block_number.saturating_sub(block) <= lookback_blocks).then_some((size_usd, sqrt_price_x96))
+}
+
+/// Anota los pools por los que acaba de pasar un trade propio ejecutado en `block_number`.
+pub fn record_own_trade(pools: &[H160], block_number: u64) {
+    let mut traded = OWN_TRADED_POOLS.lock().unwrap();
+    for pool in pools {
+        traded.insert(*pool, block_number);
+    }
+}
+
+/// Bloque del trade propio por el pool si fue dentro de los últimos `lookback_blocks`: su estado
+/// puede reflejar aún nuestro propio desequilibrio.
+pub fn recent_own_trade(address: &H160, block_number: u64, lookback_blocks: u64) -> Option<u64> {
+    let traded = OWN_TRADED_POOLS.lock().unwrap();
+    let &block = traded.get(address)?;
+    (block_number.saturating_sub(block) <= lookback_blocks).then_some(block)
+}
+
+/// Registra un pool de un token vigilado para incluirlo en los próximos refrescos. `false` si ya estaba.
+pub fn watch_pool(address: H160, pool: WatchedPool) -> bool {
+    WATCHED_POOLS.lock().unwrap().insert(address, pool).is_none()
+}
+
+/// Si el pool vigilado ya forma parte del universo operable.
+pub fn is_watched_pool_admitted(address: &H160) -> bool {
+    ADMITTED_WATCHED_POOLS.lock().unwrap().contains(address)
+}
+
+/// Añade a `pools` los pools vigilados que no están en la caché, con los datos de su `PoolCreated`; los
+/// decimales llegan con los datos en vivo y el TVL lo calcula el refresco.
+fn merge_watched_pools(pools: &mut Vec<Pool>) {
+    let watched: Vec<(H160, WatchedPool)> = WATCHED_POOLS.lock().unwrap().iter().map(|(a, w)| (*a, *w)).collect();
+    for (address, watched) in watched {
+        if pools.iter().any(|p| p.address == address) { continue; }
+        pools.push(Pool {
+            address,
+            token0: watched.token0,
+            token1: watched.token1,
+            decimals0: 0,
+            decimals1: 0,
+            fee: watched.fee,
+            version: watched.version,
+            tvl_usd: 0.0,
+        });
+    }
+}
+
+/// Anclas del mapa de precios: `SETTLE_ANCHORS` si está definido, si no el WETH de la chain (oráculo) y,
+/// en Arbitrum, USDC (peg 1.0).
+fn price_anchors() -> Vec<(H160, PriceAnchor)> {
+    if !CONFIG.price_anchors.is_empty() {
+        return CONFIG.price_anchors.clone();
+    }
+    let mut anchors = vec![(CONFIG.weth_address, PriceAnchor::Oracle)];
+    if CONFIG.chain_id == constants::ARBITRUM_CHAIN_ID {
+        anchors.insert(0, (*USDC_ADDRESS, PriceAnchor::Peg(1.0)));
+    }
+    anchors
+}
+
+/// Precios de las anclas en este refresco: los pegs tal cual y el resto desde su oráculo.
+async fn anchor_prices<M: Middleware + 'static>(
+    anchors: &[(H160, PriceAnchor)],
+    oracle_map: &OracleMap,
+    provider: Arc<M>,
+    now: u64,
+) -> PriceMap {
+    let mut price_map = PriceMap::default();
+    for &(token, anchor) in anchors {
+        let priced = match anchor {
+            PriceAnchor::Peg(peg) => Some((peg, PriceSource::Peg)),
+            PriceAnchor::Oracle => crate::feeds::get_price(oracle_map, &token, provider.clone()).await.map(|info| (info.price, PriceSource::Oracle)),
+        };
+        match priced {
+            Some((price, source)) => price_map.insert(token, price, source, now),
+            None => warn!("Ancla de precio {token:?} sin precio de oráculo en este refresco."),
+        }
+    }
+    info!("Anclas de precio: {:?} ({} con precio).", anchors, price_map.len());
+    price_map
+}
+
+/// Completa `price_map` desde los tokens de `price_depth` (token -> saltos hasta un ancla) a través
+/// de los precios spot de `raw_data`.
+fn derive_prices(raw_data: &HashMap<H160, RawPoolData>, price_map: &mut PriceMap, price_depth: &mut HashMap<H160, u32>, now: u64) {
+    // Derivación por niveles desde las anclas: cada token guarda a cuántos saltos está de un precio
+    // de oráculo/peg, y no se derivan precios más allá de `max_price_derivation_hops` (cadenas largas derivan).
+    for hop in 1..=CONFIG.max_price_derivation_hops {
+        for data in raw_data.values() {
+            let (t0, t1) = (data.token0, data.token1);
+            // Sólo se deriva desde tokens conocidos al inicio del nivel, para que la profundidad sea exacta.
+            let p0_known = price_depth.get(&t0).is_some_and(|&d| d < hop);
+            let p1_known = price_depth.get(&t1).is_some_and(|&d| d < hop);
+            // 2^96 no cabe en un `Decimal`: el precio spot (token1 por token0) se calcula en f64.
+            let price_t1_t0 = sqrt_price_f64(data.sqrt_price_x96).powi(2);
+            if price_t1_t0 <= 0.0 { continue; }
+            if p0_known && !price_depth.contains_key(&t1) {
+                let price0 = price_map.price(&t0).unwrap();
+                let price1 = price0 / price_t1_t0 * 10f64.powi((data.decimals1 as i32) - (data.decimals0 as i32));
+                if price1.is_finite() && price1 > 0.0 {
+                    price_map.insert(t1, price1, PriceSource::DerivedFromPool { hops: hop }, now);
+                    price_depth.insert(t1, hop);
+                }
+            } else if p1_known && !price_depth.contains_key(&t0) {
+                let price1 = price_map.price(&t1).unwrap();
+                let price0 = price1 * price_t1_t0 * 10f64.powi((data.decimals0 as i32) - (data.decimals1 as i32));
+                if price0.is_finite() && price0 > 0.0 {
+                    price_map.insert(t0, price0, PriceSource::DerivedFromPool { hops: hop }, now);
+                    price_depth.insert(t0, hop);
+                }
+            }
+        }
+    }
+}
+
+/// Carga los pools directamente desde el archivo de caché y los enriquece con datos en tiempo real.
+pub async fn load_all_pools_v3(
+    provider: Arc<Provider<Http>>,
+    oracle_map: &Arc<OracleMap>,
+) -> Result<Vec<Pool>> {
+    let mut pools = read_pool_cache()?;
+    merge_watched_pools(&mut pools);
+
+    // Arranque en caliente: los atributos inmutables del snapshot evitan la pasada estática del multicall.
+    let previous = PRICE_MAP.lock().unwrap().clone();
+    let snapshot = match &CONFIG.price_snapshot_path {
+        Some(path) if previous.is_empty() => load_price_snapshot(path),
+        _ => WarmStartSnapshot::default(),
+    };
+    if !snapshot.pools.is_empty() {
+        let seeded = multi::seed_static_pool_data(snapshot.pools);
+        info!("Snapshot de arranque: atributos estáticos de {} pools reutilizados, sólo se piden los datos dinámicos.", seeded);
+    }
 
     info!("Cargados {} pools desde la caché. Enriqueciendo con datos en tiempo real...", pools.len());
 
@@ -59,52 +725,452 @@ pub async fn load_all_pools_v3(
         unique_tokens.insert(data.token1);
     }
 
-    let mut price_map = HashMap::new();
-    let known_tokens = [ *USDC_ADDRESS, crate::constants::WETH_ADDRESS.clone() ];
-    for &token in &known_tokens {
-        if let Some(price_info) = oracle_map.get_price::<Provider<Ws>>(&token, provider.clone()).await {
-            price_map.insert(token, price_info.price);
-        }
-    }
-    price_map.insert(*USDC_ADDRESS, 1.0);
+    let now = unix_now();
+    let anchors = price_anchors();
+    let mut price_map = anchor_prices(&anchors, oracle_map, provider.clone(), now).await;
+    // Sólo las anclas en vivo siembran la derivación.
+    let mut price_depth: HashMap<H160, u32> = price_map.entries.keys().map(|&t| (t, 0)).collect();
 
-    for data in raw_data.values() {
-        let (t0, t1) = (data.token0, data.token1);
-        let (p0_known, p1_known) = (price_map.contains_key(&t0), price_map.contains_key(&t1));
-
-        if p0_known && !p1_known {
-            if let Ok(sqrt_price_x96) = Decimal::from_str(&data.sqrt_price_x96.to_string()) {
-                let price0 = *price_map.get(&t0).unwrap();
-                let price_t1_t0 = (sqrt_price_x96 / Decimal::from_u128(2u128.pow(96)).unwrap()).powi(2);
-                let price_t0_t1 = Decimal::ONE / price_t1_t0;
-                let price1 = price0 * (price_t0_t1.to_f64().unwrap_or(0.0) * 10f64.powi((data.decimals0 as i32) - (data.decimals1 as i32)));
-                if price1 > 0.0 { price_map.insert(t1, price1); }
-            }
-        } else if !p0_known && p1_known {
-            if let Ok(sqrt_price_x96) = Decimal::from_str(&data.sqrt_price_x96.to_string()) {
-                let price1 = *price_map.get(&t1).unwrap();
-                let price_t1_t0 = (sqrt_price_x96 / Decimal::from_u128(2u128.pow(96)).unwrap()).powi(2);
-                let price0 = price1 * (price_t1_t0.to_f64().unwrap_or(0.0) * 10f64.powi((data.decimals1 as i32) - (data.decimals0 as i32)));
-                if price0 > 0.0 { price_map.insert(t0, price0); }
+    // Entre refrescos (o al arrancar en caliente desde el snapshot) las anclas siempre se refrescan en vivo;
+    // el resto de tokens del mapa anterior entra como precio conocido con su origen y timestamp originales,
+    // hasta que se re-deriva o caduca.
+    let (carried, max_age_secs, carried_from) = if !previous.is_empty() {
+        (previous.entries, CONFIG.price_map_ttl_secs, "mapa de precios anterior")
+    } else {
+        (snapshot.prices, CONFIG.price_snapshot_max_age_secs, "snapshot de precios")
+    };
+    if !carried.is_empty() {
+        let total = carried.len();
+        let mut reused = 0;
+        for (token, entry) in fresh_snapshot_entries(carried, max_age_secs, now) {
+            if unique_tokens.contains(&token) && !price_map.contains(&token) {
+                price_map.entries.insert(token, entry);
+                reused += 1;
             }
         }
+        info!("Precios del {}: {} de {} entradas reutilizadas (máx. {}s de antigüedad).", carried_from, reused, total, max_age_secs);
+    }
+
+    derive_prices(&raw_data, &mut price_map, &mut price_depth, now);
+    let mut depth_distribution: BTreeMap<u32, usize> = BTreeMap::new();
+    for depth in price_depth.values() {
+        *depth_distribution.entry(*depth).or_default() += 1;
     }
+    let unpriced = unique_tokens.iter().filter(|t| !price_map.contains(t)).count();
+    info!(
+        "Profundidad de derivación de precios (saltos -> tokens): {:?}. Sin precio (> {} saltos o aislados): {}",
+        depth_distribution, CONFIG.max_price_derivation_hops, unpriced
+    );
     info!("Mapa de precios expandido a {} tokens por derivación.", price_map.len());
+    let covered = unique_tokens.len() - unpriced;
+    info!(
+        "Cobertura del mapa de precios: {}/{} tokens de los pools ({:.1}%) desde {} anclas.",
+        covered, unique_tokens.len(), covered as f64 / unique_tokens.len().max(1) as f64 * 100.0, anchors.len()
+    );
+
+    if let Some(path) = &CONFIG.price_snapshot_path {
+        let snapshot = WarmStartSnapshot { prices: price_map.entries.clone(), pools: multi::static_pool_data() };
+        if let Err(e) = save_price_snapshot(path, &snapshot) {
+            warn!("No se pudo guardar el snapshot de precios en {}: {}", path, e);
+        }
+    }
+    *PRICE_MAP.lock().unwrap() = price_map.clone();
 
+    let mut metrics = HashMap::new();
+    let watched_addresses: HashSet<H160> = WATCHED_POOLS.lock().unwrap().keys().copied().collect();
     for pool in &mut pools {
         if let Some(data) = raw_data.get(&pool.address) {
-            let price0 = price_map.get(&data.token0).cloned().unwrap_or(0.0);
-            let price1 = price_map.get(&data.token1).cloned().unwrap_or(0.0);
+            // Los pools vigilados no vienen de la caché: sus decimales salen de los datos en vivo.
+            // Los decimales fijados por configuración corrigen también los de la caché.
+            if watched_addresses.contains(&pool.address) || CONFIG.token_decimals_overrides.contains_key(&pool.token0) {
+                pool.decimals0 = data.decimals0;
+            }
+            if watched_addresses.contains(&pool.address) || CONFIG.token_decimals_overrides.contains_key(&pool.token1) {
+                pool.decimals1 = data.decimals1;
+            }
+            reconcile_fee(pool, data.fee);
+            let price0 = price_map.price(&data.token0).unwrap_or(0.0);
+            let price1 = price_map.price(&data.token1).unwrap_or(0.0);
             if price0 == 0.0 || price1 == 0.0 { pool.tvl_usd = 0.0; continue; }
 
-            let balance0_dec = Decimal::from_u128(data.balance0.as_u128()).unwrap_or_default() / Decimal::from(10u128.pow(data.decimals0 as u32));
-            let balance1_dec = Decimal::from_u128(data.balance1.as_u128()).unwrap_or_default() / Decimal::from(10u128.pow(data.decimals1 as u32));
-            pool.tvl_usd = (Decimal::from_f64(price0).unwrap_or_default() * balance0_dec + Decimal::from_f64(price1).unwrap_or_default() * balance1_dec).to_f64().unwrap_or(0.0);
+            if has_untrusted_balance(data) {
+                info!("Pool {:?}: TVL por reservas virtuales (token con balanceOf no fiable).", pool.address);
+            }
+            let raw_tvl_usd = raw_tvl_usd(data, price0, price1);
+            let effective_tvl_usd = effective_tvl_usd(data, price0, price1);
+
+            // El filtro de rutas y el scoring usan la profundidad efectiva; el TVL bruto queda en `POOL_METRICS`.
+            pool.tvl_usd = effective_tvl_usd;
+            metrics.insert(pool.address, PoolMetrics { raw: *data, raw_tvl_usd, effective_tvl_usd, price0_usd: price0, price1_usd: price1 });
         }
     }
 
-    let final_pools: Vec<Pool> = pools.into_iter().filter(|p| p.tvl_usd > 10_000_000.0).collect();
+    let final_pools: Vec<Pool> = pools
+        .into_iter()
+        .filter(|p| metrics.get(&p.address).is_some_and(|m| m.raw_tvl_usd > 10_000_000.0))
+        .collect();
+    *POOL_METRICS.lock().unwrap() = metrics;
+    let final_pools = filter_young_pools(provider.as_ref(), final_pools).await;
     info!("Total de pools con TVL > $10M listos para operar: {}", final_pools.len());
+    {
+        let watched = WATCHED_POOLS.lock().unwrap();
+        let mut admitted = ADMITTED_WATCHED_POOLS.lock().unwrap();
+        for pool in final_pools.iter().filter(|p| watched.contains_key(&p.address)) {
+            if admitted.insert(pool.address) {
+                warn!(
+                    " ALERTA: el pool vigilado {:?} ({:?}/{:?}, {:?}) alcanzó el umbral de TVL y entra al universo.",
+                    pool.address, pool.token0, pool.token1, pool.version
+                );
+            }
+        }
+    }
 
     Ok(final_pools)
 }
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    /// Sustituye el mapa de precios del último refresco de la instancia.
+    pub(crate) fn set_price_map(price_map: PriceMap) {
+        *PRICE_MAP.lock().unwrap() = price_map;
+    }
+
+    fn raw(liquidity: u128, balance: U256) -> RawPoolData {
+        RawPoolData {
+            liquidity,
+            // sqrt(P) = 1: un token1 por token0, ambos de 18 decimales.
+            sqrt_price_x96: U256::from(2).pow(96.into()),
+            decimals0: 18,
+            decimals1: 18,
+            balance0: balance,
+            balance1: balance,
+            ..Default::default()
+        }
+    }
+
+    fn entry(price: f64, timestamp: u64) -> PriceSnapshotEntry {
+        PriceSnapshotEntry { price, timestamp, source: None }
+    }
+
+    #[test]
+    fn fresh_snapshot_entries_drops_stale_and_unpriced_tokens() {
+        let (fresh, edge, stale, unpriced) = (
+            H160::from_low_u64_be(1),
+            H160::from_low_u64_be(2),
+            H160::from_low_u64_be(3),
+            H160::from_low_u64_be(4),
+        );
+        let snapshot = HashMap::from([
+            (fresh, entry(1.0, 950)),
+            (edge, entry(2.0, 900)),
+            (stale, entry(3.0, 899)),
+            (unpriced, entry(0.0, 1_000)),
+        ]);
+        let kept = fresh_snapshot_entries(snapshot, 100, 1_000);
+        let mut tokens: Vec<H160> = kept.keys().copied().collect();
+        tokens.sort();
+        assert_eq!(tokens, vec![fresh, edge]);
+        // Un timestamp futuro (reloj desajustado) cuenta como fresco.
+        let future = fresh_snapshot_entries(HashMap::from([(fresh, entry(1.0, 2_000))]), 100, 1_000);
+        assert_eq!(future.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn warm_start_snapshot_skips_the_static_multicall_pass() {
+        use crate::{instance::with_test_config, paths::tests::addr, testing::MockChain};
+
+        let (pool, factory, weth, usdc) = (addr(600), addr(1), addr(2), addr(3));
+        let path = std::env::temp_dir().join(format!("warm_start_{}.json", std::process::id()));
+        let path = path.to_str().unwrap().to_string();
+        let static_data = StaticPoolData { factory, token0: weth, token1: usdc, decimals0: 18, decimals1: 6, fee: 500 };
+        let snapshot = WarmStartSnapshot {
+            prices: HashMap::from([(weth, entry(3_000.0, 1_000))]),
+            pools: HashMap::from([(pool, static_data)]),
+        };
+        save_price_snapshot(&path, &snapshot).unwrap();
+        let loaded = load_price_snapshot(&path);
+        assert_eq!(loaded.pools[&pool], static_data);
+        assert_eq!(loaded.prices[&weth].price, 3_000.0);
+
+        // El nodo sólo responde a los datos dinámicos: sin el snapshot, el pool se descartaría.
+        let chain = MockChain::new()
+            .slot0(pool, U256::from(2).pow(96.into()), 0, true)
+            .returns(pool, "liquidity()", &[], &[ethers::abi::Token::Uint(1_000u64.into())])
+            .balance_of(weth, pool, U256::exp10(18))
+            .balance_of(usdc, pool, U256::exp10(9));
+        with_test_config(|_| {}, async {
+            multi::seed_static_pool_data(loaded.pools);
+            let data = batch_get_pool_data(chain.provider(), &[pool]).await.unwrap();
+            assert_eq!((data[&pool].decimals1, data[&pool].liquidity), (6, 1_000));
+            assert_eq!(chain.calls_to("factory()") + chain.calls_to("decimals()"), 0);
+        })
+        .await;
+
+        // Un snapshot del formato anterior (sólo precios) sigue cargando.
+        fs::write(&path, serde_json::to_string(&HashMap::from([(weth, entry(3_000.0, 1_000))])).unwrap()).unwrap();
+        let legacy = load_price_snapshot(&path);
+        assert_eq!((legacy.prices.len(), legacy.pools.len()), (1, 0));
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn effective_tvl_counts_only_the_liquidity_near_the_price() {
+        let liquidity = 1_000_000u128 * 10u128.pow(18);
+        // 1M tokens por lado en balances, casi todos en posiciones fuera de rango.
+        let data = raw(liquidity, U256::exp10(24));
+        let effective = effective_tvl_usd(&data, 1.0, 1.0);
+        // ±1% con L = 1M: (1 - 1/sqrt(1.01)) + (1 - sqrt(0.99)) ~ 0,9975% de L.
+        assert!((effective - 9_975.0).abs() < 5.0, "{effective}");
+        assert!((effective_tvl_usd(&raw(liquidity * 2, U256::zero()), 1.0, 1.0) - effective * 2.0).abs() < 1e-6);
+        assert_eq!(effective_tvl_usd(&raw(0, U256::exp10(24)), 1.0, 1.0), 0.0);
+    }
+
+    #[test]
+    fn reconcile_fee_prefers_the_onchain_fee() {
+        use crate::paths::tests::pool;
+        let mut cached = pool(12, 1, 2, 1_000_000.0);
+        assert_eq!(cached.fee, 500);
+        reconcile_fee(&mut cached, 3000);
+        assert_eq!(cached.fee, 3000);
+        // Sin lectura on-chain se mantiene el fee de la caché.
+        reconcile_fee(&mut cached, 0);
+        assert_eq!(cached.fee, 3000);
+    }
+
+
+    #[tokio::test]
+    async fn untrusted_balance_tokens_use_virtual_reserves() {
+        use crate::instance::with_test_config;
+
+        let rebasing = H160::from_low_u64_be(7);
+        // 1M tokens por lado en balances, pero sólo L = 1.000 tokens de reservas virtuales.
+        let data = RawPoolData { token0: rebasing, token1: H160::from_low_u64_be(8), ..raw(1_000 * 10u128.pow(18), U256::exp10(24)) };
+        let trusted = with_test_config(|_| {}, async { raw_tvl_usd(&data, 1.0, 1.0) }).await;
+        assert!((trusted - 2_000_000.0).abs() < 1e-6, "{trusted}");
+        let untrusted = with_test_config(|c| c.untrusted_balance_tokens = vec![rebasing], async { raw_tvl_usd(&data, 1.0, 1.0) }).await;
+        assert!((untrusted - 2_000.0).abs() < 1e-6, "{untrusted}");
+    }
+
+
+    /// Tokens con precio tras sembrar las anclas configuradas y derivar sobre `raw_data`.
+    async fn priced_tokens(raw_data: &HashMap<H160, RawPoolData>, anchors: Vec<(H160, PriceAnchor)>) -> Vec<(H160, f64)> {
+        use crate::{instance::with_test_config, testing::MockChain};
+
+        with_test_config(|c| c.price_anchors = anchors, async {
+            let mut price_map = anchor_prices(&price_anchors(), &OracleMap::new(), MockChain::new().provider(), 0).await;
+            let mut price_depth = price_map.entries.keys().map(|&t| (t, 0)).collect();
+            derive_prices(raw_data, &mut price_map, &mut price_depth, 0);
+            let mut priced: Vec<(H160, f64)> = price_map.entries.iter().map(|(t, e)| (*t, e.price)).collect();
+            priced.sort_by_key(|(t, _)| *t);
+            priced
+        })
+        .await
+    }
+
+    #[tokio::test]
+    async fn custom_anchors_expand_price_coverage() {
+        let token = H160::from_low_u64_be;
+        let (arb, gmx, magic) = (token(21), token(22), token(23));
+        // ARB/GMX y GMX/MAGIC con P = 0,25 (cada token vale 4 del siguiente), sin pools contra WETH o USDC.
+        let pair = |token0, token1| RawPoolData { token0, token1, sqrt_price_x96: U256::from(2).pow(95.into()), ..raw(0, U256::zero()) };
+        let raw_data = HashMap::from([(token(2122), pair(arb, gmx)), (token(2223), pair(gmx, magic))]);
+
+        let default_anchors = priced_tokens(&raw_data, vec![]).await;
+        assert!(default_anchors.iter().all(|(t, _)| ![arb, gmx, magic].contains(t)), "{default_anchors:?}");
+        // Con ARB como ancla fija, los dos pools quedan con precio.
+        let with_arb = priced_tokens(&raw_data, vec![(arb, PriceAnchor::Peg(1.5))]).await;
+        assert_eq!(with_arb, vec![(arb, 1.5), (gmx, 6.0), (magic, 24.0)]);
+    }
+
+
+    #[tokio::test]
+    async fn watched_pools_join_with_their_own_attributes() {
+        use crate::instance::with_test_config;
+        use crate::paths::tests::{addr, pool};
+
+        with_test_config(|_| {}, async {
+            let mut pools = vec![pool(10, 1, 2, 5e6)];
+            watch_pool(addr(20), WatchedPool { token0: addr(3), token1: addr(4), fee: 10_000, version: DexVariant::PancakeV3 });
+            watch_pool(addr(10), WatchedPool { token0: addr(1), token1: addr(2), fee: 500, version: DexVariant::UniswapV3 });
+            merge_watched_pools(&mut pools);
+
+            // El ya cacheado no se duplica; el nuevo no hereda nada de otro pool.
+            assert_eq!(pools.len(), 2);
+            let added = &pools[1];
+            assert_eq!((added.address, added.token0, added.token1, added.fee), (addr(20), addr(3), addr(4), 10_000));
+            assert!(matches!(added.version, DexVariant::PancakeV3));
+            assert_eq!((added.decimals0, added.decimals1, added.tvl_usd), (0, 0, 0.0));
+        })
+        .await;
+    }
+
+
+    #[tokio::test]
+    async fn swap_logs_use_a_topic_filter_over_the_range_and_keep_known_pools() {
+        use crate::{paths::tests::{addr, pool}, testing::FakeNode};
+        use serde_json::json;
+
+        let (known, foreign) = (pool(12, 1, 2, 1_000_000.0), addr(99));
+        let swap_log = |address: H160, block: u64| json!({
+            "address": address, "topics": [H256::from(ethers::utils::keccak256(SWAP_EVENT))], "data": "0x",
+            "blockNumber": format!("{block:#x}"), "transactionHash": H256::zero(), "logIndex": "0x0",
+        });
+        let node = FakeNode::start(move |method, _| match method {
+            "eth_getLogs" => Ok(json!([swap_log(known.address, 98), swap_log(foreign, 99), swap_log(known.address, 100)])),
+            _ => Err(format!("método inesperado {method}")),
+        })
+        .await;
+
+        let logs = fetch_swap_logs(node.client().as_ref(), std::slice::from_ref(&known), 98, 100).await.unwrap();
+        assert_eq!(logs.iter().map(|l| (l.address, l.block_number.unwrap().as_u64())).collect::<Vec<_>>(), vec![(known.address, 98), (known.address, 100)]);
+        let filter = &node.requests("eth_getLogs")[0][0];
+        assert!(filter.get("address").is_none_or(|a| a.is_null()));
+        assert_eq!((filter["fromBlock"].as_str(), filter["toBlock"].as_str()), (Some("0x62"), Some("0x64")));
+    }
+
+
+    #[tokio::test]
+    async fn young_pools_are_excluded_with_a_chunked_retried_creation_scan() {
+        use crate::{instance::with_test_config, paths::tests::pool, testing::FakeNode};
+        use serde_json::json;
+        use std::sync::atomic::AtomicUsize;
+
+        let (young, old) = (pool(12, 1, 2, 1_000_000.0), pool(13, 1, 3, 1_000_000.0));
+        let young_address = young.address;
+        // El primer tramo falla una vez (límite de ritmo) y el reintento lo recupera.
+        let failures_left = Arc::new(AtomicUsize::new(1));
+        let fail = failures_left.clone();
+        let node = FakeNode::start(move |method, _| match method {
+            "eth_blockNumber" => Ok(json!("0x186a0")),
+            "eth_getLogs" if fail.fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| n.checked_sub(1)).is_ok() => Err("rate limited".to_string()),
+            "eth_getLogs" => Ok(json!([{
+                "address": H160::zero(),
+                "topics": [H256::from(ethers::utils::keccak256(POOL_CREATED_EVENT))],
+                "data": format!("0x{:064x}{:0>64}", 60, ethers::utils::hex::encode(young_address)),
+                "blockNumber": "0x1869f", "transactionHash": H256::zero(), "logIndex": "0x0",
+            }])),
+            _ => Err(format!("método inesperado {method}")),
+        })
+        .await;
+        let client = node.client();
+
+        with_test_config(|c| { c.min_pool_age_blocks = 25_000; c.include_unknown_age_pools = false; }, async {
+            let kept = filter_young_pools(client.as_ref(), vec![young.clone(), old.clone()]).await;
+            assert_eq!(kept.iter().map(|p| p.address).collect::<Vec<_>>(), vec![old.address]);
+            // Ventana [75.001, 100.000] en tramos de `LOG_CHUNK_BLOCKS`, con el primero repetido.
+            let ranges: Vec<(u64, u64)> = node.requests("eth_getLogs").iter().map(|params| {
+                let block = |key: &str| u64::from_str_radix(params[0][key].as_str().unwrap().trim_start_matches("0x"), 16).unwrap();
+                (block("fromBlock"), block("toBlock"))
+            }).collect();
+            assert_eq!(ranges, vec![(75_001, 85_000), (75_001, 85_000), (85_001, 95_000), (95_001, 100_000)]);
+            // Ya anotados, un segundo refresco no vuelve a barrer.
+            filter_young_pools(client.as_ref(), vec![young.clone(), old.clone()]).await;
+            assert_eq!(node.requests("eth_getLogs").len(), 4);
+        })
+        .await;
+
+        // Un barrido que agota los intentos no se cachea: los pools cuentan como de edad desconocida
+        // sólo en este refresco y se vuelven a buscar en el siguiente.
+        failures_left.store(usize::MAX, Ordering::SeqCst);
+        with_test_config(|c| { c.min_pool_age_blocks = 100; c.include_unknown_age_pools = true; }, async {
+            assert_eq!(filter_young_pools(client.as_ref(), vec![young.clone()]).await.len(), 1);
+            assert!(!POOL_CREATION_BLOCKS.lock().unwrap().contains_key(&young.address));
+            failures_left.store(0, Ordering::SeqCst);
+            assert!(filter_young_pools(client.as_ref(), vec![young.clone()]).await.is_empty());
+        })
+        .await;
+    }
+
+
+    #[tokio::test]
+    async fn raw_tvl_converts_balances_beyond_u128() {
+        use crate::instance::with_test_config;
+
+        // 10^40 unidades base (supply enorme de un token de 18 decimales) no caben en u128.
+        let data = RawPoolData { decimals1: 6, balance1: U256::from(2_500_000u64), ..raw(0, U256::exp10(40)) };
+        let tvl = with_test_config(|_| {}, async { raw_tvl_usd(&data, 1e-20, 1.0) }).await;
+        assert!((tvl - 102.5).abs() < 1e-9);
+    }
+
+    #[tokio::test]
+    async fn provenance_follows_the_derivation_chain_up_to_the_hop_limit() {
+        use crate::{instance::with_test_config, testing::MockChain};
+
+        let token = H160::from_low_u64_be;
+        let (arb, gmx, magic, far) = (token(21), token(22), token(23), token(24));
+        // ARB -> GMX -> MAGIC -> FAR, cada token vale 4 del siguiente.
+        let pair = |token0, token1| RawPoolData { token0, token1, sqrt_price_x96: U256::from(2).pow(95.into()), ..raw(0, U256::zero()) };
+        let raw_data = HashMap::from([(token(2122), pair(arb, gmx)), (token(2223), pair(gmx, magic)), (token(2324), pair(magic, far))]);
+
+        let edit = move |c: &mut crate::config::Config| {
+            c.price_anchors = vec![(arb, PriceAnchor::Peg(1.5))];
+            c.max_price_derivation_hops = 2;
+        };
+        let sources = with_test_config(edit, async {
+            let mut price_map = anchor_prices(&price_anchors(), &OracleMap::new(), MockChain::new().provider(), 42).await;
+            let mut price_depth = price_map.entries.keys().map(|&t| (t, 0)).collect();
+            derive_prices(&raw_data, &mut price_map, &mut price_depth, 42);
+            *PRICE_MAP.lock().unwrap() = price_map;
+            [arb, gmx, magic, far].map(|t| price_source(&t).map(|e| (e.price, e.source, e.timestamp)))
+        })
+        .await;
+
+        assert_eq!(sources[0], Some((1.5, Some(PriceSource::Peg), 42)));
+        assert_eq!(sources[1], Some((6.0, Some(PriceSource::DerivedFromPool { hops: 1 }), 42)));
+        assert_eq!(sources[2], Some((24.0, Some(PriceSource::DerivedFromPool { hops: 2 }), 42)));
+        // A tres saltos del ancla queda sin precio.
+        assert_eq!(sources[3], None);
+    }
+
+
+    #[test]
+    fn cache_drift_ratio_is_relative_to_the_cache_size() {
+        assert_eq!(cache_drift_ratio(200, 50), 0.25);
+        assert_eq!(cache_drift_ratio(200, 0), 0.0);
+        assert_eq!(cache_drift_ratio(0, 3), 3.0);
+    }
+
+    #[tokio::test]
+    async fn stale_cache_is_detected_from_recent_pool_creations() {
+        use crate::{instance::with_test_config, testing::FakeNode};
+        use serde_json::json;
+
+        // Caché generada hace una hora: 14.400 bloques de 250 ms, dos tramos de `eth_getLogs`.
+        let cache = std::env::temp_dir().join(format!("pools_drift_{}.csv", std::process::id()));
+        fs::File::create(&cache).unwrap().set_modified(SystemTime::now() - std::time::Duration::from_secs(3_600)).unwrap();
+        let created_log = |block: u64| json!({
+            "address": H160::from_low_u64_be(0xfac), "topics": [H256::from(ethers::utils::keccak256(POOL_CREATED_EVENT))], "data": "0x",
+            "blockNumber": format!("{block:#x}"), "transactionHash": H256::zero(), "logIndex": "0x0",
+        });
+        let node = |logs: Vec<serde_json::Value>| FakeNode::start(move |method, _| match method {
+            "eth_blockNumber" => Ok(json!("0xf4240")),
+            "eth_getLogs" => Ok(json!(logs)),
+            _ => Err(format!("método inesperado {method}")),
+        });
+        let (stale, fresh) = (node(vec![created_log(999_990); 3]).await, node(vec![]).await);
+        let cache_path = cache.to_string_lossy().into_owned();
+        let edit = move |c: &mut crate::config::Config| {
+            c.cache_path = cache_path;
+            c.cache_drift_threshold = 0.2;
+            c.block_time_ms = 250;
+        };
+        let (stale_drift, fresh_drift) = with_test_config(edit, async {
+            (check_cache_drift(stale.client().as_ref(), 10).await, check_cache_drift(fresh.client().as_ref(), 10).await)
+        })
+        .await;
+        let _ = fs::remove_file(&cache);
+
+        // Tres pools nuevos frente a diez en caché superan el 20%: basta el tramo más reciente.
+        assert_eq!(stale_drift, Some(0.3));
+        let requests = stale.requests("eth_getLogs");
+        assert_eq!(requests.len(), 1);
+        assert_eq!((requests[0][0]["fromBlock"].as_str(), requests[0][0]["toBlock"].as_str()), (Some("0xf1b31"), Some("0xf4240")));
+        // Sin pools nuevos se recorre todo el rango hasta el bloque estimado de la caché.
+        assert_eq!(fresh_drift, Some(0.0));
+        let requests = fresh.requests("eth_getLogs");
+        assert_eq!(requests.len(), 2);
+        assert_eq!(requests[1][0]["fromBlock"].as_str(), Some("0xf0a00"));
+    }
+}
@@ -1,10 +1,157 @@
 use crate::config::CONFIG;
+use crate::instance::{self, InstanceLocal};
 use anyhow::{Result, Error};
 use ethers::{
     prelude::*,
     providers::{Http, Provider},
+    types::transaction::eip2718::TypedTransaction,
 };
-use std::{sync::Arc, time::Duration};
+use lazy_static::lazy_static;
+use log::{debug, info, warn};
+use prometheus::{register_int_counter_vec, register_int_gauge_vec, IntCounterVec, IntGaugeVec};
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+// Variación máxima del monto de entrada (bps) para reutilizar una estimación de gas cacheada.
+const GAS_CACHE_MAX_AMOUNT_DRIFT_BPS: u64 = 1_000;
+// Bloques recientes sobre los que se mide el tiempo de bloque al arrancar.
+const BLOCK_TIME_SAMPLE_BLOCKS: u64 = 100;
+// Divergencia relativa entre el tiempo de bloque medido y `BLOCK_TIME_MS` a partir de la cual se avisa.
+const BLOCK_TIME_DIVERGENCE_WARN_RATIO: f64 = 0.25;
+
+/// Estimación de gas de una ruta, con el monto y bloque en que se obtuvo.
+#[derive(Debug, Clone, Copy)]
+struct CachedGasEstimate {
+    gas: U256,
+    amount_in: U256,
+    block: u64,
+}
+
+// Si el nodo de simulación acepta el bloque `pending` (se comprueba al arrancar).
+static PENDING_STATE_SUPPORTED: InstanceLocal<AtomicBool> = InstanceLocal::new(|| AtomicBool::new(false));
+// Head del stream de bloques (WS) y si las simulaciones se fijan a él porque el nodo HTTP diverge.
+static STREAM_HEAD: InstanceLocal<AtomicU64> = InstanceLocal::new(|| AtomicU64::new(0));
+static PINNED_TO_STREAM_HEAD: InstanceLocal<AtomicBool> = InstanceLocal::new(|| AtomicBool::new(false));
+// Último head leído del nodo HTTP de simulación.
+static HTTP_HEAD: InstanceLocal<AtomicU64> = InstanceLocal::new(|| AtomicU64::new(0));
+
+lazy_static! {
+    static ref GAS_CACHE_HITS: IntCounterVec = register_int_counter_vec!("gas_estimate_cache_hits_total", "Estimaciones de gas servidas desde la caché por ruta", &["chain"]).unwrap();
+    static ref ORACLE_QUEUE_DEPTH: IntGaugeVec = register_int_gauge_vec!("oracle_reads_queued", "Lecturas de oráculo esperando turno en el limitador", &["chain"]).unwrap();
+    static ref HEAD_DIVERGENCE_BLOCKS: IntGaugeVec = register_int_gauge_vec!("head_divergence_blocks", "Diferencia entre el head del stream WS y el del nodo HTTP de simulación", &["chain"]).unwrap();
+    static ref ORACLE_THROTTLED: IntCounterVec = register_int_counter_vec!("oracle_reads_throttled_total", "Lecturas de oráculo retrasadas por el limitador", &["chain"]).unwrap();
+}
+
+static GAS_ESTIMATE_CACHE: InstanceLocal<Mutex<HashMap<String, CachedGasEstimate>>> = InstanceLocal::new(|| Mutex::new(HashMap::new()));
+// Limitador compartido de las lecturas de oráculo; `None` con `ORACLE_MAX_RPS=0`.
+static ORACLE_LIMITER: InstanceLocal<Option<TokenBucket>> = InstanceLocal::new(|| {
+    (CONFIG.oracle_max_rps > 0.0).then(|| TokenBucket::new(CONFIG.oracle_max_rps, CONFIG.oracle_burst.max(1) as f64))
+});
+
+/// Token bucket: admite ráfagas de hasta `capacity` y un ritmo sostenido de `refill_per_sec`.
+pub struct TokenBucket {
+    capacity: f64,
+    refill_per_sec: f64,
+    // (tokens disponibles, último relleno)
+    state: Mutex<(f64, Instant)>,
+}
+
+impl TokenBucket {
+    pub fn new(refill_per_sec: f64, capacity: f64) -> Self {
+        TokenBucket { capacity, refill_per_sec, state: Mutex::new((capacity, Instant::now())) }
+    }
+
+    /// Toma un token si hay; si no, devuelve cuánto falta para el siguiente.
+    pub fn try_take(&self, now: Instant) -> std::result::Result<(), Duration> {
+        let mut state = self.state.lock().unwrap();
+        let (tokens, last) = *state;
+        let tokens = (tokens + now.saturating_duration_since(last).as_secs_f64() * self.refill_per_sec).min(self.capacity);
+        if tokens >= 1.0 {
+            *state = (tokens - 1.0, now);
+            Ok(())
+        } else {
+            *state = (tokens, now);
+            Err(Duration::from_secs_f64((1.0 - tokens) / self.refill_per_sec))
+        }
+    }
+
+    /// Espera hasta obtener un token. Devuelve `true` si tuvo que esperar.
+    pub async fn acquire(&self) -> bool {
+        let mut waited = false;
+        while let Err(wait) = self.try_take(Instant::now()) {
+            waited = true;
+            tokio::time::sleep(wait).await;
+        }
+        waited
+    }
+}
+
+/// Turno para una lectura de oráculo: con `ORACLE_MAX_RPS` las lecturas se encolan en vez de salir en ráfaga.
+async fn throttle_oracle_read() {
+    let Some(limiter) = ORACLE_LIMITER.as_ref() else { return };
+    instance::labeled(&ORACLE_QUEUE_DEPTH).inc();
+    if limiter.acquire().await {
+        instance::labeled(&ORACLE_THROTTLED).inc();
+    }
+    instance::labeled(&ORACLE_QUEUE_DEPTH).dec();
+}
+
+/// Middleware de la capa de oráculo: cada `eth_call` que llega al nodo pasa por el limitador de
+/// `ORACLE_MAX_RPS`. Las lecturas que `feeds`/`OracleMap` sirven desde su caché no consumen turno.
+#[derive(Debug)]
+pub struct OracleThrottled<M>(M);
+
+impl<M: Middleware> OracleThrottled<M> {
+    pub fn new(inner: M) -> Self {
+        OracleThrottled(inner)
+    }
+}
+
+/// Error de `OracleThrottled`: siempre el de la capa inferior.
+#[derive(Debug)]
+pub struct OracleThrottledError<M: Middleware>(M::Error);
+
+impl<M: Middleware> std::fmt::Display for OracleThrottledError<M> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+impl<M: Middleware> std::error::Error for OracleThrottledError<M> {}
+
+impl<M: Middleware> MiddlewareError for OracleThrottledError<M> {
+    type Inner = M::Error;
+
+    fn from_err(e: M::Error) -> Self {
+        OracleThrottledError(e)
+    }
+
+    fn as_inner(&self) -> Option<&M::Error> {
+        Some(&self.0)
+    }
+}
+
+#[async_trait::async_trait]
+impl<M: Middleware> Middleware for OracleThrottled<M> {
+    type Error = OracleThrottledError<M>;
+    type Provider = M::Provider;
+    type Inner = M;
+
+    fn inner(&self) -> &M {
+        &self.0
+    }
+
+    async fn call(&self, tx: &TypedTransaction, block: Option<BlockId>) -> std::result::Result<Bytes, Self::Error> {
+        throttle_oracle_read().await;
+        self.0.call(tx, block).await.map_err(OracleThrottledError)
+    }
+}
 
 /// Establece la conexión principal con el proveedor RPC (HTTP).
 /// Esta conexión se usará para todas las consultas on-chain y el envío de transacciones.
@@ -20,21 +167,444 @@ pub fn connect_provider() -> Result<Arc<Provider<Http>>> {
     Ok(Arc::new(provider))
 }
 
+/// Comprueba si el nodo expone el bloque `pending`. Si no, las simulaciones siguen contra `latest`.
+pub async fn detect_pending_support<M: Middleware>(provider: &M) -> bool {
+    let supported = matches!(provider.get_block(BlockNumber::Pending).await, Ok(Some(_)));
+    PENDING_STATE_SUPPORTED.store(supported, Ordering::Relaxed);
+    supported
+}
+
+/// Comprueba que el nodo está en la chain configurada: firmar con un `CHAIN_ID` distinto al de la red
+/// produce TX que fallan o, peor, que podrían reproducirse en otra red.
+pub async fn verify_chain_id<M: Middleware>(provider: &M, label: &str) -> Result<()> {
+    let reported = provider.get_chainid().await
+        .map_err(|e| Error::msg(format!("No se pudo leer el chain id del proveedor {label}: {e}")))?;
+    check_chain_id(label, reported, CONFIG.chain_id)
+}
+
+/// Compara el chain id reportado por un proveedor con el esperado.
+pub fn check_chain_id(label: &str, reported: U256, expected: u64) -> Result<()> {
+    if reported != U256::from(expected) {
+        return Err(Error::msg(format!(
+            "El proveedor {label} está en la chain {reported} pero CHAIN_ID es {expected}. Revisa la URL del nodo o la configuración."
+        )));
+    }
+    Ok(())
+}
+
+/// Tiempo de bloque medio (ms) entre dos bloques `(número, timestamp en s)`; `None` si el intervalo
+/// es vacío o los timestamps no avanzan.
+pub fn measured_block_time_ms(from: (u64, u64), to: (u64, u64)) -> Option<u64> {
+    let blocks = to.0.checked_sub(from.0).filter(|b| *b > 0)?;
+    let elapsed_secs = to.1.checked_sub(from.1).filter(|s| *s > 0)?;
+    Some(elapsed_secs * 1000 / blocks)
+}
+
+/// Mide el tiempo de bloque sobre los últimos `BLOCK_TIME_SAMPLE_BLOCKS` bloques y avisa si se aleja
+/// más de `BLOCK_TIME_DIVERGENCE_WARN_RATIO` de `block_time_ms`.
+pub async fn measure_block_time<M: Middleware>(provider: &M) -> Option<u64> {
+    let latest = provider.get_block_number().await.ok()?.as_u64();
+    let start = latest.saturating_sub(BLOCK_TIME_SAMPLE_BLOCKS);
+    let head = provider.get_block(latest).await.ok()??;
+    let first = provider.get_block(start).await.ok()??;
+    let measured = measured_block_time_ms((start, first.timestamp.as_u64()), (latest, head.timestamp.as_u64()))?;
+    let divergence = (measured as f64 - CONFIG.block_time_ms as f64).abs() / CONFIG.block_time_ms.max(1) as f64;
+    if divergence > BLOCK_TIME_DIVERGENCE_WARN_RATIO {
+        warn!(
+            "Tiempo de bloque medido {} ms (últimos {} bloques) frente a BLOCK_TIME_MS = {} ms. Revisa la configuración de la chain.",
+            measured, latest - start, CONFIG.block_time_ms
+        );
+    } else {
+        info!("Tiempo de bloque medido: {} ms (configurado {} ms).", measured, CONFIG.block_time_ms);
+    }
+    Some(measured)
+}
+
+/// Anota el head del stream de bloques, la referencia autoritativa cuando los nodos divergen.
+pub fn set_stream_head(block_number: u64) {
+    STREAM_HEAD.store(block_number, Ordering::Relaxed);
+}
+
+/// Compara el head del nodo HTTP con el del stream WS. Más allá de `head_divergence_tolerance_blocks`
+/// (nodos distintos desincronizados) avisa y fija las simulaciones a un bloque explícito hasta que vuelvan
+/// a coincidir (ver `simulation_block`). Devuelve si quedan fijadas.
+pub fn check_head_divergence(stream_head: u64, http_head: u64) -> bool {
+    HTTP_HEAD.store(http_head, Ordering::Relaxed);
+    let divergence = stream_head.abs_diff(http_head);
+    instance::labeled(&HEAD_DIVERGENCE_BLOCKS).set(divergence as i64);
+    let pinned = divergence > CONFIG.head_divergence_tolerance_blocks;
+    let was_pinned = PINNED_TO_STREAM_HEAD.swap(pinned, Ordering::Relaxed);
+    if pinned {
+        warn!(
+            "Head del nodo HTTP (#{}) a {} bloques del stream WS (#{}), tolerancia {}: simulaciones fijadas al bloque #{}.",
+            http_head, divergence, stream_head, CONFIG.head_divergence_tolerance_blocks, stream_head.min(http_head)
+        );
+    } else if was_pinned {
+        info!("Heads HTTP (#{http_head}) y WS (#{stream_head}) de nuevo sincronizados: simulaciones sin fijar.");
+    }
+    pinned
+}
+
+/// Lee el head del nodo HTTP y lo compara con el último head del stream (ver `check_head_divergence`).
+pub async fn refresh_head_divergence<M: Middleware>(provider: &M) {
+    let stream_head = STREAM_HEAD.load(Ordering::Relaxed);
+    if CONFIG.head_divergence_tolerance_blocks == 0 || stream_head == 0 { return; }
+    match provider.get_block_number().await {
+        Ok(http_head) => { check_head_divergence(stream_head, http_head.as_u64()); }
+        Err(e) => debug!("No se pudo leer el head del nodo HTTP: {e}"),
+    }
+}
+
+/// Si las simulaciones están fijadas a un bloque por divergencia de heads; mientras lo estén, el head
+/// HTTP se vuelve a leer en cada bloque.
+pub fn simulations_pinned() -> bool {
+    PINNED_TO_STREAM_HEAD.load(Ordering::Relaxed)
+}
+
+/// Bloque contra el que se simulan las rutas: si el nodo HTTP diverge del stream, el menor de los dos heads
+/// (el head del stream, salvo que el nodo HTTP aún no lo tenga: simular contra él fallaría siempre);
+/// si no, `pending` si está activado y el nodo lo soporta, o `None` (último confirmado).
+pub fn simulation_block() -> Option<BlockId> {
+    let stream_head = STREAM_HEAD.load(Ordering::Relaxed);
+    if PINNED_TO_STREAM_HEAD.load(Ordering::Relaxed) && stream_head > 0 {
+        let http_head = HTTP_HEAD.load(Ordering::Relaxed);
+        let block = if http_head > 0 { stream_head.min(http_head) } else { stream_head };
+        return Some(BlockId::Number(BlockNumber::Number(block.into())));
+    }
+    (CONFIG.simulate_pending && PENDING_STATE_SUPPORTED.load(Ordering::Relaxed))
+        .then_some(BlockId::Number(BlockNumber::Pending))
+}
+
 /// Estima el gas para una llamada de contrato con una lógica de reintentos y un margen de seguridad.
+/// Un revert limpio significa que la oportunidad ya no existe: se devuelve error (sin fallback)
+/// para no enviar una TX condenada y que la ruta entre en cooldown.
 pub async fn estimate_gas<M: Middleware>(
     call: &ContractCall<M, ()>,
+    fallback_gas: u64,
 ) -> Result<U256> {
+    // Si después de 3 intentos la estimación falla por errores transitorios, usamos el fallback
+    // proporcional a los saltos de la ruta (`Config::gas_for_hops`).
+    Ok(try_estimate_gas(call).await?.unwrap_or_else(|| U256::from(fallback_gas)))
+}
+
+/// Selector de `Error(string)`, el revert estándar de Solidity.
+const ERROR_STRING_SELECTOR: [u8; 4] = [0x08, 0xc3, 0x79, 0xa0];
+
+/// Decodifica el motivo de un revert: el mensaje de `Error(string)` o los bytes en hex.
+pub fn decode_revert_reason(data: &[u8]) -> String {
+    if data.len() >= 4 && data[..4] == ERROR_STRING_SELECTOR {
+        if let Ok(tokens) = ethers::abi::decode(&[ethers::abi::ParamType::String], &data[4..]) {
+            if let Some(reason) = tokens.into_iter().next().and_then(|t| t.into_string()) {
+                return reason;
+            }
+        }
+    }
+    if data.is_empty() { "sin datos".to_string() } else { Bytes::from(data.to_vec()).to_string() }
+}
+
+/// Simulación que revierte, con el motivo decodificado: la oportunidad ya no existe. Se distingue de
+/// los fallos de RPC con `downcast_ref` para registrar el motivo por ruta.
+#[derive(Debug)]
+pub struct Reverted {
+    pub stage: &'static str,
+    pub reason: String,
+}
+
+impl std::fmt::Display for Reverted {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} revierte ({}): la oportunidad ya no existe", self.stage, self.reason)
+    }
+}
+
+impl std::error::Error for Reverted {}
+
+/// Motivo del revert de una TX ya minada, re-ejecutándola contra el bloque en que falló.
+pub async fn onchain_revert_reason<M: Middleware>(provider: &M, tx_hash: TxHash, block_number: U64) -> Option<String> {
+    let tx = provider.get_transaction(tx_hash).await.ok()??;
+    let typed: TypedTransaction = (&tx).into();
+    let Err(e) = provider.call(&typed, Some(BlockId::from(block_number))).await else { return None };
+    let response = e.as_error_response()?;
+    Some(response
+        .as_revert_data()
+        .filter(|data| !data.is_empty())
+        .map_or_else(|| response.message.clone(), |data| decode_revert_reason(&data)))
+}
+
+/// Motivo del revert si el error de la llamada es un revert de ejecución (no un fallo de RPC).
+pub fn revert_reason<M: Middleware>(error: &ContractError<M>) -> Option<String> {
+    if let Some(data) = error.as_revert() {
+        return Some(decode_revert_reason(data));
+    }
+    let response = error.as_middleware_error()?.as_error_response()?;
+    if !response.message.to_lowercase().contains("revert") { return None; }
+    let data = response.as_revert_data().unwrap_or_default();
+    Some(if data.is_empty() { response.message.clone() } else { decode_revert_reason(&data) })
+}
+
+/// Intenta estimar el gas hasta 3 veces. `Ok(None)` si todas fallan por errores transitorios;
+/// error si la llamada revierte.
+async fn try_estimate_gas<M: Middleware>(call: &ContractCall<M, ()>) -> Result<Option<U256>> {
     // Intenta estimar el gas hasta 3 veces con un pequeño delay entre intentos.
     for attempt in 0..3 {
-        if let Ok(gas) = call.estimate_gas().await {
+        match call.estimate_gas().await {
             // Si la estimación tiene éxito, le añadimos un buffer del 25% por seguridad.
             // Esto ayuda a prevenir que la transacción falle por cambios mínimos en el estado.
-            return Ok(gas * 125 / 100);
+            Ok(gas) => return Ok(Some(gas * 125 / 100)),
+            Err(e) => {
+                if let Some(reason) = revert_reason(&e) {
+                    return Err(Reverted { stage: "la estimación de gas", reason }.into());
+                }
+                debug!("Estimación de gas fallida (intento {}): {e}", attempt + 1);
+            }
         }
         tokio::time::sleep(Duration::from_millis(50 * (attempt + 1))).await;
     }
+    Ok(None)
+}
+
+/// Simula la TX firmada con `eth_callBundle` en el relay privado, que la ejecuta entera (flashloan
+/// incluido) sobre el estado del siguiente bloque y devuelve su `gasUsed`. `Ok(None)` si el relay no
+/// soporta el método o falla el RPC (se recurre a `estimate_gas`); error si la ejecución revierte.
+pub async fn simulate_bundle_gas<P: JsonRpcClient>(relay: &Provider<P>, raw: &Bytes, block_number: u64) -> Result<Option<U256>> {
+    let params = serde_json::json!([{
+        "txs": [raw],
+        "blockNumber": U64::from(block_number + 1),
+        "stateBlockNumber": "latest",
+    }]);
+    let response: serde_json::Value = match relay.request("eth_callBundle", params).await {
+        Ok(response) => response,
+        Err(e) => {
+            let Some(error) = RpcError::as_error_response(&e).filter(|r| r.message.to_lowercase().contains("revert")) else {
+                debug!("Simulación previa al envío no disponible: {e}");
+                return Ok(None);
+            };
+            let reason = error
+                .as_revert_data()
+                .filter(|data| !data.is_empty())
+                .map_or_else(|| error.message.clone(), |data| decode_revert_reason(&data));
+            return Err(Reverted { stage: "la simulación previa al envío", reason }.into());
+        }
+    };
+    let result = &response["results"][0];
+    if let Some(error) = result["error"].as_str() {
+        // El relay da el motivo ya decodificado o los datos del revert en hex.
+        let reason = match result["revert"].as_str() {
+            Some(revert) => revert.parse::<Bytes>().map_or_else(|_| revert.to_string(), |data| decode_revert_reason(&data)),
+            None => error.to_string(),
+        };
+        return Err(Reverted { stage: "la simulación previa al envío", reason }.into());
+    }
+    let gas_used = result["gasUsed"]
+        .as_u64()
+        .map(U256::from)
+        .or_else(|| serde_json::from_value::<U256>(result["gasUsed"].clone()).ok());
+    if gas_used.is_none() {
+        debug!("Simulación previa al envío sin gasUsed: {response}");
+    }
+    Ok(gas_used)
+}
+
+/// Como `estimate_gas`, pero reutiliza la estimación de la misma ruta si tiene menos de
+/// `gas_estimate_cache_blocks` bloques y el monto no varió más de `GAS_CACHE_MAX_AMOUNT_DRIFT_BPS`.
+/// Sólo se cachean estimaciones reales; el fallback nunca se reutiliza.
+pub async fn estimate_gas_cached<M: Middleware>(
+    call: &ContractCall<M, ()>,
+    route_key: &str,
+    amount_in: U256,
+    block_number: u64,
+    fallback_gas: u64,
+) -> Result<U256> {
+    if CONFIG.gas_estimate_cache_blocks == 0 {
+        return estimate_gas(call, fallback_gas).await;
+    }
+    let cached = GAS_ESTIMATE_CACHE.lock().unwrap().get(route_key).copied();
+    if let Some(entry) = cached {
+        let fresh = block_number.saturating_sub(entry.block) < CONFIG.gas_estimate_cache_blocks;
+        let drift = if amount_in > entry.amount_in { amount_in - entry.amount_in } else { entry.amount_in - amount_in };
+        let similar = !entry.amount_in.is_zero()
+            && drift * U256::from(10_000) <= entry.amount_in * U256::from(GAS_CACHE_MAX_AMOUNT_DRIFT_BPS);
+        if fresh && similar {
+            instance::labeled(&GAS_CACHE_HITS).inc();
+            debug!("Gas de la ruta {} servido desde caché (bloque {}).", route_key, entry.block);
+            return Ok(entry.gas);
+        }
+    }
+    match try_estimate_gas(call).await? {
+        Some(gas) => {
+            cache_gas_estimate(route_key, gas, amount_in, block_number);
+            Ok(gas)
+        }
+        None => Ok(U256::from(fallback_gas)),
+    }
+}
+
+pub(crate) fn cache_gas_estimate(route_key: &str, gas: U256, amount_in: U256, block_number: u64) {
+    let mut cache = GAS_ESTIMATE_CACHE.lock().unwrap();
+    cache.retain(|_, e| block_number.saturating_sub(e.block) < CONFIG.gas_estimate_cache_blocks);
+    cache.insert(route_key.to_string(), CachedGasEstimate { gas, amount_in, block: block_number });
+}
+
+/// Última estimación real de gas de la ruta, si sigue en caché: el cálculo de profit la prefiere al
+/// modelo por saltos.
+pub fn cached_route_gas(route_key: &str) -> Option<u64> {
+    GAS_ESTIMATE_CACHE.lock().unwrap().get(route_key).map(|e| e.gas.low_u64())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{MockChain, ESTIMATED_GAS};
+
+    #[test]
+    fn decode_revert_reason_reads_error_string() {
+        let mut data = ERROR_STRING_SELECTOR.to_vec();
+        data.extend(ethers::abi::encode(&[ethers::abi::Token::String("STF".to_string())]));
+        assert_eq!(decode_revert_reason(&data), "STF");
+        assert_eq!(decode_revert_reason(&[]), "sin datos");
+        assert_eq!(decode_revert_reason(&[0xde, 0xad, 0xbe, 0xef]), "0xdeadbeef");
+        // Selector correcto con datos truncados: se devuelven los bytes.
+        assert_eq!(decode_revert_reason(&ERROR_STRING_SELECTOR), "0x08c379a0");
+    }
+
+    #[tokio::test]
+    async fn estimate_gas_falls_back_only_on_transient_errors() {
+        let target = H160::from_low_u64_be(0xa4b);
+        let estimate = |chain: MockChain| async move {
+            let abi = ethers::abi::parse_abi(&["function executeArbitrage() external"]).unwrap();
+            let call = Contract::new(target, abi, chain.provider()).method::<_, ()>("executeArbitrage", ()).unwrap();
+            estimate_gas(&call, 2_000_000).await
+        };
+
+        let healthy = MockChain::new().returns(target, "executeArbitrage()", &[], &[]);
+        assert_eq!(estimate(healthy).await.unwrap(), U256::from(ESTIMATED_GAS * 125 / 100));
+        // La oportunidad ya no existe: se aborta el envío en vez de mandar 2M de gas a revertir.
+        let error = estimate(MockChain::new()).await.unwrap_err();
+        assert!(error.downcast_ref::<Reverted>().is_some(), "{error:?}");
+        // Un fallo del nodo no dice nada de la oportunidad: se usa el gas de fallback.
+        let flaky = MockChain::new().errors(target, "executeArbitrage()", &[], "header not found");
+        assert_eq!(estimate(flaky).await.unwrap(), U256::from(2_000_000));
+    }
+
+
+    #[test]
+    fn check_chain_id_compares_reported_and_expected() {
+        assert!(check_chain_id("HTTP", U256::from(42161), 42161).is_ok());
+        let error = check_chain_id("WS", U256::from(1), 42161).unwrap_err().to_string();
+        assert!(error.contains("WS") && error.contains("42161"));
+    }
+
+    #[tokio::test]
+    async fn verify_chain_id_rejects_a_node_on_another_chain() {
+        use crate::instance::with_test_config;
+
+        // El mock responde en orden inverso al de inserción: primero 42161, luego 10.
+        let (provider, mock) = Provider::mocked();
+        mock.push(U256::from(10)).unwrap();
+        mock.push(U256::from(42161)).unwrap();
+        with_test_config(|c| c.chain_id = 42161, async {
+            assert!(verify_chain_id(&provider, "HTTP").await.is_ok());
+            assert!(verify_chain_id(&provider, "HTTP").await.is_err());
+        })
+        .await;
+    }
 
-    // Si después de 3 intentos la estimación falla, usamos el valor de fallback
-    // definido en nuestra configuración. Es un valor alto para asegurar la ejecución.
-    Ok(U256::from(CONFIG.gas_limit))
+
+    #[test]
+    fn token_bucket_allows_bursts_and_refills() {
+        let bucket = TokenBucket::new(2.0, 2.0);
+        let start = Instant::now();
+        assert_eq!(bucket.try_take(start), Ok(()));
+        assert_eq!(bucket.try_take(start), Ok(()));
+        let wait = bucket.try_take(start).unwrap_err();
+        assert!((wait.as_secs_f64() - 0.5).abs() < 1e-6);
+        assert_eq!(bucket.try_take(start + Duration::from_millis(500)), Ok(()));
+        // El relleno no pasa de la capacidad aunque pase mucho tiempo.
+        let later = start + Duration::from_secs(60);
+        assert_eq!(bucket.try_take(later), Ok(()));
+        assert_eq!(bucket.try_take(later), Ok(()));
+        assert!(bucket.try_take(later).is_err());
+    }
+
+    #[tokio::test]
+    async fn oracle_limiter_only_spends_turns_on_reads_that_reach_the_node() {
+        use crate::{config::OracleFeed, instance::with_test_config, oracle::OracleMap};
+
+        // Feed configurado para el token 1; el 2 no tiene feed y su lectura no toca el nodo.
+        let (token, aggregator, unpriced) = (H160::from_low_u64_be(1), H160::from_low_u64_be(50), H160::from_low_u64_be(2));
+        let chain = MockChain::new().latest_round_data(aggregator, 2_000 * 10i64.pow(8), crate::pools::unix_now()).decimals(aggregator, 8);
+        let edit = move |c: &mut crate::config::Config| {
+            c.oracle_feeds = HashMap::from([(token, OracleFeed::Chainlink(aggregator))]);
+            c.oracle_max_rps = 100.0;
+            c.oracle_burst = 1;
+        };
+        let (unpriced_waits, priced_waits, price) = with_test_config(edit, async {
+            let (provider, oracle_map) = (chain.provider(), OracleMap::new());
+            let throttled = || instance::labeled(&ORACLE_THROTTLED).get();
+            let before = throttled();
+            for _ in 0..3 {
+                assert!(crate::feeds::get_price(&oracle_map, &unpriced, provider.clone()).await.is_none());
+            }
+            let unpriced_waits = throttled() - before;
+            let mut price = None;
+            for _ in 0..2 {
+                price = crate::feeds::get_price(&oracle_map, &token, provider.clone()).await.map(|info| info.price);
+            }
+            (unpriced_waits, throttled() - before - unpriced_waits, price)
+        })
+        .await;
+
+        assert_eq!(price, Some(2_000.0));
+        assert_eq!(unpriced_waits, 0);
+        // Dos lecturas de `latestRoundData` + `decimals` con ráfaga de 1: las tres últimas esperan turno.
+        assert_eq!((chain.calls_to("latestRoundData()"), chain.calls_to("decimals()")), (2, 2));
+        assert_eq!(priced_waits, 3);
+    }
+
+
+    #[test]
+    fn measured_block_time_averages_the_interval() {
+        assert_eq!(measured_block_time_ms((100, 1_000), (110, 1_020)), Some(2_000));
+        assert_eq!(measured_block_time_ms((100, 1_000), (400, 1_075)), Some(250));
+        assert_eq!(measured_block_time_ms((100, 1_000), (100, 1_020)), None);
+        assert_eq!(measured_block_time_ms((100, 1_000), (110, 1_000)), None);
+        assert_eq!(measured_block_time_ms((110, 1_000), (100, 1_020)), None);
+    }
+
+    #[tokio::test]
+    async fn block_time_is_measured_over_the_last_blocks() {
+        let (provider, mock) = Provider::mocked();
+        let block = |number: u64, timestamp: u64| Block::<TxHash> { number: Some(number.into()), timestamp: timestamp.into(), ..Default::default() };
+        // El mock responde en orden inverso: número del head, bloque head y bloque 100 atrás.
+        mock.push(block(9_900, 1_000)).unwrap();
+        mock.push(block(10_000, 1_025)).unwrap();
+        mock.push(U64::from(10_000)).unwrap();
+
+        let measured = crate::instance::with_test_config(|c| c.block_time_ms = 2_000, measure_block_time(&provider)).await;
+        assert_eq!(measured, Some(250));
+    }
+
+
+    #[tokio::test]
+    async fn divergent_heads_pin_simulations_to_a_block_both_nodes_have() {
+        let (provider, mock) = Provider::mocked();
+        let pinned_at = |block: u64| Some(BlockId::Number(BlockNumber::Number(block.into())));
+        let states = crate::instance::with_test_config(|c| c.head_divergence_tolerance_blocks = 2, async {
+            let mut states = Vec::new();
+            // HTTP retrasado, HTTP adelantado y, por último, dentro de la tolerancia.
+            for (stream_head, http_head) in [(1_000u64, 990u64), (1_001, 1_010), (1_002, 1_001)] {
+                set_stream_head(stream_head);
+                mock.push(U64::from(http_head)).unwrap();
+                refresh_head_divergence(&provider).await;
+                states.push((simulations_pinned(), instance::labeled(&HEAD_DIVERGENCE_BLOCKS).get(), simulation_block()));
+            }
+            states
+        })
+        .await;
+
+        // Con el nodo HTTP por detrás se simula en su head, que sí tiene; por delante, en el del stream.
+        assert_eq!(states[0], (true, 10, pinned_at(990)));
+        assert_eq!(states[1], (true, 9, pinned_at(1_001)));
+        // De nuevo sincronizados, sin bloque fijado (sin `pending`).
+        assert_eq!(states[2], (false, 1, None));
+    }
 }
@@ -0,0 +1,143 @@
+use crate::{
+    optimization::{self, ArbitrageOpportunity, RouteHistory, ROUTE_STATS},
+    paths::ArbPath,
+    pools::{self, PoolMetrics, POOL_METRICS},
+    types::Pool,
+};
+use anyhow::Result;
+use ethers::prelude::*;
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    fs::{self, File},
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+/// Entradas de evaluación de una ruta en el bloque capturado.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CapturedPath {
+    pub key: String,
+    pub pools: [H160; 3],
+    pub tokens: [H160; 3],
+    pub oracle_price: f64,
+    pub lag: f64,
+    pub history: Option<RouteHistory>,
+}
+
+/// Todo lo necesario para re-ejecutar la evaluación de un bloque: cabecera, datos de pools,
+/// precios de oráculo usados y rutas evaluadas.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CapturedBlock {
+    pub block: Block<H256>,
+    pub block_number: u64,
+    pub base_fee: U256,
+    pub eth_price: f64,
+    pub pools: HashMap<H160, PoolMetrics>,
+    pub paths: Vec<CapturedPath>,
+}
+
+/// Precio de oráculo y lag vistos por ruta (clave de la ruta) durante la evaluación del bloque.
+pub type OracleSeen = HashMap<String, (f64, f64)>;
+
+/// Serializa el estado de evaluación del bloque a `{dir}/block_{n}.json`.
+/// Sólo se capturan las rutas con precio de oráculo observado (las únicas que llegaron a optimizarse).
+pub fn capture_block(
+    dir: &str,
+    block: &Block<H256>,
+    base_fee: U256,
+    eth_price: f64,
+    paths: &[ArbPath],
+    oracle_seen: &OracleSeen,
+) -> Result<PathBuf> {
+    let block_number = block.number.unwrap_or_default().as_u64();
+    let stats = ROUTE_STATS.lock().unwrap().clone();
+    let captured_paths = paths
+        .iter()
+        .filter_map(|p| {
+            let key = p.key();
+            let (oracle_price, lag) = *oracle_seen.get(&key)?;
+            Some(CapturedPath {
+                history: stats.get(&key).cloned(),
+                key,
+                pools: [p.pool_1.address, p.pool_2.address, p.pool_3.address],
+                tokens: [p.token_a, p.token_b, p.token_c],
+                oracle_price,
+                lag,
+            })
+        })
+        .collect();
+    let captured = CapturedBlock {
+        block: block.clone(),
+        block_number,
+        base_fee,
+        eth_price,
+        pools: POOL_METRICS.lock().unwrap().clone(),
+        paths: captured_paths,
+    };
+
+    fs::create_dir_all(dir)?;
+    let file = PathBuf::from(dir).join(format!("block_{block_number}.json"));
+    serde_json::to_writer_pretty(File::create(&file)?, &captured)?;
+    info!(" Bloque #{block_number} capturado en {:?} ({} rutas)", file, captured.paths.len());
+    Ok(file)
+}
+
+/// Reconstruye una ruta capturada a partir de la caché de pools y los datos en vivo capturados.
+fn rebuild_path(captured: &CapturedPath, cache: &HashMap<H160, Pool>, metrics: &HashMap<H160, PoolMetrics>) -> Option<ArbPath> {
+    let hop = |i: usize| -> Option<Pool> {
+        let mut pool = cache.get(&captured.pools[i])?.clone();
+        if let Some(m) = metrics.get(&pool.address) {
+            pool.tvl_usd = m.effective_tvl_usd;
+            if m.raw.fee != 0 { pool.fee = m.raw.fee; }
+        }
+        Some(pool)
+    };
+    Some(ArbPath {
+        pool_1: hop(0)?,
+        pool_2: hop(1)?,
+        pool_3: hop(2)?,
+        token_a: captured.tokens[0],
+        token_b: captured.tokens[1],
+        token_c: captured.tokens[2],
+        score: 0.0,
+    })
+}
+
+/// Re-ejecuta la optimización de un bloque capturado con los precios capturados y las simulaciones
+/// fijadas a ese bloque (requiere un nodo con estado histórico). Devuelve las oportunidades encontradas.
+pub async fn replay_captured_block<M: Middleware + 'static>(file: &Path, provider: Arc<M>) -> Result<Vec<ArbitrageOpportunity>> {
+    let captured: CapturedBlock = serde_json::from_reader(File::open(file)?)?;
+    info!(" Replay del bloque #{} con {} rutas...", captured.block_number, captured.paths.len());
+
+    let cache: HashMap<H160, Pool> = pools::read_pool_cache()?.into_iter().map(|p| (p.address, p)).collect();
+    *POOL_METRICS.lock().unwrap() = captured.pools.clone();
+    let pinned = Some(BlockId::Number(BlockNumber::Number(captured.block_number.into())));
+
+    let mut opportunities = Vec::new();
+    for captured_path in &captured.paths {
+        let mut path = match rebuild_path(captured_path, &cache, &captured.pools) {
+            Some(path) => path,
+            None => {
+                warn!("No se pudo reconstruir la ruta {}: pool ausente en la caché.", captured_path.key);
+                continue;
+            }
+        };
+        if let Some(history) = &captured_path.history {
+            ROUTE_STATS.lock().unwrap().insert(captured_path.key.clone(), history.clone());
+        }
+        if let Some(opp) = optimization::optimize_with_prices(
+            provider.clone(), &mut path, captured.base_fee, captured_path.oracle_price, captured_path.lag,
+            captured.eth_price, captured.block_number, pinned,
+        ).await {
+            info!(
+                "  {} | in {} | out {} | profit ${:.2} | score {:.2}",
+                captured_path.key, opp.optimal_amount_in, opp.expected_output, opp.net_profit_usd, opp.score
+            );
+            opportunities.push(opp);
+        }
+    }
+    info!(" Replay completado: {} oportunidades de {} rutas.", opportunities.len(), captured.paths.len());
+    Ok(opportunities)
+}
@@ -1,11 +1,57 @@
-use crate::constants::{PANCAKESWAP_V3_QUOTER, SUSHISWAP_V3_QUOTER, UNISWAP_V3_QUOTER};
-use crate::types::DexVariant;
-use anyhow::Result;
+use crate::config::CONFIG;
+use crate::instance::{self, InstanceLocal};
+use crate::pools;
+use crate::types::{DexVariant, Pool};
+use anyhow::{anyhow, Result};
 use ethers::{
     prelude::*,
     types::{H160, U256},
 };
-use std::sync::Arc;
+use lazy_static::lazy_static;
+use log::{info, warn};
+use prometheus::{register_int_counter_vec, IntCounterVec};
+use std::{
+    collections::HashSet,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+};
+
+// `eth_call`s de cotización consumidos en el bloque actual, y si ya se avisó del agotamiento.
+static QUOTES_THIS_BLOCK: InstanceLocal<AtomicU64> = InstanceLocal::new(|| AtomicU64::new(0));
+static BUDGET_EXHAUSTED_LOGGED: InstanceLocal<AtomicBool> = InstanceLocal::new(|| AtomicBool::new(false));
+
+// Fracción del balance de token0 del pool que se cotiza en la comprobación de quoters (impacto despreciable).
+const QUOTER_CHECK_BALANCE_DIVISOR: u64 = 10_000;
+// Rango aceptable de la cotización de prueba relativa al precio spot del pool (fee e impacto incluidos).
+const QUOTER_CHECK_MIN_RATIO: f64 = 0.5;
+const QUOTER_CHECK_MAX_RATIO: f64 = 1.5;
+
+lazy_static! {
+    static ref QUOTES_OVER_BUDGET: IntCounterVec = register_int_counter_vec!("quotes_over_rpc_budget_total", "Cotizaciones omitidas por agotar el presupuesto de eth_call del bloque", &["chain"]).unwrap();
+}
+
+// Quoters desactivados por la comprobación de arranque: sus pools no entran en la generación de rutas.
+static DISABLED_QUOTERS: InstanceLocal<Mutex<HashSet<H160>>> = InstanceLocal::new(|| Mutex::new(HashSet::new()));
+
+/// Reinicia el presupuesto de `eth_call`s; se llama al empezar cada bloque.
+pub fn reset_rpc_budget() {
+    QUOTES_THIS_BLOCK.store(0, Ordering::Relaxed);
+    BUDGET_EXHAUSTED_LOGGED.store(false, Ordering::Relaxed);
+}
+
+/// Reserva una cotización del presupuesto del bloque. `false` si ya se agotó (0 = sin límite).
+fn take_rpc_budget() -> bool {
+    let limit = CONFIG.max_eth_calls_per_block;
+    if limit == 0 { return true; }
+    if QUOTES_THIS_BLOCK.fetch_add(1, Ordering::Relaxed) < limit { return true; }
+    instance::labeled(&QUOTES_OVER_BUDGET).inc();
+    if !BUDGET_EXHAUSTED_LOGGED.swap(true, Ordering::Relaxed) {
+        warn!("Presupuesto de {} eth_call por bloque agotado: el resto de cotizaciones se omiten hasta el próximo bloque.", limit);
+    }
+    false
+}
 
 // CORRECCIÓN FINAL: El ABI debe listar los parámetros de forma individual, no dentro de un `params` struct.
 abigen!(
@@ -13,11 +59,58 @@ abigen!(
     r#"[{"name":"quoteExactInputSingle","type":"function","stateMutability":"nonpayable","inputs":[{"name":"tokenIn","type":"address"},{"name":"tokenOut","type":"address"},{"name":"fee","type":"uint24"},{"name":"amountIn","type":"uint256"},{"name":"sqrtPriceLimitX96","type":"uint160"}],"outputs":[{"name":"amountOut","type":"uint256"}]}]"#,
 );
 
+abigen!(
+    IQuoterMultiHop,
+    r#"[function quoteExactInput(bytes path, uint256 amountIn) external returns (uint256 amountOut)]"#,
+);
+
+/// Quoter de `variant` en la chain configurada; la dirección cero si el DEX no está desplegado en ella.
 pub fn get_quoter_address(variant: DexVariant) -> H160 {
-    match variant {
-        DexVariant::UniswapV3 => *UNISWAP_V3_QUOTER,
-        DexVariant::SushiV3 => *SUSHISWAP_V3_QUOTER,
-        DexVariant::PancakeV3 => *PANCAKESWAP_V3_QUOTER,
+    CONFIG.dex(variant).map_or_else(H160::zero, |dex| dex.quoter)
+}
+
+/// Si el DEX está configurado en la chain y sigue activo (su quoter no falló la comprobación de arranque).
+pub fn is_dex_enabled(variant: DexVariant) -> bool {
+    CONFIG.dex(variant).is_some_and(|dex| !DISABLED_QUOTERS.lock().unwrap().contains(&dex.quoter))
+}
+
+/// Comprobación de arranque de los quoters: cotiza una fracción pequeña del pool más líquido de cada DEX
+/// y desactiva el DEX si el quoter revierte o devuelve algo lejos del precio spot. Un quoter de otra chain
+/// haría revertir en silencio todas las cotizaciones de sus pools en cada bloque.
+pub async fn verify_quoters<M: Middleware + 'static>(provider: Arc<M>, pools: &[Pool]) {
+    for variant in [DexVariant::UniswapV3, DexVariant::SushiV3, DexVariant::PancakeV3] {
+        let same = |v: DexVariant| std::mem::discriminant(&v) == std::mem::discriminant(&variant);
+        let dex_pools: Vec<&Pool> = pools.iter().filter(|p| same(p.version)).collect();
+        if dex_pools.is_empty() { continue; }
+        if dex_pools.len() < CONFIG.min_pools_per_dex {
+            warn!(
+                "Sólo {} pools cargados para {:?} (mínimo {}): su factory o quoter puede no ser de esta chain.",
+                dex_pools.len(), variant, CONFIG.min_pools_per_dex
+            );
+        }
+        let Some(pool) = dex_pools.into_iter().max_by(|a, b| a.tvl_usd.total_cmp(&b.tvl_usd)) else { continue };
+        let Some(metrics) = pools::pool_metrics(&pool.address) else { continue };
+        let amount_in = metrics.raw.balance0 / U256::from(QUOTER_CHECK_BALANCE_DIVISOR);
+        if amount_in.is_zero() { continue; }
+        let spot_out = amount_in.to_string().parse::<f64>().unwrap_or(0.0) * pools::sqrt_price_f64(metrics.raw.sqrt_price_x96).powi(2);
+        let problem = match quote_exact_input_single(provider.clone(), variant, pool.token0, pool.token1, pool.fee, amount_in, None).await {
+            Err(e) => Some(format!("la cotización falla ({e})")),
+            Ok(out) => {
+                let ratio = out.to_string().parse::<f64>().unwrap_or(0.0) / spot_out;
+                (!(QUOTER_CHECK_MIN_RATIO..=QUOTER_CHECK_MAX_RATIO).contains(&ratio))
+                    .then(|| format!("devuelve {out} frente a ~{spot_out:.0} al precio spot"))
+            }
+        };
+        match problem {
+            Some(reason) => {
+                warn!(
+                    " Quoter de {:?} ({:?}) desactivado: {} en el pool {:?}. Sus pools se excluyen de las rutas; revisa la dirección en constants.rs.",
+                    variant, get_quoter_address(variant), reason, pool.address
+                );
+                DISABLED_QUOTERS.lock().unwrap().insert(get_quoter_address(variant));
+            }
+            None => info!("Quoter de {:?} verificado en el pool {:?}.", variant, pool.address),
+        }
     }
 }
 
@@ -28,15 +121,81 @@ pub async fn quote_exact_input_single<M: Middleware + 'static>(
     token_out: H160,
     fee: u32,
     amount_in: U256,
+    block: Option<BlockId>,
 ) -> Result<U256> {
+    if !take_rpc_budget() {
+        return Err(anyhow!("Presupuesto de eth_call del bloque agotado"));
+    }
     let quoter_address = get_quoter_address(variant);
     let quoter = IQuoterV2::new(quoter_address, provider);
 
     // CORRECCIÓN FINAL: Los parámetros se pasan directamente a la función.
-    let amount_out = quoter
-        .quote_exact_input_single(token_in, token_out, fee, amount_in, U256::zero())
-        .call()
-        .await?;
+    let mut call = quoter.quote_exact_input_single(token_in, token_out, fee, amount_in, U256::zero());
+    // Permite fijar el estado a un bloque concreto (replay determinista).
+    if let Some(block) = block {
+        call = call.block(block);
+    }
+    let amount_out = call.call().await?;
 
     Ok(amount_out)
 }
+
+/// Cotiza una ruta multi-hop codificada (`token, fee, token, ...`) con una sola llamada al quoter del DEX.
+pub async fn quote_exact_input<M: Middleware + 'static>(
+    provider: Arc<M>,
+    variant: DexVariant,
+    path: Bytes,
+    amount_in: U256,
+    block: Option<BlockId>,
+) -> Result<U256> {
+    if !take_rpc_budget() {
+        return Err(anyhow!("Presupuesto de eth_call del bloque agotado"));
+    }
+    let quoter = IQuoterMultiHop::new(get_quoter_address(variant), provider);
+    let mut call = quoter.quote_exact_input(path, amount_in);
+    if let Some(block) = block {
+        call = call.block(block);
+    }
+    Ok(call.call().await?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::OracleFeed;
+    use crate::instance::with_test_config;
+    use crate::multi::RawPoolData;
+    use crate::oracle::OracleMap;
+    use crate::paths::{generate_triangular_paths, tests::{addr, pool}};
+    use crate::pools::{PoolMetrics, POOL_METRICS};
+    use crate::testing::MockChain;
+    use std::collections::HashMap;
+
+    #[tokio::test]
+    async fn a_failing_or_absurd_quoter_takes_its_pools_out_of_the_routes() {
+        // Triángulo 1-2-3 en Uniswap V3; el pool 1-2 es el más líquido y el que se cotiza, a precio spot 1.
+        let pools = vec![pool(12, 1, 2, 2e6), pool(23, 2, 3, 1e6), pool(13, 1, 3, 1e6)];
+        let amount_in = U256::exp10(22) / U256::from(QUOTER_CHECK_BALANCE_DIVISOR);
+        let oracle_map = OracleMap::new();
+        let (pools, oracle_map) = (&pools, &oracle_map);
+        let check = |quoted: Option<U256>| {
+            let feeds: HashMap<H160, OracleFeed> = (1..=3).map(|t| (addr(t), OracleFeed::Chainlink(addr(t)))).collect();
+            with_test_config(move |c| c.oracle_feeds = feeds, async move {
+                let raw = RawPoolData { balance0: U256::exp10(22), sqrt_price_x96: U256::one() << 96, unlocked: true, ..Default::default() };
+                POOL_METRICS.lock().unwrap().insert(addr(12), PoolMetrics { raw, ..Default::default() });
+                let chain = match quoted {
+                    Some(out) => MockChain::new().quote_single(DexVariant::UniswapV3, addr(1), addr(2), 500, amount_in, out),
+                    None => MockChain::new().quote_single_reverts(DexVariant::UniswapV3, addr(1), addr(2), 500, amount_in),
+                };
+                verify_quoters(chain.provider(), pools).await;
+                (is_dex_enabled(DexVariant::UniswapV3), generate_triangular_paths(pools, addr(1), oracle_map).len())
+            })
+        };
+
+        // Una cotización cercana al spot (fee incluida) deja el DEX y las dos direcciones del ciclo.
+        assert_eq!(check(Some(amount_in * 997 / 1000)).await, (true, 2));
+        // Un quoter que revierte o devuelve diez veces el spot desactiva el DEX y vacía sus rutas.
+        assert_eq!(check(None).await, (false, 0));
+        assert_eq!(check(Some(amount_in * 10)).await, (false, 0));
+    }
+}
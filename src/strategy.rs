@@ -1,38 +1,119 @@
 use crate::{
-    config::CONFIG,
-    constants::{PANCAKESWAP_V3_FACTORY, SUSHISWAP_V3_FACTORY, UNISWAP_V3_FACTORY},
-    execution,
+    config::{pair_key, ExecutionStrategy, CONFIG},
+    constants::{OPPORTUNITY_BUNDLE_SIZE, PANCAKESWAP_V3_FACTORY, SUSHISWAP_V3_FACTORY, UNISWAP_V3_FACTORY},
+    execution, feeds,
+    instance::{self, InstanceLocal},
+    math::to_usd,
     optimization::{self, ArbitrageOpportunity, ROUTE_STATS},
-    oracle::{self, OracleMap},
+    oracle::OracleMap,
     paths::{self, generate_triangular_paths, ArbPath},
-    pools,
+    pools, replay, simulator,
+    telemetry,
     streams::Event,
     types::{DexVariant, Pool}, // Importación directa de Pool
 };
 use ethers::{prelude::*, types::U256};
 use futures_util::{stream::FuturesUnordered, StreamExt};
 use lazy_static::lazy_static;
-use log::{info, warn};
-use prometheus::{register_int_counter, register_int_gauge, IntCounter, IntGauge};
-use std::{collections::HashSet, sync::Arc};
-use tokio::sync::broadcast::Sender;
+use log::{debug, error, info, warn};
+use prometheus::{register_int_counter_vec, register_int_gauge_vec, IntCounterVec, IntGaugeVec};
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+};
+use tokio::sync::{broadcast::Receiver, OwnedSemaphorePermit, Semaphore};
 
+// La etiqueta `chain` de las métricas es el nombre de la instancia (`CHAIN_INSTANCES`, ver `instance::labeled`).
 lazy_static! {
-    static ref ROUTES_EVALUATED: IntCounter = register_int_counter!("routes_evaluated_total", "Total de rutas evaluadas").unwrap();
-    static ref TRADES_EXECUTED: IntCounter = register_int_counter!("trades_executed_total", "Total de trades enviados").unwrap();
-    static ref TRADES_FAILED: IntCounter = register_int_counter!("trades_failed_total", "Total de trades que fallaron").unwrap();
-    static ref CURRENT_PATHS: IntGauge = register_int_gauge!("current_paths_available", "Rutas de arbitraje disponibles").unwrap();
+    static ref ROUTES_EVALUATED: IntCounterVec = register_int_counter_vec!("routes_evaluated_total", "Total de rutas evaluadas", &["chain"]).unwrap();
+    static ref TRADES_EXECUTED: IntCounterVec = register_int_counter_vec!("trades_executed_total", "Total de trades enviados", &["chain"]).unwrap();
+    static ref TRADES_FAILED: IntCounterVec = register_int_counter_vec!("trades_failed_total", "Total de trades que fallaron", &["chain"]).unwrap();
+    static ref BLOCKS_PROCESSED: IntCounterVec = register_int_counter_vec!("blocks_processed_total", "Bloques procesados", &["chain"]).unwrap();
+    static ref CURRENT_PATHS: IntGaugeVec = register_int_gauge_vec!("current_paths_available", "Rutas de arbitraje disponibles", &["chain"]).unwrap();
+    static ref INFLIGHT_BUNDLES: IntGaugeVec = register_int_gauge_vec!("inflight_bundles", "Bundles enviados pendientes de resultado", &["chain"]).unwrap();
+    static ref REPRICE_DROPPED: IntCounterVec = register_int_counter_vec!("reprice_dropped_total", "Oportunidades descartadas al re-simular antes del envío", &["chain"]).unwrap();
+    static ref RUN_TRADES: IntGaugeVec = register_int_gauge_vec!("run_trades_submitted", "Trades enviados (o en envío) en esta ejecución del proceso", &["chain"]).unwrap();
+    static ref RUN_LIMIT_REACHED: IntGaugeVec = register_int_gauge_vec!("run_limit_reached", "1 si se alcanzó el tope de trades o pérdidas de esta ejecución", &["chain"]).unwrap();
+    static ref BUNDLES_DROPPED: IntCounterVec = register_int_counter_vec!("bundles_dropped_total", "Bundles descartados por límite de bundles en vuelo y en cola", &["chain"]).unwrap();
+    static ref REVERSE_DUPLICATES_DROPPED: IntCounterVec = register_int_counter_vec!("reverse_duplicates_dropped_total", "Oportunidades descartadas por ser el sentido inverso de un ciclo ya seleccionado", &["chain"]).unwrap();
+}
+
+// Refresco de pools/rutas pedido por el operador; se atiende en el siguiente bloque.
+static FORCE_REFRESH: InstanceLocal<AtomicBool> = InstanceLocal::new(|| AtomicBool::new(false));
+
+/// Pide un refresco completo de pools y rutas en el próximo bloque, ignorando `path_refresh_interval_blocks`.
+pub fn request_refresh() {
+    FORCE_REFRESH.store(true, Ordering::SeqCst);
+}
+
+/// Si toca refrescar pools y rutas en `block_number`: `Some(true)` si lo pidió el operador (la
+/// petición se consume), `Some(false)` si venció el intervalo y `None` si no toca.
+pub(crate) fn take_refresh_due(last_refresh_block: u64, block_number: u64, refresh_interval: u64) -> Option<bool> {
+    let manual = FORCE_REFRESH.swap(false, Ordering::SeqCst);
+    let due = last_refresh_block == 0 || block_number.saturating_sub(last_refresh_block) >= refresh_interval;
+    (manual || due).then_some(manual)
 }
 
-const OPPORTUNITY_BUNDLE_SIZE: usize = 5;
 const ROUTE_FAILURE_COOLDOWN_BLOCKS: u64 = 10;
+// Bloques durante los que el profit del último envío de una ruta sirve de referencia para reenviarla.
+const RESUBMIT_IMPROVEMENT_WINDOW_BLOCKS: u64 = 50;
+// Bloques hacia atrás en los que un swap grande cuenta como "muy reciente" para el detector de cebos.
+const BAIT_SWAP_LOOKBACK_BLOCKS: u64 = 2;
+// Hashes de bloques recientes guardados para detectar reorgs.
+const REORG_TRACKED_BLOCKS: usize = 64;
+// Bundles que esperan turno cuando ya hay `max_inflight_bundles` en vuelo; a partir de ahí se descartan.
+const MAX_QUEUED_BUNDLES: usize = 2;
+// Cada cuántos bloques se compara el head del nodo HTTP con el del stream WS.
+const HEAD_CHECK_INTERVAL_BLOCKS: u64 = 10;
+// Rutas listadas en el resumen periódico de PnL.
+const PNL_SUMMARY_TOP_ROUTES: usize = 5;
+// Cambio relativo de TVL entre refrescos a partir del cual un pool cuenta como movimiento de liquidez.
+const CHURN_LIQUIDITY_SHIFT_RATIO: f64 = 0.2;
+// Fracción de pools con cambios por encima de la cual se acorta el intervalo de refresco, y por debajo
+// de la cual se alarga.
+const CHURN_HIGH_RATIO: f64 = 0.05;
+const CHURN_LOW_RATIO: f64 = 0.01;
+
+/// Fracción de pools que cambiaron entre dos refrescos: altas, bajas y movimientos de TVL por encima
+/// de `CHURN_LIQUIDITY_SHIFT_RATIO`.
+fn pool_churn(previous: &[Pool], current: &[Pool]) -> f64 {
+    let before: HashMap<H160, f64> = previous.iter().map(|p| (p.address, p.tvl_usd)).collect();
+    let after: HashMap<H160, f64> = current.iter().map(|p| (p.address, p.tvl_usd)).collect();
+    let removed = before.keys().filter(|a| !after.contains_key(*a)).count();
+    let changed = after.iter().filter(|(address, tvl)| match before.get(*address) {
+        None => true,
+        Some(old) => (*tvl - old).abs() > old.abs().max(1.0) * CHURN_LIQUIDITY_SHIFT_RATIO,
+    }).count();
+    let added = after.keys().filter(|a| !before.contains_key(*a)).count();
+    let total = (before.len() + added).max(1);
+    (removed + changed) as f64 / total as f64
+}
+
+/// Nuevo intervalo de refresco (bloques) según la rotación observada: se reduce a la mitad con mucha
+/// rotación o pools nuevos, y crece un 50% con el universo estable, siempre dentro de `[min, max]`.
+/// Devuelve el motivo del cambio, si lo hubo.
+fn adapt_refresh_interval(current: u64, churn: f64, new_pools: u64, min: u64, max: u64) -> (u64, Option<String>) {
+    let (min, max) = (min.max(1), max.max(min.max(1)));
+    let (next, reason) = if new_pools > 0 || churn > CHURN_HIGH_RATIO {
+        (current / 2, format!("rotación {:.1}% y {} pools nuevos", churn * 100.0, new_pools))
+    } else if churn < CHURN_LOW_RATIO {
+        (current + current / 2, format!("universo estable (rotación {:.1}%)", churn * 100.0))
+    } else {
+        (current, String::new())
+    };
+    let next = next.clamp(min, max);
+    (next, (next != current).then_some(reason))
+}
 
 // CORRECCIÓN FINAL: La firma ahora coincide perfectamente con el tipo de `client` creado en `lib.rs`
 pub async fn event_handler(
     client: Arc<SignerMiddleware<Provider<Http>, LocalWallet>>,
-    provider_ws: Arc<Provider<Ws>>,
+    sim_provider: Arc<Provider<Http>>,
     oracle_map: Arc<OracleMap>,
-    event_sender: Sender<Event>,
+    mut event_receiver: Receiver<Event>,
     initial_pools: Vec<Pool>, // Usamos `Pool` directamente desde `types`
     initial_paths: Vec<ArbPath>,
 ) -> anyhow::Result<()> {
@@ -45,112 +126,219 @@ pub async fn event_handler(
 
     let mut pools = initial_pools;
     let mut paths = initial_paths;
-    CURRENT_PATHS.set(paths.len() as i64);
+    instance::labeled(&CURRENT_PATHS).set(paths.len() as i64);
 
-    let mut event_receiver = event_sender.subscribe();
     let mut last_refresh_block = 0u64;
+    // En modo adaptativo parte de `path_refresh_interval_blocks` y se ajusta tras cada refresco.
+    let mut refresh_interval = CONFIG.path_refresh_interval_blocks;
+    let mut recent_hashes: BTreeMap<u64, H256> = BTreeMap::new();
+    // Último bloque cuyos `Swap` ya se leyeron: los bloques saltados se recuperan en la siguiente consulta.
+    let mut last_swap_log_block: Option<u64> = None;
+    // Limita cuántos bundles pueden estar esperando resultado a la vez (nonces y capital compartidos).
+    let bundle_slots = BundleSlots::new(CONFIG.max_inflight_bundles.max(1), MAX_QUEUED_BUNDLES);
     info!(" Estrategia lista con {} rutas. Esperando nuevos bloques...", paths.len());
 
     loop {
         if let Ok(Event::Block(block)) = event_receiver.recv().await {
             let block_number = block.number.unwrap_or_default().as_u64();
+            let block_span = telemetry::block_span(block_number);
             info!("--- Bloque Nuevo #{block_number} ---");
+            instance::labeled(&BLOCKS_PROCESSED).inc();
+            execution::observe_block_timestamp(block.timestamp.as_u64());
+            if let Some(fork_block) = detect_reorg(client.as_ref(), &mut recent_hashes, block_number, block.hash, block.parent_hash).await {
+                warn!(" Reorg detectado: el bloque #{fork_block} cambió. Las oportunidades se re-evalúan sobre el nuevo head.");
+                if CONFIG.cancel_on_reorg {
+                    let base_fee = block.base_fee_per_gas.unwrap_or_else(U256::zero);
+                    instance::spawn(execution::cancel_inflight_after_reorg(client.clone(), fork_block, base_fee));
+                }
+            }
+            if CONFIG.pnl_summary_interval_blocks > 0 && block_number % CONFIG.pnl_summary_interval_blocks == 0 {
+                instance::spawn(log_pnl_summary(client.clone(), block_number));
+            }
 
-            if last_refresh_block == 0
-                || block_number.saturating_sub(last_refresh_block)
-                    >= CONFIG.path_refresh_interval_blocks
-            {
-                info!(" Refrescando lista de pools y rutas...");
-                pools = pools::load_all_pools_v3(provider_ws.clone(), &oracle_map).await?;
+            if let Some(manual_refresh) = take_refresh_due(last_refresh_block, block_number, refresh_interval) {
+                if manual_refresh {
+                    info!(" Refrescando lista de pools y rutas (forzado manualmente)...");
+                } else {
+                    info!(" Refrescando lista de pools y rutas...");
+                }
+                let previous_pools = std::mem::take(&mut pools);
+                pools = block_span.in_phase("refresh_pools", String::new(), pools::load_all_pools_v3(sim_provider.clone(), &oracle_map)).await?;
+                if CONFIG.adaptive_path_refresh {
+                    let churn = pool_churn(&previous_pools, &pools);
+                    let (next, reason) = adapt_refresh_interval(
+                        refresh_interval, churn, pools::take_pools_created(), CONFIG.path_refresh_min_blocks, CONFIG.path_refresh_max_blocks,
+                    );
+                    if let Some(reason) = reason {
+                        info!("Intervalo de refresco de rutas: {} -> {} bloques ({}).", refresh_interval, next, reason);
+                        refresh_interval = next;
+                    }
+                }
                 paths = generate_triangular_paths(&pools, CONFIG.token_in_address, &oracle_map);
-                CURRENT_PATHS.set(paths.len() as i64);
+                paths::log_universe_summary(&pools, &paths, &oracle_map, log::Level::Debug);
+                instance::labeled(&CURRENT_PATHS).set(paths.len() as i64);
                 last_refresh_block = block_number;
                 crate::clear_old_locks(block_number);
             }
 
             let base_gas_price = block.base_fee_per_gas.unwrap_or_else(U256::zero);
+            if CONFIG.bait_price_deviation_bps > 0 || CONFIG.cooldown_settle_blocks > 0 || CONFIG.max_oracle_pool_divergence_bps > 0 {
+                let from_block = swap_logs_from_block(last_swap_log_block, block_number);
+                match pools::fetch_swap_logs(sim_provider.as_ref(), &pools, from_block, block_number).await {
+                    Ok(logs) => {
+                        pools::record_large_swaps(&logs, block_number, CONFIG.bait_large_swap_usd);
+                        pools::record_swap_activity(&logs, block_number);
+                        last_swap_log_block = Some(block_number);
+                    }
+                    Err(e) => warn!("No se pudieron leer los swaps de los bloques #{from_block}-#{block_number}: {e:?}"),
+                }
+            }
+            execution::refresh_priority_fee_floor(sim_provider.as_ref()).await;
+            optimization::refresh_l1_fee_per_byte(sim_provider.clone()).await;
+            crate::provider::set_stream_head(block_number);
+            if block_number % HEAD_CHECK_INTERVAL_BLOCKS == 0 || crate::provider::simulations_pinned() {
+                crate::provider::refresh_head_divergence(sim_provider.as_ref()).await;
+            }
+            simulator::reset_rpc_budget();
             let tasks = FuturesUnordered::new();
+            // Precios de oráculo vistos por ruta, sólo si la captura de bloques está activa.
+            let oracle_seen: Option<Arc<Mutex<replay::OracleSeen>>> =
+                CONFIG.capture_dir.as_ref().map(|_| Arc::new(Mutex::new(HashMap::new())));
+
+            // Todas las rutas parten de `token_in`: un solo precio de oráculo por bloque para el spot.
+            let token_in_price = feeds::get_price(&oracle_map, &CONFIG.token_in_address, sim_provider.clone())
+                .await
+                .map(|info| info.price);
+            // Pools lejos del oráculo, calculados una vez por bloque antes de optimizar ninguna ruta.
+            let divergent_pools = if CONFIG.max_oracle_pool_divergence_bps > 0 {
+                oracle_divergent_pools(&sim_provider, &oracle_map, &paths).await
+            } else {
+                HashMap::new()
+            };
 
             for path in &paths {
-                let is_in_cooldown = {
-                    let stats_map = ROUTE_STATS.lock().unwrap();
-                    if let Some(stats) = stats_map.get(&path.key()) {
-                        block_number < stats.last_failure_block + ROUTE_FAILURE_COOLDOWN_BLOCKS
-                    } else {
-                        false
-                    }
-                };
-                if is_in_cooldown { continue; }
+                if route_in_cooldown(path, block_number) { continue; }
+                if skip_divergent_route(path, &divergent_pools) { continue; }
 
                 let mut p = path.clone();
-                let prov = Arc::new(client.provider().clone());
+                let prov = sim_provider.clone();
                 let omap = oracle_map.clone();
-                tasks.push(tokio::spawn(async move {
-                    ROUTES_EVALUATED.inc();
-                    let spot_price = p.get_spot_price(prov.clone()).await.ok()?;
-                    let oracle_info =
-                        oracle::get_max_profit_oracle(&p.token_a, spot_price, &omap, prov.clone())
-                            .await?;
+                let seen = oracle_seen.clone();
+                let path_key = p.key();
+                tasks.push(instance::spawn(block_span.simulate_path(path_key, async move {
+                    instance::labeled(&ROUTES_EVALUATED).inc();
+                    let spot_price = p.get_spot_price(prov.clone(), token_in_price).await.ok()?;
+                    let oracle_info = feeds::get_max_profit_oracle(&p, spot_price, &omap, prov.clone()).await?;
+                    if stale_before_optimization(&p, oracle_info.lag, block_number) { return None; }
+                    if let Some(seen) = &seen {
+                        seen.lock().unwrap().insert(p.key(), (oracle_info.price, oracle_info.lag));
+                    }
                     optimization::find_best_trade_golden_section(
                         prov, &mut p, base_gas_price, oracle_info, &omap, block_number,
                     ).await
-                }));
+                })));
             }
 
             let mut profitable_opportunities: Vec<ArbitrageOpportunity> =
                 tasks.filter_map(|res| async { res.ok().flatten() }).collect().await;
 
+            if let (Some(dir), Some(seen)) = (&CONFIG.capture_dir, &oracle_seen) {
+                if CONFIG.capture_block == Some(block_number)
+                    || (CONFIG.capture_on_opportunity && !profitable_opportunities.is_empty())
+                {
+                    let eth_price = feeds::get_price(&oracle_map, &CONFIG.weth_address, sim_provider.clone())
+                        .await
+                        .map_or(0.0, |info| info.price);
+                    let seen = seen.lock().unwrap().clone();
+                    if let Err(e) = replay::capture_block(dir, &block, base_gas_price, eth_price, &paths, &seen) {
+                        warn!("No se pudo capturar el bloque #{block_number}: {e:?}");
+                    }
+                }
+            }
+
             if profitable_opportunities.is_empty() {
                 info!("No se encontraron oportunidades rentables en este bloque.");
                 continue;
             }
 
-            profitable_opportunities.sort_by(|a, b| b.score.partial_cmp(&a.score).unwrap_or(std::cmp::Ordering::Equal));
-
-            let mut bundle_to_execute = Vec::new();
-            let mut used_pools = HashSet::new();
-
-            for opp in profitable_opportunities {
-                if bundle_to_execute.len() >= OPPORTUNITY_BUNDLE_SIZE { break; }
-
-                let p1 = opp.path.address(1);
-                let p2 = opp.path.address(2);
-                let p3 = opp.path.address(3);
-                if used_pools.contains(&p1) || used_pools.contains(&p2) || used_pools.contains(&p3) { continue; }
+            for opp in &profitable_opportunities {
+                if (1..=3).any(|i| paths::is_rotated_pool(&opp.path.address(i))) {
+                    info!(" Pool rotado produjo oportunidad en la ruta {} (${:.2}).", opp.path.key(), opp.net_profit_usd);
+                }
+            }
 
-                let mut final_opp = opp.clone();
-                final_opp.slippage_bps = calculate_dynamic_slippage(opp.tvl, opp.net_profit_usd);
+            if CONFIG.bait_price_deviation_bps > 0 {
+                profitable_opportunities = screen_sandwich_bait(&sim_provider, &oracle_map, profitable_opportunities, block_number).await;
+            }
+            if CONFIG.own_trade_lookback_blocks > 0 {
+                profitable_opportunities = screen_own_trade_pools(profitable_opportunities, block_number);
+            }
+            rank_opportunities(&mut profitable_opportunities);
+            if CONFIG.profit_curve_diagnostic {
+                if let Some(top) = profitable_opportunities.first().cloned() {
+                    let curve_provider = sim_provider.clone();
+                    instance::spawn(async move {
+                        let curve = optimization::sample_profit_curve(curve_provider, &top, base_gas_price, crate::provider::simulation_block()).await;
+                        optimization::log_profit_curve(&top, &curve);
+                    });
+                }
+            }
 
-                if crate::lock_opportunity(block_number, &final_opp.path) {
-                    used_pools.insert(p1);
-                    used_pools.insert(p2);
-                    used_pools.insert(p3);
-                    bundle_to_execute.push(final_opp);
+            // Topes por ejecución para experimentos: al alcanzarlos no se envía nada más.
+            if let Some(reason) = run_limit_reached() {
+                if instance::labeled(&RUN_LIMIT_REACHED).get() == 0 {
+                    warn!(" Tope de la ejecución alcanzado ({reason}). No se enviarán más trades.");
+                    instance::labeled(&RUN_LIMIT_REACHED).set(1);
                 }
+                if CONFIG.exit_on_run_limit {
+                    info!("EXIT_ON_RUN_LIMIT activo: deteniendo la estrategia.");
+                    return Ok(());
+                }
+                continue;
             }
 
+            let bundle_to_execute = select_for_execution(profitable_opportunities, block_number);
+
             if !bundle_to_execute.is_empty() {
-                let execution_results = execution::execute_arbitrage_bundle(
-                    client.clone(), bundle_to_execute, base_gas_price,
-                ).await;
-                for result in execution_results {
-                    match result {
-                        Ok((_tx_hash, path_key)) => {
-                            TRADES_EXECUTED.inc();
-                            let mut stats_map = ROUTE_STATS.lock().unwrap();
-                            let stats = stats_map.entry(path_key).or_default();
-                            stats.successes += 1;
-                        }
-                        Err((e, path_key)) => {
-                            TRADES_FAILED.inc();
-                            let mut stats_map = ROUTE_STATS.lock().unwrap();
-                            let stats = stats_map.entry(path_key.clone()).or_default();
-                            stats.failures += 1;
-                            stats.last_failure_block = block_number;
-                            warn!(" Falló TX del bundle para la ruta {path_key}: {e:?}");
-                        }
+                let queued = bundle_slots.all_inflight();
+                let Some(ticket) = bundle_slots.reserve() else {
+                    instance::labeled(&BUNDLES_DROPPED).inc();
+                    for opp in &bundle_to_execute {
+                        crate::release_opportunity_lock(block_number, &opp.path);
                     }
+                    warn!(
+                        "Bundle de {} oportunidades descartado: ya hay {} bundles en vuelo y {} en cola.",
+                        bundle_to_execute.len(), CONFIG.max_inflight_bundles.max(1), MAX_QUEUED_BUNDLES
+                    );
+                    continue;
+                };
+                if queued {
+                    info!("Bundle de {} oportunidades en cola hasta que termine un bundle en vuelo.", bundle_to_execute.len());
                 }
+                instance::labeled(&RUN_TRADES).add(bundle_to_execute.len() as i64);
+                let exec_client = client.clone();
+                let reprice_provider = sim_provider.clone();
+                let submit_span = block_span.clone();
+                instance::spawn(async move {
+                    let permits = ticket.ready().await;
+                    instance::labeled(&INFLIGHT_BUNDLES).inc();
+                    let bundle_to_execute = if CONFIG.reprice_before_submit {
+                        reprice_opportunities(reprice_provider, bundle_to_execute, block_number).await
+                    } else {
+                        bundle_to_execute
+                    };
+                    if bundle_to_execute.is_empty() {
+                        instance::labeled(&INFLIGHT_BUNDLES).dec();
+                        return;
+                    }
+                    let execution_results = submit_span.in_phase(
+                        "submit_bundle", String::new(),
+                        execution::execute_arbitrage_bundle(exec_client, bundle_to_execute, base_gas_price, block_number),
+                    ).await;
+                    record_execution_results(execution_results, block_number);
+                    instance::labeled(&INFLIGHT_BUNDLES).dec();
+                    drop(permits);
+                });
             } else {
                 info!("No se encontraron oportunidades no conflictivas para ejecutar.");
             }
@@ -158,6 +346,540 @@ pub async fn event_handler(
     }
 }
 
+/// Oportunidades a ejecutar en el bloque, en orden del ranking: hasta una en `SingleBest` o
+/// `OPPORTUNITY_BUNDLE_SIZE` en modo bundle (y nunca más de las que quedan de `MAX_TRADES_PER_RUN`),
+/// sin pools compartidos. Cada elegida queda bloqueada para el bloque, igual en ambos modos; el último
+/// envío de la ruta se anota sólo cuando la TX llega a enviarse.
+fn select_for_execution(profitable_opportunities: Vec<ArbitrageOpportunity>, block_number: u64) -> Vec<ArbitrageOpportunity> {
+    let mut bundle_to_execute: Vec<ArbitrageOpportunity> = Vec::new();
+    let mut used_pools = HashSet::new();
+    // En `SingleBest` se toma sólo la primera oportunidad no conflictiva; el bloqueo y el
+    // cooldown se aplican igual que en modo bundle.
+    let max_opportunities = match CONFIG.execution_strategy {
+        ExecutionStrategy::SingleBest => 1,
+        ExecutionStrategy::Bundle => OPPORTUNITY_BUNDLE_SIZE,
+    };
+    let max_opportunities = match CONFIG.max_trades_per_run {
+        0 => max_opportunities,
+        limit => max_opportunities.min(limit.saturating_sub(instance::labeled(&RUN_TRADES).get() as u64) as usize),
+    };
+
+    for opp in profitable_opportunities {
+        if bundle_to_execute.len() >= max_opportunities { break; }
+
+        let p1 = opp.path.address(1);
+        let p2 = opp.path.address(2);
+        let p3 = opp.path.address(3);
+        if used_pools.contains(&p1) || used_pools.contains(&p2) || used_pools.contains(&p3) { continue; }
+
+        // Las dos direcciones de un mismo ciclo se anulan entre sí: sólo se ejecuta la de mayor score,
+        // que es la primera en llegar tras el ranking.
+        if let Some(kept) = bundle_to_execute.iter().find(|k| k.path.is_reverse_of(&opp.path)) {
+            instance::labeled(&REVERSE_DUPLICATES_DROPPED).inc();
+            info!(
+                "Ruta {} descartada: recorre en sentido inverso el ciclo de {} (score {:.4} vs {:.4}).",
+                opp.path.key(), kept.path.key(), opp.score, kept.score
+            );
+            continue;
+        }
+
+        // Rutas vetadas por reverts repetidos con el mismo motivo no se vuelven a enviar hasta que expira el veto.
+        if optimization::route_is_blacklisted(&opp.path.key(), block_number) {
+            debug!("Ruta {} vetada por reverts repetidos: omitida.", opp.path.key());
+            continue;
+        }
+
+        // Rutas rentables en simulación pero perdedoras en la práctica quedan en el banquillo.
+        let benched = ROUTE_STATS.lock().unwrap().get(&opp.path.key()).filter(|s| {
+            s.is_benched(CONFIG.min_winrate_for_execution, CONFIG.min_attempts_for_winrate)
+        }).map(|s| (s.winrate(), s.successes + s.failures));
+        if let Some((winrate, attempts)) = benched {
+            info!(
+                "Ruta {} en banquillo: winrate {:.1}% en {} intentos (mínimo {:.1}%).",
+                opp.path.key(), winrate * 100.0, attempts, CONFIG.min_winrate_for_execution * 100.0
+            );
+            continue;
+        }
+
+        // Un edge estable que ya se envió sin fallar no se reenvía salvo que mejore lo suficiente.
+        let stale = ROUTE_STATS.lock().unwrap().get(&opp.path.key()).filter(|s| {
+            s.is_stale_resubmission(opp.net_profit_usd, block_number, CONFIG.min_profit_improvement, RESUBMIT_IMPROVEMENT_WINDOW_BLOCKS)
+        }).map(|s| s.last_submitted_profit_usd);
+        if let Some(previous_usd) = stale {
+            debug!(
+                "Ruta {} no se reenvía: profit ${:.2} sin mejora suficiente sobre el último envío (${:.2}).",
+                opp.path.key(), opp.net_profit_usd, previous_usd
+            );
+            continue;
+        }
+
+        let mut final_opp = opp.clone();
+        final_opp.slippage_bps = calculate_dynamic_slippage(opp.tvl, opp.net_profit_usd);
+        // Rutas cuyo slippage real supera el presupuesto no ganan tolerancia (eso sólo aceptaría fills
+        // peores): el exceso observado se descuenta del profit esperado y deben seguir siendo rentables.
+        let excess = ROUTE_STATS.lock().unwrap().get(&opp.path.key()).and_then(|s| s.excess_slippage_bps(final_opp.slippage_bps));
+        if let Some(excess_bps) = excess {
+            let adjusted = slippage_adjusted_profit_usd(&final_opp, excess_bps);
+            if adjusted < CONFIG.min_profit_usd {
+                info!(
+                    "Ruta {} descartada: su slippage real supera el presupuesto en {} bps y el profit ajustado (${:.2}) no cubre el mínimo.",
+                    opp.path.key(), excess_bps, adjusted
+                );
+                continue;
+            }
+            debug!(
+                "Profit de la ruta {} ajustado por fills reales: ${:.2} -> ${:.2} ({} bps sobre el presupuesto).",
+                opp.path.key(), final_opp.net_profit_usd, adjusted, excess_bps
+            );
+            final_opp.net_profit_usd = adjusted;
+        }
+        // Pares conocidos como difíciles tienen un mínimo de slippage configurado a mano.
+        if let Some(pair_floor) = pair_slippage_floor(&opp.path) {
+            if pair_floor > final_opp.slippage_bps {
+                info!(
+                    "Slippage de la ruta {} elevado por override de par: {} -> {} bps",
+                    opp.path.key(), final_opp.slippage_bps, pair_floor
+                );
+                final_opp.slippage_bps = pair_floor;
+            }
+        }
+
+        if crate::lock_opportunity(block_number, &final_opp.path) {
+            used_pools.insert(p1);
+            used_pools.insert(p2);
+            used_pools.insert(p3);
+            bundle_to_execute.push(final_opp);
+        }
+    }
+    bundle_to_execute
+}
+
+/// Profit de la oportunidad descontando `excess_bps` de slippage sobre el notional de entrada.
+fn slippage_adjusted_profit_usd(opp: &ArbitrageOpportunity, excess_bps: u32) -> f64 {
+    let notional_usd = to_usd(opp.optimal_amount_in, opp.path.token_a, opp.token_price_usd, opp.path.get_input_decimals());
+    opp.net_profit_usd - notional_usd * excess_bps as f64 / 10_000.0
+}
+
+/// Plazas para bundles: `inflight` se envían a la vez y, con todas ocupadas, hasta `queued` más esperan
+/// turno en lugar de descartarse. Sólo se descarta un bundle si la cola también está llena.
+struct BundleSlots {
+    inflight: Arc<Semaphore>,
+    pending: Arc<Semaphore>,
+}
+
+/// Plaza reservada por `BundleSlots::reserve`; `ready` espera a que haya hueco para enviar.
+struct BundleTicket {
+    inflight: Arc<Semaphore>,
+    pending: OwnedSemaphorePermit,
+}
+
+impl BundleSlots {
+    fn new(inflight: usize, queued: usize) -> Self {
+        Self { inflight: Arc::new(Semaphore::new(inflight)), pending: Arc::new(Semaphore::new(inflight + queued)) }
+    }
+
+    /// `true` si un bundle nuevo tendría que esperar en cola.
+    fn all_inflight(&self) -> bool {
+        self.inflight.available_permits() == 0
+    }
+
+    /// Reserva plaza sin bloquear el bucle de bloques; `None` si no cabe ni en vuelo ni en cola.
+    fn reserve(&self) -> Option<BundleTicket> {
+        let pending = self.pending.clone().try_acquire_owned().ok()?;
+        Some(BundleTicket { inflight: self.inflight.clone(), pending })
+    }
+}
+
+impl BundleTicket {
+    /// Espera a que termine un bundle en vuelo; las plazas se liberan al soltar el resultado.
+    async fn ready(self) -> (OwnedSemaphorePermit, OwnedSemaphorePermit) {
+        let inflight = self.inflight.acquire_owned().await.expect("el semáforo de bundles no se cierra");
+        (inflight, self.pending)
+    }
+}
+
+/// Ruta enviada hace poco sin fallar cuyo lag spot/oráculo no mejoró `min_profit_improvement`: no se
+/// optimiza, que es donde se gastan los RPC. El profit optimizado se vuelve a filtrar al seleccionar.
+fn stale_before_optimization(path: &ArbPath, edge: f64, block_number: u64) -> bool {
+    let stale = ROUTE_STATS.lock().unwrap().get(&path.key()).is_some_and(|s| {
+        s.is_stale_edge(edge, block_number, CONFIG.min_profit_improvement, RESUBMIT_IMPROVEMENT_WINDOW_BLOCKS)
+    });
+    if stale {
+        debug!("Ruta {} no se optimiza: lag {:.4} sin mejora suficiente sobre el último envío.", path.key(), edge);
+    }
+    stale
+}
+
+/// Cooldown tras un fallo: `ROUTE_FAILURE_COOLDOWN_BLOCKS` bloques y, con `cooldown_settle_blocks`, además
+/// que el precio de los pools de la ruta lleve ese número de bloques dentro de `cooldown_settle_tolerance_bps`
+/// (p. ej. la TX competidora que causó el fallo ya confirmó). Los swaps pequeños de un pool líquido no lo
+/// impiden, y `cooldown_settle_max_wait_blocks` acota la espera extra. Una vez superado, los swaps
+/// posteriores no la devuelven al cooldown.
+fn route_in_cooldown(path: &ArbPath, block_number: u64) -> bool {
+    let mut stats_map = ROUTE_STATS.lock().unwrap();
+    let Some(stats) = stats_map.get_mut(&path.key()) else { return false };
+    if stats.last_failure_block == 0 || stats.cooldown_cleared_block >= stats.last_failure_block { return false; }
+    let cooldown_end = stats.last_failure_block + ROUTE_FAILURE_COOLDOWN_BLOCKS;
+    if block_number < cooldown_end { return true; }
+    let waited_blocks = block_number - cooldown_end;
+    if CONFIG.cooldown_settle_blocks > 0
+        && (CONFIG.cooldown_settle_max_wait_blocks == 0 || waited_blocks < CONFIG.cooldown_settle_max_wait_blocks)
+        && !(1..=3).all(|i| pools::pool_settled(&path.address(i), block_number, CONFIG.cooldown_settle_blocks))
+    {
+        return true;
+    }
+    stats.cooldown_cleared_block = block_number;
+    false
+}
+
+/// Registra el hash del bloque y devuelve el primer bloque reorganizado si el nuevo bloque reemplaza
+/// a uno ya visto o no encadena con el anterior conocido. Si no encadena, la nueva cadena se recorre
+/// hacia atrás (`fork_point`) para detectar también reorgs de varios bloques.
+async fn detect_reorg<M: Middleware>(
+    provider: &M, recent_hashes: &mut BTreeMap<u64, H256>, number: u64, hash: Option<H256>, parent_hash: H256,
+) -> Option<u64> {
+    let hash = hash?;
+    let replaced = recent_hashes.get(&number).is_some_and(|known| *known != hash);
+    let orphaned_parent = number > 0 && recent_hashes.get(&(number - 1)).is_some_and(|known| *known != parent_hash);
+    let fork_block = if orphaned_parent {
+        Some(fork_point(provider, recent_hashes, number - 1, parent_hash).await)
+    } else {
+        replaced.then_some(number)
+    };
+    // Los bloques desde la bifurcación (o posteriores al nuevo head) pertenecían a la cadena abandonada.
+    recent_hashes.split_off(&fork_block.unwrap_or(number));
+    recent_hashes.insert(number, hash);
+    while recent_hashes.len() > REORG_TRACKED_BLOCKS {
+        recent_hashes.pop_first();
+    }
+    fork_block
+}
+
+/// Primer bloque reorganizado sabiendo que el bloque `number` de la nueva cadena (`hash`) ya no es el
+/// conocido: retrocede por los padres hasta uno que coincida con `recent_hashes` o salga de la ventana.
+/// Si el nodo no devuelve un bloque, se corta ahí y se avisa.
+async fn fork_point<M: Middleware>(provider: &M, recent_hashes: &BTreeMap<u64, H256>, mut number: u64, mut hash: H256) -> u64 {
+    while let Some(known) = number.checked_sub(1).and_then(|previous| recent_hashes.get(&previous)) {
+        let parent_hash = match provider.get_block(hash).await {
+            Ok(Some(block)) => block.parent_hash,
+            result => {
+                warn!("No se pudo seguir el reorg más allá del bloque #{number} ({hash:?}): {:?}", result.err());
+                return number;
+            }
+        };
+        if parent_hash == *known { return number; }
+        number -= 1;
+        hash = parent_hash;
+    }
+    number
+}
+
+/// Primer bloque a consultar por `Swap`: el siguiente al último leído, sin pasar de
+/// `SWAP_LOGS_MAX_RANGE_BLOCKS` hacia atrás (ni volver a un bloque ya leído tras un reorg).
+fn swap_logs_from_block(last_read: Option<u64>, block_number: u64) -> u64 {
+    let oldest = block_number.saturating_sub(pools::SWAP_LOGS_MAX_RANGE_BLOCKS - 1);
+    last_read.map_or(block_number, |last| (last + 1).clamp(oldest, block_number))
+}
+
+/// Posible cebo de sandwich: algún salto tuvo un swap grande muy reciente que dejó su precio a más de
+/// `bait_price_deviation_bps` del oráculo. Devuelve (pool, desviación en bps, tamaño del swap en USD).
+async fn sandwich_bait_hop<M: Middleware + 'static>(
+    provider: &Arc<M>, oracle_map: &OracleMap, path: &ArbPath, block_number: u64,
+) -> Option<(H160, f64, f64)> {
+    for pool in [&path.pool_1, &path.pool_2, &path.pool_3] {
+        let Some((size_usd, sqrt_price_x96)) = pools::recent_large_swap(&pool.address, block_number, BAIT_SWAP_LOOKBACK_BLOCKS) else { continue };
+        let Some(deviation_bps) = oracle_pool_deviation_bps(provider, oracle_map, pool, sqrt_price_x96).await else { continue };
+        if deviation_bps > CONFIG.bait_price_deviation_bps as f64 {
+            return Some((pool.address, deviation_bps, size_usd));
+        }
+    }
+    None
+}
+
+/// Desviación (bps) entre el precio del pool con `sqrt_price_x96` y el implícito de los oráculos de sus tokens.
+/// `None` si algún token no tiene feed propio: un precio derivado de pools compararía el pool consigo mismo.
+async fn oracle_pool_deviation_bps<M: Middleware + 'static>(
+    provider: &Arc<M>, oracle_map: &OracleMap, pool: &Pool, sqrt_price_x96: U256,
+) -> Option<f64> {
+    if !feeds::has_feed(oracle_map, &pool.token0) || !feeds::has_feed(oracle_map, &pool.token1) { return None; }
+    let (Some(price0), Some(price1)) = tokio::join!(
+        feeds::get_price(oracle_map, &pool.token0, provider.clone()),
+        feeds::get_price(oracle_map, &pool.token1, provider.clone()),
+    ) else { return None };
+    pool_deviation_bps(pool, sqrt_price_x96, price0.price, price1.price)
+}
+
+/// Desviación (bps) del precio del pool frente al implícito de los precios USD de sus tokens.
+fn pool_deviation_bps(pool: &Pool, sqrt_price_x96: U256, price0_usd: f64, price1_usd: f64) -> Option<f64> {
+    if price1_usd <= 0.0 { return None; }
+    // Precio token1/token0 en unidades base: el del pool frente al implícito del oráculo.
+    let pool_price = pools::sqrt_price_f64(sqrt_price_x96).powi(2);
+    let oracle_price = price0_usd / price1_usd * 10f64.powi(pool.decimals1 as i32 - pool.decimals0 as i32);
+    if oracle_price <= 0.0 { return None; }
+    Some((pool_price / oracle_price - 1.0).abs() * 10_000.0)
+}
+
+/// Pools de las rutas cuyo precio actual se aleja más de `max_oracle_pool_divergence_bps` del oráculo, con su
+/// desvío en bps: un desvío tan grande suele ser un pool manipulado o roto más que un edge real. Se lee un
+/// precio por token con feed propio, todos a la vez, en lugar de dos lecturas por salto y ruta.
+async fn oracle_divergent_pools<M: Middleware + 'static>(
+    provider: &Arc<M>, oracle_map: &OracleMap, paths: &[ArbPath],
+) -> HashMap<H160, f64> {
+    let mut hops: HashMap<H160, &Pool> = HashMap::new();
+    for path in paths {
+        for pool in [&path.pool_1, &path.pool_2, &path.pool_3] {
+            hops.entry(pool.address).or_insert(pool);
+        }
+    }
+    let tokens: Vec<H160> = hops.values()
+        .flat_map(|pool| [pool.token0, pool.token1])
+        .filter(|token| feeds::has_feed(oracle_map, token))
+        .collect::<HashSet<_>>()
+        .into_iter()
+        .collect();
+    let prices = futures::future::join_all(tokens.iter().map(|token| feeds::get_price(oracle_map, token, provider.clone()))).await;
+    let prices: HashMap<H160, f64> = tokens.into_iter().zip(prices)
+        .filter_map(|(token, info)| Some((token, info?.price)))
+        .collect();
+    let mut divergent = HashMap::new();
+    for (address, pool) in hops {
+        let (Some(&price0), Some(&price1)) = (prices.get(&pool.token0), prices.get(&pool.token1)) else { continue };
+        let Some(sqrt_price_x96) = pools::current_sqrt_price_x96(&address) else { continue };
+        let Some(deviation_bps) = pool_deviation_bps(pool, sqrt_price_x96, price0, price1) else { continue };
+        if deviation_bps <= CONFIG.max_oracle_pool_divergence_bps as f64 { continue; }
+        warn!(
+            " Pool sospechoso {:?}: a {:.0} bps del oráculo (máximo {} bps); sus rutas {}.",
+            address, deviation_bps, CONFIG.max_oracle_pool_divergence_bps,
+            if CONFIG.oracle_divergence_flag_only { "sólo se registran" } else { "no se optimizan" }
+        );
+        divergent.insert(address, deviation_bps);
+    }
+    divergent
+}
+
+/// Si la ruta pasa por un pool de `divergent_pools` y debe saltarse (salvo `oracle_divergence_flag_only`).
+fn skip_divergent_route(path: &ArbPath, divergent_pools: &HashMap<H160, f64>) -> bool {
+    let Some((pool, deviation_bps)) = (1..=3).find_map(|i| divergent_pools.get_key_value(&path.address(i))) else { return false };
+    debug!("Salto sospechoso en la ruta {}: pool {:?} a {:.0} bps del oráculo.", path.key(), pool, deviation_bps);
+    !CONFIG.oracle_divergence_flag_only
+}
+
+/// Descarta las oportunidades marcadas como cebo o, si `bait_extra_profit_usd` > 0, les exige ese
+/// margen adicional sobre el profit mínimo.
+async fn screen_sandwich_bait<M: Middleware + 'static>(
+    provider: &Arc<M>, oracle_map: &OracleMap, opportunities: Vec<ArbitrageOpportunity>, block_number: u64,
+) -> Vec<ArbitrageOpportunity> {
+    // Las rutas se comprueban a la vez: sólo las que pasan por un swap grande reciente leen oráculos.
+    let verdicts = futures::future::join_all(
+        opportunities.iter().map(|opp| sandwich_bait_hop(provider, oracle_map, &opp.path, block_number)),
+    )
+    .await;
+    let mut kept = Vec::with_capacity(opportunities.len());
+    for (opp, verdict) in opportunities.into_iter().zip(verdicts) {
+        let Some((pool, deviation_bps, size_usd)) = verdict else {
+            kept.push(opp);
+            continue;
+        };
+        let required_usd = optimization::min_profit_usd() + CONFIG.bait_extra_profit_usd;
+        let keep = CONFIG.bait_extra_profit_usd > 0.0 && opp.net_profit_usd >= required_usd;
+        warn!(
+            " Posible cebo de sandwich en la ruta {}: pool {:?} a {:.0} bps del oráculo tras un swap de ${:.0} ({}).",
+            opp.path.key(), pool, deviation_bps, size_usd,
+            if keep { format!("se mantiene: ${:.2} >= ${:.2}", opp.net_profit_usd, required_usd) } else { "descartada".to_string() }
+        );
+        if keep { kept.push(opp); }
+    }
+    kept
+}
+
+/// Rutas por pools que acabamos de operar: su precio puede reflejar nuestro propio desequilibrio, que
+/// otros arbitrarán de vuelta. Se descartan o, si `own_trade_extra_profit_usd` > 0, se les exige ese
+/// margen adicional sobre el profit mínimo.
+fn screen_own_trade_pools(opportunities: Vec<ArbitrageOpportunity>, block_number: u64) -> Vec<ArbitrageOpportunity> {
+    opportunities.into_iter().filter(|opp| {
+        let Some((pool, traded_block)) = (1..=3).find_map(|i| {
+            let pool = opp.path.address(i);
+            pools::recent_own_trade(&pool, block_number, CONFIG.own_trade_lookback_blocks).map(|b| (pool, b))
+        }) else { return true };
+        let required_usd = optimization::min_profit_usd() + CONFIG.own_trade_extra_profit_usd;
+        let keep = CONFIG.own_trade_extra_profit_usd > 0.0 && opp.net_profit_usd >= required_usd;
+        info!(
+            "Ruta {} pasa por el pool {:?}, operado por nosotros en el bloque #{} ({}).",
+            opp.path.key(), pool, traded_block,
+            if keep { format!("se mantiene: ${:.2} >= ${:.2}", opp.net_profit_usd, required_usd) } else { "descartada".to_string() }
+        );
+        keep
+    }).collect()
+}
+
+/// Motivo por el que la ejecución alcanzó su tope de trades o de pérdida acumulada, si lo hizo.
+fn run_limit_reached() -> Option<String> {
+    run_limit(instance::labeled(&RUN_TRADES).get() as u64, execution::realized_pnl_usd())
+}
+
+/// Motivo por el que `trades` enviados y un PnL realizado de `pnl` USD alcanzan los topes de la ejecución.
+fn run_limit(trades: u64, pnl: f64) -> Option<String> {
+    if CONFIG.max_trades_per_run > 0 && trades >= CONFIG.max_trades_per_run {
+        return Some(format!("{trades} trades de un máximo de {}", CONFIG.max_trades_per_run));
+    }
+    if CONFIG.max_cumulative_loss_usd > 0.0 && pnl <= -CONFIG.max_cumulative_loss_usd {
+        return Some(format!("pérdida realizada ${:.2} (máximo ${:.2})", -pnl, CONFIG.max_cumulative_loss_usd));
+    }
+    None
+}
+
+/// Rendimiento de la ejecución según los contadores de Prometheus y `ROUTE_STATS`.
+#[derive(Debug, Clone, PartialEq)]
+struct PnlSummary {
+    attempted: i64,
+    executed: u64,
+    failed: u64,
+    inflight: i64,
+    simulated_usd: f64,
+    realized_usd: f64,
+    gas_spent_eth: f64,
+    /// Motivo del tope de la ejecución si saltó.
+    breaker: Option<String>,
+    /// Las `PNL_SUMMARY_TOP_ROUTES` rutas con más profit realizado.
+    top_routes: Vec<(String, f64)>,
+}
+
+fn pnl_summary() -> PnlSummary {
+    let mut top_routes: Vec<(String, f64)> = ROUTE_STATS
+        .lock()
+        .unwrap()
+        .iter()
+        .filter(|(_, s)| s.realized_profit_usd != 0.0)
+        .map(|(key, s)| (key.clone(), s.realized_profit_usd))
+        .collect();
+    top_routes.sort_unstable_by(|a, b| b.1.total_cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+    top_routes.truncate(PNL_SUMMARY_TOP_ROUTES);
+    PnlSummary {
+        attempted: instance::labeled(&RUN_TRADES).get(),
+        executed: instance::labeled(&TRADES_EXECUTED).get(),
+        failed: instance::labeled(&TRADES_FAILED).get(),
+        inflight: instance::labeled(&INFLIGHT_BUNDLES).get(),
+        simulated_usd: execution::simulated_pnl_usd(),
+        realized_usd: execution::realized_pnl_usd(),
+        gas_spent_eth: execution::gas_spent_eth(),
+        breaker: run_limit_reached(),
+        top_routes,
+    }
+}
+
+/// Resumen periódico de rendimiento (`pnl_summary`) con el balance actual de la wallet.
+async fn log_pnl_summary(client: Arc<SignerMiddleware<Provider<Http>, LocalWallet>>, block_number: u64) {
+    let summary = pnl_summary();
+    let balance = match client.get_balance(client.address(), None).await {
+        Ok(wei) => format!("{:.4} ETH", crate::math::token_units(wei, 18)),
+        Err(e) => format!("no disponible ({e})"),
+    };
+
+    info!("=== Resumen de PnL (bloque #{block_number}) ===");
+    info!(
+        "Trades: {} intentados | {} ejecutados | {} fallidos | {} en vuelo",
+        summary.attempted, summary.executed, summary.failed, summary.inflight
+    );
+    info!(
+        "Profit simulado ${:.2} vs realizado ${:.2} | gas pagado {:.5} ETH",
+        summary.simulated_usd, summary.realized_usd, summary.gas_spent_eth
+    );
+    info!("Balance de la wallet: {balance} | tope de la ejecución: {}", summary.breaker.as_deref().unwrap_or("inactivo"));
+    for (i, (key, profit)) in summary.top_routes.iter().enumerate() {
+        info!("  #{} {}: ${:.2} realizados", i + 1, key, profit);
+    }
+}
+
+/// Re-simula el `expected_output` de cada oportunidad justo antes del envío (sin repetir la búsqueda)
+/// y descarta las que ya no superan `min_profit_usd`. Las descartadas liberan su lock.
+async fn reprice_opportunities(
+    provider: Arc<Provider<Http>>,
+    opportunities: Vec<ArbitrageOpportunity>,
+    block_number: u64,
+) -> Vec<ArbitrageOpportunity> {
+    let mut repriced = Vec::with_capacity(opportunities.len());
+    // Mismo bloque que la búsqueda: si el nodo HTTP va por detrás del stream, se fija al head del stream.
+    let sim_block = crate::provider::simulation_block();
+    for mut opp in opportunities {
+        let decimals = opp.path.get_input_decimals();
+        let fresh_output = opp.path.simulate_v3_path_at(provider.clone(), opp.optimal_amount_in, sim_block).await;
+        let fresh_profit = fresh_output.map(|out| {
+            let old = to_usd(opp.expected_output, opp.path.token_a, opp.token_price_usd, decimals);
+            let new = to_usd(out, opp.path.token_a, opp.token_price_usd, decimals);
+            (out, opp.net_profit_usd + new - old)
+        });
+        match fresh_profit {
+            // `expected_output` es la salida del ciclo en `token_a` también con préstamo cruzado; el
+            // `expected_return` del swap de salida al token prestado no se re-cotiza aquí.
+            Some((out, profit)) if profit > optimization::min_profit_usd() => {
+                opp.expected_output = out;
+                opp.net_profit_usd = profit;
+                repriced.push(opp);
+            }
+            _ => {
+                instance::labeled(&REPRICE_DROPPED).inc();
+                info!("Ruta {} descartada en el re-precio previo al envío.", opp.path.key());
+                crate::release_opportunity_lock(block_number, &opp.path);
+            }
+        }
+    }
+    repriced
+}
+
+/// Mayor override de slippage configurado para cualquiera de los pares de la ruta.
+fn pair_slippage_floor(path: &ArbPath) -> Option<u32> {
+    [&path.pool_1, &path.pool_2, &path.pool_3]
+        .iter()
+        .filter_map(|pool| CONFIG.pair_slippage_overrides.get(&pair_key(pool.token0, pool.token1)).copied())
+        .max()
+}
+
+/// Orden total y determinista: score descendente, luego profit neto descendente y por último la
+/// clave de la ruta. Un score NaN es un bug aguas arriba: se registra y la oportunidad se descarta.
+fn rank_opportunities(opportunities: &mut Vec<ArbitrageOpportunity>) {
+    opportunities.retain(|opp| {
+        if opp.score.is_nan() {
+            error!("BUG: score NaN en la ruta {} (profit ${:.2}); oportunidad descartada.", opp.path.key(), opp.net_profit_usd);
+            return false;
+        }
+        true
+    });
+    opportunities.sort_by(|a, b| {
+        b.score
+            .total_cmp(&a.score)
+            .then_with(|| b.net_profit_usd.total_cmp(&a.net_profit_usd))
+            .then_with(|| a.path.key().cmp(&b.path.key()))
+    });
+}
+
+/// Actualiza métricas y `ROUTE_STATS` con el resultado de cada TX del bundle.
+fn record_execution_results(
+    execution_results: Vec<Result<(TxHash, String), (anyhow::Error, String)>>,
+    block_number: u64,
+) {
+    for result in execution_results {
+        match result {
+            Ok((_tx_hash, path_key)) => {
+                instance::labeled(&TRADES_EXECUTED).inc();
+                let mut stats_map = ROUTE_STATS.lock().unwrap();
+                let stats = stats_map.entry(path_key).or_default();
+                stats.successes += 1;
+            }
+            Err((e, path_key)) => {
+                instance::labeled(&TRADES_FAILED).inc();
+                let mut stats_map = ROUTE_STATS.lock().unwrap();
+                let stats = stats_map.entry(path_key.clone()).or_default();
+                stats.failures += 1;
+                stats.last_failure_block = block_number;
+                drop(stats_map);
+                // Un revert de la simulación previa al envío es casi siempre una carrera perdida: la racha de
+                // reverts idénticos sólo cuenta los on-chain, que registra `track_execution_receipt`.
+                warn!(" Falló TX del bundle para la ruta {path_key}: {e:?}");
+            }
+        }
+    }
+}
+
 fn calculate_dynamic_slippage(tvl: f64, net_profit_usd: f64) -> u32 {
     if tvl > 5_000_000.0 {
         if net_profit_usd < 100.0 { 8 } else if net_profit_usd < 1000.0 { 12 } else { 15 }
@@ -165,3 +887,609 @@ fn calculate_dynamic_slippage(tvl: f64, net_profit_usd: f64) -> u32 {
         if net_profit_usd < 50.0 { 18 } else { 25 }
     } else { 40 }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{instance::with_test_config, paths::tests::path};
+
+    #[tokio::test]
+    async fn bundles_queue_behind_the_inflight_limit() {
+        let slots = BundleSlots::new(1, 1);
+        let first = slots.reserve().expect("plaza libre");
+        assert!(!slots.all_inflight());
+        let first = first.ready().await;
+
+        // Con el único hueco ocupado, el siguiente bundle espera en cola y el tercero se descarta.
+        assert!(slots.all_inflight());
+        let second = slots.reserve().expect("plaza en cola");
+        assert!(slots.reserve().is_none());
+
+        let waiting = tokio::spawn(second.ready());
+        tokio::task::yield_now().await;
+        assert!(!waiting.is_finished());
+        drop(first);
+        let second = waiting.await.unwrap();
+        assert!(slots.reserve().is_some());
+        drop(second);
+    }
+
+    fn opportunity(tokens: [u64; 3], score: f64, net_profit_usd: f64) -> ArbitrageOpportunity {
+        ArbitrageOpportunity {
+            path: path(tokens),
+            optimal_amount_in: U256::zero(),
+            expected_output: U256::zero(),
+            net_profit_usd,
+            bribe_usd: 0.0,
+            bribe_percent: 0.0,
+            lag: 0.0,
+            tvl: 0.0,
+            score,
+            slippage_bps: 0,
+            token_price_usd: 0.0,
+            eth_price_usd: 0.0,
+            flash_loan_leg: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn single_best_and_bundle_lock_the_same_way() {
+        // La segunda comparte el primer pool con la mejor; la tercera no comparte ninguno.
+        let candidates = || vec![opportunity([1, 2, 3], 3.0, 30.0), opportunity([1, 2, 4], 2.0, 20.0), opportunity([5, 6, 7], 1.0, 10.0)];
+        for (strategy, expected) in [(ExecutionStrategy::Bundle, 2), (ExecutionStrategy::SingleBest, 1)] {
+            with_test_config(|c| c.execution_strategy = strategy, async {
+                let selected = select_for_execution(candidates(), 100);
+                let keys: Vec<String> = selected.iter().map(|o| o.path.key()).collect();
+                assert_eq!(selected.len(), expected, "{strategy:?}");
+                assert_eq!(keys[0], path([1, 2, 3]).key());
+                if strategy == ExecutionStrategy::Bundle {
+                    assert_eq!(keys[1], path([5, 6, 7]).key());
+                }
+                // Seleccionar no cuenta como envío: eso se anota cuando la TX sale.
+                assert!(keys.iter().all(|key| !ROUTE_STATS.lock().unwrap().contains_key(key)), "{strategy:?}");
+                // Las elegidas quedan bloqueadas en el bloque: no se vuelven a seleccionar.
+                let again = select_for_execution(candidates(), 100);
+                assert!(again.iter().all(|o| !keys.contains(&o.path.key())), "{strategy:?}");
+            })
+            .await;
+        }
+    }
+
+    #[test]
+    fn excess_slippage_is_charged_against_the_profit() {
+        let mut opp = opportunity([1, 2, 3], 1.0, 30.0);
+        // 10 tokens de 18 decimales a $2.000: $20.000 de notional.
+        opp.optimal_amount_in = U256::exp10(19);
+        opp.token_price_usd = 2_000.0;
+        assert!((slippage_adjusted_profit_usd(&opp, 10) - 10.0).abs() < 1e-9);
+        assert_eq!(slippage_adjusted_profit_usd(&opp, 0), 30.0);
+    }
+
+    #[tokio::test]
+    async fn run_limit_is_per_chain() {
+        crate::config::init_test_env();
+        let limited = |name: &str| {
+            let mut config = crate::config::Config::for_chain_instance(name).unwrap();
+            config.max_trades_per_run = 2;
+            instance::StrategyInstance::new(name, config)
+        };
+        limited("runlimit_a")
+            .scope(async {
+                instance::labeled(&RUN_TRADES).add(2);
+                assert!(run_limit_reached().unwrap().contains("2 trades"));
+            })
+            .await;
+        // Los trades de la otra chain no cuentan contra el tope de ésta.
+        limited("runlimit_b").scope(async { assert_eq!(run_limit_reached(), None) }).await;
+    }
+
+    #[test]
+    fn rank_opportunities_orders_by_score_profit_and_key() {
+        let mut opportunities = vec![
+            opportunity([1, 2, 3], 1.0, 5.0),
+            opportunity([4, 5, 6], f64::NAN, 100.0),
+            opportunity([7, 8, 9], 2.0, 1.0),
+            opportunity([1, 3, 2], 1.0, 9.0),
+            opportunity([2, 1, 3], 1.0, 9.0),
+        ];
+        rank_opportunities(&mut opportunities);
+        let keys: Vec<String> = opportunities.iter().map(|o| o.path.key()).collect();
+        let mut tied = [path([1, 3, 2]).key(), path([2, 1, 3]).key()];
+        tied.sort();
+        assert_eq!(keys, vec![path([7, 8, 9]).key(), tied[0].clone(), tied[1].clone(), path([1, 2, 3]).key()]);
+    }
+
+    #[tokio::test]
+    async fn only_unsent_locks_are_released() {
+        with_test_config(|_| {}, async {
+            let (sent, dropped) = (path([1, 2, 3]), path([4, 5, 6]));
+            assert!(crate::lock_opportunity(100, &sent) && crate::lock_opportunity(100, &dropped));
+            assert!(!crate::lock_opportunity(100, &sent));
+            crate::mark_opportunity_sent(100, &sent);
+            crate::release_opportunity_lock(100, &sent);
+            crate::release_opportunity_lock(100, &dropped);
+            // La enviada sigue bloqueada en su bloque; la descartada antes del envío se puede reintentar.
+            assert!(!crate::lock_opportunity(100, &sent));
+            assert!(crate::lock_opportunity(100, &dropped));
+        })
+        .await;
+    }
+
+
+    #[tokio::test]
+    async fn pair_override_raises_the_dynamic_slippage() {
+        let candidate = || {
+            let mut opp = opportunity([1, 2, 3], 1.0, 30.0);
+            opp.tvl = 10_000_000.0;
+            vec![opp]
+        };
+        let dynamic = with_test_config(|_| {}, async { select_for_execution(candidate(), 200)[0].slippage_bps }).await;
+        assert_eq!(dynamic, calculate_dynamic_slippage(10_000_000.0, 30.0));
+        // El override se aplica aunque el par venga en el orden inverso al del pool.
+        let pair = pair_key(H160::from_low_u64_be(3), H160::from_low_u64_be(2));
+        let raised = with_test_config(|c| c.pair_slippage_overrides = HashMap::from([(pair, 25)]), async {
+            select_for_execution(candidate(), 201)[0].slippage_bps
+        })
+        .await;
+        assert_eq!((dynamic, raised), (8, 25));
+        // Un override por debajo del valor dinámico no lo reduce.
+        let kept = with_test_config(|c| c.pair_slippage_overrides = HashMap::from([(pair, 5)]), async {
+            select_for_execution(candidate(), 202)[0].slippage_bps
+        })
+        .await;
+        assert_eq!(kept, 8);
+    }
+
+
+    #[tokio::test]
+    async fn routes_with_a_poor_established_winrate_are_benched() {
+        let candidates = || vec![opportunity([1, 2, 3], 3.0, 300.0), opportunity([5, 6, 7], 1.0, 10.0)];
+        let selected = |min_winrate: f64| {
+            with_test_config(move |c| (c.min_winrate_for_execution, c.min_attempts_for_winrate) = (min_winrate, 5), async {
+                // 1 éxito en 10 intentos: la más rentable en simulación pierde en la práctica.
+                let losing = optimization::RouteHistory { successes: 1, failures: 9, ..Default::default() };
+                ROUTE_STATS.lock().unwrap().insert(path([1, 2, 3]).key(), losing);
+                select_for_execution(candidates(), 300).iter().map(|o| o.path.key()).collect::<Vec<_>>()
+            })
+        };
+        assert_eq!(selected(0.3).await, [path([5, 6, 7]).key()]);
+        // Con el umbral por defecto (0) no se aparta ninguna ruta.
+        assert_eq!(selected(0.0).await, [path([1, 2, 3]).key(), path([5, 6, 7]).key()]);
+    }
+
+
+    #[tokio::test]
+    async fn run_limits_halt_execution_at_the_boundary() {
+        let candidates = || vec![opportunity([1, 2, 3], 3.0, 30.0), opportunity([5, 6, 7], 1.0, 10.0)];
+        with_test_config(|c| c.max_trades_per_run = 3, async {
+            instance::labeled(&RUN_TRADES).set(2);
+            assert_eq!(run_limit_reached(), None);
+            // Queda un trade: el bundle se recorta a la mejor oportunidad.
+            assert_eq!(select_for_execution(candidates(), 400).len(), 1);
+            instance::labeled(&RUN_TRADES).inc();
+            assert!(run_limit_reached().unwrap().contains("3 trades de un máximo de 3"));
+        })
+        .await;
+        with_test_config(|c| c.max_cumulative_loss_usd = 100.0, async {
+            assert_eq!(run_limit(0, -99.99), None);
+            assert!(run_limit(0, -100.0).unwrap().contains("pérdida realizada $100.00"));
+            // Las ganancias nunca detienen la ejecución.
+            assert_eq!(run_limit(0, 1_000.0), None);
+        })
+        .await;
+    }
+
+
+    #[tokio::test]
+    async fn pnl_summary_aggregates_the_recorded_trades() {
+        let trades = [("a", 12.0, 10.0), ("b", 40.0, 45.0), ("c", -3.0, 5.0), ("d", 8.0, 8.0), ("e", 1.0, 2.0), ("f", 20.0, 18.0)];
+        with_test_config(|c| c.max_trades_per_run = 7, async {
+            let mut results: Vec<Result<(TxHash, String), (anyhow::Error, String)>> =
+                trades.iter().map(|(route, ..)| Ok((TxHash::zero(), route.to_string()))).collect();
+            results.push(Err((anyhow::anyhow!("timeout del relay"), "g".to_string())));
+            instance::labeled(&RUN_TRADES).set(7);
+            record_execution_results(results, 100);
+            for (route, realized, simulated) in trades {
+                execution::record_trade_pnl(realized, simulated);
+                ROUTE_STATS.lock().unwrap().entry(route.to_string()).or_default().realized_profit_usd += realized;
+            }
+
+            let summary = pnl_summary();
+            assert_eq!((summary.attempted, summary.executed, summary.failed), (7, 6, 1));
+            assert!((summary.realized_usd - 78.0).abs() < 1e-9);
+            assert!((summary.simulated_usd - 88.0).abs() < 1e-9);
+            assert_eq!(summary.breaker.as_deref(), Some("7 trades de un máximo de 7"));
+            let top: Vec<(&str, f64)> = summary.top_routes.iter().map(|(k, p)| (k.as_str(), *p)).collect();
+            assert_eq!(top, vec![("b", 40.0), ("f", 20.0), ("a", 12.0), ("d", 8.0), ("e", 1.0)]);
+        })
+        .await;
+    }
+
+
+    fn hash(n: u64) -> H256 {
+        H256::from_low_u64_be(n)
+    }
+
+    /// `detect_reorg` contra un nodo que sólo conoce los bloques de `chain`.
+    async fn detect(chain: &crate::testing::MockChain, recent: &mut BTreeMap<u64, H256>, number: u64, block_hash: Option<H256>, parent: H256) -> Option<u64> {
+        detect_reorg(chain.provider().as_ref(), recent, number, block_hash, parent).await
+    }
+
+    #[tokio::test]
+    async fn detect_reorg_ignores_a_linear_chain() {
+        let chain = crate::testing::MockChain::new();
+        let mut recent = BTreeMap::new();
+        assert_eq!(detect(&chain, &mut recent, 10, Some(hash(10)), hash(9)).await, None);
+        assert_eq!(detect(&chain, &mut recent, 11, Some(hash(11)), hash(10)).await, None);
+        assert_eq!(detect(&chain, &mut recent, 11, Some(hash(11)), hash(10)).await, None);
+        assert_eq!(detect(&chain, &mut recent, 12, None, hash(11)).await, None);
+    }
+
+    #[tokio::test]
+    async fn detect_reorg_reports_the_first_replaced_block() {
+        let chain = crate::testing::MockChain::new().block(12, hash(12), hash(11));
+        let mut recent = BTreeMap::new();
+        for n in 10..=12 {
+            detect(&chain, &mut recent, n, Some(hash(n)), hash(n - 1)).await;
+        }
+        // Mismo número, otro hash: el bloque 12 fue reemplazado.
+        assert_eq!(detect(&chain, &mut recent, 12, Some(hash(112)), hash(11)).await, Some(12));
+        // El padre no encadena con el 12 conocido, pero sí con el 11: la divergencia empieza en el 12.
+        assert_eq!(detect(&chain, &mut recent, 13, Some(hash(113)), hash(12)).await, Some(12));
+        // Un head anterior descarta los bloques posteriores de la cadena abandonada.
+        assert_eq!(detect(&chain, &mut recent, 11, Some(hash(211)), hash(10)).await, Some(11));
+        assert_eq!(recent.keys().copied().collect::<Vec<_>>(), vec![10, 11]);
+    }
+
+    #[tokio::test]
+    async fn detect_reorg_follows_the_new_chain_back_to_the_fork() {
+        // La nueva cadena se separa en el 13: 113 <- 114 <- 115 <- 16.
+        let chain = crate::testing::MockChain::new()
+            .block(13, hash(113), hash(12))
+            .block(14, hash(114), hash(113))
+            .block(15, hash(115), hash(114));
+        let mut recent = BTreeMap::new();
+        for n in 10..=15 {
+            detect(&chain, &mut recent, n, Some(hash(n)), hash(n - 1)).await;
+        }
+        assert_eq!(detect(&chain, &mut recent, 16, Some(hash(116)), hash(115)).await, Some(13));
+        assert_eq!(recent.keys().copied().collect::<Vec<_>>(), vec![10, 11, 12, 16]);
+
+        // Sin los bloques de la nueva cadena no se puede bajar más: se corta en el último comprobado.
+        let mut recent = BTreeMap::new();
+        for n in 10..=15 {
+            detect(&chain, &mut recent, n, Some(hash(n)), hash(n - 1)).await;
+        }
+        let unknown = crate::testing::MockChain::new();
+        assert_eq!(detect(&unknown, &mut recent, 16, Some(hash(116)), hash(115)).await, Some(15));
+    }
+
+    #[tokio::test]
+    async fn detect_reorg_tracks_a_bounded_window() {
+        let chain = crate::testing::MockChain::new();
+        let mut recent = BTreeMap::new();
+        for n in 1..=(REORG_TRACKED_BLOCKS as u64 + 10) {
+            detect(&chain, &mut recent, n, Some(hash(n)), hash(n - 1)).await;
+        }
+        assert_eq!(recent.len(), REORG_TRACKED_BLOCKS);
+        assert_eq!(recent.keys().next(), Some(&11));
+    }
+
+
+    #[tokio::test]
+    async fn flat_profit_is_not_resubmitted_across_blocks() {
+        with_test_config(|c| c.min_profit_improvement = 0.1, async {
+            let mut flat = opportunity([1, 2, 3], 1.0, 30.0);
+            flat.lag = 0.02;
+            let selected = |block: u64, opp: &ArbitrageOpportunity| select_for_execution(vec![opp.clone()], block).len() == 1;
+
+            // Un envío fallido no deja referencia: el bloque siguiente la vuelve a intentar.
+            assert!(selected(100, &flat));
+            assert!(!stale_before_optimization(&flat.path, flat.lag, 101));
+            assert!(selected(101, &flat));
+            optimization::record_submission(&flat, 101);
+
+            // Con el mismo profit y lag, los bloques siguientes ni la optimizan ni la reenvían.
+            for block in 102..105 {
+                assert!(stale_before_optimization(&flat.path, flat.lag, block));
+                assert!(!selected(block, &flat));
+            }
+            // Un lag que mejora el margen sí se optimiza, y un profit que lo mejora se reenvía.
+            assert!(!stale_before_optimization(&flat.path, 0.023, 105));
+            let mut improved = flat.clone();
+            improved.net_profit_usd = 33.5;
+            assert!(selected(105, &improved));
+            // Pasada la ventana, o si el envío anterior falló, vuelve a competir.
+            assert!(!stale_before_optimization(&flat.path, flat.lag, 101 + RESUBMIT_IMPROVEMENT_WINDOW_BLOCKS));
+            ROUTE_STATS.lock().unwrap().get_mut(&flat.path.key()).unwrap().last_failure_block = 106;
+            assert!(!stale_before_optimization(&flat.path, flat.lag, 107));
+        })
+        .await;
+    }
+
+
+    #[tokio::test]
+    async fn recent_imbalance_against_the_oracle_flags_sandwich_bait() {
+        use crate::{
+            config::OracleFeed,
+            multi::RawPoolData,
+            paths::tests::addr,
+            pools::{PoolMetrics, POOL_METRICS},
+            testing::MockChain,
+        };
+
+        // token_a (addr 1) a $2.000 y token_b (addr 2) a $1 según sus feeds; el pool 1->2 quedó a 2.500
+        // tras un swap de 10 token_a ($20.000): 2.500 bps por encima del oráculo.
+        let (feed_a, feed_b) = (addr(201), addr(202));
+        let now = crate::pools::unix_now();
+        let chain = MockChain::new()
+            .latest_round_data(feed_a, 2_000 * 100_000_000, now)
+            .decimals(feed_a, 8)
+            .latest_round_data(feed_b, 100_000_000, now)
+            .decimals(feed_b, 8);
+        let oracle_map = OracleMap::new();
+        let bait = opportunity([1, 2, 3], 1.0, 30.0);
+        let pool = bait.path.pool_1.clone();
+        let swap = |block: u64| Log {
+            address: pool.address,
+            data: [
+                U256::exp10(19),
+                (!(U256::from(19_000) * U256::exp10(18))).overflowing_add(U256::one()).0,
+                U256::from(50) * U256::from(2).pow(96.into()),
+            ]
+            .iter()
+            .flat_map(|word| { let mut bytes = [0u8; 32]; word.to_big_endian(&mut bytes); bytes })
+            .collect::<Vec<u8>>()
+            .into(),
+            block_number: Some(block.into()),
+            ..Default::default()
+        };
+        let seed = || {
+            POOL_METRICS.lock().unwrap().insert(pool.address, PoolMetrics {
+                raw: RawPoolData { token0: pool.token0, token1: pool.token1, decimals0: 18, decimals1: 18, ..Default::default() },
+                raw_tvl_usd: 1_000_000.0,
+                effective_tvl_usd: 1_000_000.0,
+                price0_usd: 2_000.0,
+                price1_usd: 1.0,
+            });
+        };
+        let with_feeds = |c: &mut crate::config::Config| {
+            c.oracle_feeds = [(pool.token0, OracleFeed::Chainlink(feed_a)), (pool.token1, OracleFeed::Chainlink(feed_b))].into();
+            c.bait_price_deviation_bps = 500;
+            c.bait_large_swap_usd = 10_000.0;
+            c.min_profit_usd = 10.0;
+        };
+
+        with_test_config(with_feeds, async {
+            seed();
+            pools::record_large_swaps(&[swap(100)], 101, CONFIG.bait_large_swap_usd);
+            // El log se anota en su propio bloque: dentro de la ventana en 102, fuera en 103.
+            assert_eq!(pools::recent_large_swap(&pool.address, 102, BAIT_SWAP_LOOKBACK_BLOCKS).map(|(usd, _)| usd), Some(20_000.0));
+            let (flagged, deviation_bps, size_usd) = sandwich_bait_hop(&chain.provider(), &oracle_map, &bait.path, 102).await.unwrap();
+            assert_eq!((flagged, size_usd), (pool.address, 20_000.0));
+            assert!((deviation_bps - 2_500.0).abs() < 1.0);
+            assert!(screen_sandwich_bait(&chain.provider(), &oracle_map, vec![bait.clone()], 102).await.is_empty());
+            assert_eq!(screen_sandwich_bait(&chain.provider(), &oracle_map, vec![bait.clone()], 103).await.len(), 1);
+        })
+        .await;
+        // Con margen extra exigido, la ruta se mantiene si su profit lo cubre.
+        with_test_config(|c| { with_feeds(c); c.bait_extra_profit_usd = 15.0; }, async {
+            seed();
+            pools::record_large_swaps(&[swap(100)], 100, CONFIG.bait_large_swap_usd);
+            let kept = screen_sandwich_bait(&chain.provider(), &oracle_map, vec![bait.clone(), opportunity([1, 2, 4], 1.0, 20.0)], 101).await;
+            assert_eq!(kept.iter().map(|o| o.net_profit_usd).collect::<Vec<_>>(), vec![30.0]);
+        })
+        .await;
+        // Sin feed propio para los tokens no hay referencia independiente del pool: no se marca ni se lee nada.
+        let calls = chain.calls_to("latestRoundData()");
+        with_test_config(|c| { with_feeds(c); c.oracle_feeds.clear(); }, async {
+            seed();
+            pools::record_large_swaps(&[swap(100)], 100, CONFIG.bait_large_swap_usd);
+            assert!(sandwich_bait_hop(&chain.provider(), &oracle_map, &bait.path, 101).await.is_none());
+        })
+        .await;
+        assert_eq!(chain.calls_to("latestRoundData()"), calls);
+    }
+
+    #[test]
+    fn swap_logs_resume_after_the_last_block_read() {
+        assert_eq!(swap_logs_from_block(None, 100), 100);
+        assert_eq!(swap_logs_from_block(Some(99), 100), 100);
+        assert_eq!(swap_logs_from_block(Some(95), 100), 96);
+        assert_eq!(swap_logs_from_block(Some(10), 100), 100 - pools::SWAP_LOGS_MAX_RANGE_BLOCKS + 1);
+        // Tras un reorg el head puede retroceder: se relee sólo el bloque actual.
+        assert_eq!(swap_logs_from_block(Some(105), 100), 100);
+    }
+
+
+    #[tokio::test]
+    async fn only_the_best_direction_of_a_cycle_is_selected() {
+        // 1->3->2 y 1->2->3 recorren el mismo ciclo en sentidos opuestos por pools distintos.
+        let candidates = vec![opportunity([1, 3, 2], 3.0, 30.0), opportunity([2, 3, 1], 2.0, 20.0), opportunity([5, 6, 7], 1.0, 10.0)];
+        let keys = with_test_config(|c| c.execution_strategy = ExecutionStrategy::Bundle, async {
+            select_for_execution(candidates, 100).iter().map(|o| o.path.key()).collect::<Vec<_>>()
+        })
+        .await;
+        assert_eq!(keys, vec![path([1, 3, 2]).key(), path([5, 6, 7]).key()]);
+    }
+
+
+    #[test]
+    fn pool_churn_counts_additions_removals_and_liquidity_shifts() {
+        use crate::paths::tests::pool;
+
+        let previous = vec![pool(1, 1, 2, 100.0), pool(2, 1, 2, 100.0), pool(3, 1, 2, 100.0), pool(4, 1, 2, 100.0)];
+        assert_eq!(pool_churn(&previous, &previous), 0.0);
+
+        let removed = previous[..3].to_vec();
+        assert_eq!(pool_churn(&previous, &removed), 0.25);
+
+        let mut shifted = previous.clone();
+        shifted[0].tvl_usd = 150.0;
+        shifted[1].tvl_usd = 110.0;
+        assert_eq!(pool_churn(&previous, &shifted), 0.25);
+
+        let mut added = previous.clone();
+        added.push(pool(5, 1, 2, 100.0));
+        assert_eq!(pool_churn(&previous, &added), 0.2);
+        assert_eq!(pool_churn(&[], &[]), 0.0);
+    }
+
+    #[test]
+    fn adapt_refresh_interval_follows_the_churn() {
+        let (next, reason) = adapt_refresh_interval(100, 0.0, 1, 10, 1_000);
+        assert_eq!(next, 50);
+        assert!(reason.unwrap().contains("1 pools nuevos"));
+        assert_eq!(adapt_refresh_interval(100, 0.10, 0, 10, 1_000).0, 50);
+        assert_eq!(adapt_refresh_interval(100, 0.0, 0, 10, 1_000).0, 150);
+        assert_eq!(adapt_refresh_interval(100, 0.03, 0, 10, 1_000), (100, None));
+    }
+
+    #[test]
+    fn adapt_refresh_interval_stays_within_bounds() {
+        assert_eq!(adapt_refresh_interval(12, 0.5, 3, 10, 1_000).0, 10);
+        assert_eq!(adapt_refresh_interval(900, 0.0, 0, 10, 1_000).0, 1_000);
+        assert_eq!(adapt_refresh_interval(1_000, 0.0, 0, 10, 1_000), (1_000, None));
+        // Límites incoherentes: el máximo nunca baja del mínimo y el mínimo es al menos 1.
+        assert_eq!(adapt_refresh_interval(1, 0.5, 1, 0, 0).0, 1);
+    }
+
+
+    #[tokio::test]
+    async fn routes_through_a_just_traded_pool_need_the_extra_margin() {
+        let screened = |extra_profit_usd: f64| with_test_config(
+            move |c| {
+                c.min_profit_usd = 5.0;
+                c.own_trade_lookback_blocks = 2;
+                c.own_trade_extra_profit_usd = extra_profit_usd;
+            },
+            async {
+                let traded = path([1, 2, 3]);
+                pools::record_own_trade(&[traded.address(2)], 100);
+                let candidates = || vec![opportunity([1, 2, 3], 1.0, 12.0), opportunity([1, 2, 3], 1.0, 20.0), opportunity([5, 6, 7], 1.0, 6.0)];
+                let keys = |opps: Vec<ArbitrageOpportunity>| opps.iter().map(|o| o.net_profit_usd).collect::<Vec<_>>();
+                (keys(screen_own_trade_pools(candidates(), 102)), keys(screen_own_trade_pools(candidates(), 103)))
+            },
+        );
+
+        // Dentro de la ventana, la ruta por el pool operado necesita min_profit + extra (5 + 10 = 15) o se
+        // descarta sin extra; la ruta ajena sólo el mínimo. Pasada la ventana, vuelve el trato normal.
+        assert_eq!(screened(10.0).await, (vec![20.0, 6.0], vec![12.0, 20.0, 6.0]));
+        assert_eq!(screened(0.0).await, (vec![6.0], vec![12.0, 20.0, 6.0]));
+    }
+
+
+    #[tokio::test]
+    async fn presend_simulation_reverts_do_not_count_toward_the_blacklist() {
+        let lost_race = || Err((anyhow::Error::new(crate::provider::Reverted { stage: "eth_call", reason: "STF".to_string() }), "ruta".to_string()));
+        let stats = with_test_config(|c| c.identical_revert_threshold = 2, async {
+            record_execution_results((0..5).map(|_| lost_race()).collect(), 100);
+            let stats = ROUTE_STATS.lock().unwrap()["ruta"].clone();
+            (stats.failures, stats.identical_reverts, optimization::route_is_blacklisted("ruta", 101))
+        })
+        .await;
+
+        // Cinco carreras perdidas con el mismo motivo son fallos, no una racha de reverts on-chain.
+        assert_eq!(stats, (5, 0, false));
+    }
+
+
+    #[tokio::test]
+    async fn failed_route_stays_cooled_while_its_pool_keeps_moving() {
+        let route = path([1, 2, 3]);
+        let pool = route.address(2);
+        // Swap en `block` que deja el sqrtPrice del pool en `sqrt_bps` diezmilésimas de 2^96.
+        let swap = |block: u64, sqrt_bps: u64| Log {
+            address: pool,
+            data: [U256::zero(), U256::zero(), (U256::one() << 96) * sqrt_bps / 10_000]
+                .iter()
+                .flat_map(|word| { let mut bytes = [0u8; 32]; word.to_big_endian(&mut bytes); bytes })
+                .collect::<Vec<u8>>()
+                .into(),
+            block_number: Some(block.into()),
+            ..Default::default()
+        };
+        let route = &route;
+        // Ruta fallida en el bloque 100 (cooldown fijo hasta el 110) con un swap por bloque hasta `block`.
+        let cooled = |sqrt_bps: fn(u64) -> u64, block: u64| {
+            with_test_config(
+                |c| {
+                    c.cooldown_settle_blocks = 3;
+                    c.cooldown_settle_tolerance_bps = 5;
+                    c.cooldown_settle_max_wait_blocks = 20;
+                },
+                async move {
+                    ROUTE_STATS.lock().unwrap().entry(route.key()).or_default().last_failure_block = 100;
+                    let logs: Vec<Log> = (100..=block).map(|b| swap(b, sqrt_bps(b))).collect();
+                    pools::record_swap_activity(&logs, block);
+                    route_in_cooldown(route, block)
+                },
+            )
+        };
+        // ~1% de precio por bloque frente a un vaivén de 2 bps que no sale de la tolerancia.
+        let trending: fn(u64) -> u64 = |b| 10_000 + 50 * (b - 100);
+        let jitter: fn(u64) -> u64 = |b| 10_000 + b % 2;
+
+        // Vencido el cooldown fijo, el pool que sigue moviéndose mantiene la ruta fuera...
+        assert!(cooled(trending, 115).await);
+        assert!(cooled(trending, 129).await);
+        // ...hasta agotar la espera extra máxima.
+        assert!(!cooled(trending, 130).await);
+        // Un pool líquido con swaps en cada bloque pero precio estable no la retiene.
+        assert!(cooled(jitter, 109).await);
+        assert!(!cooled(jitter, 110).await);
+    }
+
+
+    #[tokio::test]
+    async fn pools_far_from_the_oracle_are_screened_before_optimization() {
+        use crate::config::OracleFeed;
+        use crate::multi::RawPoolData;
+        use crate::oracle::OracleMap;
+        use crate::paths::tests::addr;
+        use crate::pools::{PoolMetrics, POOL_METRICS};
+        use crate::testing::MockChain;
+
+        // token 1 a $2.000 y tokens 2 y 3 a $1 según sus feeds; el pool 1-2 cotiza a 2.500 (2.500 bps de más).
+        let feeds: HashMap<H160, OracleFeed> = (1..=3).map(|t| (addr(t), OracleFeed::Chainlink(addr(200 + t)))).collect();
+        let now = crate::pools::unix_now();
+        let chain = MockChain::new()
+            .latest_round_data(addr(201), 2_000 * 100_000_000, now)
+            .latest_round_data(addr(202), 100_000_000, now)
+            .latest_round_data(addr(203), 100_000_000, now);
+        let chain = (201..=203).fold(chain, |chain, feed| chain.decimals(addr(feed), 8));
+        let sqrt_price = |price: f64| U256::from((price.sqrt() * 2f64.powi(48)) as u128) << 48;
+        let routes = vec![path([1, 2, 3]), path([1, 3, 2])];
+        let oracle_map = OracleMap::new();
+        let (routes, chain, oracle_map) = (&routes, &chain, &oracle_map);
+        let screen = |flag_only: bool| {
+            let feeds = feeds.clone();
+            with_test_config(
+                move |c| {
+                    c.oracle_feeds = feeds;
+                    c.max_oracle_pool_divergence_bps = 500;
+                    c.oracle_divergence_flag_only = flag_only;
+                },
+                async move {
+                    for (pool, price) in [(routes[0].address(1), 2_500.0), (routes[0].address(2), 1.0), (routes[0].address(3), 1.0 / 2_000.0)] {
+                        let raw = RawPoolData { sqrt_price_x96: sqrt_price(price), unlocked: true, ..Default::default() };
+                        POOL_METRICS.lock().unwrap().insert(pool, PoolMetrics { raw, ..Default::default() });
+                    }
+                    let divergent = oracle_divergent_pools(&chain.provider(), oracle_map, routes).await;
+                    let skipped: Vec<bool> = routes.iter().map(|route| skip_divergent_route(route, &divergent)).collect();
+                    (divergent.into_iter().map(|(pool, bps)| (pool, bps.round())).collect::<Vec<_>>(), skipped)
+                },
+            )
+        };
+
+        let calls = chain.calls_to("latestRoundData()");
+        let (divergent, skipped) = screen(false).await;
+        assert_eq!(divergent, vec![(routes[0].address(1), 2_500.0)]);
+        // La ruta por el pool desviado se salta; la otra no pasa por él. Cada token se lee una sola vez por
+        // bloque aunque aparezca en varios saltos y rutas.
+        assert_eq!(skipped, vec![true, false]);
+        assert_eq!(chain.calls_to("latestRoundData()") - calls, 3);
+        // Sólo registrar: el pool se marca pero la ruta se sigue optimizando.
+        assert_eq!(screen(true).await.1, vec![false, false]);
+    }
+}
@@ -1,11 +1,37 @@
+use crate::{
+    config::CONFIG,
+    instance, pools, strategy,
+    types::DexVariant,
+};
+use anyhow::{anyhow, Result};
 use ethers::{
     prelude::*,
     providers::{Middleware, Provider, Ws},
 };
 use futures_util::StreamExt;
-use log::{error, info, warn};
-use std::sync::Arc;
-use tokio::sync::broadcast::Sender;
+use lazy_static::lazy_static;
+use log::{debug, error, info, warn};
+use prometheus::{register_int_counter_vec, IntCounterVec};
+use std::{
+    collections::HashSet,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use tokio::{
+    sync::{broadcast::Sender, mpsc, Mutex},
+    task::JoinSet,
+};
+
+lazy_static! {
+    static ref WS_RECONNECTS: IntCounterVec = register_int_counter_vec!("ws_reconnects_total", "Reconexiones del WebSocket de bloques", &["chain"]).unwrap();
+    static ref MEMPOOL_TXS_DROPPED: IntCounterVec = register_int_counter_vec!("mempool_txs_dropped_total", "Hashes del mempool descartados por cola llena", &["chain"]).unwrap();
+}
+
+// Espera entre intentos de reconexión fallidos.
+const WS_RECONNECT_BACKOFF_SECS: u64 = 2;
+// Intervalo mínimo entre refrescos forzados por liquidez nueva en pools vigilados.
+const WATCH_REFRESH_COOLDOWN_SECS: u64 = 60;
+const MINT_EVENT: &str = "Mint(address,address,int24,int24,uint128,uint256,uint256)";
 
 /// Define los eventos que el bot puede procesar.
 /// Por ahora, el principal es `Block`, que actúa como el "latido" del bot.
@@ -17,58 +43,399 @@ pub enum Event {
 
 /// Escucha el stream de nuevos bloques de la red y emite un evento `Event::Block`
 /// para cada uno. Este es el disparador principal de nuestra estrategia.
-pub async fn stream_new_blocks(provider: Arc<Provider<Ws>>, sender: Sender<Event>) {
-    let mut stream = match provider.subscribe_blocks().await {
-        Ok(s) => s,
-        Err(e) => {
-            error!(" No se pudo suscribir a los bloques: {e:?}");
-            return;
+/// Si la suscripción muere o el heartbeat no responde, se reconecta con un proveedor nuevo.
+/// Termina cuando los receptores del canal, que ya se habían suscrito, se cierran todos.
+pub async fn stream_new_blocks(mut provider: Arc<Provider<Ws>>, sender: Sender<Event>) {
+    let mut had_receivers = false;
+    loop {
+        match run_block_subscription(&provider, &sender, &mut had_receivers).await {
+            Ok(()) => {
+                info!("Sin receptores en el canal de eventos: stream de bloques detenido.");
+                return;
+            }
+            Err(e) => warn!(" Suscripción de bloques caída: {e:?}. Reconectando..."),
         }
-    };
+        instance::labeled(&WS_RECONNECTS).inc();
+        loop {
+            match Provider::<Ws>::connect(&CONFIG.wss_url).await {
+                Ok(new_provider) => {
+                    provider = Arc::new(new_provider);
+                    break;
+                }
+                Err(e) => {
+                    error!("No se pudo reconectar el WebSocket: {e:?}");
+                    tokio::time::sleep(Duration::from_secs(WS_RECONNECT_BACKOFF_SECS)).await;
+                }
+            }
+        }
+    }
+}
+
+/// `true` si el canal tuvo receptores y ya no queda ninguno: el consumidor terminó y seguir emitiendo
+/// sólo gasta RPC. Que no haya receptores desde el arranque (aún no se han suscrito) no cuenta.
+fn receivers_gone(sender: &Sender<Event>, had_receivers: &mut bool) -> bool {
+    let count = sender.receiver_count();
+    *had_receivers |= count > 0;
+    *had_receivers && count == 0
+}
+
+/// Procesa una suscripción de bloques hasta que el stream termina o falla el heartbeat (error), o
+/// hasta que no quedan receptores (`Ok`).
+/// El heartbeat (`eth_blockNumber`) detecta conexiones medio abiertas que el SO tardaría minutos en cerrar.
+async fn run_block_subscription(provider: &Provider<Ws>, sender: &Sender<Event>, had_receivers: &mut bool) -> Result<()> {
+    let mut stream = provider.subscribe_blocks().await?;
     info!(" Subscripción a nuevos bloques iniciada.");
 
-    while let Some(block_header) = stream.next().await {
-        if let Some(hash) = block_header.hash {
-            // Obtenemos el bloque completo, ya que contiene información valiosa como el `base_fee_per_gas`.
-            match provider.get_block(hash).await {
-                Ok(Some(full_block)) => {
-                    if sender.send(Event::Block(full_block)).is_err() {
-                        // Esto ocurre si el receptor (el `strategy_handler`) ha terminado.
-                        // Podemos salir del bucle para no seguir trabajando inútilmente.
-                        warn!("El canal de eventos de bloques está cerrado. Terminando stream.");
-                        break;
+    let mut heartbeat = tokio::time::interval(Duration::from_secs(CONFIG.ws_heartbeat_interval_secs.max(1)));
+    heartbeat.tick().await; // El primer tick es inmediato.
+    let heartbeat_timeout = Duration::from_secs(CONFIG.ws_heartbeat_timeout_secs);
+
+    loop {
+        tokio::select! {
+            maybe_header = stream.next() => {
+                let block_header = match maybe_header {
+                    Some(header) => header,
+                    None => return Err(anyhow!("el stream de bloques terminó")),
+                };
+                if receivers_gone(sender, had_receivers) { return Ok(()); }
+                if let Some(hash) = block_header.hash {
+                    // Obtenemos el bloque completo, ya que contiene información valiosa como el `base_fee_per_gas`.
+                    match provider.get_block(hash).await {
+                        Ok(Some(full_block)) => {
+                            if sender.send(Event::Block(full_block)).is_err() {
+                                // `send` sólo falla si no hay receptores suscritos en este momento (p. ej. durante
+                                // el arranque). No es un error: seguimos emitiendo hasta que alguien se suscriba.
+                                debug!("Sin receptores suscritos al canal de eventos; bloque descartado.");
+                            }
+                        }
+                        Ok(None) => warn!("Reorganización de bloque detectada, el bloque {hash:?} ya no existe."),
+                        Err(e) => error!("Error al obtener el bloque completo {hash:?}: {e:?}"),
                     }
                 }
-                Ok(None) => warn!("Reorganización de bloque detectada, el bloque {hash:?} ya no existe."),
-                Err(e) => error!("Error al obtener el bloque completo {hash:?}: {e:?}"),
             }
+            _ = heartbeat.tick() => {
+                match tokio::time::timeout(heartbeat_timeout, provider.get_block_number()).await {
+                    Ok(Ok(_)) => {}
+                    Ok(Err(e)) => return Err(anyhow!("heartbeat falló: {e:?}")),
+                    Err(_) => return Err(anyhow!("heartbeat sin respuesta en {:?}", heartbeat_timeout)),
+                }
+            }
+        }
+    }
+}
+
+/// Escucha `PoolCreated` de las factories de la chain (`CONFIG.dexes`) y `Mint`/`Swap` de los pools de
+/// los tokens vigilados (`WATCH_TOKENS`). Un pool nuevo se registra para los refrescos; un pool nuevo o
+/// con actividad que aún no está en el universo fuerza un refresco en el próximo bloque (el filtro de
+/// TVL decide).
+pub async fn stream_watch_token_events(mut provider: Arc<Provider<Ws>>) {
+    let factories: Vec<(H160, DexVariant)> = CONFIG.dexes.iter().map(|dex| (dex.factory, dex.variant)).collect();
+    // Pools ya conocidos de los tokens vigilados (caché CSV) cuyos eventos interesan desde el inicio.
+    let mut watched: HashSet<H160> = pools::read_pool_cache()
+        .unwrap_or_default()
+        .into_iter()
+        .filter(|p| CONFIG.watch_tokens.contains(&p.token0) || CONFIG.watch_tokens.contains(&p.token1))
+        .map(|p| p.address)
+        .collect();
+    info!(" Vigilando {} tokens ({} pools conocidos).", CONFIG.watch_tokens.len(), watched.len());
+    let mut last_refresh: Option<Instant> = None;
+
+    loop {
+        match run_watch_subscription(&provider, &factories, &mut watched, &mut last_refresh).await {
+            // Pool nuevo vigilado: se renueva la suscripción con el mismo proveedor.
+            Ok(()) => continue,
+            Err(e) => warn!("Suscripción a eventos de tokens vigilados caída: {e:?}. Reconectando..."),
+        }
+        tokio::time::sleep(Duration::from_secs(WS_RECONNECT_BACKOFF_SECS)).await;
+        match Provider::<Ws>::connect(&CONFIG.wss_url).await {
+            Ok(new_provider) => provider = Arc::new(new_provider),
+            Err(e) => error!("No se pudo reconectar el WebSocket de tokens vigilados: {e:?}"),
+        }
+    }
+}
+
+/// Procesa una suscripción a `PoolCreated`, `Mint` y `Swap` hasta que aparece un pool vigilado nuevo
+/// (`Ok`, hay que renovar el filtro de los pools) o el stream termina o falla (`Err`, hay que reconectar).
+async fn run_watch_subscription(
+    provider: &Provider<Ws>,
+    factories: &[(H160, DexVariant)],
+    watched: &mut HashSet<H160>,
+    last_refresh: &mut Option<Instant>,
+) -> Result<()> {
+    let created_filter = Filter::new()
+        .address(factories.iter().map(|(f, _)| *f).collect::<Vec<_>>())
+        .event(pools::POOL_CREATED_EVENT);
+    let activity_filter = Filter::new()
+        .address(watched.iter().copied().collect::<Vec<_>>())
+        .events([MINT_EVENT, pools::SWAP_EVENT]);
+    let created = provider.subscribe_logs(&created_filter).await?;
+    // Sin pools vigilados no se suscribe a su actividad: un filtro sin direcciones traería todos los pools.
+    let mut events = if watched.is_empty() {
+        created.boxed()
+    } else {
+        let activity = provider.subscribe_logs(&activity_filter).await?;
+        futures_util::stream::select(created, activity).boxed()
+    };
+
+    while let Some(log) = events.next().await {
+        if handle_watch_log(&log, factories, watched, last_refresh) { return Ok(()); }
+    }
+    Err(anyhow!("el stream de eventos de tokens vigilados terminó"))
+}
+
+/// Procesa un log de la suscripción de tokens vigilados. Un pool nuevo de un token vigilado se registra
+/// y fuerza un refresco (la liquidez puede llegar en la misma TX que lo crea, antes de suscribirse a él);
+/// `Mint` o `Swap` en un pool vigilado aún fuera del universo también lo fuerzan, como mucho uno cada
+/// `WATCH_REFRESH_COOLDOWN_SECS`. `true` si hay que renovar la suscripción para incluir un pool nuevo.
+fn handle_watch_log(
+    log: &Log,
+    factories: &[(H160, DexVariant)],
+    watched: &mut HashSet<H160>,
+    last_refresh: &mut Option<Instant>,
+) -> bool {
+    if let Some(&(_, version)) = factories.iter().find(|(f, _)| *f == log.address) {
+        let Some((address, pool)) = decode_pool_created(log, version) else { return false };
+        if let Some(block) = log.block_number {
+            pools::record_pool_creation(address, block.as_u64());
+        }
+        if !CONFIG.watch_tokens.contains(&pool.token0) && !CONFIG.watch_tokens.contains(&pool.token1) { return false; }
+        if pools::watch_pool(address, pool) {
+            warn!(" ALERTA: nuevo pool {address:?} ({:?}, fee {}) para un token vigilado.", version, pool.fee);
+            strategy::request_refresh();
+            *last_refresh = Some(Instant::now());
         }
+        return watched.insert(address);
     }
+    if !watched.contains(&log.address) || pools::is_watched_pool_admitted(&log.address) { return false; }
+    if last_refresh.is_some_and(|t| t.elapsed() < Duration::from_secs(WATCH_REFRESH_COOLDOWN_SECS)) { return false; }
+    info!(" Actividad en el pool vigilado {:?}: refresco dirigido en el próximo bloque.", log.address);
+    strategy::request_refresh();
+    *last_refresh = Some(Instant::now());
+    false
+}
+
+/// Decodifica `PoolCreated(token0, token1, fee, tickSpacing, pool)`: tokens y fee van indexados;
+/// `tickSpacing` y `pool` en `data`.
+fn decode_pool_created(log: &Log, version: DexVariant) -> Option<(H160, pools::WatchedPool)> {
+    let topic_address = |i: usize| log.topics.get(i).map(|t| H160::from_slice(&t.as_bytes()[12..]));
+    let token0 = topic_address(1)?;
+    let token1 = topic_address(2)?;
+    let fee = U256::from_big_endian(log.topics.get(3)?.as_bytes()).as_u32();
+    if log.data.len() < 64 { return None; }
+    let address = H160::from_slice(&log.data[44..64]);
+    Some((address, pools::WatchedPool { token0, token1, fee, version }))
+}
+
+/// Pool fijo de `workers` tareas que atienden una cola acotada de `queue_size` hashes con `handle`.
+/// Quien encola usa `try_send`: con la cola llena el hash se descarta en vez de crecer sin límite.
+fn spawn_mempool_workers<F, Fut>(workers: usize, queue_size: usize, handle: F) -> (mpsc::Sender<TxHash>, JoinSet<()>)
+where
+    F: Fn(TxHash) -> Fut + Clone + Send + 'static,
+    Fut: std::future::Future<Output = ()> + Send,
+{
+    let (hash_sender, hash_receiver) = mpsc::channel::<TxHash>(queue_size.max(1));
+    let hash_receiver = Arc::new(Mutex::new(hash_receiver));
+    let mut set = JoinSet::new();
+    for _ in 0..workers.max(1) {
+        let receiver = hash_receiver.clone();
+        let handle = handle.clone();
+        set.spawn(async move {
+            loop {
+                let tx_hash = match receiver.lock().await.recv().await {
+                    Some(hash) => hash,
+                    None => break,
+                };
+                handle(tx_hash).await;
+            }
+        });
+    }
+    (hash_sender, set)
 }
 
 /// (Opcional) Escucha el mempool para transacciones pendientes.
 /// Útil para estrategias de back-running. Puede ser intensivo en recursos.
+/// Los detalles de cada TX los obtiene un pool fijo de workers alimentado por una cola acotada;
+/// si la cola se llena, los hashes se descartan en vez de lanzar tareas sin límite.
 pub async fn stream_pending_txs(provider: Arc<Provider<Ws>>, sender: Sender<Event>) {
-    let mut stream = match provider.subscribe_pending_txs().await {
+    let subscription = provider.clone();
+    let mut stream = match subscription.subscribe_pending_txs().await {
         Ok(s) => s,
         Err(e) => {
             error!(" No se pudo suscribir al mempool: {e:?}");
             return;
         }
     };
-    info!(" Subscripción al mempool iniciada.");
+    info!(" Subscripción al mempool iniciada ({} workers).", CONFIG.mempool_workers);
+    let sender_for_workers = sender.clone();
+
+    // Al salir de esta función (o al abortar la tarea) el `JoinSet` se destruye y aborta a los workers.
+    let (hash_sender, mut workers) = spawn_mempool_workers(CONFIG.mempool_workers, CONFIG.mempool_queue_size, move |tx_hash| {
+        let provider = provider.clone();
+        let sender = sender_for_workers.clone();
+        async move {
+            if let Ok(Some(tx)) = provider.get_transaction(tx_hash).await {
+                // No logueamos el error de envío: el consumidor puede estar ocupado o cerrado.
+                let _ = sender.send(Event::MempoolTx(tx));
+            }
+        }
+    });
 
+    let mut had_receivers = false;
     while let Some(tx_hash) = stream.next().await {
-        let provider_clone = provider.clone();
-        let sender_clone = sender.clone();
-        // Lanzamos una tarea separada para obtener los detalles de la TX.
-        // Esto evita que una llamada lenta a `get_transaction` bloquee todo el stream.
-        tokio::spawn(async move {
-            if let Ok(Some(tx)) = provider_clone.get_transaction(tx_hash).await {
-                if sender_clone.send(Event::MempoolTx(tx)).is_err() {
-                    // No logueamos como error, ya que el consumidor puede estar ocupado o cerrado.
+        if receivers_gone(&sender, &mut had_receivers) {
+            info!("Sin receptores en el canal de eventos: stream del mempool detenido.");
+            break;
+        }
+        if hash_sender.try_send(tx_hash).is_err() {
+            instance::labeled(&MEMPOOL_TXS_DROPPED).inc();
+        }
+    }
+    workers.abort_all();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::instance::with_test_config;
+    use tokio::sync::broadcast;
+
+    /// `PoolCreated` de `factory` para el par (token0, token1) con fee 3000; `data_len` recorta el `data`.
+    fn pool_created_log(factory: H160, tokens: (H160, H160), data_len: usize) -> (Log, H160) {
+        let pool = H160::from_low_u64_be(0xabcd);
+        let mut data = ethers::abi::encode(&[ethers::abi::Token::Int(U256::from(60)), ethers::abi::Token::Address(pool)]);
+        data.truncate(data_len);
+        let log = Log {
+            address: factory,
+            topics: vec![
+                H256::from(ethers::utils::keccak256(pools::POOL_CREATED_EVENT)),
+                H256::from(tokens.0),
+                H256::from(tokens.1),
+                H256::from_low_u64_be(3000),
+            ],
+            data: data.into(),
+            ..Default::default()
+        };
+        (log, pool)
+    }
+
+    fn tokens() -> (H160, H160) {
+        (H160::from_low_u64_be(1), H160::from_low_u64_be(2))
+    }
+
+    #[test]
+    fn decode_pool_created_reads_tokens_fee_and_pool() {
+        let (log, pool) = pool_created_log(H160::zero(), tokens(), 64);
+        let (address, watched) = decode_pool_created(&log, DexVariant::PancakeV3).unwrap();
+        assert_eq!(address, pool);
+        assert_eq!((watched.token0, watched.token1), tokens());
+        assert_eq!(watched.fee, 3000);
+        assert!(matches!(watched.version, DexVariant::PancakeV3));
+    }
+
+    #[test]
+    fn decode_pool_created_rejects_incomplete_logs() {
+        let (log, _) = pool_created_log(H160::zero(), tokens(), 32);
+        assert!(decode_pool_created(&log, DexVariant::UniswapV3).is_none());
+
+        let (mut log, _) = pool_created_log(H160::zero(), tokens(), 64);
+        log.topics.truncate(3);
+        assert!(decode_pool_created(&log, DexVariant::UniswapV3).is_none());
+    }
+
+    #[tokio::test]
+    async fn watched_token_pool_events_trigger_a_refresh() {
+        let factory = H160::from_low_u64_be(0xfac);
+        let watched_token = H160::from_low_u64_be(2);
+        with_test_config(|c| c.watch_tokens = vec![watched_token], async move {
+            let factories = [(factory, DexVariant::SushiV3)];
+            let mut watched = HashSet::new();
+            let mut last_refresh = None;
+            let refresh_requested = || strategy::take_refresh_due(100, 101, 50) == Some(true);
+
+            // Pools de otros tokens, o de contratos que no son factories de la chain, no cuentan.
+            let (other, _) = pool_created_log(factory, (H160::from_low_u64_be(1), H160::from_low_u64_be(3)), 64);
+            assert!(!handle_watch_log(&other, &factories, &mut watched, &mut last_refresh));
+            let (foreign, _) = pool_created_log(H160::from_low_u64_be(0xbad), tokens(), 64);
+            assert!(!handle_watch_log(&foreign, &factories, &mut watched, &mut last_refresh));
+            assert!(!refresh_requested());
+
+            // Un pool nuevo del token vigilado se registra, renueva la suscripción y fuerza el refresco.
+            let (created, pool) = pool_created_log(factory, tokens(), 64);
+            assert!(handle_watch_log(&created, &factories, &mut watched, &mut last_refresh));
+            assert!(watched.contains(&pool));
+            assert!(refresh_requested());
+
+            // Su liquidez posterior también lo fuerza, respetando el intervalo mínimo entre refrescos.
+            let activity = |event: &str| Log {
+                address: pool,
+                topics: vec![H256::from(ethers::utils::keccak256(event))],
+                ..Default::default()
+            };
+            assert!(!handle_watch_log(&activity(MINT_EVENT), &factories, &mut watched, &mut last_refresh));
+            assert!(!refresh_requested());
+            last_refresh = None;
+            assert!(!handle_watch_log(&activity(MINT_EVENT), &factories, &mut watched, &mut last_refresh));
+            assert!(refresh_requested());
+            // Y un swap, que puede llevar el TVL sobre el umbral sin liquidez nueva.
+            last_refresh = None;
+            handle_watch_log(&activity(pools::SWAP_EVENT), &factories, &mut watched, &mut last_refresh);
+            assert!(refresh_requested());
+        })
+        .await;
+    }
+
+    #[test]
+    fn receivers_gone_only_after_the_receivers_existed() {
+        let (sender, first) = broadcast::channel::<Event>(4);
+        drop(first);
+        let mut had_receivers = false;
+        // Arranque: todavía nadie se ha suscrito.
+        assert!(!receivers_gone(&sender, &mut had_receivers));
+
+        let receiver = sender.subscribe();
+        assert!(!receivers_gone(&sender, &mut had_receivers));
+        let other = sender.subscribe();
+        drop(receiver);
+        assert!(!receivers_gone(&sender, &mut had_receivers));
+        drop(other);
+        assert!(receivers_gone(&sender, &mut had_receivers));
+    }
+
+    #[tokio::test]
+    async fn mempool_workers_stay_bounded_under_a_flood() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        let active = Arc::new(AtomicUsize::new(0));
+        let peak = Arc::new(AtomicUsize::new(0));
+        let release = Arc::new(tokio::sync::Semaphore::new(0));
+        let (queue, mut workers) = spawn_mempool_workers(3, 5, {
+            let (active, peak, release) = (active.clone(), peak.clone(), release.clone());
+            move |_| {
+                let (active, peak, release) = (active.clone(), peak.clone(), release.clone());
+                async move {
+                    let now = active.fetch_add(1, Ordering::SeqCst) + 1;
+                    peak.fetch_max(now, Ordering::SeqCst);
+                    release.acquire().await.unwrap().forget();
+                    active.fetch_sub(1, Ordering::SeqCst);
                 }
             }
         });
+
+        let mut accepted = 0;
+        for n in 0..1_000u64 {
+            if queue.try_send(H256::from_low_u64_be(n)).is_ok() {
+                accepted += 1;
+            }
+            tokio::task::yield_now().await;
+        }
+        // Tres en proceso y cinco en cola: el resto del flood se descarta.
+        assert_eq!(accepted, 8);
+        assert_eq!(peak.load(Ordering::SeqCst), 3);
+        assert_eq!(workers.len(), 3);
+
+        release.add_permits(accepted);
+        drop(queue);
+        while workers.join_next().await.is_some() {}
+        assert_eq!(active.load(Ordering::SeqCst), 0);
     }
 }
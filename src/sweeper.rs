@@ -0,0 +1,223 @@
+use crate::{config::CONFIG, execution, instance, math::to_usd, multi::IERC20, oracle::OracleMap};
+use anyhow::{anyhow, Result};
+use ethers::{prelude::*, types::transaction::eip2718::TypedTransaction};
+use lazy_static::lazy_static;
+use log::{info, warn};
+use prometheus::{register_int_counter_vec, IntCounterVec};
+use std::{sync::Arc, time::Duration};
+
+// Retirada de fondos del contrato de arbitraje (sólo el owner). El ABI del contrato no la declara: cada
+// barrido se simula antes de enviarse, así que un contrato sin esta función no recibe ninguna TX.
+abigen!(
+    IProfitSweep,
+    r#"[function withdrawToken(address token, address to, uint256 amount) external]"#,
+);
+
+lazy_static! {
+    static ref SWEEPS_SENT: IntCounterVec = register_int_counter_vec!("sweep_transfers_total", "Transferencias enviadas por el sweeper de profits", &["chain"]).unwrap();
+}
+
+/// Saldo de un token que el sweeper va a mover.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SweepTransfer {
+    pub token: H160,
+    pub amount: U256,
+    pub value_usd: f64,
+}
+
+/// Elige qué saldos barrer: los que valen al menos `min_usd`. Un token sin precio no se barre
+/// (no se puede saber si compensa el gas).
+pub fn plan_sweep(balances: &[(H160, U256, u8, Option<f64>)], min_usd: f64) -> Vec<SweepTransfer> {
+    balances
+        .iter()
+        .filter_map(|&(token, amount, decimals, price)| {
+            let value_usd = to_usd(amount, token, price?, decimals);
+            (!amount.is_zero() && value_usd >= min_usd).then_some(SweepTransfer { token, amount, value_usd })
+        })
+        .collect()
+}
+
+/// TX del barrido sin enviar: `withdrawToken` del contrato o `transfer` desde la wallet hacia `destination`.
+pub fn build_sweep_transactions<M: Middleware>(
+    client: Arc<M>, plan: &[SweepTransfer], destination: H160, from_wallet: bool,
+) -> Vec<TypedTransaction> {
+    plan.iter()
+        .map(|transfer| {
+            let mut tx = if from_wallet {
+                IERC20::new(transfer.token, client.clone()).transfer(destination, transfer.amount).tx
+            } else {
+                IProfitSweep::new(CONFIG.contract_address, client.clone()).withdraw_token(transfer.token, destination, transfer.amount).tx
+            };
+            tx.set_chain_id(CONFIG.chain_id);
+            tx
+        })
+        .collect()
+}
+
+/// Lee los saldos de `SWEEP_TOKENS` en el contrato (o la wallet) con sus decimales y precio de oráculo.
+async fn read_sweep_balances(
+    client: &Arc<SignerMiddleware<Provider<Http>, LocalWallet>>, oracle_map: &OracleMap, holder: H160,
+) -> Vec<(H160, U256, u8, Option<f64>)> {
+    let mut balances = Vec::with_capacity(CONFIG.sweep_tokens.len());
+    for &token in &CONFIG.sweep_tokens {
+        let erc20 = IERC20::new(token, client.clone());
+        let (Ok(amount), Ok(decimals)) = (erc20.balance_of(holder).call().await, erc20.decimals().call().await) else {
+            warn!("Sweeper: no se pudo leer el saldo de {token:?} en {holder:?}.");
+            continue;
+        };
+        let price = crate::feeds::get_price(oracle_map, &token, Arc::new(client.provider().clone())).await.map(|info| info.price);
+        balances.push((token, amount, decimals, price));
+    }
+    balances
+}
+
+/// Tarea periódica (`SWEEP_INTERVAL_SECS`) que mueve los profits acumulados a `SWEEP_DESTINATION`.
+pub async fn run_sweeper(client: Arc<SignerMiddleware<Provider<Http>, LocalWallet>>, oracle_map: Arc<OracleMap>) {
+    let Some(destination) = CONFIG.sweep_destination else { return };
+    let holder = if CONFIG.sweep_from_wallet { client.address() } else { CONFIG.contract_address };
+    info!(
+        " Sweeper activo: {} tokens de {:?} hacia {:?} cada {}s (mínimo ${:.2}).",
+        CONFIG.sweep_tokens.len(), holder, destination, CONFIG.sweep_interval_secs, CONFIG.sweep_min_usd
+    );
+    let mut ticker = tokio::time::interval(Duration::from_secs(CONFIG.sweep_interval_secs));
+    loop {
+        ticker.tick().await;
+        let balances = read_sweep_balances(&client, &oracle_map, holder).await;
+        let plan = plan_sweep(&balances, CONFIG.sweep_min_usd);
+        let txs = build_sweep_transactions(client.clone(), &plan, destination, CONFIG.sweep_from_wallet);
+        for (transfer, tx) in plan.iter().zip(txs) {
+            match send_sweep(&client, tx).await {
+                Ok(tx_hash) => info!(
+                    "Sweeper: {} unidades de {:?} (~${:.2}) enviadas a {:?}. TX {tx_hash:?}",
+                    transfer.amount, transfer.token, transfer.value_usd, destination
+                ),
+                Err(e) => warn!("Sweeper: fallo al barrer {:?}: {e:?}", transfer.token),
+            }
+        }
+    }
+}
+
+/// Simula la TX de barrido y, si no revierte, la envía con el contador de nonces compartido con las TX
+/// de arbitraje. Un `withdrawToken` que el contrato no implementa (o que exige otro owner) revierte aquí.
+async fn send_sweep(client: &SignerMiddleware<Provider<Http>, LocalWallet>, mut tx: TypedTransaction) -> Result<TxHash> {
+    tx.set_from(client.address());
+    client.call(&tx, None).await.map_err(|e| anyhow!("la simulación del barrido revierte: {e}"))?;
+    let (tx_hash, _) = execution::submit_with_shared_nonce(client, tx).await?;
+    instance::labeled(&SWEEPS_SENT).inc();
+    Ok(tx_hash)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{instance::with_test_config, testing::FakeNode};
+    use ethers::{abi::Token, utils::rlp::Rlp};
+    use serde_json::json;
+
+    #[test]
+    fn plan_sweep_keeps_priced_balances_above_the_minimum() {
+        let (usdc, weth, unpriced, dust) = (
+            H160::from_low_u64_be(1),
+            H160::from_low_u64_be(2),
+            H160::from_low_u64_be(3),
+            H160::from_low_u64_be(4),
+        );
+        let balances = [
+            (usdc, U256::from(250_000_000u64), 6, Some(1.0)),
+            (weth, U256::exp10(17), 18, Some(2_000.0)),
+            (unpriced, U256::exp10(24), 18, None),
+            (dust, U256::from(5_000_000u64), 6, Some(1.0)),
+            (usdc, U256::zero(), 6, Some(1.0)),
+        ];
+        let plan = plan_sweep(&balances, 10.0);
+        assert_eq!(
+            plan,
+            vec![
+                SweepTransfer { token: usdc, amount: U256::from(250_000_000u64), value_usd: 250.0 },
+                SweepTransfer { token: weth, amount: U256::exp10(17), value_usd: 200.0 },
+            ]
+        );
+        assert!(plan_sweep(&balances, 1_000.0).is_empty());
+    }
+
+    #[tokio::test]
+    async fn sweep_transactions_withdraw_from_the_contract_or_transfer_from_the_wallet() {
+        let (token, destination) = (H160::from_low_u64_be(1), H160::from_low_u64_be(0xc01d));
+        let plan = [SweepTransfer { token, amount: U256::from(250_000_000u64), value_usd: 250.0 }];
+        let (provider, _) = Provider::mocked();
+        let client = Arc::new(provider);
+        let (contract, contract_txs, wallet_txs) = with_test_config(|_| {}, async {
+            let build = |from_wallet| build_sweep_transactions(client.clone(), &plan, destination, from_wallet);
+            (CONFIG.contract_address, build(false), build(true))
+        })
+        .await;
+
+        let args = |tx: &TypedTransaction, types: &[ethers::abi::ParamType]| ethers::abi::decode(types, &tx.data().unwrap()[4..]).unwrap();
+        let address = ethers::abi::ParamType::Address;
+        let amount = ethers::abi::ParamType::Uint(256);
+
+        let withdraw = &contract_txs[0];
+        assert_eq!(withdraw.to_addr(), Some(&contract));
+        assert_eq!(withdraw.data().unwrap()[..4], ethers::utils::id("withdrawToken(address,address,uint256)"));
+        assert_eq!(
+            args(withdraw, &[address.clone(), address.clone(), amount.clone()]),
+            vec![Token::Address(token), Token::Address(destination), Token::Uint(plan[0].amount)]
+        );
+        let transfer = &wallet_txs[0];
+        assert_eq!(transfer.to_addr(), Some(&token));
+        assert_eq!(transfer.data().unwrap()[..4], ethers::utils::id("transfer(address,uint256)"));
+        assert_eq!(args(transfer, &[address, amount]), vec![Token::Address(destination), Token::Uint(plan[0].amount)]);
+        assert!([withdraw, transfer].iter().all(|tx| tx.chain_id() == Some(42161u64.into())));
+    }
+
+    /// Nodo que responde a la simulación del barrido (o la revierte) y acepta los envíos.
+    async fn sweep_node(simulation_reverts: bool) -> FakeNode {
+        FakeNode::start(move |method, _| match method {
+            "eth_call" if simulation_reverts => Err("execution reverted".to_string()),
+            "eth_call" => Ok(json!("0x")),
+            "eth_getTransactionCount" => Ok(json!("0x5")),
+            "eth_sendRawTransaction" => Ok(json!(TxHash::from_low_u64_be(99))),
+            _ => Err(format!("método inesperado {method}")),
+        })
+        .await
+    }
+
+    #[tokio::test]
+    async fn sweeps_are_simulated_first_and_share_the_arbitrage_nonce_counter() {
+        let plan = [SweepTransfer { token: H160::from_low_u64_be(1), amount: U256::from(250_000_000u64), value_usd: 250.0 }];
+        let sweep = |node: &FakeNode| {
+            let client = node.client();
+            async move {
+                let mut tx = build_sweep_transactions(client.clone(), &plan, H160::from_low_u64_be(0xc01d), false).remove(0);
+                tx.set_gas(100_000u64);
+                if let Some(eip1559) = tx.as_eip1559_mut() {
+                    eip1559.max_fee_per_gas = Some(U256::exp10(9));
+                    eip1559.max_priority_fee_per_gas = Some(U256::exp10(8));
+                }
+                send_sweep(&client, tx).await
+            }
+        };
+
+        // Un contrato sin `withdrawToken` revierte en la simulación y no se envía nada.
+        let reverting = sweep_node(true).await;
+        assert!(with_test_config(|_| {}, sweep(&reverting)).await.is_err());
+        assert!(reverting.requests("eth_sendRawTransaction").is_empty());
+
+        // Con el nodo en `pending` = 5, dos barridos seguidos toman 5 y 6 del contador compartido.
+        let node = sweep_node(false).await;
+        with_test_config(|_| {}, async {
+            sweep(&node).await.unwrap();
+            sweep(&node).await.unwrap();
+        })
+        .await;
+        let nonces: Vec<U256> = node
+            .requests("eth_sendRawTransaction")
+            .iter()
+            .map(|params| {
+                let raw: Bytes = serde_json::from_value(params[0].clone()).unwrap();
+                *TypedTransaction::decode_signed(&Rlp::new(&raw)).unwrap().0.nonce().unwrap()
+            })
+            .collect();
+        assert_eq!(nonces, vec![U256::from(5), U256::from(6)]);
+    }
+}
@@ -0,0 +1,101 @@
+//! Trazas OpenTelemetry (feature `otel`). Sin la feature todo es un no-op y el logging
+//! de `log`/`fern` no cambia.
+
+use crate::optimization::ArbitrageOpportunity;
+use anyhow::Result;
+use std::future::Future;
+
+/// Instala el exportador OTLP como subscriber global de `tracing`. El endpoint se toma de
+/// `OTEL_EXPORTER_OTLP_ENDPOINT` (por defecto `http://localhost:4317`).
+#[cfg(feature = "otel")]
+pub fn init_tracing() -> Result<()> {
+    use opentelemetry::KeyValue;
+    use opentelemetry_sdk::{trace, Resource};
+    use tracing_subscriber::layer::SubscriberExt;
+
+    let tracer = opentelemetry_otlp::new_pipeline()
+        .tracing()
+        .with_exporter(opentelemetry_otlp::new_exporter().tonic())
+        .with_trace_config(trace::config().with_resource(Resource::new(vec![KeyValue::new(
+            "service.name",
+            "mev-harvester",
+        )])))
+        .install_batch(opentelemetry_sdk::runtime::Tokio)?;
+    let subscriber = tracing_subscriber::registry().with(tracing_opentelemetry::layer().with_tracer(tracer));
+    tracing::subscriber::set_global_default(subscriber)?;
+    log::info!("Trazas OpenTelemetry activas (exportador OTLP).");
+    Ok(())
+}
+
+#[cfg(not(feature = "otel"))]
+pub fn init_tracing() -> Result<()> {
+    Ok(())
+}
+
+/// Vacía las trazas pendientes antes de salir.
+pub fn shutdown() {
+    #[cfg(feature = "otel")]
+    opentelemetry::global::shutdown_tracer_provider();
+}
+
+/// Span raíz del procesamiento de un bloque. Las fases (refresco, simulación de cada ruta, envío)
+/// cuelgan de él aunque corran en otras tareas, formando el árbol del bloque.
+#[derive(Clone)]
+pub struct BlockSpan {
+    #[cfg(feature = "otel")]
+    span: tracing::Span,
+}
+
+/// Abre el span raíz del bloque `block_number`.
+#[cfg(feature = "otel")]
+pub fn block_span(block_number: u64) -> BlockSpan {
+    BlockSpan { span: tracing::info_span!(parent: None, "block", block = block_number) }
+}
+
+#[cfg(not(feature = "otel"))]
+pub fn block_span(_block_number: u64) -> BlockSpan {
+    BlockSpan {}
+}
+
+#[cfg(feature = "otel")]
+impl BlockSpan {
+    fn child(&self, phase: &'static str, path_key: String) -> tracing::Span {
+        tracing::info_span!(parent: &self.span, "phase", phase, path = %path_key, profit_usd = tracing::field::Empty)
+    }
+
+    /// Ejecuta `fut` en un span hijo `phase` con la ruta como atributo.
+    pub fn in_phase<F: Future>(&self, phase: &'static str, path_key: String, fut: F) -> impl Future<Output = F::Output> {
+        use tracing::Instrument;
+        fut.instrument(self.child(phase, path_key))
+    }
+
+    /// Como `in_phase` para la simulación de una ruta: el span registra el profit neto de la oportunidad.
+    pub fn simulate_path<F>(&self, path_key: String, fut: F) -> impl Future<Output = F::Output>
+    where
+        F: Future<Output = Option<ArbitrageOpportunity>>,
+    {
+        use tracing::Instrument;
+        let span = self.child("simulate_path", path_key);
+        async move {
+            let opportunity = fut.instrument(span.clone()).await;
+            if let Some(opp) = &opportunity {
+                span.record("profit_usd", opp.net_profit_usd);
+            }
+            opportunity
+        }
+    }
+}
+
+#[cfg(not(feature = "otel"))]
+impl BlockSpan {
+    pub fn in_phase<F: Future>(&self, _phase: &'static str, _path_key: String, fut: F) -> impl Future<Output = F::Output> {
+        fut
+    }
+
+    pub fn simulate_path<F>(&self, _path_key: String, fut: F) -> impl Future<Output = F::Output>
+    where
+        F: Future<Output = Option<ArbitrageOpportunity>>,
+    {
+        fut
+    }
+}
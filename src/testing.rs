@@ -0,0 +1,456 @@
+//! Nodo simulado para los tests de la lógica genérica sobre `M: Middleware` (simulador, rutas,
+//! multicall, feeds de oráculo). Responde cada `eth_call` según el contrato y la calldata y emula
+//! `aggregate3` de Multicall3 despachando cada sub-llamada a la misma tabla. Una llamada sin
+//! respuesta registrada revierte, igual que en la chain.
+
+use crate::{simulator, types::DexVariant};
+use async_trait::async_trait;
+use ethers::{
+    abi::{self, ParamType, Token},
+    prelude::*,
+    providers::{JsonRpcClient, JsonRpcError, MockError},
+    utils::id,
+};
+use serde::{de::DeserializeOwned, Serialize};
+use serde_json::{json, Value};
+use std::{
+    collections::HashMap,
+    fmt::Debug,
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+/// Resultado de una llamada registrada.
+#[derive(Debug, Clone)]
+enum CallResult {
+    Return(Bytes),
+    Revert(Bytes),
+    // Error del nodo que no es un revert (timeout, límite de peticiones...).
+    Error(String),
+}
+
+/// Calldata de `signature` con los argumentos dados.
+pub(crate) fn calldata(signature: &str, args: &[Token]) -> Bytes {
+    let mut data = id(signature).to_vec();
+    data.extend(abi::encode(args));
+    data.into()
+}
+
+// Respuestas por (contrato, calldata, bloque).
+type BlockCalls = HashMap<(H160, Bytes, u64), CallResult>;
+
+// Salida calculada a partir de los argumentos codificados; `None` revierte.
+type Responder = Arc<dyn Fn(&[u8]) -> Option<Vec<Token>> + Send + Sync>;
+type ResponderMap = HashMap<(H160, [u8; 4]), Responder>;
+
+/// Respuestas calculadas por (contrato, selector).
+#[derive(Clone, Default)]
+struct Responders(Arc<Mutex<ResponderMap>>);
+
+impl Debug for Responders {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Responders({})", self.0.lock().unwrap().len())
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub(crate) struct MockChain {
+    // Respuestas por (contrato, calldata).
+    calls: Arc<Mutex<HashMap<(H160, Bytes), CallResult>>>,
+    // Respuestas que sólo valen en un bloque concreto; tienen prioridad sobre `calls`.
+    at_block: Arc<Mutex<BlockCalls>>,
+    // Respuestas contra el bloque `pending`; si hay alguna, el nodo expone ese bloque.
+    pending: Arc<Mutex<HashMap<(H160, Bytes), CallResult>>>,
+    // Respuestas en función de los argumentos, para las llamadas sin respuesta fija en `calls`.
+    computed: Responders,
+    // `eth_call`s recibidos (incluidas las sub-llamadas de un multicall), en orden.
+    seen: Arc<Mutex<Vec<(H160, Bytes)>>>,
+    // Bloques por hash (hash -> (número, hash del padre)) para `eth_getBlockByHash`.
+    blocks: Arc<Mutex<HashMap<H256, (u64, H256)>>>,
+    // Retardo de cada respuesta, para medir la latencia de las búsquedas.
+    latency_ms: Arc<AtomicU64>,
+}
+
+impl MockChain {
+    pub(crate) fn new() -> Self {
+        Self::default()
+    }
+
+    /// Cada petición tarda `latency` en responderse.
+    pub(crate) fn with_latency(self, latency: Duration) -> Self {
+        self.latency_ms.store(latency.as_millis() as u64, Ordering::Relaxed);
+        self
+    }
+
+    /// Provider de ethers que usa este nodo como transporte.
+    pub(crate) fn provider(&self) -> Arc<Provider<MockChain>> {
+        Arc::new(Provider::new(self.clone()))
+    }
+
+    /// El bloque `number` con hash `hash` cuelga de `parent_hash` (`eth_getBlockByHash`).
+    pub(crate) fn block(self, number: u64, hash: H256, parent_hash: H256) -> Self {
+        self.blocks.lock().unwrap().insert(hash, (number, parent_hash));
+        self
+    }
+
+    /// `to.signature(args)` devuelve `output` codificado.
+    pub(crate) fn returns(self, to: H160, signature: &str, args: &[Token], output: &[Token]) -> Self {
+        let result = CallResult::Return(abi::encode(output).into());
+        self.calls.lock().unwrap().insert((to, calldata(signature, args)), result);
+        self
+    }
+
+    /// `to.signature(args)` devuelve `output` en las llamadas con el bloque `block`.
+    pub(crate) fn returns_at(self, to: H160, signature: &str, args: &[Token], block: u64, output: &[Token]) -> Self {
+        let result = CallResult::Return(abi::encode(output).into());
+        self.at_block.lock().unwrap().insert((to, calldata(signature, args), block), result);
+        self
+    }
+
+    /// `to.signature(args)` devuelve `output` en las llamadas contra el bloque `pending`.
+    pub(crate) fn returns_pending(self, to: H160, signature: &str, args: &[Token], output: &[Token]) -> Self {
+        let result = CallResult::Return(abi::encode(output).into());
+        self.pending.lock().unwrap().insert((to, calldata(signature, args)), result);
+        self
+    }
+
+    /// `to.signature(args)` revierte con `Error(reason)`.
+    pub(crate) fn reverts(self, to: H160, signature: &str, args: &[Token], reason: &str) -> Self {
+        let data = calldata("Error(string)", &[Token::String(reason.to_string())]);
+        self.calls.lock().unwrap().insert((to, calldata(signature, args)), CallResult::Revert(data));
+        self
+    }
+
+    /// `to.signature(args)` falla con un error del nodo que no es un revert.
+    pub(crate) fn errors(self, to: H160, signature: &str, args: &[Token], message: &str) -> Self {
+        self.calls.lock().unwrap().insert((to, calldata(signature, args)), CallResult::Error(message.to_string()));
+        self
+    }
+
+    pub(crate) fn quote_single(self, variant: DexVariant, token_in: H160, token_out: H160, fee: u32, amount_in: U256, amount_out: U256) -> Self {
+        let quoter = quoter(variant);
+        self.returns(quoter, QUOTE_SINGLE, &quote_single_args(token_in, token_out, fee, amount_in), &[Token::Uint(amount_out)])
+    }
+
+    pub(crate) fn quote_single_reverts(self, variant: DexVariant, token_in: H160, token_out: H160, fee: u32, amount_in: U256) -> Self {
+        let quoter = quoter(variant);
+        self.reverts(quoter, QUOTE_SINGLE, &quote_single_args(token_in, token_out, fee, amount_in), "SPL")
+    }
+
+    pub(crate) fn quote_multi(self, variant: DexVariant, path: Vec<u8>, amount_in: U256, amount_out: U256) -> Self {
+        let args = [Token::Bytes(path), Token::Uint(amount_in)];
+        self.returns(quoter(variant), QUOTE_MULTI, &args, &[Token::Uint(amount_out)])
+    }
+
+    /// `quoteExactInput(path, amountIn)` del quoter de `variant` devuelve `quote(amountIn)` para cualquier
+    /// cantidad; `None` revierte.
+    pub(crate) fn quote_multi_pending(self, variant: DexVariant, path: Vec<u8>, amount_in: U256, amount_out: U256) -> Self {
+        let args = [Token::Bytes(path), Token::Uint(amount_in)];
+        self.returns_pending(quoter(variant), QUOTE_MULTI, &args, &[Token::Uint(amount_out)])
+    }
+
+    pub(crate) fn quote_multi_with(
+        self,
+        variant: DexVariant,
+        path: Vec<u8>,
+        quote: impl Fn(U256) -> Option<U256> + Send + Sync + 'static,
+    ) -> Self {
+        let responder = move |args: &[u8]| {
+            let decoded = abi::decode(&[ParamType::Bytes, ParamType::Uint(256)], args).ok()?;
+            match decoded.as_slice() {
+                [Token::Bytes(requested), Token::Uint(amount_in)] if *requested == path => Some(vec![Token::Uint(quote(*amount_in)?)]),
+                _ => None,
+            }
+        };
+        let key = (quoter(variant), id(QUOTE_MULTI));
+        self.computed.0.lock().unwrap().insert(key, Arc::new(responder));
+        self
+    }
+
+    /// `to.signature(...)` devuelve `respond(args)` con cualquier argumento sin respuesta fija; `None` revierte.
+    pub(crate) fn responds(self, to: H160, signature: &str, respond: impl Fn(&[u8]) -> Option<Vec<Token>> + Send + Sync + 'static) -> Self {
+        self.computed.0.lock().unwrap().insert((to, id(signature)), Arc::new(respond));
+        self
+    }
+
+    pub(crate) fn balance_of(self, token: H160, holder: H160, balance: U256) -> Self {
+        self.returns(token, "balanceOf(address)", &[Token::Address(holder)], &[Token::Uint(balance)])
+    }
+
+    pub(crate) fn balance_of_at(self, token: H160, holder: H160, block: u64, balance: U256) -> Self {
+        self.returns_at(token, "balanceOf(address)", &[Token::Address(holder)], block, &[Token::Uint(balance)])
+    }
+
+    pub(crate) fn decimals(self, token: H160, decimals: u8) -> Self {
+        self.returns(token, "decimals()", &[], &[Token::Uint(decimals.into())])
+    }
+
+    /// Atributos de un pool V3: factory, tokens, fee y liquidez.
+    pub(crate) fn pool(self, pool: H160, factory: H160, token0: H160, token1: H160, fee: u32, liquidity: u128) -> Self {
+        self.returns(pool, "factory()", &[], &[Token::Address(factory)])
+            .returns(pool, "token0()", &[], &[Token::Address(token0)])
+            .returns(pool, "token1()", &[], &[Token::Address(token1)])
+            .returns(pool, "fee()", &[], &[Token::Uint(fee.into())])
+            .returns(pool, "liquidity()", &[], &[Token::Uint(liquidity.into())])
+    }
+
+    pub(crate) fn slot0(self, pool: H160, sqrt_price_x96: U256, tick: i32, unlocked: bool) -> Self {
+        let output = [
+            Token::Uint(sqrt_price_x96),
+            Token::Int(I256::from(tick).into_raw()),
+            Token::Uint(0.into()),
+            Token::Uint(1.into()),
+            Token::Uint(1.into()),
+            Token::Uint(0.into()),
+            Token::Bool(unlocked),
+        ];
+        self.returns(pool, "slot0()", &[], &output)
+    }
+
+    /// Lectura de un feed estilo Chainlink: `latestRoundData()` con la respuesta y su `updatedAt`.
+    pub(crate) fn latest_round_data(self, feed: H160, answer: i64, updated_at: u64) -> Self {
+        let output = [
+            Token::Uint(1.into()),
+            Token::Int(I256::from(answer).into_raw()),
+            Token::Uint(updated_at.into()),
+            Token::Uint(updated_at.into()),
+            Token::Uint(1.into()),
+        ];
+        self.returns(feed, "latestRoundData()", &[], &output)
+    }
+
+    /// `eth_call`s recibidos con el selector de `signature`.
+    pub(crate) fn calls_to(&self, signature: &str) -> usize {
+        let selector = &id(signature)[..];
+        self.seen.lock().unwrap().iter().filter(|(_, data)| data.get(..4) == Some(selector)).count()
+    }
+
+    fn call(&self, to: H160, data: Bytes, block: Option<BlockNumber>) -> CallResult {
+        self.seen.lock().unwrap().push((to, data.clone()));
+        let pinned = match block {
+            Some(BlockNumber::Number(number)) => self.at_block.lock().unwrap().get(&(to, data.clone(), number.as_u64())).cloned(),
+            Some(BlockNumber::Pending) => self.pending.lock().unwrap().get(&(to, data.clone())).cloned(),
+            _ => None,
+        };
+        if let Some(result) = pinned {
+            return result;
+        }
+        if data.get(..4) == Some(&id(AGGREGATE3)[..]) {
+            return self.aggregate3(&data[4..], block);
+        }
+        if let Some(result) = self.calls.lock().unwrap().get(&(to, data.clone())).cloned() {
+            return result;
+        }
+        let responder = data.get(..4).and_then(|selector| self.computed.0.lock().unwrap().get(&(to, selector.try_into().ok()?)).cloned());
+        match responder.and_then(|respond| respond(&data[4..])) {
+            Some(output) => CallResult::Return(abi::encode(&output).into()),
+            None => CallResult::Revert(Bytes::new()),
+        }
+    }
+
+    fn aggregate3(&self, args: &[u8], block: Option<BlockNumber>) -> CallResult {
+        let call_type = ParamType::Tuple(vec![ParamType::Address, ParamType::Bool, ParamType::Bytes]);
+        let Ok(mut decoded) = abi::decode(&[ParamType::Array(Box::new(call_type))], args) else {
+            return CallResult::Revert(Bytes::new());
+        };
+        let calls = decoded.pop().and_then(Token::into_array).unwrap_or_default();
+        let results = calls
+            .into_iter()
+            .filter_map(Token::into_tuple)
+            .map(|call| {
+                let mut call = call.into_iter();
+                let target = call.next().and_then(Token::into_address).unwrap_or_default();
+                let data = call.nth(1).and_then(Token::into_bytes).unwrap_or_default();
+                let (success, data) = match self.call(target, data.into(), block) {
+                    CallResult::Return(data) => (true, data),
+                    CallResult::Revert(data) => (false, data),
+                    CallResult::Error(_) => (false, Bytes::new()),
+                };
+                Token::Tuple(vec![Token::Bool(success), Token::Bytes(data.to_vec())])
+            })
+            .collect();
+        CallResult::Return(abi::encode(&[Token::Array(results)]).into())
+    }
+
+    fn respond(&self, method: &str, params: &Value) -> Result<Value, MockError> {
+        if method == "eth_getBlockByNumber" {
+            // Sólo se expone el bloque `pending`, y sólo si hay respuestas registradas para él.
+            let exposed = params[0] == "pending" && !self.pending.lock().unwrap().is_empty();
+            return Ok(if exposed { json!({ "number": null, "hash": null }) } else { Value::Null });
+        }
+        if method == "eth_getBlockByHash" {
+            let hash: H256 = serde_json::from_value(params[0].clone())?;
+            let block = self.blocks.lock().unwrap().get(&hash).copied();
+            return Ok(block.map_or(Value::Null, |(number, parent)| json!({ "number": U64::from(number), "hash": hash, "parentHash": parent })));
+        }
+        if method != "eth_call" && method != "eth_estimateGas" {
+            return Err(MockError::EmptyResponses);
+        }
+        let tx = &params[0];
+        let to: H160 = serde_json::from_value(tx["to"].clone())?;
+        let data: Bytes = serde_json::from_value(if tx["input"].is_null() { tx["data"].clone() } else { tx["input"].clone() })?;
+        // Bloque explícito en hexadecimal o `pending`; "latest" y similares usan las respuestas generales.
+        let block = params[1].as_str().and_then(|tag| match tag {
+            "pending" => Some(BlockNumber::Pending),
+            _ => u64::from_str_radix(tag.strip_prefix("0x")?, 16).ok().map(|n| BlockNumber::Number(n.into())),
+        });
+        match self.call(to, data, block) {
+            // Una estimación que no revierte cuesta siempre `ESTIMATED_GAS`.
+            CallResult::Return(_) if method == "eth_estimateGas" => Ok(json!(U256::from(ESTIMATED_GAS))),
+            CallResult::Return(data) => Ok(json!(data)),
+            CallResult::Revert(data) => Err(MockError::JsonRpcError(JsonRpcError {
+                code: 3,
+                message: "execution reverted".to_string(),
+                data: Some(json!(data)),
+            })),
+            CallResult::Error(message) => Err(MockError::JsonRpcError(JsonRpcError { code: -32000, message, data: None })),
+        }
+    }
+}
+
+/// Quoter de `variant` en la configuración de test (Arbitrum), aunque se construya el nodo fuera de una
+/// instancia de test.
+fn quoter(variant: DexVariant) -> H160 {
+    crate::config::init_test_env();
+    simulator::get_quoter_address(variant)
+}
+
+/// Gas que devuelve `eth_estimateGas` para las llamadas que no revierten.
+pub(crate) const ESTIMATED_GAS: u64 = 200_000;
+pub(crate) const QUOTE_SINGLE: &str = "quoteExactInputSingle(address,address,uint24,uint256,uint160)";
+pub(crate) const QUOTE_MULTI: &str = "quoteExactInput(bytes,uint256)";
+const AGGREGATE3: &str = "aggregate3((address,bool,bytes)[])";
+
+fn quote_single_args(token_in: H160, token_out: H160, fee: u32, amount_in: U256) -> [Token; 5] {
+    [
+        Token::Address(token_in),
+        Token::Address(token_out),
+        Token::Uint(fee.into()),
+        Token::Uint(amount_in),
+        Token::Uint(U256::zero()),
+    ]
+}
+
+#[async_trait]
+impl JsonRpcClient for MockChain {
+    type Error = MockError;
+
+    async fn request<T, R>(&self, method: &str, params: T) -> Result<R, MockError>
+    where
+        T: Debug + Serialize + Send + Sync,
+        R: DeserializeOwned + Send,
+    {
+        let params = serde_json::to_value(params)?;
+        let latency_ms = self.latency_ms.load(Ordering::Relaxed);
+        if latency_ms > 0 {
+            tokio::time::sleep(Duration::from_millis(latency_ms)).await;
+        }
+        Ok(serde_json::from_value(self.respond(method, &params)?)?)
+    }
+}
+
+type RpcHandler = Arc<dyn Fn(&str, &Value) -> Result<Value, String> + Send + Sync>;
+
+/// Nodo JSON-RPC sobre HTTP para el código atado a `Provider<Http>` (firma y envío de TX). Cada
+/// petición se responde con `respond(método, params)`: `Err` es un error del nodo. Las peticiones
+/// quedan registradas en orden.
+pub(crate) struct FakeNode {
+    pub(crate) url: String,
+    state: Arc<NodeState>,
+}
+
+/// Estado compartido con las conexiones del nodo.
+#[derive(Default)]
+struct NodeState {
+    requests: Mutex<Vec<(String, Value)>>,
+    latency_ms: AtomicU64,
+    // Peticiones recibidas y aún sin responder, y su máximo.
+    pending: AtomicUsize,
+    peak_pending: AtomicUsize,
+}
+
+impl FakeNode {
+    pub(crate) async fn start(respond: impl Fn(&str, &Value) -> Result<Value, String> + Send + Sync + 'static) -> Self {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let state = Arc::new(NodeState::default());
+        let (shared, respond): (_, RpcHandler) = (state.clone(), Arc::new(respond));
+        tokio::spawn(async move {
+            while let Ok((socket, _)) = listener.accept().await {
+                tokio::spawn(serve_rpc(socket, shared.clone(), respond.clone()));
+            }
+        });
+        Self { url, state }
+    }
+
+    /// Retrasa cada respuesta `latency`, para que las peticiones concurrentes se solapen.
+    pub(crate) fn with_latency(self, latency: Duration) -> Self {
+        self.state.latency_ms.store(latency.as_millis() as u64, Ordering::Relaxed);
+        self
+    }
+
+    /// Cliente firmado (la clave de `init_test_env`) contra este nodo.
+    pub(crate) fn client(&self) -> Arc<SignerMiddleware<Provider<Http>, LocalWallet>> {
+        crate::config::init_test_env();
+        let wallet: LocalWallet = std::env::var("PRIVATE_KEY").unwrap().parse().unwrap();
+        let provider = Provider::<Http>::try_from(self.url.as_str()).unwrap();
+        Arc::new(SignerMiddleware::new(provider, wallet.with_chain_id(42161u64)))
+    }
+
+    /// Parámetros de cada petición recibida con `method`, en orden.
+    pub(crate) fn requests(&self, method: &str) -> Vec<Value> {
+        self.state.requests.lock().unwrap().iter().filter(|(m, _)| m == method).map(|(_, p)| p.clone()).collect()
+    }
+
+    /// Máximo de peticiones que el nodo llegó a tener a la vez sin responder.
+    pub(crate) fn peak_pending(&self) -> usize {
+        self.state.peak_pending.load(Ordering::Relaxed)
+    }
+}
+
+/// Atiende las peticiones HTTP de una conexión (keep-alive) hasta que el cliente la cierre.
+async fn serve_rpc(mut socket: tokio::net::TcpStream, state: Arc<NodeState>, respond: RpcHandler) {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    let mut buffer = Vec::new();
+    let mut chunk = [0u8; 4096];
+    loop {
+        let Some(head_end) = buffer.windows(4).position(|w| w == b"\r\n\r\n") else {
+            match socket.read(&mut chunk).await {
+                Ok(0) | Err(_) => return,
+                Ok(read) => buffer.extend_from_slice(&chunk[..read]),
+            }
+            continue;
+        };
+        let head = String::from_utf8_lossy(&buffer[..head_end]).to_ascii_lowercase();
+        let length = head
+            .lines()
+            .find_map(|line| line.strip_prefix("content-length:").and_then(|v| v.trim().parse::<usize>().ok()))
+            .unwrap_or(0);
+        if buffer.len() < head_end + 4 + length {
+            match socket.read(&mut chunk).await {
+                Ok(0) | Err(_) => return,
+                Ok(read) => buffer.extend_from_slice(&chunk[..read]),
+            }
+            continue;
+        }
+        let body: Value = serde_json::from_slice(&buffer[head_end + 4..head_end + 4 + length]).unwrap_or_default();
+        buffer.drain(..head_end + 4 + length);
+        let method = body["method"].as_str().unwrap_or_default().to_string();
+        state.requests.lock().unwrap().push((method.clone(), body["params"].clone()));
+        let pending = state.pending.fetch_add(1, Ordering::SeqCst) + 1;
+        state.peak_pending.fetch_max(pending, Ordering::SeqCst);
+        tokio::time::sleep(Duration::from_millis(state.latency_ms.load(Ordering::Relaxed))).await;
+        state.pending.fetch_sub(1, Ordering::SeqCst);
+        let reply = match respond(&method, &body["params"]) {
+            Ok(result) => json!({ "jsonrpc": "2.0", "id": body["id"], "result": result }),
+            Err(message) => json!({ "jsonrpc": "2.0", "id": body["id"], "error": { "code": -32000, "message": message } }),
+        };
+        let reply = reply.to_string();
+        let response = format!("HTTP/1.1 200 OK\r\ncontent-type: application/json\r\ncontent-length: {}\r\n\r\n{reply}", reply.len());
+        if socket.write_all(response.as_bytes()).await.is_err() { return; }
+    }
+}
@@ -1,7 +1,9 @@
-use anyhow::Result;
+use anyhow::{anyhow, Result};
 use chrono::Local;
 use fern::colors::{Color, ColoredLevelConfig};
-use log::LevelFilter;
+use log::{warn, LevelFilter};
+use prometheus::{Encoder, TextEncoder};
+use std::{future::Future, time::Duration};
 
 /// Configura el logger global para la aplicación.
 /// Esto nos permite ver los logs (info, warn, error) en la consola de una manera
@@ -37,3 +39,95 @@ pub fn setup_logger() -> Result<()> {
 
     Ok(())
 }
+
+/// Envía el registro global de Prometheus a un Pushgateway (`PUT {url}/metrics/job/{job}`).
+/// Pensado para ejecuciones cortas (backtests, dry-runs) que terminan antes de ser scrapeadas.
+pub async fn push_metrics(url: &str, job: &str) -> Result<()> {
+    let mut buffer = Vec::new();
+    TextEncoder::new().encode(&prometheus::gather(), &mut buffer)?;
+    let endpoint = format!("{}/metrics/job/{}", url.trim_end_matches('/'), job);
+    let response = reqwest::Client::new()
+        .put(&endpoint)
+        .header(reqwest::header::CONTENT_TYPE, TextEncoder::new().format_type())
+        .body(buffer)
+        .send()
+        .await?;
+    if !response.status().is_success() {
+        return Err(anyhow!("Pushgateway respondió {} en {}", response.status(), endpoint));
+    }
+    Ok(())
+}
+
+/// Empuja las métricas cada `interval` de forma indefinida. Los fallos se avisan y no detienen el bot.
+pub async fn run_metrics_pusher(url: String, job: String, interval: Duration) {
+    let mut ticker = tokio::time::interval(interval);
+    loop {
+        ticker.tick().await;
+        if let Err(e) = push_metrics(&url, &job).await {
+            warn!("No se pudieron empujar las métricas al Pushgateway: {e:?}");
+        }
+    }
+}
+
+/// Ejecuta `run` y, termine como termine, empuja un último snapshot de métricas si hay Pushgateway
+/// configurado: las ejecuciones cortas registran sus contadores aunque fallen.
+pub async fn with_final_metrics_push<T>(run: impl Future<Output = Result<T>>) -> Result<T> {
+    let result = run.await;
+    if let Some(url) = &crate::config::CONFIG.pushgateway_url {
+        if let Err(e) = push_metrics(url, &crate::config::CONFIG.pushgateway_job).await {
+            warn!("No se pudo empujar el último snapshot de métricas: {e:?}");
+        }
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::instance::with_test_config;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    /// Pushgateway de una sola petición: responde 200 y devuelve el cuerpo recibido.
+    async fn fake_pushgateway() -> (String, tokio::task::JoinHandle<String>) {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let handle = tokio::spawn(async move {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let mut request = Vec::new();
+            let mut chunk = [0u8; 4096];
+            loop {
+                let read = socket.read(&mut chunk).await.unwrap();
+                request.extend_from_slice(&chunk[..read]);
+                let text = String::from_utf8_lossy(&request).to_string();
+                if let Some((headers, body)) = text.split_once("\r\n\r\n") {
+                    let length = headers
+                        .lines()
+                        .find_map(|l| l.to_ascii_lowercase().strip_prefix("content-length:").map(|v| v.trim().parse::<usize>().unwrap()))
+                        .unwrap_or(0);
+                    if body.len() >= length || read == 0 {
+                        socket.write_all(b"HTTP/1.1 200 OK\r\ncontent-length: 0\r\n\r\n").await.unwrap();
+                        return body.to_string();
+                    }
+                }
+            }
+        });
+        (url, handle)
+    }
+
+    #[tokio::test]
+    async fn failed_runs_still_push_their_metrics() {
+        let counter = prometheus::register_int_counter_vec!("push_test_runs_total", "Contador de prueba", &["chain"]).unwrap();
+        let (url, gateway) = fake_pushgateway().await;
+        let result = with_test_config(|c| c.pushgateway_url = Some(url), async {
+            with_final_metrics_push(async {
+                crate::instance::labeled(&counter).inc_by(3);
+                Err::<(), _>(anyhow!("replay fallido"))
+            })
+            .await
+        })
+        .await;
+        assert_eq!(result.unwrap_err().to_string(), "replay fallido");
+        let body = gateway.await.unwrap();
+        assert!(body.contains("push_test_runs_total{chain=\"test\"} 3"), "{body}");
+    }
+}